@@ -1,9 +1,11 @@
-use core::cell::RefCell;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 use core::mem;
 use core::ptr;
 use crate::{Result, UsbDirection, UsbError};
-use crate::endpoint::{Endpoint, EndpointDirection, EndpointType, EndpointAddress};
+use crate::endpoint::{
+    Endpoint, EndpointDirection, EndpointType, EndpointAddress, IsochronousSynchronizationType,
+    IsochronousUsageType};
 
 /// A trait for device-specific USB peripherals. Implement this to add support for a new hardware
 /// platform.
@@ -26,7 +28,7 @@ pub trait UsbBus: Sync + Sized {
     ///   attempt to return an endpoint with the specified address. If None, the implementation
     ///   should return the next available one.
     /// * `max_packet_size` - Maximum packet size in bytes.
-    /// * `interval` - Polling interval parameter for interrupt endpoints.
+    /// * `interval` - Polling interval parameter for interrupt and isochronous endpoints.
     ///
     /// # Errors
     ///
@@ -43,6 +45,34 @@ pub trait UsbBus: Sync + Sized {
         max_packet_size: u16,
         interval: u8) -> Result<EndpointAddress>;
 
+    /// Gets the maximum packet size an endpoint was actually allocated with by
+    /// [`alloc_ep`](UsbBus::alloc_ep), if the peripheral rounded up or clamped the requested size
+    /// to one supported by the hardware. This is called right after allocating the endpoint, and
+    /// the value is returned by [`Endpoint::max_packet_size`] and written to the endpoint
+    /// descriptor.
+    ///
+    /// The default implementation returns `None`, which means the requested size was used as is.
+    fn allocated_packet_size(&self, ep_addr: EndpointAddress) -> Option<u16> {
+        let _ = ep_addr;
+        None
+    }
+
+    /// Frees an endpoint previously allocated with [`alloc_ep`](UsbBus::alloc_ep) so that it can be
+    /// allocated again. This is only called when rolling back a failed allocation with
+    /// [`UsbBusAllocator::rollback`], and can only be called before [`enable`](UsbBus::enable) is
+    /// called.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - This UsbBus implementation doesn't support
+    ///   freeing endpoints.
+    fn free_ep(&mut self, ep_addr: EndpointAddress) -> Result<()> {
+        let _ = ep_addr;
+        Err(UsbError::Unsupported)
+    }
+
     /// Enables and initializes the USB peripheral. Soon after enabling the device will be reset, so
     /// there is no need to perform a USB reset in this method.
     fn enable(&mut self);
@@ -52,6 +82,11 @@ pub trait UsbBus: Sync + Sized {
     fn reset(&self);
 
     /// Sets the device USB address to `addr`.
+    ///
+    /// By default this is called after the status stage of the SET_ADDRESS request has completed,
+    /// so the new address should take effect immediately. See
+    /// [`QUIRK_SET_ADDRESS_BEFORE_STATUS`](UsbBus::QUIRK_SET_ADDRESS_BEFORE_STATUS) for peripherals
+    /// that handle the timing themselves.
     fn set_device_address(&self, addr: u8);
 
     /// Writes a single packet of data to the specified endpoint and returns number of bytes
@@ -79,6 +114,11 @@ pub trait UsbBus: Sync + Sized {
     ///
     /// This should also clear any NAK flags and prepare the endpoint to receive the next packet.
     ///
+    /// SETUP packets are read with this method as well, and whether the next packet is a SETUP
+    /// packet is reported by the `ep_setup` field of [`PollResult::Data`]. If a SETUP packet and an
+    /// OUT packet that was received after it are both pending, the SETUP packet must be returned
+    /// first so that the OUT packet is processed as the data stage of the new transfer.
+    ///
     /// # Errors
     ///
     /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - The `ep_addr` does not point to a
@@ -90,20 +130,71 @@ pub trait UsbBus: Sync + Sized {
     ///   fit in `buf`. This is generally an error in the class implementation, because the class
     ///   should use a buffer that is large enough for the `max_packet_size` it specified when
     ///   allocating the endpoint.
+    /// * [`Overflow`](crate::UsbError::Overflow) - The host sent more data than the maximum packet
+    ///   size of the endpoint, if the peripheral can detect this. The packet must be discarded and
+    ///   the endpoint prepared to receive the next packet as usual.
     ///
     /// Implementations may also return other errors if applicable.
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize>;
 
     /// Sets or clears the STALL condition for an endpoint. If the endpoint is an OUT endpoint, it
     /// should be prepared to receive data again.
+    ///
+    /// Clearing the STALL condition must also reset the data toggle of the endpoint to DATA0, as
+    /// required for CLEAR_FEATURE(ENDPOINT_HALT) by the USB specification, even if the endpoint was
+    /// not stalled.
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool);
 
+    /// Changes the maximum packet size of an endpoint after allocation, for example when an
+    /// alternate setting with a different packet size is selected. The new size is never larger
+    /// than the size the endpoint was allocated with.
+    ///
+    /// The default implementation just returns `Unsupported`, which is fine for peripherals where
+    /// the allocated size only limits the packet size.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - This UsbBus implementation doesn't support
+    ///   changing the packet size.
+    fn set_ep_packet_size(&self, ep_addr: EndpointAddress, max_packet_size: u16) -> Result<()> {
+        let _ = (ep_addr, max_packet_size);
+        Err(UsbError::Unsupported)
+    }
+
     /// Gets whether the STALL condition is set for an endpoint.
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool;
 
+    /// Gets whether an endpoint is double buffered (ping-pong buffered), so that it can hold two
+    /// packets at a time. For an IN endpoint this means [`write`](UsbBus::write) accepts a second
+    /// packet while the first one is still waiting to be sent, and only returns `WouldBlock` once
+    /// both buffers are full. For an OUT endpoint the host can send a second packet before the
+    /// first one has been read.
+    ///
+    /// A single IN complete event may then be reported for both packets if they were sent before
+    /// the device was polled.
+    ///
+    /// The default implementation returns `false`, which means the endpoint is single buffered.
+    fn is_double_buffered(&self, ep_addr: EndpointAddress) -> bool {
+        let _ = ep_addr;
+        false
+    }
+
+    /// Gets the offset of the buffer of an allocated endpoint in the packet memory or FIFO RAM of
+    /// the peripheral, for debugging the memory layout. The meaning of the offset is specific to
+    /// the peripheral, for example it may be in bytes or words and from the start of a shared
+    /// packet memory or of a per-endpoint FIFO area.
+    ///
+    /// The default implementation returns `None`, which is also appropriate for peripherals that
+    /// don't have configurable endpoint memory.
+    fn ep_fifo_offset(&self, ep_addr: EndpointAddress) -> Option<u16> {
+        let _ = ep_addr;
+        None
+    }
+
     /// Causes the USB peripheral to enter USB suspend mode, lowering power consumption and
     /// preparing to detect a USB wakeup event. This will be called after
-    /// [`poll`](crate::device::UsbDevice::poll) returns [`PollResult::Suspend`]. The device will
+    /// [`poll`](crate::device::UsbDevice::poll) returns [`PollResult::Suspend`] or
+    /// [`PollResult::Sleep`]. The device will
     /// continue be polled, and it shall return a value other than `Suspend` from `poll` when it no
     /// longer detects the suspend condition.
     fn suspend(&self);
@@ -112,6 +203,62 @@ pub trait UsbBus: Sync + Sized {
     /// suspended.
     fn resume(&self);
 
+    /// Signals remote wakeup to the host by driving resume signaling on the bus, which asks the host
+    /// to resume the suspended bus. This is called by
+    /// [`UsbDevice::remote_wakeup`](crate::device::UsbDevice::remote_wakeup), which checks that the
+    /// host has enabled remote wakeup and the bus is suspended. The implementation is responsible
+    /// for the timing of the resume signaling, and the bus is resumed as usual when `poll` reports
+    /// [`PollResult::Resume`].
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - This UsbBus implementation doesn't support
+    ///   remote wakeup.
+    fn remote_wakeup(&self) -> Result<()> {
+        Err(UsbError::Unsupported)
+    }
+
+    /// Sets whether the peripheral acknowledges Link Power Management (LPM) transactions, with
+    /// which a USB 2.0 host puts the device into the L1 sleep state. An acknowledged transaction
+    /// must be reported by `poll` as [`PollResult::Sleep`], and the exit from L1 as
+    /// [`PollResult::Resume`]. While disabled, the peripheral must not acknowledge LPM
+    /// transactions, so that the host falls back to regular suspend.
+    ///
+    /// LPM should only be enabled if it is also advertised to the host with
+    /// [`BosWriter::lpm`](crate::descriptor::BosWriter::lpm). This is called by
+    /// [`UsbDevice::set_lpm_enabled`](crate::device::UsbDevice::set_lpm_enabled).
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - This UsbBus implementation doesn't support
+    ///   LPM.
+    fn set_lpm_enabled(&self, enabled: bool) -> Result<()> {
+        let _ = enabled;
+        Err(UsbError::Unsupported)
+    }
+
+    /// Gets the frame number from the most recent start-of-frame (SOF) packet, for classes that
+    /// need to schedule isochronous or otherwise time-synchronized data. The frame number is an
+    /// 11-bit counter, so it wraps from 2047 back to 0. At high speed all 8 microframes of a frame
+    /// have the same number.
+    ///
+    /// The value may not advance if the peripheral doesn't track SOF packets, for example while
+    /// the bus is suspended.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - This UsbBus implementation doesn't
+    ///   provide the frame number.
+    fn frame_number(&self) -> Result<u16> {
+        Err(UsbError::Unsupported)
+    }
+
     /// Gets information about events and incoming data. Usually called in a loop or from an
     /// interrupt handler. See the [`PollResult`] struct for more information.
     fn poll(&self) -> PollResult;
@@ -119,7 +266,10 @@ pub trait UsbBus: Sync + Sized {
     /// Simulates a disconnect from the USB bus, causing the host to reset and re-enumerate the
     /// device.
     ///
-    /// The default implementation just returns `Unsupported`.
+    /// This is usually done by disabling the D+ (or D- for low speed) pull-up resistor for long
+    /// enough for the host to notice, and then enabling it again. Peripherals without control over
+    /// the pull-up can't support this and should keep the default implementation, which just
+    /// returns `Unsupported`.
     ///
     /// # Errors
     ///
@@ -129,16 +279,152 @@ pub trait UsbBus: Sync + Sized {
         Err(UsbError::Unsupported)
     }
 
+    /// Gets the speed the device is currently operating at. The speed is determined during the bus
+    /// reset, so the value is only meaningful after a reset has been reported by `poll`.
+    ///
+    /// The default implementation returns [`UsbSpeed::Full`], which is correct for peripherals that
+    /// only support full speed.
+    fn speed(&self) -> UsbSpeed {
+        UsbSpeed::Full
+    }
+
     /// Indicates that `set_device_address` must be called before accepting the corresponding
     /// control transfer, not after.
     ///
     /// The default value for this constant is `false`, which corresponds to the USB 2.0 spec, 9.4.6
+    /// where the device keeps using the old address until the status stage of the SET_ADDRESS
+    /// request has completed. Set it to `true` for peripherals that latch the new address
+    /// themselves and only apply it once the status stage is finished, because on those calling
+    /// `set_device_address` after the status stage would be too late.
     const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = false;
 }
 
+/// Chooses the endpoint numbers of endpoints that are allocated without a specific address.
+///
+/// The default strategy is [`LowestAvailable`]. Hardware that has constraints on which endpoint
+/// numbers can be used for which transfer types can use a custom strategy, set with
+/// [`UsbBusAllocator::set_allocation_strategy`], and buses that prefer to choose the number
+/// themselves can use [`BusChooses`]. The bus still has the final say and may reject the chosen
+/// number in [`UsbBus::alloc_ep`].
+pub trait EndpointAllocationStrategy {
+    /// Returns the endpoint number to allocate for a new endpoint, or `None` to leave the choice
+    /// to the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `ep_dir` - The endpoint direction.
+    /// * `ep_type` - The endpoint transfer type.
+    /// * `allocated` - Bit mask of the endpoint numbers already allocated or reserved in the same
+    ///   direction, where bit `n` is set if endpoint number `n` is in use. Endpoint 0 is only
+    ///   allocated after the classes, when the device is built.
+    fn endpoint_number(&self, ep_dir: UsbDirection, ep_type: EndpointType, allocated: u16)
+        -> Option<usize>;
+}
+
+/// Leaves the choice of the endpoint number to the bus by always passing `None` to
+/// [`UsbBus::alloc_ep`].
+pub struct BusChooses;
+
+impl EndpointAllocationStrategy for BusChooses {
+    fn endpoint_number(&self, _ep_dir: UsbDirection, _ep_type: EndpointType, _allocated: u16)
+        -> Option<usize>
+    {
+        None
+    }
+}
+
+/// Allocates the lowest endpoint number that isn't in use or reserved in the same direction,
+/// starting from 1.
+pub struct LowestAvailable;
+
+impl EndpointAllocationStrategy for LowestAvailable {
+    fn endpoint_number(&self, _ep_dir: UsbDirection, _ep_type: EndpointType, allocated: u16)
+        -> Option<usize>
+    {
+        (1..16).find(|&i| (allocated & (1 << i)) == 0)
+    }
+}
+
+#[derive(Copy, Clone)]
 struct AllocatorState {
     next_interface_number: u8,
     next_string_index: u8,
+    ep_out_mask: u16,
+    ep_in_mask: u16,
+    ep_out_reserved: u16,
+    ep_in_reserved: u16,
+}
+
+impl AllocatorState {
+    fn masks(&mut self, ep_dir: UsbDirection) -> (&mut u16, &mut u16) {
+        match ep_dir {
+            UsbDirection::Out => (&mut self.ep_out_mask, &mut self.ep_out_reserved),
+            UsbDirection::In => (&mut self.ep_in_mask, &mut self.ep_in_reserved),
+        }
+    }
+}
+
+/// A snapshot of the allocation state of a [`UsbBusAllocator`] created with
+/// [`UsbBusAllocator::checkpoint`].
+#[derive(Copy, Clone)]
+pub struct AllocatorCheckpoint {
+    state: AllocatorState,
+}
+
+/// A set of endpoint addresses, such as the endpoints that belong to a class. Created with
+/// [`UsbBusAllocator::endpoints_since`] or built up with [`insert`](EndpointSet::insert).
+///
+/// A class can use this to check which of the endpoint events passed to
+/// [`UsbClass::endpoint_out`](crate::class::UsbClass::endpoint_out) and
+/// [`UsbClass::endpoint_in_complete`](crate::class::UsbClass::endpoint_in_complete) are meant for
+/// it, and code that wraps other classes can do the same without knowing their endpoints.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct EndpointSet {
+    ep_out_mask: u16,
+    ep_in_mask: u16,
+}
+
+impl EndpointSet {
+    /// Creates an empty set.
+    pub fn new() -> EndpointSet {
+        EndpointSet::default()
+    }
+
+    fn mask(&self, ep_dir: UsbDirection) -> u16 {
+        match ep_dir {
+            UsbDirection::Out => self.ep_out_mask,
+            UsbDirection::In => self.ep_in_mask,
+        }
+    }
+
+    /// Adds an endpoint address to the set.
+    pub fn insert(&mut self, ep_addr: EndpointAddress) {
+        let bit = 1 << ep_addr.index();
+
+        match ep_addr.direction() {
+            UsbDirection::Out => self.ep_out_mask |= bit,
+            UsbDirection::In => self.ep_in_mask |= bit,
+        }
+    }
+
+    /// Returns true if the set contains the endpoint address.
+    pub fn contains(&self, ep_addr: EndpointAddress) -> bool {
+        self.mask(ep_addr.direction()) & (1 << ep_addr.index()) != 0
+    }
+
+    /// Returns true if the set contains no endpoints.
+    pub fn is_empty(&self) -> bool {
+        self.ep_out_mask == 0 && self.ep_in_mask == 0
+    }
+
+    /// Iterates over the endpoint addresses in the set, OUT endpoints first, each direction in
+    /// ascending order of endpoint number.
+    pub fn iter(&self) -> impl Iterator<Item = EndpointAddress> + '_ {
+        [UsbDirection::Out, UsbDirection::In].iter()
+            .flat_map(move |&dir| (0..16)
+                .filter(move |&i| self.mask(dir) & (1 << i) != 0)
+                .map(move |i| EndpointAddress::from_parts(i, dir)))
+    }
 }
 
 /// Helper type used for UsbBus resource allocation and initialization.
@@ -146,6 +432,10 @@ pub struct UsbBusAllocator<B: UsbBus> {
     bus: RefCell<B>,
     bus_ptr: AtomicPtr<B>,
     state: RefCell<AllocatorState>,
+    strategy: Cell<&'static dyn EndpointAllocationStrategy>,
+    // Whether the IN endpoint with each number still has to end its transfer with a zero-length
+    // packet, shared by the endpoints and the device
+    zlp_pending: [AtomicBool; 16],
 }
 
 impl<B: UsbBus> UsbBusAllocator<B> {
@@ -158,10 +448,37 @@ impl<B: UsbBus> UsbBusAllocator<B> {
             state: RefCell::new(AllocatorState {
                 next_interface_number: 0,
                 next_string_index: 4,
+                ep_out_mask: 0,
+                ep_in_mask: 0,
+                ep_out_reserved: 0,
+                ep_in_reserved: 0,
             }),
+            strategy: Cell::new(&LowestAvailable),
+            zlp_pending: [(); 16].map(|_| AtomicBool::new(false)),
         }
     }
 
+    /// Sets the strategy used to choose endpoint numbers for endpoints that are allocated without
+    /// a specific address. This only affects endpoints allocated after it is called.
+    ///
+    /// Default: [`LowestAvailable`]
+    pub fn set_allocation_strategy(&self, strategy: &'static dyn EndpointAllocationStrategy) {
+        self.strategy.set(strategy);
+    }
+
+    // Returns the masks of allocated OUT and IN endpoints, in which bit n is set if the endpoint
+    // with index n is allocated.
+    pub(crate) fn endpoint_masks(&self) -> (u16, u16) {
+        let state = self.state.borrow();
+        (state.ep_out_mask, state.ep_in_mask)
+    }
+
+    // Returns the flags of the IN endpoints that have a zero-length packet queued by
+    // Endpoint::write_packet_terminated, indexed by endpoint number.
+    pub(crate) fn zlp_pending(&self) -> &[AtomicBool; 16] {
+        &self.zlp_pending
+    }
+
     pub(crate) fn freeze(&self) -> &B {
         // Prevent further allocation by borrowing the allocation state permanently.
         mem::forget(self.state.borrow_mut());
@@ -186,6 +503,11 @@ impl<B: UsbBus> UsbBusAllocator<B> {
     }
 
     /// Allocates a new interface number.
+    ///
+    /// Interface numbers are handed out sequentially starting from zero, and
+    /// [`InterfaceNumber`] can only be created by this method, so classes in a composite device
+    /// can never use colliding numbers as long as they write their interface descriptors with the
+    /// numbers they allocated.
     pub fn interface(&self) -> InterfaceNumber {
         let mut state = self.state.borrow_mut();
         let number = state.next_interface_number;
@@ -203,10 +525,128 @@ impl<B: UsbBus> UsbBusAllocator<B> {
         StringIndex(index)
     }
 
+    /// Creates a checkpoint of the current allocation state that can be later restored with
+    /// [`rollback`](UsbBusAllocator::rollback).
+    pub fn checkpoint(&self) -> AllocatorCheckpoint {
+        AllocatorCheckpoint {
+            state: *self.state.borrow(),
+        }
+    }
+
+    /// Gets the endpoints allocated after `checkpoint` was created. Taking a checkpoint before
+    /// creating a class and calling this afterwards gives the set of endpoints that belong to the
+    /// class, without the class having to report them.
+    pub fn endpoints_since(&self, checkpoint: AllocatorCheckpoint) -> EndpointSet {
+        let state = self.state.borrow();
+
+        EndpointSet {
+            ep_out_mask: state.ep_out_mask & !checkpoint.state.ep_out_mask,
+            ep_in_mask: state.ep_in_mask & !checkpoint.state.ep_in_mask,
+        }
+    }
+
+    /// Rolls back all allocations made after `checkpoint` was created, so that allocation can be
+    /// retried, for example with fewer classes or smaller endpoints. Interface numbers and string
+    /// indices are returned to the allocator, and endpoints are freed with [`UsbBus::free_ep`].
+    ///
+    /// Endpoints, interface numbers and string indices allocated after the checkpoint must not be
+    /// used after rolling back. Usually this means dropping the classes that were being created
+    /// when allocation failed.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The bus doesn't support freeing endpoints.
+    ///   In this case allocation failures are not recoverable, and the bus and allocator must be
+    ///   recreated in order to try again.
+    pub fn rollback(&self, checkpoint: AllocatorCheckpoint) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let mut bus = self.bus.borrow_mut();
+
+        let ep_out = state.ep_out_mask & !checkpoint.state.ep_out_mask;
+        let ep_in = state.ep_in_mask & !checkpoint.state.ep_in_mask;
+
+        for i in 0..16 {
+            let bit = 1u16 << i;
+
+            if (ep_out & bit) != 0 {
+                bus.free_ep(EndpointAddress::from_parts(i, UsbDirection::Out))?;
+                state.ep_out_mask &= !bit;
+            }
+
+            if (ep_in & bit) != 0 {
+                bus.free_ep(EndpointAddress::from_parts(i, UsbDirection::In))?;
+                state.ep_in_mask &= !bit;
+            }
+        }
+
+        state.next_interface_number = checkpoint.state.next_interface_number;
+        state.next_string_index = checkpoint.state.next_string_index;
+        state.ep_out_reserved = checkpoint.state.ep_out_reserved;
+        state.ep_in_reserved = checkpoint.state.ep_in_reserved;
+
+        Ok(())
+    }
+
+    /// Reserves an endpoint address so that endpoints allocated without a specific address don't
+    /// use it. The endpoint itself can then be allocated later by passing the same address to
+    /// [`alloc`](UsbBusAllocator::alloc), which is useful when a class that requires a fixed
+    /// address is created after classes that take any free address.
+    ///
+    /// Reservations apply to every allocation strategy. While any addresses are reserved, endpoints
+    /// that a strategy such as [`BusChooses`] leaves to the bus get the lowest free number
+    /// instead, because the bus doesn't know about the reservations.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - The address is endpoint 0, or has
+    ///   already been allocated or reserved.
+    pub fn reserve_endpoint(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let (allocated, reserved) = state.masks(ep_addr.direction());
+        let bit = 1u16 << ep_addr.index();
+
+        if ep_addr.index() == 0 || ((*allocated | *reserved) & bit) != 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        *reserved |= bit;
+
+        Ok(())
+    }
+
     /// Allocates an endpoint with the specified direction and address.
     ///
     /// This directly delegates to [`UsbBus::alloc_ep`], so see that method for details. In most
-    /// cases classes should call the endpoint type specific methods instead.
+    /// cases classes should call the endpoint type specific methods instead. If `ep_addr` is
+    /// `None`, the address is chosen by the allocation strategy, see
+    /// [`set_allocation_strategy`](UsbBusAllocator::set_allocation_strategy). By default the
+    /// lowest free number is used.
+    ///
+    /// If the endpoint uses a different packet size in different alternate settings, allocate it
+    /// with the largest one and see
+    /// [`DescriptorWriter::endpoint_with_packet_size`](crate::descriptor::DescriptorWriter::endpoint_with_packet_size).
+    ///
+    /// Allocation failures are generally not recoverable because endpoints allocated earlier stay
+    /// allocated. Use [`checkpoint`](UsbBusAllocator::checkpoint) and
+    /// [`rollback`](UsbBusAllocator::rollback) to undo a partially successful allocation if the
+    /// bus supports it.
+    ///
+    /// The interval of an isochronous endpoint is an exponent, the endpoint is serviced every
+    /// 2^(`interval` - 1) frames at full speed or microframes at high speed, so it must be between 1
+    /// and 16 at either speed. An isochronous endpoint allocated with this method has no
+    /// synchronization and is a data endpoint, use
+    /// [`alloc_isochronous`](UsbBusAllocator::alloc_isochronous) to specify other types.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - The interval of an isochronous
+    ///   endpoint is not between 1 and 16, the direction of `ep_addr` is not the direction of the
+    ///   endpoint, `ep_addr` has already been allocated, or the allocation strategy chose an
+    ///   endpoint number that is invalid, in use or reserved.
+    /// * [`EndpointOverflow`](crate::UsbError::EndpointOverflow) - Addresses are reserved and no
+    ///   other endpoint number is free.
+    ///
+    /// Otherwise the errors of [`UsbBus::alloc_ep`] are passed through.
     pub fn alloc<'a, D: EndpointDirection>(
         &self,
         ep_addr: Option<EndpointAddress>,
@@ -214,13 +654,91 @@ impl<B: UsbBus> UsbBusAllocator<B> {
         max_packet_size: u16,
         interval: u8) -> Result<Endpoint<'_, B, D>>
     {
-        self.bus.borrow_mut()
-            .alloc_ep(
-                D::DIRECTION,
-                ep_addr, ep_type,
-                max_packet_size,
-                interval)
-            .map(|a| Endpoint::new(&self.bus_ptr, a, ep_type, max_packet_size, interval))
+        if ep_type == EndpointType::Isochronous && !(1..=16).contains(&interval) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        if matches!(ep_addr, Some(addr) if addr.direction() != D::DIRECTION) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let ep_addr = {
+            let mut state = self.state.borrow_mut();
+            let (allocated, reserved) = state.masks(D::DIRECTION);
+            let used = *allocated | *reserved;
+
+            match ep_addr {
+                Some(addr) => {
+                    if (*allocated & (1 << addr.index())) != 0 {
+                        return Err(UsbError::InvalidEndpoint);
+                    }
+
+                    Some(addr)
+                },
+                None => {
+                    let strategy = self.strategy.get();
+
+                    match strategy.endpoint_number(D::DIRECTION, ep_type, used) {
+                        Some(index) if index >= 16 || (used & (1 << index)) != 0 => {
+                            return Err(UsbError::InvalidEndpoint);
+                        },
+                        Some(index) => Some(index),
+                        // The bus doesn't know about reservations, so it can't be left to choose
+                        // the number while there are any.
+                        None if *reserved != 0 => Some(LowestAvailable
+                            .endpoint_number(D::DIRECTION, ep_type, used)
+                            .ok_or(UsbError::EndpointOverflow)?),
+                        None => None,
+                    }.map(|index| EndpointAddress::from_parts(index, D::DIRECTION))
+                },
+            }
+        };
+
+        let mut bus = self.bus.borrow_mut();
+
+        let address = bus.alloc_ep(
+            D::DIRECTION,
+            ep_addr, ep_type,
+            max_packet_size,
+            interval)?;
+
+        let max_packet_size = bus.allocated_packet_size(address).unwrap_or(max_packet_size);
+
+        drop(bus);
+
+        let mut state = self.state.borrow_mut();
+        let (allocated, reserved) = state.masks(D::DIRECTION);
+        let bit = 1u16 << address.index();
+
+        *allocated |= bit;
+        *reserved &= !bit;
+
+        Ok(Endpoint::new(
+            &self.bus_ptr,
+            &self.zlp_pending[address.index()],
+            address,
+            ep_type,
+            max_packet_size,
+            interval))
+    }
+
+    /// Allocates an isochronous endpoint with the specified address, synchronization type and
+    /// usage type, which are written to the bmAttributes field of the endpoint descriptor.
+    ///
+    /// Isochronous endpoints don't use the data toggle, and transfers that fail are not retried, so
+    /// the class must write a packet in time for every interval. See [`alloc`](Self::alloc) for
+    /// the valid range of `interval` and the errors.
+    pub fn alloc_isochronous<D: EndpointDirection>(
+        &self,
+        ep_addr: Option<EndpointAddress>,
+        synchronization: IsochronousSynchronizationType,
+        usage: IsochronousUsageType,
+        max_packet_size: u16,
+        interval: u8) -> Result<Endpoint<'_, B, D>>
+    {
+        let mut ep = self.alloc(ep_addr, EndpointType::Isochronous, max_packet_size, interval)?;
+        ep.set_isochronous_types(synchronization, usage);
+        Ok(ep)
     }
 
     /// Allocates a control endpoint.
@@ -273,12 +791,45 @@ impl<B: UsbBus> UsbBusAllocator<B> {
             .alloc(None, EndpointType::Interrupt, max_packet_size, interval)
             .expect("alloc_ep failed")
     }
+
+    /// Allocates an isochronous endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `synchronization` - Synchronization type of the endpoint.
+    /// * `usage` - Usage type of the endpoint.
+    /// * `max_packet_size` - Maximum packet size in bytes. Cannot exceed 1023 bytes at full speed
+    ///   or 1024 bytes at high speed.
+    /// * `interval` - Polling interval exponent. Must be between 1 and 16.
+    ///
+    /// # Panics
+    ///
+    /// Panics if endpoint allocation fails, because running out of endpoints or memory is not
+    /// feasibly recoverable, or if `interval` is not valid.
+    #[inline]
+    pub fn isochronous<D: EndpointDirection>(
+        &self,
+        synchronization: IsochronousSynchronizationType,
+        usage: IsochronousUsageType,
+        max_packet_size: u16,
+        interval: u8) -> Endpoint<'_, B, D>
+    {
+        self
+            .alloc_isochronous(None, synchronization, usage, max_packet_size, interval)
+            .expect("alloc_ep failed")
+    }
 }
 
 /// A handle for a USB interface that contains its number.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct InterfaceNumber(u8);
 
+impl InterfaceNumber {
+    pub(crate) fn new(index: u8) -> InterfaceNumber {
+        InterfaceNumber(index)
+    }
+}
+
 impl From<InterfaceNumber> for u8 {
     fn from(n: InterfaceNumber) -> u8 { n.0 }
 }
@@ -288,6 +839,18 @@ impl From<InterfaceNumber> for u8 {
 pub struct StringIndex(u8);
 
 impl StringIndex {
+    /// The index of the manufacturer string set with
+    /// [`UsbDeviceBuilder::manufacturer`](crate::device::UsbDeviceBuilder::manufacturer).
+    pub const MANUFACTURER: StringIndex = StringIndex(1);
+
+    /// The index of the product string set with
+    /// [`UsbDeviceBuilder::product`](crate::device::UsbDeviceBuilder::product).
+    pub const PRODUCT: StringIndex = StringIndex(2);
+
+    /// The index of the serial number string set with
+    /// [`UsbDeviceBuilder::serial_number`](crate::device::UsbDeviceBuilder::serial_number).
+    pub const SERIAL_NUMBER: StringIndex = StringIndex(3);
+
     pub(crate) fn new(index: u8) -> StringIndex {
         StringIndex(index)
     }
@@ -297,6 +860,48 @@ impl From<StringIndex> for u8 {
     fn from(i: StringIndex) -> u8 { i.0 }
 }
 
+/// USB bus speed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UsbSpeed {
+    /// Low speed (1.5 Mbit/s)
+    Low,
+    /// Full speed (12 Mbit/s)
+    Full,
+    /// High speed (480 Mbit/s)
+    High,
+    /// SuperSpeed (5 Gbit/s)
+    Super,
+}
+
+impl UsbSpeed {
+    /// Gets the largest maximum packet size allowed for endpoints of the specified type at this
+    /// speed, for example 64 bytes for bulk endpoints at full speed and 512 bytes at high speed.
+    /// Returns 0 for bulk and isochronous endpoints at low speed, which doesn't support them.
+    ///
+    /// High-bandwidth and burst transfers move several packets per interval, but each packet is
+    /// still limited to this size.
+    pub fn max_packet_size(self, ep_type: EndpointType) -> u16 {
+        match (self, ep_type) {
+            (UsbSpeed::Low, EndpointType::Control) => 8,
+            (UsbSpeed::Low, EndpointType::Interrupt) => 8,
+            (UsbSpeed::Low, _) => 0,
+            (UsbSpeed::Full, EndpointType::Isochronous) => 1023,
+            (UsbSpeed::Full, _) => 64,
+            (UsbSpeed::High, EndpointType::Control) => 64,
+            (UsbSpeed::High, EndpointType::Bulk) => 512,
+            (UsbSpeed::High, _) => 1024,
+            (UsbSpeed::Super, EndpointType::Control) => 512,
+            (UsbSpeed::Super, _) => 1024,
+        }
+    }
+}
+
+/// The BESL value reported in [`PollResult::Sleep`] by buses that acknowledge LPM transactions
+/// but can't read their BESL field. It stands for the shortest latency of 125 microseconds, so
+/// that classes choosing a low power mode by the latency are ready for the fastest possible
+/// resume.
+pub const DEFAULT_BESL: u8 = 0;
+
 /// Event and incoming packet information returned by [`UsbBus::poll`].
 pub enum PollResult {
     /// No events or packets to report.
@@ -308,6 +913,10 @@ pub enum PollResult {
     /// USB packets have been received or sent. Each data field is a bit-field where the least
     /// significant bit represents endpoint 0 etc., and a set bit signifies the event has occurred
     /// for the corresponding endpoint.
+    ///
+    /// The 16 bits cover every endpoint a device can have, because the endpoint number in an
+    /// endpoint address is only 4 bits wide. IN and OUT endpoints with the same number are
+    /// reported in separate fields.
     Data {
         /// An OUT packet has been received. This event should continue to be reported until the
         /// packet is read.
@@ -329,4 +938,14 @@ pub enum PollResult {
     /// A USB resume request has been detected after being suspended or, in the case of self-powered
     /// devices, the device has been connected to the USB bus.
     Resume,
+
+    /// The host has put the device into the L1 sleep state with a Link Power Management (LPM)
+    /// transaction that the peripheral acknowledged. Only reported by buses with LPM enabled, see
+    /// [`UsbBus::set_lpm_enabled`]. The exit from L1 is reported as [`Resume`](PollResult::Resume).
+    Sleep {
+        /// The 4-bit BESL (Best Effort Service Latency) field of the transaction, which tells the
+        /// device how long the host will drive resume signaling when it resumes the bus. Buses
+        /// that can't read it report [`DEFAULT_BESL`].
+        besl: u8,
+    },
 }
\ No newline at end of file
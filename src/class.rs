@@ -1,20 +1,61 @@
 use crate::{Result, UsbError};
-use crate::bus::{UsbBus, StringIndex};
-use crate::descriptor::{DescriptorWriter, BosWriter};
+use crate::bus::{UsbBus, InterfaceNumber, StringIndex};
+use crate::descriptor::{DescriptorWriter, BosWriter, FunctionInfo};
 use crate::control;
 use crate::control_pipe::ControlPipe;
+use crate::device::{UsbDevice, UsbDeviceState};
 use crate::endpoint::EndpointAddress;
 
 /// A trait for implementing USB classes.
 ///
 /// All methods are optional callbacks that will be called by
 /// [UsbBus::poll](crate::bus::UsbBus::poll)
+///
+/// # Callback order
+///
+/// The state-related callbacks are called in a fixed order over the lifetime of a connection:
+///
+/// 1. [`reset`](UsbClass::reset) is called on all classes when the bus is reset, before any
+///    requests from the host are processed. A reset returns all classes to the unconfigured state.
+/// 2. [`configuration_changed`](UsbClass::configuration_changed) and then
+///    [`configure`](UsbClass::configure) are called on all classes when a SET_CONFIGURATION
+///    request selects a configuration. All interfaces are in their default alternate setting 0
+///    afterwards. Selecting configuration 0 only calls `configuration_changed`.
+/// 3. [`set_alternate_setting`](UsbClass::set_alternate_setting) is called when a SET_INTERFACE
+///    request is received, which can only happen after the device has been configured.
+/// 4. [`suspend`](UsbClass::suspend) and [`resume`](UsbClass::resume) are called in pairs when
+///    the host suspends and resumes the bus while the device is configured. If the host resets
+///    the bus instead of resuming it, `reset` is called without `resume`.
+///
+/// Within each step classes are called in the order they are passed to
+/// [`UsbDevice::poll`](crate::device::UsbDevice::poll).
 pub trait UsbClass<B: UsbBus> {
     /// Called when a GET_DESCRIPTOR request is received for a configuration descriptor. When
     /// called, the implementation should write its interface, endpoint and any extra class
     /// descriptors into `writer`. The configuration descriptor itself will be written by
     /// [UsbDevice](crate::device::UsbDevice) and shouldn't be written by classes.
     ///
+    /// Descriptors are generated every time the host requests them, so the output may depend on
+    /// the state of the class, for example to report a different `bInterfaceProtocol` after a
+    /// runtime mode switch. However, hosts generally only read the descriptors during enumeration,
+    /// so after changing them the device must be re-enumerated, for example with
+    /// [`UsbDevice::force_reset`](crate::device::UsbDevice::force_reset) or by physically
+    /// disconnecting and reconnecting it, for the host to notice the change.
+    ///
+    /// This is also called with a writer that discards the descriptors when a string descriptor is
+    /// requested, to find out which class references the string. See
+    /// [`get_string`](Self::get_string).
+    ///
+    /// If the configuration descriptor is larger than the control buffer, this is called several
+    /// times within a single transfer, once for each part of the descriptor that is sent. The
+    /// descriptors must not change during the transfer, or the host will receive them corrupted.
+    ///
+    /// A class may also write no descriptors at all, for example for an optional function whose
+    /// hardware is not present, and `bNumInterfaces` only counts the interfaces actually written.
+    /// Interface numbers are not renumbered however, and hosts expect them to be contiguous
+    /// starting from zero. Optional classes must therefore allocate their interfaces after all
+    /// other classes, so that disabling them only leaves out the highest interface numbers.
+    ///
     /// # Errors
     ///
     /// Generally errors returned by `DescriptorWriter`. Implementors should propagate any errors
@@ -24,10 +65,36 @@ pub trait UsbClass<B: UsbBus> {
         Ok (())
     }
 
+    /// Declares that the interfaces of the class form a single function, such as the
+    /// communication and data interfaces of a CDC ACM serial port. If this returns `Some`,
+    /// [`UsbDevice`](crate::device::UsbDevice) writes an interface association descriptor (IAD)
+    /// immediately before the descriptors written by
+    /// [`get_configuration_descriptors`](UsbClass::get_configuration_descriptors), which then
+    /// must write exactly `interface_count` interfaces from the declared range. Otherwise the
+    /// configuration descriptor request fails with
+    /// [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor).
+    ///
+    /// Devices that use IADs must set the device class to `0xef`, the sub-class to `0x02` and
+    /// the protocol to `0x01` with
+    /// [`UsbDeviceBuilder::composite_with_iads`](crate::device::UsbDeviceBuilder::composite_with_iads)
+    /// so that the host looks for them.
+    ///
+    /// The default implementation returns `None`.
+    fn function_descriptor(&self) -> Option<FunctionInfo> {
+        None
+    }
+
     /// Called when a GET_DESCRIPTOR request is received for a BOS descriptor.
     /// When called, the implementation should write its blobs such as capability
     /// descriptors into `writer`. The BOS descriptor itself will be written by
     /// [UsbDevice](crate::device::UsbDevice) and shouldn't be written by classes.
+    ///
+    /// `UsbDevice` always writes the USB 2.0 Extension capability first, as some hosts expect it
+    /// before any other capabilities. The capabilities of the classes follow in the order the
+    /// classes are passed to [`UsbDevice::poll`](crate::device::UsbDevice::poll), regardless of
+    /// [`reverse_poll_order`](crate::device::UsbDeviceBuilder::reverse_poll_order), and within a
+    /// class in the order they are written. If the order of capabilities from different classes
+    /// matters, it is controlled by the order of the class list.
     fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
         let _ = writer;
         Ok (())
@@ -35,25 +102,161 @@ pub trait UsbClass<B: UsbBus> {
 
     /// Gets a class-specific string descriptor.
     ///
-    /// Note: All string descriptor requests are passed to all classes in turn, so implementations
-    /// should return [`None`] if an unknown index is requested.
+    /// A string request is only passed to the first class that references the string in its
+    /// configuration descriptors, either as the `iInterface` of an interface descriptor or with
+    /// [`DescriptorWriter::string_reference`], and is rejected with a STALL if that class returns
+    /// [`None`]. This way a class that claims an index it doesn't own can't shadow the string of
+    /// another class. Requests for strings that aren't referenced anywhere, for example ones only
+    /// read by host software, are passed to all classes in turn, so implementations should still
+    /// return `None` if an unknown index is requested. If every class returns `None` the request
+    /// is rejected with a STALL. Returning `Some("")` is not the same as returning `None`, and
+    /// results in an empty string descriptor being sent.
+    ///
+    /// Classes may also return strings for [`StringIndex::MANUFACTURER`],
+    /// [`StringIndex::PRODUCT`] and [`StringIndex::SERIAL_NUMBER`] to override the strings set in
+    /// [`UsbDeviceBuilder`](crate::device::UsbDeviceBuilder), for example to return a localized
+    /// product name depending on `lang_id`, or a different serial number while in a test mode. The
+    /// builder string is used for any language a class returns [`None`] for, and must be set for
+    /// the string to be present at all.
+    ///
+    /// If more than one class returns a string for an unreferenced index, the first class in the
+    /// list passed to [`UsbDevice::poll`](crate::device::UsbDevice::poll) wins. The device strings
+    /// above are always handled this way.
     ///
     /// # Arguments
     ///
     /// * `index` - A string index allocated earlier with
     ///   [`UsbAllocator`](crate::bus::UsbBusAllocator).
-    /// * `lang_id` - The language ID for the string to retrieve.
+    /// * `lang_id` - The language ID for the string to retrieve. This is always one of the languages
+    ///   set with
+    ///   [`UsbDeviceBuilder::supported_languages`](crate::device::UsbDeviceBuilder::supported_languages).
     fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
         let _ = (index, lang_id);
         None
     }
 
+    /// Called when the host sends a standard SET_DESCRIPTOR request to update a descriptor.
+    ///
+    /// Return `Ok(())` if the class accepted the descriptor, in which case no other classes are
+    /// called. If no class accepts it, the request is stalled, which is also the default. The
+    /// request is only dispatched in the `Addressed` and `Configured` states, and only if the
+    /// descriptor fits in the control buffer; longer descriptors are stalled, even if a class
+    /// handles their data in [`control_out_chunk`](UsbClass::control_out_chunk).
+    ///
+    /// # Arguments
+    ///
+    /// * `descriptor_type` - The type of the descriptor.
+    /// * `index` - The index of the descriptor.
+    /// * `lang_id` - The language ID for string descriptors, otherwise zero.
+    /// * `data` - The descriptor sent by the host.
+    fn set_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16, data: &[u8])
+        -> Result<()>
+    {
+        let _ = (descriptor_type, index, lang_id, data);
+        Err(UsbError::Unsupported)
+    }
+
     /// Called after a USB reset after the bus reset sequence is complete.
     fn reset(&mut self) { }
 
+    /// Called when the host selects the device's configuration with a SET_CONFIGURATION request.
+    /// This is also called if the host selects the configuration again while already configured,
+    /// in which case all interfaces should return to their default alternate setting.
+    fn configure(&mut self) { }
+
+    /// Called when the host selects a configuration with a SET_CONFIGURATION request, before
+    /// [`configure`](UsbClass::configure). `value` is the `bConfigurationValue` of the selected
+    /// configuration, or [`CONFIGURATION_NONE`](crate::device::CONFIGURATION_NONE) if the host
+    /// returned the device to the addressed state, in which case all endpoints other than endpoint
+    /// 0 are stalled until a configuration is selected again.
+    ///
+    /// Classes in devices with more than one configuration can use this to only use the endpoints
+    /// of the active configuration.
+    fn configuration_changed(&mut self, value: u8) {
+        let _ = value;
+    }
+
+    /// Called whenever the state of the device changes, with the state before and after the
+    /// change. Transitions are reported one at a time in the order they happen, so for example a
+    /// suspend and the following resume are two calls, from `Configured` to `Suspend` and back.
+    ///
+    /// In the first call `old` is `Default`, the state the device is created in. The first bus
+    /// reset is reported as well even though it doesn't change the state, with both `old` and
+    /// `new` being `Default`, so that a class sees the initial state on first enumeration. Later
+    /// calls always have different states.
+    ///
+    /// This is called after the more specific callbacks such as [`reset`](UsbClass::reset) and
+    /// [`configure`](UsbClass::configure).
+    fn device_state_changed(&mut self, old: UsbDeviceState, new: UsbDeviceState) {
+        let _ = (old, new);
+    }
+
+    /// Called once when the host suspends the bus while the device is configured, before the
+    /// change to the `Suspend` state is reported. Classes can use this to reduce their power
+    /// consumption, for example by stopping peripherals that feed their endpoints. Suspends before
+    /// the device has been configured are not reported.
+    fn suspend(&mut self) { }
+
+    /// Called once when the bus resumes after a suspend that was reported with
+    /// [`suspend`](UsbClass::suspend), before the change back to the `Configured` state is
+    /// reported. A bus reset while suspended doesn't resume the device, and only
+    /// [`reset`](UsbClass::reset) is called.
+    fn resume(&mut self) { }
+
+    /// Called when the host puts the device into the L1 sleep state with a Link Power Management
+    /// transaction, before [`suspend`](UsbClass::suspend) is called. Only happens if LPM was
+    /// enabled with [`UsbDevice::set_lpm_enabled`].
+    ///
+    /// Unlike a regular suspend, the host may resume the bus within microseconds, so classes
+    /// should only enter low power modes they can leave within the time given by `besl`.
+    ///
+    /// # Arguments
+    ///
+    /// * `besl` - The 4-bit BESL (Best Effort Service Latency) value from the LPM transaction, or
+    ///   [`DEFAULT_BESL`](crate::bus::DEFAULT_BESL) if the bus can't read it.
+    fn lpm_suspend(&mut self, besl: u8) {
+        let _ = besl;
+    }
+
+    /// Called when a SET_INTERFACE request is received.
+    ///
+    /// Return `true` if the interface belongs to this class and the alternate setting is supported,
+    /// in which case no other classes are called. If no class accepts the request, it is accepted
+    /// for alternate setting 0 and rejected for anything else.
+    ///
+    /// # Arguments
+    ///
+    /// * `interface` - The interface number from the request.
+    /// * `alternate_setting` - The alternate setting the host wants to select.
+    fn set_alternate_setting(&mut self, interface: InterfaceNumber, alternate_setting: u8)
+        -> bool
+    {
+        let _ = (interface, alternate_setting);
+        false
+    }
+
+    /// Called when a GET_INTERFACE request is received. Return the current alternate setting if
+    /// the interface belongs to this class, or [`None`] otherwise. If no class returns a value, the
+    /// default alternate setting 0 is reported.
+    fn get_alternate_setting(&self, interface: InterfaceNumber) -> Option<u8> {
+        let _ = interface;
+        None
+    }
+
     /// Called whenever the `UsbDevice` is polled.
     fn poll(&mut self) { }
 
+    /// Called whenever the `UsbDevice` is polled, instead of [`poll`](Self::poll). Classes that
+    /// can fail while polling, for example because of a hardware fault, can implement this instead
+    /// of `poll` to report the error to the application through
+    /// [`UsbDevice::take_class_error`](crate::device::UsbDevice::take_class_error).
+    ///
+    /// The default implementation calls `poll` and always succeeds.
+    fn try_poll(&mut self) -> Result<()> {
+        self.poll();
+        Ok(())
+    }
+
     /// Called when a control request is received with direction HostToDevice.
     ///
     /// All requests are passed to classes in turn, which can choose to accept, ignore or report an
@@ -73,6 +276,36 @@ pub trait UsbClass<B: UsbBus> {
         let _ = xfer;
     }
 
+    /// Called for each packet of the data stage of a control request with direction HostToDevice
+    /// when the data stage is longer than the control buffer, such as the firmware image of a
+    /// vendor-specific update request. The data is passed on as it arrives instead of being
+    /// buffered. Each packet is offered to the classes in turn until one of them returns
+    /// `Ok(true)` to show that it handled the packet.
+    ///
+    /// Streaming is opt-in: if no class handles a packet, the transfer is stalled right away, so
+    /// requests that don't fit in the buffer are stalled as before unless a class expects them.
+    /// Once all data has been handled, [`control_out`](UsbClass::control_out) is called as usual
+    /// to accept or reject the transfer in the status stage, but [`ControlOut::data`] is empty in
+    /// that case and [`ControlOut::try_data`] fails.
+    ///
+    /// Returning an error, for example because a firmware block failed to be written, stalls the
+    /// transfer right away as well. The remaining data is not passed on, later classes don't see
+    /// the chunk, and `control_out` is not called.
+    ///
+    /// The default implementation returns `Ok(false)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The request from the SETUP packet.
+    /// * `offset` - Offset of the chunk in the data stage.
+    /// * `chunk` - The data of one packet.
+    fn control_out_chunk(&mut self, req: &control::Request, offset: usize, chunk: &[u8])
+        -> Result<bool>
+    {
+        let _ = (req, offset, chunk);
+        Ok(false)
+    }
+
     /// Called when a control request is received with direction DeviceToHost.
     ///
     /// All requests are passed to classes in turn, which can choose to accept, ignore or report an
@@ -92,6 +325,40 @@ pub trait UsbClass<B: UsbBus> {
         let _ = xfer;
     }
 
+    /// Called to get the next part of the data of a control IN transfer that the class accepted
+    /// with [`ControlIn::accept_stream`]. This is only called on the class that accepted the
+    /// transfer.
+    ///
+    /// The class writes the data starting at `offset` to `buf` and returns the number of bytes
+    /// written. Writing fewer bytes than `buf` has room for ends the data, so the data is complete
+    /// once the return value is less than `buf.len()`. It also ends once the length requested by
+    /// the host has been reached, in which case this isn't called again.
+    ///
+    /// Returning an error stalls the transfer. The default implementation returns
+    /// [`InvalidState`](crate::UsbError::InvalidState).
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The request from the SETUP packet.
+    /// * `offset` - Offset of `buf` in the data. Data before `offset` has already been written.
+    /// * `buf` - The free part of the internal buffer of the control pipe.
+    fn control_in_chunk(&mut self, req: &control::Request, offset: usize, buf: &mut [u8])
+        -> Result<usize>
+    {
+        let _ = (req, offset, buf);
+        Err(UsbError::InvalidState)
+    }
+
+    /// Called on all classes when the control pipe has returned to idle after the status stage of
+    /// a control transfer completed successfully, so endpoint 0 is free for the next request.
+    ///
+    /// There is no separate completion callback for the class that handled the transfer. This is
+    /// called in the poll after the one where [`control_in`](UsbClass::control_in) or
+    /// [`control_out`](UsbClass::control_out) accepted the transfer at the earliest, once the host
+    /// has finished the status stage, and before [`poll`](UsbClass::poll) is called in the same
+    /// poll. It is not called for transfers that were rejected or aborted.
+    fn control_idle(&mut self) { }
+
     /// Called when endpoint with address `addr` has received a SETUP packet. Implementing this
     /// shouldn't be necessary in most cases, but is provided for completeness' sake.
     ///
@@ -101,18 +368,38 @@ pub trait UsbClass<B: UsbBus> {
         let _ = addr;
     }
 
+    /// Called after [`endpoint_setup`](UsbClass::endpoint_setup) to find out whether the SETUP
+    /// packet received by endpoint `addr` was handled. If all classes return `false`, the device
+    /// discards the packet and stalls the endpoint in both directions so that the host doesn't
+    /// wait for a response that will never come.
+    ///
+    /// Classes that keep track of their endpoints can return `false` for the endpoints they don't
+    /// own. The default implementation returns `true`, so an allocated endpoint is never stalled
+    /// unless all classes opt in. Endpoints that weren't allocated with
+    /// [`UsbBusAllocator`](crate::bus::UsbBusAllocator) can't belong to any class, and are always
+    /// stalled without calling this method.
+    fn endpoint_setup_handled(&self, addr: EndpointAddress) -> bool {
+        let _ = addr;
+        true
+    }
+
     /// Called when endpoint with address `addr` has received data (OUT packet).
     ///
     /// Note: This method may be called for an endpoint address you didn't allocate, and in that
-    /// case you should ignore the event.
+    /// case you should ignore the event. An [`EndpointSet`](crate::bus::EndpointSet) can be used
+    /// to keep track of the endpoints of the class.
     fn endpoint_out(&mut self, addr: EndpointAddress) {
         let _ = addr;
     }
 
-    /// Called when endpoint with address `addr` has completed transmitting data (IN packet).
+    /// Called when endpoint with address `addr` has completed transmitting data (IN packet). For a
+    /// transfer ended with
+    /// [`EndpointIn::write_packet_terminated`](crate::endpoint::Endpoint::write_packet_terminated),
+    /// this is only called once the zero-length packet written by the device has been sent too.
     ///
     /// Note: This method may be called for an endpoint address you didn't allocate, and in that
-    /// case you should ignore the event.
+    /// case you should ignore the event. An [`EndpointSet`](crate::bus::EndpointSet) can be used
+    /// to keep track of the endpoints of the class.
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
         let _ = addr;
     }
@@ -121,6 +408,10 @@ pub trait UsbClass<B: UsbBus> {
 /// Handle for a control IN transfer. When implementing a class, use the methods of this object to
 /// response to the transfer with either data or an error (STALL condition). To ignore the request
 /// and pass it on to the next class, simply don't call any method.
+///
+/// The host may end the data stage early by starting the status stage before it has read all of the
+/// data, for example to abort a long transfer. In that case the rest of the data is discarded and
+/// the transfer completes normally, as if the host had requested less data.
 pub struct ControlIn<'a, 'p, 'r, B: UsbBus> {
     pipe: &'p mut ControlPipe<'a, B>,
     req: &'r control::Request,
@@ -136,8 +427,41 @@ impl<'a, 'p, 'r, B: UsbBus> ControlIn<'a, 'p, 'r,  B> {
         self.req
     }
 
+    /// Gets the recipient of the request together with the interface or endpoint it is addressed
+    /// to.
+    pub fn recipient_target(&self) -> RecipientTarget {
+        RecipientTarget::from_request(self.req)
+    }
+
+    /// Gets the number of bytes the host requested in the wLength field of the SETUP packet. The
+    /// data sent in response is truncated to this length, and a shorter response is ended with a
+    /// short or zero-length packet.
+    pub fn requested_len(&self) -> usize {
+        self.req.length as usize
+    }
+
+    /// Gets the number of bytes of the data stage that have been sent so far. This is zero while
+    /// the request is being offered to the classes, because nothing is sent before the transfer is
+    /// accepted. Use [`UsbDevice::control_in_sent_len`] to follow the progress of the data stage
+    /// after that.
+    pub fn sent_len(&self) -> usize {
+        self.pipe.in_sent_len().unwrap_or(0)
+    }
+
+    /// Gets the capacity of the internal buffer of the control pipe in bytes. This is the maximum
+    /// length of data that can be written by the callback passed to [`accept`](ControlIn::accept)
+    /// without causing a `BufferOverflow`, or sent with [`accept_with`](ControlIn::accept_with).
+    pub fn buffer_len(&self) -> usize {
+        self.pipe.buffer_len()
+    }
+
     /// Accepts the transfer with the supplied buffer.
+    ///
+    /// Like with all of the `accept` methods, data past the length requested by the host is not
+    /// sent, and only the data that is sent has to fit in the internal buffer.
     pub fn accept_with(self, data: &[u8]) -> Result<()> {
+        let data = &data[..data.len().min(self.req.length as usize)];
+
         self.pipe.accept_in(|buf| {
             if data.len() > buf.len() {
                 return Err(UsbError::BufferOverflow);
@@ -149,6 +473,22 @@ impl<'a, 'p, 'r, B: UsbBus> ControlIn<'a, 'p, 'r,  B> {
         })
     }
 
+    /// Accepts the transfer with a single byte value, such as a status or a selector.
+    pub fn accept_u8(self, value: u8) -> Result<()> {
+        self.accept_with(&[value])
+    }
+
+    /// Accepts the transfer with a 16-bit value in the little-endian byte order used by USB, such
+    /// as a status bitfield.
+    pub fn accept_u16(self, value: u16) -> Result<()> {
+        self.accept_with(&value.to_le_bytes())
+    }
+
+    /// Accepts the transfer with a 32-bit value in the little-endian byte order used by USB.
+    pub fn accept_u32(self, value: u32) -> Result<()> {
+        self.accept_with(&value.to_le_bytes())
+    }
+
     /// Accepts the transfer with the supplied static buffer.
     /// This method is useful when you have a large static descriptor to send as one packet.
     pub fn accept_with_static(self, data: &'static [u8]) -> Result<()> {
@@ -161,6 +501,31 @@ impl<'a, 'p, 'r, B: UsbBus> ControlIn<'a, 'p, 'r,  B> {
         self.pipe.accept_in(f)
     }
 
+    /// Accepts the transfer with data that is produced incrementally, for responses that are
+    /// longer than the internal buffer and generated on the fly, such as a large report
+    /// descriptor. The length of the data doesn't have to be known in advance.
+    ///
+    /// Only a class can stream a response. The data is requested from the class that accepted the
+    /// transfer with [`UsbClass::control_in_chunk`], first right after
+    /// [`control_in`](UsbClass::control_in) returns and then again each time the data in the
+    /// buffer has been sent. The data is truncated to the length requested by the host, and ended
+    /// with a short or zero-length packet if it's shorter than that.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The maximum packet size of endpoint
+    ///   0 is larger than the internal buffer, so a packet couldn't be sent in one piece. The
+    ///   transfer is stalled.
+    pub fn accept_stream(self) -> Result<()> {
+        self.pipe.accept_in_stream()
+    }
+
+    // Like accept, but the callback returns the full length of data that may be longer than the
+    // buffer. The device is responsible for filling in the rest as it's sent.
+    pub(crate) fn accept_windowed(self, f: impl FnOnce(&mut [u8]) -> Result<usize>) -> Result<()> {
+        self.pipe.accept_in_windowed(f)
+    }
+
     /// Rejects the transfer by stalling the pipe.
     pub fn reject(self) -> Result<()> {
         self.pipe.reject()
@@ -185,18 +550,154 @@ impl<'a, 'p, 'r, B: UsbBus> ControlOut<'a, 'p, 'r, B> {
         self.req
     }
 
-    /// Gets the data from the data stage of the request. May be empty if there was no data stage.
+    /// Gets the recipient of the request together with the interface or endpoint it is addressed
+    /// to.
+    pub fn recipient_target(&self) -> RecipientTarget {
+        RecipientTarget::from_request(self.req)
+    }
+
+    /// Gets the data from the data stage of the request. May be empty if there was no data stage,
+    /// or if the data stage didn't fit in the buffer and was passed to
+    /// [`UsbClass::control_out_chunk`] instead.
     pub fn data(&self) -> &[u8] {
         self.pipe.data()
     }
 
+    /// Gets the data from the data stage of the request like [`data`](ControlOut::data), but fails
+    /// instead of returning partial or no data if the data stage didn't fit in the buffer.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The data stage is longer than the
+    ///   control buffer, so it was only passed to [`UsbClass::control_out_chunk`].
+    pub fn try_data(&self) -> Result<&[u8]> {
+        let data = self.pipe.data();
+
+        if data.len() < self.req.length as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        Ok(data)
+    }
+
+    /// Borrows the whole internal buffer of the control pipe for use as scratch memory while
+    /// processing the request, for example to parse or decode the data in place without a second
+    /// buffer. The data from the data stage is at the start of the buffer, so
+    /// `buffer_mut()[..data().len()]` is the same data that [`data`](ControlOut::data) returns.
+    ///
+    /// The borrow is only valid until the transfer is accepted or rejected, and the buffer is
+    /// reused for the next transfer afterwards. If a class modifies the buffer it should also
+    /// accept or reject the transfer, because otherwise the modified data is passed on to the
+    /// next class.
+    ///
+    /// ```compile_fail
+    /// # use usb_device::class_prelude::*;
+    /// fn control_out<B: UsbBus>(mut xfer: ControlOut<B>) {
+    ///     let scratch = xfer.buffer_mut();
+    ///     xfer.accept().ok();
+    ///
+    ///     // Error: the buffer cannot be used after the transfer is complete
+    ///     scratch[0] = 0;
+    /// }
+    /// ```
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        self.pipe.buffer_mut()
+    }
+
     /// Accepts the transfer by succesfully responding to the status stage.
     pub fn accept(self) -> Result<()> {
         self.pipe.accept_out()
     }
 
+    /// Accepts the data of the transfer but defers the status stage, which is NAKed until it is
+    /// completed with [`DeferredStatus::complete`] or [`DeferredStatus::reject`]. This is useful
+    /// for requests that take a long time to process, such as writing to flash memory.
+    ///
+    /// The data returned by [`data`](ControlOut::data) is only valid during this callback, so it
+    /// must be copied if it is needed later.
+    ///
+    /// Hosts only wait for a limited time for the status stage. The USB specification requires
+    /// standard requests to complete within 50 ms of the last data packet, and host operating
+    /// systems typically time out class and vendor requests after about 5 seconds, after which
+    /// the transfer fails and completing it no longer has any effect.
+    pub fn accept_deferred(self) -> Result<DeferredStatus> {
+        self.pipe.accept_out_deferred().map(|id| DeferredStatus { id })
+    }
+
+    /// Accepts the transfer and then disconnects the device from the bus so that the host
+    /// re-enumerates it, for example after switching to a different mode with new descriptors.
+    ///
+    /// The disconnect happens once the host has completed the status stage, so that the host sees
+    /// the request succeed. See [`UsbDevice::force_reenumerate`](crate::device::UsbDevice::force_reenumerate)
+    /// for details. If the host aborts the transfer, for example with a new SETUP packet, the
+    /// device stays connected.
+    pub fn accept_and_reenumerate(self) -> Result<()> {
+        self.pipe.accept_out()?;
+        self.pipe.request_reenumerate();
+        Ok(())
+    }
+
     /// Rejects the transfer by stalling the pipe.
     pub fn reject(self) -> Result<()> {
         self.pipe.reject()
     }
 }
+
+/// The recipient of a control request, with the interface number or endpoint address from the
+/// `index` field for interface and endpoint requests. See [`ControlIn::recipient_target`] and
+/// [`ControlOut::recipient_target`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RecipientTarget {
+    /// The request is for the entire device.
+    Device,
+    /// The request is for the interface with the specified number.
+    Interface(InterfaceNumber),
+    /// The request is for the endpoint with the specified address.
+    Endpoint(EndpointAddress),
+    /// The request has the recipient `Other` or a reserved recipient.
+    Other,
+}
+
+impl RecipientTarget {
+    fn from_request(req: &control::Request) -> RecipientTarget {
+        use crate::control::Recipient;
+
+        match req.recipient {
+            Recipient::Device => RecipientTarget::Device,
+            Recipient::Interface
+                => RecipientTarget::Interface(InterfaceNumber::new(req.index as u8)),
+            Recipient::Endpoint
+                => RecipientTarget::Endpoint(((req.index as u8) & 0x8f).into()),
+            _ => RecipientTarget::Other,
+        }
+    }
+}
+
+/// A control OUT transfer whose status stage has been deferred with
+/// [`ControlOut::accept_deferred`].
+#[must_use = "the status stage is NAKed until the transfer is completed"]
+pub struct DeferredStatus {
+    id: u16,
+}
+
+impl DeferredStatus {
+    /// Completes the transfer by successfully responding to the status stage.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - The transfer has been aborted, for
+    ///   example because the host started a new control transfer or the device was reset.
+    pub fn complete<B: UsbBus>(self, device: &mut UsbDevice<'_, B>) -> Result<()> {
+        device.complete_deferred_status(self.id, true)
+    }
+
+    /// Rejects the transfer by stalling the pipe.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - The transfer has been aborted, for
+    ///   example because the host started a new control transfer or the device was reset.
+    pub fn reject<B: UsbBus>(self, device: &mut UsbDevice<'_, B>) -> Result<()> {
+        device.complete_deferred_status(self.id, false)
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{Result, UsbDirection, UsbError};
+use core::convert::TryFrom;
 use core::mem;
 
 /// Control request type.
@@ -88,6 +89,12 @@ impl Request {
     /// Standard USB control request Synch Frame
     pub const SYNCH_FRAME: u8 = 12;
 
+    /// Standard USB control request Set SEL (SuperSpeed only)
+    pub const SET_SEL: u8 = 48;
+
+    /// Standard USB control request Set Isochronous Delay (SuperSpeed only)
+    pub const SET_ISOCH_DELAY: u8 = 49;
+
     /// Standard USB feature Endpoint Halt for Set/Clear Feature
     pub const FEATURE_ENDPOINT_HALT: u16 = 0;
 
@@ -115,8 +122,105 @@ impl Request {
         })
     }
 
+    /// Checks whether this is the specified request with the specified type and recipient. The
+    /// direction is not checked, as it is already implied by whether the request is passed to
+    /// [`control_in`](crate::class::UsbClass::control_in) or
+    /// [`control_out`](crate::class::UsbClass::control_out).
+    pub fn is(&self, request_type: RequestType, recipient: Recipient, request: u8) -> bool {
+        self.request_type == request_type && self.recipient == recipient && self.request == request
+    }
+
+    /// Checks whether this is the specified standard request to the specified recipient.
+    pub fn is_standard(&self, recipient: Recipient, request: u8) -> bool {
+        self.is(RequestType::Standard, recipient, request)
+    }
+
+    /// Checks whether this is the specified class request to the specified recipient.
+    pub fn is_class(&self, recipient: Recipient, request: u8) -> bool {
+        self.is(RequestType::Class, recipient, request)
+    }
+
+    /// Checks whether this is the specified vendor request to the specified recipient.
+    pub fn is_vendor(&self, recipient: Recipient, request: u8) -> bool {
+        self.is(RequestType::Vendor, recipient, request)
+    }
+
     /// Gets the descriptor type and index from the value field of a GET_DESCRIPTOR request.
     pub fn descriptor_type_index(&self) -> (u8, u8) {
         ((self.value >> 8) as u8, self.value as u8)
     }
+
+    /// Gets the feature selected by a standard SET_FEATURE or CLEAR_FEATURE request. Returns
+    /// `None` for other requests and for unknown feature selectors.
+    pub fn feature(&self) -> Option<Feature> {
+        match (self.request_type, self.request) {
+            (RequestType::Standard, Request::SET_FEATURE)
+            | (RequestType::Standard, Request::CLEAR_FEATURE)
+                => Feature::try_from((self.recipient, self.value)).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Standard feature selector for SET_FEATURE and CLEAR_FEATURE requests.
+///
+/// The same selector value means different features for different recipients, so a feature is
+/// parsed from a `(Recipient, u16)` pair of the request recipient and value.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Feature {
+    /// ENDPOINT_HALT (endpoint recipient)
+    EndpointHalt,
+    /// FUNCTION_SUSPEND (interface recipient, SuperSpeed only)
+    FunctionSuspend,
+    /// DEVICE_REMOTE_WAKEUP (device recipient)
+    DeviceRemoteWakeup,
+    /// TEST_MODE (device recipient, high speed only)
+    TestMode,
+    /// U1_ENABLE (device recipient, SuperSpeed only)
+    U1Enable,
+    /// U2_ENABLE (device recipient, SuperSpeed only)
+    U2Enable,
+    /// LTM_ENABLE (device recipient, SuperSpeed only)
+    LtmEnable,
+}
+
+impl Feature {
+    /// Gets the recipient the feature applies to.
+    pub fn recipient(self) -> Recipient {
+        match self {
+            Feature::EndpointHalt => Recipient::Endpoint,
+            Feature::FunctionSuspend => Recipient::Interface,
+            _ => Recipient::Device,
+        }
+    }
+
+    /// Gets the feature selector value used in the wValue field of the request.
+    pub fn selector(self) -> u16 {
+        match self {
+            Feature::EndpointHalt => 0,
+            Feature::FunctionSuspend => 0,
+            Feature::DeviceRemoteWakeup => 1,
+            Feature::TestMode => 2,
+            Feature::U1Enable => 48,
+            Feature::U2Enable => 49,
+            Feature::LtmEnable => 50,
+        }
+    }
+}
+
+impl TryFrom<(Recipient, u16)> for Feature {
+    type Error = UsbError;
+
+    fn try_from((recipient, selector): (Recipient, u16)) -> Result<Feature> {
+        Ok(match (recipient, selector) {
+            (Recipient::Endpoint, 0) => Feature::EndpointHalt,
+            (Recipient::Interface, 0) => Feature::FunctionSuspend,
+            (Recipient::Device, 1) => Feature::DeviceRemoteWakeup,
+            (Recipient::Device, 2) => Feature::TestMode,
+            (Recipient::Device, 48) => Feature::U1Enable,
+            (Recipient::Device, 49) => Feature::U2Enable,
+            (Recipient::Device, 50) => Feature::LtmEnable,
+            _ => return Err(UsbError::ParseError),
+        })
+    }
 }
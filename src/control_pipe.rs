@@ -11,20 +11,24 @@ enum ControlState {
     DataIn,
     DataInZlp,
     DataInLast,
+    DataInWindow,
     CompleteIn(Request),
     StatusOut,
     CompleteOut,
     DataOut(Request),
+    DataOutStream(Request),
+    StatusInDeferred,
     StatusIn,
     Error,
 }
 
 // Maximum length of control transfer data stage in bytes. 128 bytes by default. You can define the
-// feature "control-buffer-256" to make it 256 bytes if you have larger control transfers.
+// feature "control-buffer-256" to make it 256 bytes if you have larger control transfers. The
+// length actually used can be reduced with UsbDeviceBuilder::control_buffer_size.
 #[cfg(not(feature = "control-buffer-256"))]
-const CONTROL_BUF_LEN: usize = 128;
+pub(crate) const CONTROL_BUF_LEN: usize = 128;
 #[cfg(feature = "control-buffer-256")]
-const CONTROL_BUF_LEN: usize = 256;
+pub(crate) const CONTROL_BUF_LEN: usize = 256;
 
 /// Buffers and parses USB control transfers.
 pub struct ControlPipe<'a, B: UsbBus> {
@@ -32,21 +36,48 @@ pub struct ControlPipe<'a, B: UsbBus> {
     ep_in: EndpointIn<'a, B>,
     state: ControlState,
     buf: [u8; CONTROL_BUF_LEN],
+    // Length of the part of buf that is used
+    buf_len: usize,
     static_in_buf: Option<&'static [u8]>,
+    // Offset of the start of the buffer in the data when sending data larger than the buffer
+    in_window: Option<usize>,
+    // Whether more data may follow the data in the buffer when streaming an IN response, and the
+    // wLength of the request being streamed
+    in_stream: bool,
+    in_limit: usize,
+    // Request, offset and length of the last packet received while streaming OUT data that doesn't
+    // fit in the buffer. The packet is at the start of the buffer.
+    out_chunk: Option<(Request, usize, usize)>,
     i: usize,
     len: usize,
+    short_in: bool,
+    deferred_id: u16,
+    completed: bool,
+    // Whether to disconnect from the bus once the current transfer has completed
+    reenumerate: bool,
 }
 
 impl<B: UsbBus> ControlPipe<'_, B> {
-    pub fn new<'a>(ep_out: EndpointOut<'a, B>, ep_in: EndpointIn<'a, B>) -> ControlPipe<'a, B> {
+    pub fn new<'a>(ep_out: EndpointOut<'a, B>, ep_in: EndpointIn<'a, B>, buf_len: usize)
+        -> ControlPipe<'a, B>
+    {
         ControlPipe {
             ep_out,
             ep_in,
             state: ControlState::Idle,
             buf: [0; CONTROL_BUF_LEN],
+            buf_len: min(buf_len, CONTROL_BUF_LEN),
             static_in_buf: None,
+            in_window: None,
+            in_stream: false,
+            in_limit: 0,
+            out_chunk: None,
             i: 0,
             len: 0,
+            short_in: false,
+            deferred_id: 0,
+            completed: false,
+            reenumerate: false,
         }
     }
 
@@ -57,15 +88,74 @@ impl<B: UsbBus> ControlPipe<'_, B> {
         }
     }
 
+    // Returns the number of bytes of the data stage of the current IN transfer that have been
+    // written to the endpoint, if there is one.
+    pub fn in_sent_len(&self) -> Option<usize> {
+        match self.state {
+            ControlState::CompleteIn(_) => Some(0),
+            ControlState::DataIn
+                | ControlState::DataInZlp
+                | ControlState::DataInLast
+                | ControlState::DataInWindow => Some(self.i),
+            _ => None,
+        }
+    }
+
+    pub fn buffer_len(&self) -> usize {
+        self.buf_len
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.buf[0..self.len]
     }
 
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.buf_len]
+    }
+
     pub fn reset(&mut self) {
         self.state = ControlState::Idle;
+        self.in_window = None;
+        self.in_stream = false;
+        self.out_chunk = None;
+        self.completed = false;
+        self.reenumerate = false;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, ControlState::Idle)
+    }
+
+    // Returns whether a transfer has completed since the last call.
+    pub fn take_completed(&mut self) -> bool {
+        core::mem::replace(&mut self.completed, false)
+    }
+
+    // Requests a disconnect once the current transfer has completed. The request is dropped if the
+    // transfer is aborted by a new SETUP packet or a reset.
+    pub fn request_reenumerate(&mut self) {
+        self.reenumerate = true;
+    }
+
+    pub fn take_reenumerate(&mut self) -> bool {
+        core::mem::replace(&mut self.reenumerate, false)
+    }
+
+    pub fn abort(&mut self) {
+        self.state = ControlState::Idle;
+        self.static_in_buf = None;
+        self.in_window = None;
+        self.in_stream = false;
+        self.out_chunk = None;
+        self.reenumerate = false;
+        self.ep_out.unstall();
+        self.ep_in.unstall();
     }
 
     pub fn handle_setup<'p>(&'p mut self) -> Option<Request> {
+        self.out_chunk = None;
+        self.reenumerate = false;
+
         let count = match self.ep_out.read(&mut self.buf[..]) {
             Ok(count) => count,
             Err(UsbError::WouldBlock) => return None,
@@ -95,15 +185,15 @@ impl<B: UsbBus> ControlPipe<'_, B> {
             if req.length > 0 {
                 // Has data stage
 
-                if req.length as usize > self.buf.len() {
-                    // Data stage won't fit in buffer
-                    self.set_error();
-                    return None;
-                }
-
                 self.i = 0;
                 self.len = req.length as usize;
-                self.state = ControlState::DataOut(req);
+
+                self.state = if self.len > self.buf_len {
+                    // Data stage won't fit in buffer, so it's passed on a packet at a time
+                    ControlState::DataOutStream(req)
+                } else {
+                    ControlState::DataOut(req)
+                };
             } else {
                 // No data stage
 
@@ -125,7 +215,7 @@ impl<B: UsbBus> ControlPipe<'_, B> {
         match self.state {
             ControlState::DataOut(req) => {
                 let i = self.i;
-                let count = match self.ep_out.read(&mut self.buf[i..]) {
+                let count = match self.ep_out.read(&mut self.buf[i..self.buf_len]) {
                     Ok(count) => count,
                     Err(UsbError::WouldBlock) => return None,
                     Err(_) => {
@@ -143,9 +233,51 @@ impl<B: UsbBus> ControlPipe<'_, B> {
                     return Some(req);
                 }
             },
+            ControlState::DataOutStream(req) => {
+                let count = match self.ep_out.read(&mut self.buf[..self.buf_len]) {
+                    Ok(count) if self.i + count <= self.len => count,
+                    Err(UsbError::WouldBlock) => return None,
+                    _ => {
+                        // Failed to read, or the host sent more data than it indicated in the
+                        // SETUP request
+                        self.set_error();
+                        return None;
+                    },
+                };
+
+                self.out_chunk = Some((req, self.i, count));
+                self.i += count;
+
+                if self.i >= self.len {
+                    // The data has already been passed on in chunks
+                    self.len = 0;
+                    self.state = ControlState::CompleteOut;
+                    return Some(req);
+                }
+            },
             ControlState::StatusOut => {
                 self.ep_out.read(&mut []).ok();
                 self.state = ControlState::Idle;
+                self.completed = true;
+            },
+            ControlState::DataIn
+                | ControlState::DataInZlp
+                | ControlState::DataInLast
+                | ControlState::DataInWindow =>
+            {
+                if self.ep_out.read(&mut []).is_err() {
+                    // Only a zero-length status packet is valid during an IN transfer
+                    self.set_error();
+                    return None;
+                }
+
+                // The host ended the data stage early with the status stage, for example to abort
+                // a long transfer. The rest of the data is discarded.
+                self.static_in_buf = None;
+                self.in_window = None;
+                self.in_stream = false;
+                self.state = ControlState::Idle;
+                self.completed = true;
             },
             _ => {
                 // Discard the packet
@@ -159,6 +291,20 @@ impl<B: UsbBus> ControlPipe<'_, B> {
         return None;
     }
 
+    // Returns the request, offset and data of the OUT packet received by the last handle_out call
+    // while streaming a data stage that doesn't fit in the buffer.
+    pub fn take_out_chunk(&mut self) -> Option<(Request, usize, &[u8])> {
+        self.out_chunk.take().map(move |(req, offset, len)| (req, offset, &self.buf[..len]))
+    }
+
+    // Stalls a streamed data stage that a class failed to process.
+    pub fn abort_out_stream(&mut self) {
+        match self.state {
+            ControlState::DataOutStream(_) | ControlState::CompleteOut => self.set_error(),
+            _ => { },
+        }
+    }
+
     pub fn handle_in_complete(&mut self) -> bool {
         match self.state {
             ControlState::DataIn => {
@@ -179,8 +325,12 @@ impl<B: UsbBus> ControlPipe<'_, B> {
             },
             ControlState::StatusIn => {
                 self.state = ControlState::Idle;
+                self.completed = true;
                 return true;
             },
+            ControlState::Idle => {
+                // A data packet the host read before ending the data stage early
+            },
             _ => {
                 // Unexpected IN packet
                 self.set_error();
@@ -191,10 +341,26 @@ impl<B: UsbBus> ControlPipe<'_, B> {
     }
 
     fn write_in_chunk(&mut self) {
-        let count = min(self.len - self.i, self.ep_in.max_packet_size() as usize);
+        let mps = self.ep_in.max_packet_size() as usize;
+        let count = min(self.len - self.i, mps);
+
+        let window_start = match self.in_window {
+            Some(start) if self.i + count > start + self.buf_len
+                || (self.in_stream && self.len - self.i < mps) =>
+            {
+                // The next packet is past the end of the buffer, or the data streamed so far is
+                // not enough for a full packet, wait for fill_window or fill_stream
+                self.state = ControlState::DataInWindow;
+                return;
+            },
+            Some(start) => start,
+            None => 0,
+        };
+
+        let start = self.i - window_start;
 
         let buffer = self.static_in_buf.unwrap_or(&self.buf);
-        let count = match self.ep_in.write(&buffer[self.i..(self.i+count)]) {
+        let count = match self.ep_in.write(&buffer[start..(start+count)]) {
             Ok(c) => c,
             // There isn't much we can do if the write fails, except to wait for another poll or for
             // the host to resend the request.
@@ -203,10 +369,13 @@ impl<B: UsbBus> ControlPipe<'_, B> {
 
         self.i += count;
 
-        if self.i >= self.len {
+        if self.i >= self.len && !self.in_stream {
             self.static_in_buf = None;
+            self.in_window = None;
 
-            self.state = if count == self.ep_in.max_packet_size() as usize {
+            // A full last packet only needs to be followed by a ZLP if the host requested more data
+            // than is being sent, otherwise the host knows the transfer is complete.
+            self.state = if self.short_in && count == self.ep_in.max_packet_size() as usize {
                 ControlState::DataInZlp
             } else {
                 ControlState::DataInLast
@@ -225,22 +394,193 @@ impl<B: UsbBus> ControlPipe<'_, B> {
         Ok(())
     }
 
+    pub fn accept_out_deferred(&mut self) -> Result<u16> {
+        match self.state {
+            ControlState::CompleteOut => {},
+            _ => return Err(UsbError::InvalidState),
+        };
+
+        // The status stage is NAKed until the transfer is completed, since nothing is written
+        self.deferred_id = self.deferred_id.wrapping_add(1);
+        self.state = ControlState::StatusInDeferred;
+        Ok(self.deferred_id)
+    }
+
+    pub fn complete_deferred(&mut self, id: u16, accept: bool) -> Result<()> {
+        match self.state {
+            ControlState::StatusInDeferred if id == self.deferred_id => {},
+            _ => return Err(UsbError::InvalidState),
+        };
+
+        if accept {
+            self.ep_in.write(&[]).ok();
+            self.state = ControlState::StatusIn;
+        } else {
+            self.set_error();
+        }
+
+        Ok(())
+    }
+
     pub fn accept_in(&mut self, f: impl FnOnce(&mut [u8]) -> Result<usize>) -> Result<()> {
         let req = match self.state {
             ControlState::CompleteIn(req) => req,
             _ => return Err(UsbError::InvalidState),
         };
 
-        let len = f(&mut self.buf[..])?;
+        let len = f(&mut self.buf[..self.buf_len])?;
 
-        if len > self.buf.len() {
+        if len > self.buf_len {
             self.set_error();
             return Err(UsbError::BufferOverflow);
         }
 
+        self.in_window = None;
         self.start_in_transfer(req, len)
     }
 
+    // Like accept_in, but the data may be longer than the buffer. `f` returns the full length of
+    // the data and fills in as much of it as fits. The rest is requested from the device a window
+    // at a time with window_pending and fill_window, which must produce the same data again.
+    pub fn accept_in_windowed(&mut self, f: impl FnOnce(&mut [u8]) -> Result<usize>)
+        -> Result<()>
+    {
+        let req = match self.state {
+            ControlState::CompleteIn(req) => req,
+            _ => return Err(UsbError::InvalidState),
+        };
+
+        let len = f(&mut self.buf[..self.buf_len])?;
+
+        if len > self.buf_len && self.ep_in.max_packet_size() as usize > self.buf_len {
+            // A single packet wouldn't fit in a window
+            self.set_error();
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.in_window = if len > self.buf_len { Some(0) } else { None };
+        self.start_in_transfer(req, len)
+    }
+
+    pub fn window_pending(&self) -> bool {
+        matches!(self.state, ControlState::DataInWindow)
+    }
+
+    // Refills the buffer starting from the next unsent byte. `f` is called with the buffer and the
+    // offset of the window in the data, and returns the full length of the data.
+    pub fn fill_window(&mut self, f: impl FnOnce(&mut [u8], usize) -> Result<usize>)
+        -> Result<()>
+    {
+        if !self.window_pending() {
+            return Err(UsbError::InvalidState);
+        }
+
+        if self.in_stream {
+            // A streamed response can only be continued with fill_stream
+            self.set_error();
+            return Err(UsbError::InvalidState);
+        }
+
+        let start = self.i;
+
+        match f(&mut self.buf[..self.buf_len], start) {
+            Ok(len) if len >= self.len => { },
+            Ok(_) => {
+                // The data got shorter since the transfer was started
+                self.set_error();
+                return Err(UsbError::InvalidState);
+            },
+            Err(err) => {
+                self.set_error();
+                return Err(err);
+            },
+        }
+
+        self.in_window = Some(start);
+        self.state = ControlState::DataIn;
+        self.write_in_chunk();
+
+        Ok(())
+    }
+
+    // Starts an IN transfer whose data is produced a buffer at a time by fill_stream, without
+    // knowing the length of the data in advance. The transfer is in the DataInWindow state until
+    // the first call to fill_stream.
+    pub fn accept_in_stream(&mut self) -> Result<()> {
+        let req = match self.state {
+            ControlState::CompleteIn(req) => req,
+            _ => return Err(UsbError::InvalidState),
+        };
+
+        if req.length == 0 {
+            // No data stage to stream
+            self.in_window = None;
+            return self.start_in_transfer(req, 0);
+        }
+
+        if self.ep_in.max_packet_size() as usize > self.buf_len {
+            // A single packet wouldn't fit in the buffer
+            self.set_error();
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.static_in_buf = None;
+        self.in_window = Some(0);
+        self.in_stream = true;
+        self.in_limit = req.length as usize;
+        self.i = 0;
+        self.len = 0;
+        self.state = ControlState::DataInWindow;
+
+        Ok(())
+    }
+
+    // Continues a streamed IN transfer. The data in the buffer that hasn't been sent yet is moved
+    // to the start, and `f` is called with the free space after it and the offset of the free space
+    // in the data. `f` returns the number of bytes it wrote, and writing less than the space given
+    // to it ends the data.
+    pub fn fill_stream(&mut self, f: impl FnOnce(&mut [u8], usize) -> Result<usize>)
+        -> Result<()>
+    {
+        if !self.window_pending() || !self.in_stream {
+            return Err(UsbError::InvalidState);
+        }
+
+        let start = self.in_window.unwrap_or(0);
+        let unsent = self.len - self.i;
+
+        self.buf.copy_within((self.i - start)..(self.len - start), 0);
+
+        let space = self.buf_len - unsent;
+
+        let count = match f(&mut self.buf[unsent..self.buf_len], self.len) {
+            Ok(count) if count <= space => count,
+            Ok(_) => {
+                self.set_error();
+                return Err(UsbError::BufferOverflow);
+            },
+            Err(err) => {
+                self.set_error();
+                return Err(err);
+            },
+        };
+
+        self.in_window = Some(self.i);
+        self.len += count;
+
+        if count < space || self.len >= self.in_limit {
+            // The data ended or the host doesn't want more of it
+            self.len = min(self.len, self.in_limit);
+            self.in_stream = false;
+        }
+
+        self.short_in = self.len < self.in_limit;
+        self.state = ControlState::DataIn;
+        self.write_in_chunk();
+
+        Ok(())
+    }
+
     pub fn accept_in_static(&mut self, data: &'static [u8]) -> Result<()> {
         let req = match self.state {
             ControlState::CompleteIn(req) => req,
@@ -248,12 +588,15 @@ impl<B: UsbBus> ControlPipe<'_, B> {
         };
 
         self.static_in_buf = Some(data);
+        self.in_window = None;
 
         self.start_in_transfer(req, data.len())
     }
 
     fn start_in_transfer(&mut self, req: Request, data_len: usize) -> Result<()> {
         self.len = min(data_len, req.length as usize);
+        self.short_in = self.len < req.length as usize;
+        self.in_stream = false;
         self.i = 0;
         self.state = ControlState::DataIn;
         self.write_in_chunk();
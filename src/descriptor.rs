@@ -1,7 +1,7 @@
 use crate::{Result, UsbError};
-use crate::bus::{UsbBus, InterfaceNumber};
+use crate::bus::{UsbBus, UsbSpeed, InterfaceNumber, StringIndex};
 use crate::device;
-use crate::endpoint::{Endpoint, EndpointDirection};
+use crate::endpoint::{Endpoint, EndpointDirection, EndpointType};
 
 /// Standard descriptor types
 #[allow(missing_docs)]
@@ -11,8 +11,30 @@ pub mod descriptor_type {
     pub const STRING: u8 = 3;
     pub const INTERFACE: u8 = 4;
     pub const ENDPOINT: u8 = 5;
+    pub const DEVICE_QUALIFIER: u8 = 6;
+    pub const OTHER_SPEED_CONFIGURATION: u8 = 7;
+    pub const IAD: u8 = 11;
     pub const BOS: u8 = 15;
     pub const CAPABILITY: u8 = 16;
+    pub const CS_INTERFACE: u8 = 0x24;
+    pub const CS_ENDPOINT: u8 = 0x25;
+}
+
+/// Class codes that are only valid in the device descriptor, for use with
+/// [`UsbDeviceBuilder::device_class`](crate::device::UsbDeviceBuilder::device_class).
+pub mod device_class {
+    /// Billboard device, which describes the alternate modes a USB Type-C device supports. The
+    /// device must also have a Billboard capability in its BOS descriptor, otherwise the BOS
+    /// descriptor request is stalled.
+    pub const BILLBOARD: u8 = 0x11;
+
+    /// Miscellaneous device. Together with sub-class `0x02` and protocol `0x01` this tells the host
+    /// that the device uses interface association descriptors, see
+    /// [`UsbDeviceBuilder::composite_with_iads`](crate::device::UsbDeviceBuilder::composite_with_iads).
+    pub const MISCELLANEOUS: u8 = 0xef;
+
+    /// Wireless controller.
+    pub const WIRELESS_CONTROLLER: u8 = 0xe0;
 }
 
 /// String descriptor language IDs.
@@ -31,51 +53,808 @@ pub mod capability_type {
     pub const SS_USB_DEVICE: u8 = 3;
     pub const CONTAINER_ID: u8 = 4;
     pub const PLATFORM: u8 = 5;
+    pub const SUPERSPEED_PLUS: u8 = 10;
+    pub const BILLBOARD: u8 = 13;
+}
+
+/// Formats a unique ID, such as the 96-bit unique device ID of many microcontrollers, as an
+/// uppercase hexadecimal string for use as the serial number string descriptor. The bytes are
+/// formatted in order, two digits each.
+///
+/// # Errors
+///
+/// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - `buf` is shorter than twice the length
+///   of `id`.
+pub fn format_serial_hex<'b>(id: &[u8], buf: &'b mut [u8]) -> Result<&'b str> {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let len = id.len() * 2;
+
+    if len > buf.len() {
+        return Err(UsbError::BufferOverflow);
+    }
+
+    for (digits, &b) in buf.chunks_exact_mut(2).zip(id) {
+        digits[0] = DIGITS[(b >> 4) as usize];
+        digits[1] = DIGITS[(b & 0x0f) as usize];
+    }
+
+    core::str::from_utf8(&buf[..len]).map_err(|_| UsbError::ParseError)
+}
+
+/// Helpers for writing CDC (Communications Device Class) functional descriptors.
+///
+/// These should be written after the communications class interface descriptor and before its
+/// endpoint descriptors.
+pub mod cdc {
+    use crate::{Result, UsbError};
+    use crate::bus::InterfaceNumber;
+    use super::{DescriptorWriter, descriptor_type};
+
+    /// CDC functional descriptor subtypes
+    #[allow(missing_docs)]
+    pub mod descriptor_subtype {
+        pub const HEADER: u8 = 0x00;
+        pub const CALL_MANAGEMENT: u8 = 0x01;
+        pub const ACM: u8 = 0x02;
+        pub const UNION: u8 = 0x06;
+    }
+
+    /// Writes a header functional descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `cdc_version` - The CDC specification release number in BCD, e.g. `0x0110` for 1.10.
+    pub fn header(writer: &mut DescriptorWriter, cdc_version: u16) -> Result<()> {
+        writer.write(
+            descriptor_type::CS_INTERFACE,
+            &[
+                descriptor_subtype::HEADER, // bDescriptorSubtype
+                cdc_version as u8, (cdc_version >> 8) as u8, // bcdCDC
+            ])
+    }
+
+    /// Writes a call management functional descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `capabilities` - The bmCapabilities field.
+    /// * `data_interface` - The data class interface used for call management.
+    pub fn call_management(
+        writer: &mut DescriptorWriter,
+        capabilities: u8,
+        data_interface: InterfaceNumber) -> Result<()>
+    {
+        writer.write(
+            descriptor_type::CS_INTERFACE,
+            &[
+                descriptor_subtype::CALL_MANAGEMENT, // bDescriptorSubtype
+                capabilities, // bmCapabilities
+                data_interface.into(), // bDataInterface
+            ])
+    }
+
+    /// Writes an abstract control management functional descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `capabilities` - The bmCapabilities field.
+    pub fn acm(writer: &mut DescriptorWriter, capabilities: u8) -> Result<()> {
+        writer.write(
+            descriptor_type::CS_INTERFACE,
+            &[
+                descriptor_subtype::ACM, // bDescriptorSubtype
+                capabilities, // bmCapabilities
+            ])
+    }
+
+    /// Writes a union functional descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `control_interface` - The controlling interface of the union.
+    /// * `subordinate_interfaces` - The other interfaces in the union.
+    ///
+    /// # Errors
+    ///
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - There are no subordinate
+    ///   interfaces, or the control interface is also listed as a subordinate interface.
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The descriptor does not fit in the
+    ///   buffer or is longer than 255 bytes.
+    pub fn union(
+        writer: &mut DescriptorWriter,
+        control_interface: InterfaceNumber,
+        subordinate_interfaces: &[InterfaceNumber]) -> Result<()>
+    {
+        if subordinate_interfaces.is_empty()
+            || subordinate_interfaces.contains(&control_interface)
+        {
+            return Err(UsbError::MalformedDescriptor);
+        }
+
+        let mut buf = [0u8; 253];
+        let len = 2 + subordinate_interfaces.len();
+
+        if len > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        buf[0] = descriptor_subtype::UNION; // bDescriptorSubtype
+        buf[1] = control_interface.into(); // bControlInterface
+
+        for (b, iface) in buf[2..len].iter_mut().zip(subordinate_interfaces) {
+            *b = (*iface).into(); // bSubordinateInterface
+        }
+
+        writer.write(descriptor_type::CS_INTERFACE, &buf[..len])
+    }
+}
+
+/// Helpers for WebUSB, which lets the browser suggest a landing page when the device is plugged in.
+///
+/// The device advertises WebUSB with a platform capability in its BOS descriptor, written with
+/// [`capability`](webusb::capability) in
+/// [`get_bos_descriptors`](crate::class::UsbClass::get_bos_descriptors). The browser then reads the
+/// landing page with a vendor GET_URL request, which can be recognized with
+/// [`url_request`](webusb::url_request) and answered with [`url_descriptor`](webusb::url_descriptor)
+/// in [`control_in`](crate::class::UsbClass::control_in).
+pub mod webusb {
+    use crate::{Result, UsbError};
+    use crate::control::{Recipient, Request};
+    use super::BosWriter;
+
+    /// The WebUSB platform capability UUID {3408b638-09a9-47a0-8bfd-a0768815b665} in the byte
+    /// order used in the descriptor.
+    pub const PLATFORM_CAPABILITY_UUID: [u8; 16] = [
+        0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47,
+        0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+    ];
+
+    /// The wIndex value of a GET_URL request.
+    pub const GET_URL: u16 = 2;
+
+    /// The descriptor type of a URL descriptor.
+    pub const URL_DESCRIPTOR_TYPE: u8 = 3;
+
+    /// URL scheme prefixes for the bScheme field of a URL descriptor
+    #[allow(missing_docs)]
+    pub mod scheme {
+        pub const HTTP: u8 = 0;
+        pub const HTTPS: u8 = 1;
+        /// The URL includes its scheme.
+        pub const NONE: u8 = 255;
+    }
+
+    /// Writes a WebUSB platform capability.
+    ///
+    /// # Arguments
+    ///
+    /// * `vendor_code` - The bRequest value the host uses for WebUSB requests.
+    /// * `landing_page` - The index of the landing page URL, or 0 for no landing page.
+    pub fn capability(writer: &mut BosWriter, vendor_code: u8, landing_page: u8) -> Result<()> {
+        let mut data = [0u8; 4];
+
+        data[0..2].copy_from_slice(&0x0100u16.to_le_bytes()); // bcdVersion 1.0
+        data[2] = vendor_code; // bVendorCode
+        data[3] = landing_page; // iLandingPage
+
+        writer.platform_capability(&PLATFORM_CAPABILITY_UUID, &data)
+    }
+
+    /// Returns the URL index of a GET_URL request with the specified vendor code, or `None` if
+    /// `req` is some other request.
+    pub fn url_request(req: &Request, vendor_code: u8) -> Option<u8> {
+        if req.is_vendor(Recipient::Device, vendor_code) && req.index == GET_URL {
+            Some(req.value as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Writes a URL descriptor to `buf` and returns its length. This can be used as the callback
+    /// of [`ControlIn::accept`](crate::class::ControlIn::accept), in which case a URL that doesn't
+    /// fit in the control buffer causes the request to be stalled.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The descriptor does not fit in
+    ///   `buf` or is longer than 255 bytes.
+    pub fn url_descriptor(scheme: u8, url: &str, buf: &mut [u8]) -> Result<usize> {
+        let len = 3 + url.len();
+
+        if len > buf.len() || len > 255 {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        buf[0] = len as u8; // bLength
+        buf[1] = URL_DESCRIPTOR_TYPE; // bDescriptorType
+        buf[2] = scheme; // bScheme
+        buf[3..len].copy_from_slice(url.as_bytes()); // URL
+
+        Ok(len)
+    }
 }
 
+/// Helpers for Microsoft OS 2.0 descriptors, which let Windows 8.1 and later load a driver such as
+/// WinUSB for the device without an INF file.
+///
+/// The device advertises the descriptor set with a platform capability in its BOS descriptor,
+/// written with [`capability`](msos20::capability) in
+/// [`get_bos_descriptors`](crate::class::UsbClass::get_bos_descriptors). Windows then reads the
+/// descriptor set with a vendor request using the vendor code from the capability, which can be
+/// recognized with [`descriptor_set_request`](msos20::descriptor_set_request) in
+/// [`control_in`](crate::class::UsbClass::control_in). The set itself is built with
+/// [`MsOs20Descriptor`](msos20::MsOs20Descriptor), usually once when the device starts up. Since
+/// it's often longer than the control buffer, it should be built in a static buffer and sent with
+/// [`ControlIn::accept_with_static`](crate::class::ControlIn::accept_with_static).
+///
+/// The length passed to `capability` must be the length returned by
+/// [`MsOs20Descriptor::finish`](msos20::MsOs20Descriptor::finish), or Windows ignores the set.
+pub mod msos20 {
+    use crate::{Result, UsbError};
+    use crate::bus::InterfaceNumber;
+    use crate::control::{Recipient, Request, RequestType};
+    use super::BosWriter;
+
+    /// The MS OS 2.0 platform capability UUID {d8dd60df-4589-4cc7-9cd2-659d9e648a9f} in the byte
+    /// order used in the descriptor.
+    pub const PLATFORM_CAPABILITY_UUID: [u8; 16] = [
+        0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c,
+        0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+    ];
+
+    /// The dwWindowsVersion value for Windows 8.1, the first version that supports MS OS 2.0
+    /// descriptors.
+    pub const WINDOWS_8_1: u32 = 0x0603_0000;
+
+    /// The wIndex value of the request for the descriptor set.
+    pub const DESCRIPTOR_INDEX: u16 = 7;
+
+    /// Registry property data types for
+    /// [`registry_property`](MsOs20Descriptor::registry_property).
+    #[allow(missing_docs)]
+    pub mod property_data_type {
+        pub const REG_SZ: u16 = 1;
+        pub const REG_EXPAND_SZ: u16 = 2;
+        pub const REG_BINARY: u16 = 3;
+        pub const REG_DWORD_LITTLE_ENDIAN: u16 = 4;
+        pub const REG_DWORD_BIG_ENDIAN: u16 = 5;
+        pub const REG_LINK: u16 = 6;
+        pub const REG_MULTI_SZ: u16 = 7;
+    }
+
+    const SET_HEADER_DESCRIPTOR: u16 = 0;
+    const SUBSET_HEADER_CONFIGURATION: u16 = 1;
+    const SUBSET_HEADER_FUNCTION: u16 = 2;
+    const FEATURE_COMPATIBLE_ID: u16 = 3;
+    const FEATURE_REG_PROPERTY: u16 = 4;
+
+    /// Writes an MS OS 2.0 platform capability for Windows 8.1 and later.
+    ///
+    /// # Arguments
+    ///
+    /// * `vendor_code` - The bRequest value Windows uses to request the descriptor set.
+    /// * `descriptor_set_len` - The total length of the descriptor set.
+    pub fn capability(writer: &mut BosWriter, vendor_code: u8, descriptor_set_len: u16)
+        -> Result<()>
+    {
+        let mut data = [0u8; 8];
+        let total_length = descriptor_set_len.to_le_bytes();
+
+        data[0..4].copy_from_slice(&WINDOWS_8_1.to_le_bytes()); // dwWindowsVersion
+        data[4..6].copy_from_slice(&total_length); // wMSOSDescriptorSetTotalLength
+        data[6] = vendor_code; // bMS_VendorCode
+        data[7] = 0; // bAltEnumCode
+
+        writer.platform_capability(&PLATFORM_CAPABILITY_UUID, &data)
+    }
+
+    /// Returns whether `req` is a request for the descriptor set with the specified vendor code.
+    pub fn descriptor_set_request(req: &Request, vendor_code: u8) -> bool {
+        req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == vendor_code
+            && req.index == DESCRIPTOR_INDEX
+    }
+
+    /// A writer for an MS OS 2.0 descriptor set.
+    ///
+    /// Features that apply to the whole device, such as a compatible ID for a device with a
+    /// single interface, are written right after creating the writer. Composite devices instead
+    /// start a [`configuration_subset`](Self::configuration_subset) and then a
+    /// [`function_subset`](Self::function_subset) for each function, and the features belong to
+    /// the most recently started subset.
+    ///
+    /// None of the methods panic. If a descriptor doesn't fit in the buffer, the method returns
+    /// [`BufferOverflow`](crate::UsbError::BufferOverflow).
+    pub struct MsOs20Descriptor<'a> {
+        buf: &'a mut [u8],
+        position: usize,
+        configuration_mark: Option<usize>,
+        function_mark: Option<usize>,
+    }
+
+    impl<'a> MsOs20Descriptor<'a> {
+        /// Creates a writer for a descriptor set in `buf` and writes the set header.
+        ///
+        /// # Errors
+        ///
+        /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The set header does not fit in
+        ///   `buf`.
+        pub fn new(buf: &'a mut [u8]) -> Result<MsOs20Descriptor<'a>> {
+            let mut w = MsOs20Descriptor {
+                buf,
+                position: 0,
+                configuration_mark: None,
+                function_mark: None,
+            };
+
+            let version = WINDOWS_8_1.to_le_bytes();
+
+            w.write(SET_HEADER_DESCRIPTOR, &[
+                version[0], version[1], version[2], version[3], // dwWindowsVersion
+                0, 0, // wTotalLength
+            ], &[])?;
+
+            Ok(w)
+        }
+
+        /// Starts a configuration subset, which holds the function subsets for one configuration.
+        /// Any subsets in progress are ended.
+        ///
+        /// # Arguments
+        ///
+        /// * `configuration_index` - The zero-based index of the configuration. This is the
+        ///   index used in GET_DESCRIPTOR, not the `bConfigurationValue`.
+        pub fn configuration_subset(&mut self, configuration_index: u8) -> Result<()> {
+            self.end_function();
+            self.end_configuration();
+
+            let mark = self.position + 6;
+
+            self.write(SUBSET_HEADER_CONFIGURATION, &[
+                configuration_index, // bConfigurationValue
+                0, // bReserved
+                0, 0, // wTotalLength
+            ], &[])?;
+
+            self.configuration_mark = Some(mark);
+
+            Ok(())
+        }
+
+        /// Starts a function subset, which holds the features for the function starting at
+        /// `first_interface`. Any function subset in progress is ended.
+        ///
+        /// # Errors
+        ///
+        /// * [`InvalidState`](crate::UsbError::InvalidState) - No configuration subset has been
+        ///   started.
+        pub fn function_subset(&mut self, first_interface: InterfaceNumber) -> Result<()> {
+            if self.configuration_mark.is_none() {
+                return Err(UsbError::InvalidState);
+            }
+
+            self.end_function();
+
+            let mark = self.position + 6;
+
+            self.write(SUBSET_HEADER_FUNCTION, &[
+                first_interface.into(), // bFirstInterface
+                0, // bReserved
+                0, 0, // wSubsetLength
+            ], &[])?;
+
+            self.function_mark = Some(mark);
+
+            Ok(())
+        }
+
+        /// Writes a compatible ID feature descriptor, such as `"WINUSB"` to load WinUSB.
+        ///
+        /// # Errors
+        ///
+        /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - An ID is longer than
+        ///   8 bytes.
+        pub fn compatible_id(&mut self, compatible_id: &str, sub_compatible_id: &str)
+            -> Result<()>
+        {
+            if compatible_id.len() > 8 || sub_compatible_id.len() > 8 {
+                return Err(UsbError::MalformedDescriptor);
+            }
+
+            let mut data = [0u8; 16];
+            data[..compatible_id.len()].copy_from_slice(compatible_id.as_bytes()); // CompatibleID
+            data[8..8 + sub_compatible_id.len()]
+                .copy_from_slice(sub_compatible_id.as_bytes()); // SubCompatibleID
+
+            self.write(FEATURE_COMPATIBLE_ID, &data, &[])
+        }
+
+        /// Writes a registry property feature descriptor. The name is written as a null
+        /// terminated UTF-16 string, and `data` as is, so string data must already be encoded as
+        /// UTF-16.
+        ///
+        /// # Arguments
+        ///
+        /// * `name` - The name of the registry value.
+        /// * `data_type` - The type of the value, see [`property_data_type`].
+        /// * `data` - The data of the value.
+        pub fn registry_property(&mut self, name: &str, data_type: u16, data: &[u8])
+            -> Result<()>
+        {
+            let name_len = (name.encode_utf16().count() + 1) * 2;
+
+            self.write_header(FEATURE_REG_PROPERTY, 2 + 2 + name_len + 2 + data.len())?;
+            self.put(&data_type.to_le_bytes()); // wPropertyDataType
+            self.put(&(name_len as u16).to_le_bytes()); // wPropertyNameLength
+            self.put_utf16(name, 1); // PropertyName
+            self.put(&(data.len() as u16).to_le_bytes()); // wPropertyDataLength
+            self.put(data); // PropertyData
+
+            Ok(())
+        }
+
+        /// Writes the `DeviceInterfaceGUIDs` registry property, through which applications find
+        /// the device when WinUSB is loaded for it.
+        ///
+        /// # Arguments
+        ///
+        /// * `guid` - The device interface GUID in braces, for example
+        ///   `"{5a0f08a7-8a07-4e1c-9e3f-f7e8a15e4a5b}"`.
+        pub fn device_interface_guid(&mut self, guid: &str) -> Result<()> {
+            const NAME: &str = "DeviceInterfaceGUIDs";
+
+            // A REG_MULTI_SZ with a single string is ended with two nulls
+            let name_len = (NAME.len() + 1) * 2;
+            let data_len = (guid.encode_utf16().count() + 2) * 2;
+
+            self.write_header(FEATURE_REG_PROPERTY, 2 + 2 + name_len + 2 + data_len)?;
+            self.put(&property_data_type::REG_MULTI_SZ.to_le_bytes()); // wPropertyDataType
+            self.put(&(name_len as u16).to_le_bytes()); // wPropertyNameLength
+            self.put_utf16(NAME, 1); // PropertyName
+            self.put(&(data_len as u16).to_le_bytes()); // wPropertyDataLength
+            self.put_utf16(guid, 2); // PropertyData
+
+            Ok(())
+        }
+
+        /// Ends all subsets, fills in the total length and returns the length of the descriptor
+        /// set, which must also be passed to [`capability`].
+        pub fn finish(mut self) -> usize {
+            self.end_function();
+            self.end_configuration();
+
+            let len = self.position as u16;
+            self.buf[8..10].copy_from_slice(&len.to_le_bytes());
+
+            self.position
+        }
+
+        fn end_configuration(&mut self) {
+            if let Some(mark) = self.configuration_mark.take() {
+                self.patch_length(mark);
+            }
+        }
+
+        fn end_function(&mut self) {
+            if let Some(mark) = self.function_mark.take() {
+                self.patch_length(mark);
+            }
+        }
+
+        // Writes the length of the subset whose header has its length field at `mark`
+        fn patch_length(&mut self, mark: usize) {
+            let len = (self.position - (mark - 6)) as u16;
+            self.buf[mark..mark + 2].copy_from_slice(&len.to_le_bytes());
+        }
+
+        fn write(&mut self, descriptor_type: u16, fields: &[u8], data: &[u8]) -> Result<()> {
+            self.write_header(descriptor_type, fields.len() + data.len())?;
+            self.put(fields);
+            self.put(data);
+
+            Ok(())
+        }
+
+        // Writes the wLength and wDescriptorType fields after checking that the whole descriptor
+        // with `len` bytes following the header fits.
+        fn write_header(&mut self, descriptor_type: u16, len: usize) -> Result<()> {
+            let len = 4 + len;
+
+            if self.position + len > self.buf.len() || self.position + len > u16::MAX as usize {
+                return Err(UsbError::BufferOverflow);
+            }
+
+            self.put(&(len as u16).to_le_bytes()); // wLength
+            self.put(&descriptor_type.to_le_bytes()); // wDescriptorType
+
+            Ok(())
+        }
+
+        fn put(&mut self, data: &[u8]) {
+            self.buf[self.position..self.position + data.len()].copy_from_slice(data);
+            self.position += data.len();
+        }
+
+        // Writes `s` as UTF-16 followed by `nulls` null characters
+        fn put_utf16(&mut self, s: &str, nulls: usize) {
+            for c in s.encode_utf16() {
+                self.put(&c.to_le_bytes());
+            }
+
+            for _ in 0..nulls {
+                self.put(&[0, 0]);
+            }
+        }
+    }
+}
+
+/// Describes a function made up of several interfaces, for which an interface association
+/// descriptor (IAD) is written. See
+/// [`UsbClass::function_descriptor`](crate::class::UsbClass::function_descriptor).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FunctionInfo {
+    /// The first interface of the function.
+    pub first_interface: InterfaceNumber,
+
+    /// The number of contiguous interfaces in the function, starting from `first_interface`.
+    pub interface_count: u8,
+
+    /// Function class code assigned by USB.org.
+    pub function_class: u8,
+
+    /// Function sub-class code. Depends on class.
+    pub function_sub_class: u8,
+
+    /// Function protocol code. Depends on class and sub-class.
+    pub function_protocol: u8,
+}
+
+// Interfaces declared for the function in progress: (first, count, written so far)
+type FunctionState = (u8, u8, u8);
+
 /// A writer for USB descriptors.
+///
+/// None of the methods panic. If a descriptor doesn't fit in the buffer or a field would overflow,
+/// the method returns an error and the contents of the buffer past the last successfully written
+/// descriptor are unspecified.
+///
+/// Configuration descriptors that are larger than the control buffer are generated several times,
+/// each time with the buffer holding a different window of the descriptor. In that case the
+/// writer discards anything outside the window, and the position is still the offset from the
+/// start of the whole descriptor.
 pub struct DescriptorWriter<'a> {
     buf: &'a mut [u8],
+    window_start: Option<usize>,
     position: usize,
     num_interfaces_mark: Option<usize>,
+    num_interfaces: u8,
     num_endpoints_mark: Option<usize>,
+    num_endpoints: u8,
+    last_standard_descriptor: Option<u8>,
+    function: Option<FunctionState>,
+    speed: UsbSpeed,
+    overflowed: Option<u8>,
+    other_speed: Option<UsbSpeed>,
+    configuration_value: u8,
+    // String index being looked for by new_string_probe, and whether it has been referenced
+    probed_string: Option<u8>,
+    string_referenced: bool,
+    has_functions: bool,
 }
 
 impl DescriptorWriter<'_> {
     pub(crate) fn new(buf: &mut [u8]) -> DescriptorWriter<'_> {
         DescriptorWriter {
             buf,
+            window_start: None,
             position: 0,
             num_interfaces_mark: None,
+            num_interfaces: 0,
             num_endpoints_mark: None,
+            num_endpoints: 0,
+            last_standard_descriptor: None,
+            function: None,
+            speed: UsbSpeed::Full,
+            overflowed: None,
+            other_speed: None,
+            configuration_value: device::CONFIGURATION_VALUE,
+            probed_string: None,
+            string_referenced: false,
+            has_functions: false,
+        }
+    }
+
+    // Creates a writer that only stores the part of the descriptors starting at `window_start`
+    // that fits in `buf`. Descriptors are not limited by the size of the buffer, only by the 16-bit
+    // wTotalLength field.
+    pub(crate) fn new_window(buf: &mut [u8], window_start: usize) -> DescriptorWriter<'_> {
+        DescriptorWriter {
+            window_start: Some(window_start),
+            ..DescriptorWriter::new(buf)
         }
     }
 
-    /// Gets the current position in the buffer, i.e. the number of bytes written so far.
+    // Like new_window, but for descriptors of a device operating at `speed`.
+    pub(crate) fn new_window_speed(buf: &mut [u8], window_start: usize, speed: UsbSpeed)
+        -> DescriptorWriter<'_>
+    {
+        DescriptorWriter {
+            speed,
+            ..DescriptorWriter::new_window(buf, window_start)
+        }
+    }
+
+    // Like new_window, but for the other speed configuration descriptor of a device that can
+    // operate at both full and high speed. Endpoint packet sizes are limited to the ones allowed at
+    // `speed`.
+    pub(crate) fn new_window_other_speed(buf: &mut [u8], window_start: usize, speed: UsbSpeed)
+        -> DescriptorWriter<'_>
+    {
+        DescriptorWriter {
+            other_speed: Some(speed),
+            ..DescriptorWriter::new_window_speed(buf, window_start, speed)
+        }
+    }
+
+    // Creates a writer that discards all descriptors and only records whether the string with the
+    // specified index is referenced, for finding out which class owns a string.
+    pub(crate) fn new_string_probe(buf: &mut [u8], index: u8) -> DescriptorWriter<'_> {
+        DescriptorWriter {
+            probed_string: Some(index),
+            ..DescriptorWriter::new_window(buf, usize::MAX)
+        }
+    }
+
+    // Returns whether the probed string has been referenced since the last call.
+    pub(crate) fn take_string_referenced(&mut self) -> bool {
+        core::mem::replace(&mut self.string_referenced, false)
+    }
+
+    /// Records a reference to a string descriptor from a class-specific descriptor and returns
+    /// the index to write into the descriptor. Interface descriptors record their `iInterface`
+    /// string automatically.
+    ///
+    /// [`UsbDevice`](crate::device::UsbDevice) only passes string requests to the class whose
+    /// descriptors reference the string, so strings referenced from class-specific descriptors
+    /// should be written with this.
+    pub fn string_reference(&mut self, index: StringIndex) -> u8 {
+        let index = index.into();
+
+        if self.probed_string == Some(index) {
+            self.string_referenced = true;
+        }
+
+        index
+    }
+
+    /// Gets the speed the descriptors are being written for if it is not the speed the device is
+    /// currently operating at, which is the case while writing the other speed configuration
+    /// descriptor of a high-speed capable device. Endpoint packet sizes are automatically limited
+    /// to the ones allowed at that speed, but classes may use this to also adjust other fields,
+    /// such as the interval of interrupt endpoints.
+    pub fn other_speed(&self) -> Option<UsbSpeed> {
+        self.other_speed
+    }
+
+    /// Gets the speed the configuration descriptor is being written for. This is the speed the
+    /// device is currently operating at, or the other speed while writing the other speed
+    /// configuration descriptor.
+    ///
+    /// Interfaces or alternate settings that only work at some speeds, such as high-bandwidth
+    /// streaming alternate settings that need more than full speed can provide, should be left out
+    /// at the other speeds so that the host never tries to select them. The class should then also
+    /// reject them in [`set_alternate_setting`](crate::class::UsbClass::set_alternate_setting).
+    pub fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    /// Gets the `bConfigurationValue` of the configuration descriptor being written. Devices with
+    /// more than one configuration call
+    /// [`get_configuration_descriptors`](crate::class::UsbClass::get_configuration_descriptors)
+    /// once for each of them, and classes that are only part of some configurations should skip
+    /// writing their descriptors for the others.
+    pub fn configuration_value(&self) -> u8 {
+        self.configuration_value
+    }
+
+    /// Gets the current position in the buffer, i.e. the number of bytes written so far. For
+    /// configuration descriptors this includes any bytes that didn't fit in the control buffer.
+    ///
+    /// Descriptors larger than the control endpoint packet size are sent in multiple packets, and
+    /// string descriptors are limited to 126 characters. This can be used to check the size of the
+    /// descriptors a class writes.
     pub fn position(&self) -> usize {
         self.position
     }
 
+    /// Gets the number of bytes that can still be written, including the 2-byte header of each
+    /// descriptor. For configuration descriptors this is limited by the 16-bit `wTotalLength`
+    /// field rather than the control buffer, otherwise by the size of the buffer.
+    pub fn remaining(&self) -> usize {
+        self.limit().saturating_sub(self.position)
+    }
+
+    /// Gets the descriptor type of the first descriptor that failed to be written with
+    /// [`BufferOverflow`](crate::UsbError::BufferOverflow), if any. Capability descriptors written
+    /// with a [`BosWriter`] are reported as
+    /// [`CAPABILITY`](crate::descriptor::descriptor_type::CAPABILITY).
+    ///
+    /// This can be used to find out which descriptor pushed the descriptors over the limit, as the
+    /// error itself doesn't say.
+    pub fn overflowed_descriptor_type(&self) -> Option<u8> {
+        self.overflowed
+    }
+
+    fn limit(&self) -> usize {
+        match self.window_start {
+            Some(_) => u16::MAX as usize,
+            None => self.buf.len(),
+        }
+    }
+
+    // Returns a BufferOverflow error, remembering the type of the first descriptor that caused one.
+    fn overflow<T>(&mut self, descriptor_type: u8) -> Result<T> {
+        self.overflowed.get_or_insert(descriptor_type);
+
+        Err(UsbError::BufferOverflow)
+    }
+
     /// Writes an arbitrary (usually class-specific) descriptor.
+    ///
+    /// Class-specific interface descriptors (`CS_INTERFACE`) must directly follow an interface
+    /// descriptor or other descriptors belonging to it, before any endpoint descriptors.
+    /// Class-specific endpoint descriptors (`CS_ENDPOINT`) must follow an endpoint descriptor.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The descriptor does not fit in the
+    ///   buffer or is longer than 255 bytes.
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - A class-specific interface
+    ///   or endpoint descriptor is not placed after the standard descriptor it belongs to.
     pub fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> Result<()> {
         let length = descriptor.len();
 
-        if (self.position + 2 + length) > self.buf.len() || (length + 2) > 255 {
-            return Err(UsbError::BufferOverflow);
+        let required_descriptor = match descriptor_type {
+            descriptor_type::CS_INTERFACE => Some(descriptor_type::INTERFACE),
+            descriptor_type::CS_ENDPOINT => Some(descriptor_type::ENDPOINT),
+            _ => None,
+        };
+
+        if required_descriptor.is_some() && required_descriptor != self.last_standard_descriptor {
+            return Err(UsbError::MalformedDescriptor);
         }
 
-        self.buf[self.position] = (length + 2) as u8;
-        self.buf[self.position + 1] = descriptor_type;
+        if (self.position + 2 + length) > self.limit() || (length + 2) > 255 {
+            return self.overflow(descriptor_type);
+        }
+
+        self.put(self.position, &[(length + 2) as u8, descriptor_type]);
 
         let start = self.position + 2;
 
-        self.buf[start..start + length].copy_from_slice(descriptor);
+        self.put(start, descriptor);
 
         self.position = start + length;
 
         Ok(())
     }
 
+    // Copies data to an offset from the start of the descriptors, discarding anything outside the
+    // window held in the buffer.
+    fn put(&mut self, offset: usize, data: &[u8]) {
+        let window_start = self.window_start.unwrap_or(0);
+
+        for (i, &b) in data.iter().enumerate() {
+            if let Some(byte) = (offset + i).checked_sub(window_start)
+                .and_then(|pos| self.buf.get_mut(pos))
+            {
+                *byte = b;
+            }
+        }
+    }
+
     pub(crate) fn device(&mut self, config: &device::Config) -> Result<()> {
         self.write(
             descriptor_type::DEVICE,
@@ -91,34 +870,178 @@ impl DescriptorWriter<'_> {
                 config.manufacturer.map_or(0, |_| 1), // iManufacturer
                 config.product.map_or(0, |_| 2), // iProduct
                 config.serial_number.map_or(0, |_| 3), // iSerialNumber
-                1, // bNumConfigurations
+                config.num_configurations, // bNumConfigurations
             ])
     }
 
-    pub(crate) fn configuration(&mut self, config: &device::Config) -> Result<()> {
-        self.num_interfaces_mark = Some(self.position + 4);
+    pub(crate) fn device_qualifier(&mut self, config: &device::Config, other_speed: UsbSpeed)
+        -> Result<()>
+    {
+        // Endpoint 0 always has a maximum packet size of 64 at high speed
+        let max_packet_size_0 = match other_speed {
+            UsbSpeed::High => 64,
+            _ => config.max_packet_size_0,
+        };
 
         self.write(
-            descriptor_type::CONFIGURATION,
+            descriptor_type::DEVICE_QUALIFIER,
+            &[
+                0x00, 0x02, // bcdUSB 2.0
+                config.device_class, // bDeviceClass
+                config.device_sub_class, // bDeviceSubClass
+                config.device_protocol, // bDeviceProtocol
+                max_packet_size_0, // bMaxPacketSize0
+                config.num_configurations, // bNumConfigurations
+                0, // bReserved
+            ])
+    }
+
+    pub(crate) fn configuration(&mut self, config: &device::Config, value: u8) -> Result<()> {
+        let mark = self.position + 4;
+        let dtype = match self.other_speed {
+            Some(_) => descriptor_type::OTHER_SPEED_CONFIGURATION,
+            None => descriptor_type::CONFIGURATION,
+        };
+
+        self.write(
+            dtype,
             &[
                 0, 0, // wTotalLength
                 0, // bNumInterfaces
-                device::CONFIGURATION_VALUE, // bConfigurationValue
+                value, // bConfigurationValue
                 0, // iConfiguration
                 0x80
                     | if config.self_powered { 0x40 } else { 0x00 }
                     | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
                 config.max_power // bMaxPower
-            ])
+            ])?;
+
+        self.num_interfaces_mark = Some(mark);
+        self.num_interfaces = 0;
+        self.configuration_value = value;
+        self.last_standard_descriptor = None;
+
+        Ok(())
+    }
+
+    // Increments a count field previously written at `mark`.
+    fn increment_mark(&mut self, mark: Option<usize>) -> Result<()> {
+        let count = match mark.and_then(|mark| self.buf.get_mut(mark)) {
+            Some(count) => count,
+            None => return Err(UsbError::InvalidState),
+        };
+
+        *count = count.checked_add(1).ok_or(UsbError::MalformedDescriptor)?;
+
+        Ok(())
+    }
+
+    // Increments a count field previously written at `mark` and returns the new count. The count
+    // is passed in instead of read back from the buffer, since the field may be outside the window.
+    fn increment_count(&mut self, mark: Option<usize>, count: u8) -> Result<u8> {
+        let mark = mark.ok_or(UsbError::InvalidState)?;
+        let count = count.checked_add(1).ok_or(UsbError::MalformedDescriptor)?;
+
+        self.put(mark, &[count]);
+
+        Ok(count)
+    }
+
+    // Writes a length field into the 16-bit field at offset 2 of the first descriptor.
+    fn write_total_length(&mut self) {
+        let position = self.position as u16;
+
+        self.put(2, &position.to_le_bytes());
+    }
+
+    // Writes an interface association descriptor and checks that the interfaces written before
+    // end_class match it.
+    pub(crate) fn begin_function(&mut self, info: &FunctionInfo) -> Result<()> {
+        if self.num_interfaces_mark.is_none() {
+            return Err(UsbError::InvalidState);
+        }
+
+        let first: u8 = info.first_interface.into();
+
+        if info.interface_count == 0 || first.checked_add(info.interface_count - 1).is_none() {
+            return Err(UsbError::MalformedDescriptor);
+        }
+
+        self.write(
+            descriptor_type::IAD,
+            &[
+                first, // bFirstInterface
+                info.interface_count, // bInterfaceCount
+                info.function_class, // bFunctionClass
+                info.function_sub_class, // bFunctionSubClass
+                info.function_protocol, // bFunctionProtocol
+                0, // iFunction
+            ])?;
+
+        self.function = Some((first, info.interface_count, 0));
+        self.has_functions = true;
+
+        Ok(())
+    }
+
+    // Returns whether any interface association descriptors have been written.
+    pub(crate) fn has_functions(&self) -> bool {
+        self.has_functions
+    }
+
+    /// Writes an interface association descriptor (IAD), which groups the following interfaces
+    /// into a single function, such as the control and data interfaces of a CDC-ACM serial port.
+    /// The interface descriptors of the function must follow it directly, and the device checks
+    /// that exactly `interface_count` interfaces starting from `first_interface` are written by the
+    /// class.
+    ///
+    /// Classes that always form a function can return it from
+    /// [`UsbClass::function_descriptor`](crate::class::UsbClass::function_descriptor) instead, in
+    /// which case the device writes the IAD and this method must not be called.
+    ///
+    /// # Arguments
+    ///
+    /// * `first_interface` - The first interface of the function, previously allocated with
+    ///   [`UsbBusAllocator::interface`](crate::bus::UsbBusAllocator::interface).
+    /// * `interface_count` - The number of contiguous interfaces in the function.
+    /// * `function_class` - Function class code assigned by USB.org.
+    /// * `function_sub_class` - Function sub-class code. Depends on class.
+    /// * `function_protocol` - Function protocol code. Depends on class and sub-class.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - `interface_count` is zero, or an IAD has
+    ///   already been written for the class.
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - The interface numbers of
+    ///   the function would overflow.
+    pub fn iad(&mut self, first_interface: InterfaceNumber, interface_count: u8,
+        function_class: u8, function_sub_class: u8, function_protocol: u8) -> Result<()>
+    {
+        if interface_count == 0 || self.function.is_some() {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.begin_function(&FunctionInfo {
+            first_interface,
+            interface_count,
+            function_class,
+            function_sub_class,
+            function_protocol,
+        })
     }
 
-    pub(crate) fn end_class(&mut self) {
+    pub(crate) fn end_class(&mut self) -> Result<()> {
         self.num_endpoints_mark = None;
+        self.last_standard_descriptor = None;
+
+        match self.function.take() {
+            Some((_, count, written)) if written != count => Err(UsbError::MalformedDescriptor),
+            _ => Ok(()),
+        }
     }
 
     pub(crate) fn end_configuration(&mut self) {
-        let position = self.position as u16;
-        self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        self.write_total_length();
     }
 
     /// Writes a interface descriptor.
@@ -134,28 +1057,134 @@ impl DescriptorWriter<'_> {
     pub fn interface(&mut self, number: InterfaceNumber,
         interface_class: u8, interface_sub_class: u8, interface_protocol: u8) -> Result<()>
     {
-        match self.num_interfaces_mark {
-            Some(mark) => self.buf[mark] += 1,
-            None => return Err(UsbError::InvalidState),
-        };
+        self.interface_alt(
+            number,
+            device::DEFAULT_ALTERNATE_SETTING,
+            interface_class,
+            interface_sub_class,
+            interface_protocol,
+            None)
+    }
+
+    /// Writes an interface descriptor for a specific alternate setting. The descriptor for the
+    /// default alternate setting 0 must be written first, followed by the other alternate settings
+    /// of the same interface, each followed by its own endpoints.
+    ///
+    /// Alternate settings other than 0 are not counted in `bNumInterfaces`.
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - Interface number previously allocated with
+    ///   [`UsbBusAllocator::interface`](crate::bus::UsbBusAllocator::interface).
+    /// * `alternate_setting` - Number of the alternate setting.
+    /// * `interface_class` - Class code assigned by USB.org. Use `0xff` for vendor-specific devices
+    ///   that do not conform to any class.
+    /// * `interface_sub_class` - Sub-class code. Depends on class.
+    /// * `interface_protocol` - Protocol code. Depends on class and sub-class.
+    /// * `interface_string` - Index of a string descriptor describing the interface, previously
+    ///   allocated with [`UsbBusAllocator::string`](crate::bus::UsbBusAllocator::string). The
+    ///   class must also return the string from
+    ///   [`UsbClass::get_string`](crate::class::UsbClass::get_string).
+    ///
+    /// The sub-class and protocol are written as is, so vendor-specific interfaces can use any
+    /// values for them, and each alternate setting can use its own.
+    ///
+    /// # Errors
+    ///
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - `interface_class` is
+    ///   `0x00`, which is reserved and only valid in the device descriptor, or the interface is
+    ///   outside the function being written, see [`iad`](Self::iad).
+    pub fn interface_alt(&mut self, number: InterfaceNumber, alternate_setting: u8,
+        interface_class: u8, interface_sub_class: u8, interface_protocol: u8,
+        interface_string: Option<StringIndex>) -> Result<()>
+    {
+        if self.num_interfaces_mark.is_none() {
+            return Err(UsbError::InvalidState);
+        }
+
+        if interface_class == 0x00 {
+            return Err(UsbError::MalformedDescriptor);
+        }
 
-        self.num_endpoints_mark = Some(self.position + 4);
+        let is_default = alternate_setting == device::DEFAULT_ALTERNATE_SETTING;
+
+        if let (true, Some((first, count, _))) = (is_default, self.function) {
+            let n: u8 = number.into();
+
+            if n < first || n - first >= count {
+                return Err(UsbError::MalformedDescriptor);
+            }
+        }
+
+        let mark = self.position + 4;
+        let interface_string = interface_string.map_or(0, |s| self.string_reference(s));
 
         self.write(
             descriptor_type::INTERFACE,
             &[
                 number.into(), // bInterfaceNumber
-                device::DEFAULT_ALTERNATE_SETTING, // bAlternateSetting (how to even handle these...)
+                alternate_setting, // bAlternateSetting
                 0, // bNumEndpoints
                 interface_class, // bInterfaceClass
                 interface_sub_class, // bInterfaceSubClass
                 interface_protocol, // bInterfaceProtocol
-                0, // iInterface
+                interface_string, // iInterface
             ])?;
 
+        if is_default {
+            self.num_interfaces =
+                self.increment_count(self.num_interfaces_mark, self.num_interfaces)?;
+
+            if let Some((_, _, written)) = self.function.as_mut() {
+                *written += 1;
+            }
+        }
+
+        self.num_endpoints_mark = Some(mark);
+        self.num_endpoints = 0;
+        self.last_standard_descriptor = Some(descriptor_type::INTERFACE);
+
         Ok(())
     }
 
+    /// Writes the descriptors for an interface whose default alternate setting 0 has no endpoints
+    /// and uses no bandwidth, followed by alternate setting 1 that carries the data. This is
+    /// required for example for USB audio and video streaming interfaces, so that the host only
+    /// reserves bandwidth while streaming.
+    ///
+    /// Both interface descriptors are written with the same class codes and string. The endpoints
+    /// and any class-specific descriptors written after this belong to alternate setting 1.
+    /// Further alternate settings can be added with [`interface_alt`](Self::interface_alt).
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - Interface number previously allocated with
+    ///   [`UsbBusAllocator::interface`](crate::bus::UsbBusAllocator::interface).
+    /// * `interface_class` - Class code assigned by USB.org.
+    /// * `interface_sub_class` - Sub-class code. Depends on class.
+    /// * `interface_protocol` - Protocol code. Depends on class and sub-class.
+    /// * `interface_string` - Index of a string descriptor describing the interface, if any.
+    pub fn zero_bandwidth_interface(&mut self, number: InterfaceNumber,
+        interface_class: u8, interface_sub_class: u8, interface_protocol: u8,
+        interface_string: Option<StringIndex>) -> Result<()>
+    {
+        self.interface_alt(
+            number,
+            device::DEFAULT_ALTERNATE_SETTING,
+            interface_class,
+            interface_sub_class,
+            interface_protocol,
+            interface_string)?;
+
+        self.interface_alt(
+            number,
+            1,
+            interface_class,
+            interface_sub_class,
+            interface_protocol,
+            interface_string)
+    }
+
     /// Writes an endpoint descriptor.
     ///
     /// # Arguments
@@ -165,21 +1194,152 @@ impl DescriptorWriter<'_> {
     pub fn endpoint<'e, B: UsbBus, D: EndpointDirection>(&mut self, endpoint: &Endpoint<'e, B, D>)
         -> Result<()>
     {
-        match self.num_endpoints_mark {
-            Some(mark) => self.buf[mark] += 1,
-            None => return Err(UsbError::InvalidState),
-        };
+        self.write_endpoint(endpoint, endpoint.max_packet_size(), None)
+    }
+
+    /// Writes an endpoint descriptor with a smaller maximum packet size than the endpoint was
+    /// allocated with. This is used when the packet size of an endpoint differs between the
+    /// alternate settings of an interface, in which case the endpoint should be allocated with the
+    /// largest size used by any alternate setting.
+    ///
+    /// The class should also call [`Endpoint::set_max_packet_size`] when the alternate setting is
+    /// selected.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - Endpoint previously allocated with
+    ///   [`UsbBusAllocator`](crate::bus::UsbBusAllocator).
+    /// * `max_packet_size` - The wMaxPacketSize value for this alternate setting.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - `max_packet_size` is larger than
+    ///   the size the endpoint was allocated with.
+    pub fn endpoint_with_packet_size<'e, B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'e, B, D>,
+        max_packet_size: u16) -> Result<()>
+    {
+        if max_packet_size > endpoint.max_packet_size() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.write_endpoint(endpoint, max_packet_size, None)
+    }
+
+    /// Writes an endpoint descriptor for a high-bandwidth high-speed isochronous or interrupt
+    /// endpoint that performs more than one transaction per microframe.
+    ///
+    /// High-bandwidth endpoints are only valid for devices operating at high speed, so classes
+    /// should check [`speed`](DescriptorWriter::speed) and only request additional transactions
+    /// at high speed.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - Endpoint previously allocated with
+    ///   [`UsbBusAllocator`](crate::bus::UsbBusAllocator).
+    /// * `additional_transactions` - Number of additional transactions per microframe. Must be 0, 1
+    ///   or 2.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - The endpoint is not an
+    ///   isochronous or interrupt endpoint, its maximum packet size is larger than 1024 bytes,
+    ///   `additional_transactions` is larger than 2, or `additional_transactions` is not 0 and the
+    ///   descriptor is not being written for high speed.
+    pub fn endpoint_high_bandwidth<'e, B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'e, B, D>,
+        additional_transactions: u8) -> Result<()>
+    {
+        match endpoint.ep_type() {
+            EndpointType::Isochronous | EndpointType::Interrupt => { },
+            _ => return Err(UsbError::InvalidEndpoint),
+        }
 
         let mps = endpoint.max_packet_size();
 
-        self.write(
-            descriptor_type::ENDPOINT,
-            &[
-                endpoint.address().into(), // bEndpointAddress
-                endpoint.ep_type() as u8, // bmAttributes
-                mps as u8, (mps >> 8) as u8, // wMaxPacketSize
-                endpoint.interval(), // bInterval
-            ])?;
+        if mps > 1024 || additional_transactions > 2 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        if additional_transactions > 0 && self.speed != UsbSpeed::High {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.write_endpoint(endpoint, mps | ((additional_transactions as u16) << 11), None)
+    }
+
+    /// Writes an audio class 1.0 (UAC1) endpoint descriptor for an isochronous synch (feedback)
+    /// endpoint.
+    ///
+    /// UAC1 endpoint descriptors are two bytes longer than standard endpoint descriptors. For a
+    /// feedback endpoint, `bRefresh` specifies how often the feedback value is updated and
+    /// `bSynchAddress` is zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - Endpoint previously allocated with
+    ///   [`UsbBusAllocator`](crate::bus::UsbBusAllocator).
+    /// * `refresh` - Feedback update rate as an exponent, i.e. the feedback value is updated every
+    ///   2^`refresh` ms. Must be between 1 and 9.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - The endpoint is not an
+    ///   isochronous endpoint, or `refresh` is not between 1 and 9.
+    pub fn endpoint_feedback<'e, B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'e, B, D>,
+        refresh: u8) -> Result<()>
+    {
+        if endpoint.ep_type() != EndpointType::Isochronous || !(1..=9).contains(&refresh) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.write_endpoint(endpoint, endpoint.max_packet_size(), Some((refresh, 0)))
+    }
+
+    // `audio` contains the bRefresh and bSynchAddress fields of a UAC1 endpoint descriptor.
+    fn write_endpoint<'e, B: UsbBus, D: EndpointDirection>(
+        &mut self,
+        endpoint: &Endpoint<'e, B, D>,
+        mps: u16,
+        audio: Option<(u8, u8)>) -> Result<()>
+    {
+        if self.num_endpoints_mark.is_none() {
+            return Err(UsbError::InvalidState);
+        }
+
+        let (refresh, synch_address) = audio.unwrap_or((0, 0));
+
+        let mps = match self.other_speed {
+            Some(speed) => {
+                let limit = speed.max_packet_size(endpoint.ep_type());
+
+                // Additional transactions per microframe are only possible at high speed
+                let extra = if speed == UsbSpeed::High { mps & !0x07ff } else { 0 };
+
+                (mps & 0x07ff).min(limit) | extra
+            },
+            None => mps,
+        };
+
+        let descriptor = [
+            endpoint.address().into(), // bEndpointAddress
+            endpoint.attributes(), // bmAttributes
+            mps as u8, (mps >> 8) as u8, // wMaxPacketSize
+            endpoint.interval(), // bInterval
+            refresh, // bRefresh
+            synch_address, // bSynchAddress
+        ];
+
+        let len = if audio.is_some() { 7 } else { 5 };
+
+        self.write(descriptor_type::ENDPOINT, &descriptor[..len])?;
+
+        self.num_endpoints = self.increment_count(self.num_endpoints_mark, self.num_endpoints)?;
+        self.last_standard_descriptor = Some(descriptor_type::ENDPOINT);
 
         Ok(())
     }
@@ -189,7 +1349,7 @@ impl DescriptorWriter<'_> {
         let mut pos = self.position;
 
         if pos + 2 > self.buf.len() {
-            return Err(UsbError::BufferOverflow);
+            return self.overflow(descriptor_type::STRING);
         }
 
         self.buf[pos] = 0; // length placeholder
@@ -198,8 +1358,9 @@ impl DescriptorWriter<'_> {
         pos += 2;
 
         for c in string.encode_utf16() {
-            if pos >= self.buf.len() {
-                return Err(UsbError::BufferOverflow);
+            // The length of a string descriptor must fit in its one byte bLength field
+            if pos + 2 > self.buf.len() || (pos + 2 - self.position) > 255 {
+                return self.overflow(descriptor_type::STRING);
             }
 
             self.buf[pos..pos + 2].copy_from_slice(&c.to_le_bytes());
@@ -218,6 +1379,9 @@ impl DescriptorWriter<'_> {
 pub struct BosWriter<'w, 'a: 'w> {
     writer: &'w mut DescriptorWriter<'a>,
     num_caps_mark: Option<usize>,
+    // Position of the bmAttributes field of the USB 2.0 Extension capability
+    usb2_extension_mark: Option<usize>,
+    billboard: bool,
 }
 
 impl<'w, 'a: 'w> BosWriter<'w, 'a> {
@@ -225,11 +1389,14 @@ impl<'w, 'a: 'w> BosWriter<'w, 'a> {
         Self {
             writer: writer,
             num_caps_mark: None,
+            usb2_extension_mark: None,
+            billboard: false,
         }
     }
 
     pub(crate) fn bos(&mut self) -> Result<()> {
-        self.num_caps_mark= Some(self.writer.position + 4);
+        let mark = self.writer.position + 4;
+
         self.writer.write(
             descriptor_type::BOS,
             &[
@@ -237,7 +1404,51 @@ impl<'w, 'a: 'w> BosWriter<'w, 'a> {
                 0x00, // bNumDeviceCaps
             ])?;
 
+        self.num_caps_mark = Some(mark);
+
+        let usb2_extension_mark = self.writer.position + 3;
         self.capability(capability_type::USB_2_0_EXTENSION, &[0; 4])?;
+        self.usb2_extension_mark = Some(usb2_extension_mark);
+
+        Ok(())
+    }
+
+    /// Advertises support for Link Power Management (LPM) in the USB 2.0 Extension capability,
+    /// which is always written at the start of the BOS descriptor. The bus must also acknowledge
+    /// LPM transactions, see
+    /// [`UsbDevice::set_lpm_enabled`](crate::device::UsbDevice::set_lpm_enabled).
+    ///
+    /// # Arguments
+    ///
+    /// * `baseline_besl` - The recommended BESL value for the default power mode, if any.
+    /// * `deep_besl` - The recommended BESL value for a deeper power mode, if any.
+    ///
+    /// # Errors
+    ///
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - A BESL value doesn't fit
+    ///   in 4 bits.
+    pub fn lpm(&mut self, baseline_besl: Option<u8>, deep_besl: Option<u8>) -> Result<()> {
+        let mark = match self.usb2_extension_mark {
+            Some(mark) => mark,
+            None => return Err(UsbError::InvalidState),
+        };
+
+        if baseline_besl.unwrap_or(0) > 0x0f || deep_besl.unwrap_or(0) > 0x0f {
+            return Err(UsbError::MalformedDescriptor);
+        }
+
+        // LPM and BESL support, followed by the recommended values if they are valid
+        let mut attributes: u32 = 0x0000_0006;
+
+        if let Some(besl) = baseline_besl {
+            attributes |= 0x0000_0008 | ((besl as u32) << 8);
+        }
+
+        if let Some(besl) = deep_besl {
+            attributes |= 0x0000_0010 | ((besl as u32) << 12);
+        }
+
+        self.writer.buf[mark..mark+4].copy_from_slice(&attributes.to_le_bytes());
 
         Ok(())
     }
@@ -249,16 +1460,15 @@ impl<'w, 'a: 'w> BosWriter<'w, 'a> {
     /// * `capability_type` - Type of a capability
     /// * `data` - Binary data of the descriptor
     pub fn capability(&mut self, capability_type: u8, data: &[u8]) -> Result<()> {
-        match self.num_caps_mark{
-            Some(mark) => self.writer.buf[mark] += 1,
-            None => return Err(UsbError::InvalidState),
+        if self.num_caps_mark.is_none() {
+            return Err(UsbError::InvalidState);
         }
 
         let mut start = self.writer.position;
         let blen = data.len();
 
         if (start + blen + 3) > self.writer.buf.len() || (blen + 3) > 255 {
-            return Err(UsbError::BufferOverflow);
+            return self.writer.overflow(descriptor_type::CAPABILITY);
         }
 
         self.writer.buf[start] = (blen + 3) as u8;
@@ -269,12 +1479,115 @@ impl<'w, 'a: 'w> BosWriter<'w, 'a> {
         self.writer.buf[start..start+blen].copy_from_slice(data);
         self.writer.position = start + blen;
 
+        self.writer.increment_mark(self.num_caps_mark)?;
+
+        if capability_type == self::capability_type::BILLBOARD {
+            self.billboard = true;
+        }
+
         Ok(())
     }
 
+    /// Writes a platform capability descriptor, which is how platform-specific features such as
+    /// [`webusb`] and [`msos20`] are advertised.
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid` - The PlatformCapabilityUUID in the byte order used in the descriptor.
+    /// * `data` - The platform-specific data following the UUID.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The descriptor does not fit in the
+    ///   buffer or is longer than 255 bytes.
+    pub fn platform_capability(&mut self, uuid: &[u8; 16], data: &[u8]) -> Result<()> {
+        let mut buf = [0u8; 252];
+        let len = 1 + 16 + data.len();
+
+        if len > buf.len() {
+            return self.writer.overflow(descriptor_type::CAPABILITY);
+        }
+
+        // buf[0] is bReserved
+        buf[1..17].copy_from_slice(uuid); // PlatformCapabilityUUID
+        buf[17..len].copy_from_slice(data);
+
+        self.capability(capability_type::PLATFORM, &buf[..len])
+    }
+
+    /// Writes a SuperSpeedPlus USB Device Capability descriptor. This should only be written by
+    /// devices whose bus can operate at SuperSpeedPlus (USB 3.1 Gen 2 or later).
+    ///
+    /// # Arguments
+    ///
+    /// * `speed_id_count` - Number of distinct sublink speed IDs used by
+    ///   `sublink_speed_attributes`.
+    /// * `min_speed_id` - Sublink speed ID of the minimum speed at which the device is fully
+    ///   functional.
+    /// * `min_rx_lanes` - Minimum number of receive lanes at which the device is fully functional.
+    /// * `min_tx_lanes` - Minimum number of transmit lanes at which the device is fully functional.
+    /// * `sublink_speed_attributes` - The bmSublinkSpeedAttr values, usually a receive and a
+    ///   transmit attribute for each speed ID.
+    ///
+    /// # Errors
+    ///
+    /// * [`MalformedDescriptor`](crate::UsbError::MalformedDescriptor) - There are no attributes or
+    ///   more than 32, `speed_id_count` doesn't match the speed IDs in the attributes,
+    ///   `min_speed_id` is not one of them, or a lane count doesn't fit in 4 bits.
+    pub fn superspeed_plus(
+        &mut self,
+        speed_id_count: u8,
+        min_speed_id: u8,
+        min_rx_lanes: u8,
+        min_tx_lanes: u8,
+        sublink_speed_attributes: &[u32]) -> Result<()>
+    {
+        const MAX_ATTRIBUTES: usize = 32;
+
+        let num_attributes = sublink_speed_attributes.len();
+
+        if num_attributes == 0 || num_attributes > MAX_ATTRIBUTES
+            || min_speed_id > 0x0f || min_rx_lanes > 0x0f || min_tx_lanes > 0x0f
+        {
+            return Err(UsbError::MalformedDescriptor);
+        }
+
+        // Bit set of the sublink speed IDs in use
+        let speed_ids = sublink_speed_attributes.iter()
+            .fold(0u16, |ids, attr| ids | (1 << (attr & 0x0f)));
+
+        if speed_id_count as u32 != speed_ids.count_ones() || speed_ids & (1 << min_speed_id) == 0 {
+            return Err(UsbError::MalformedDescriptor);
+        }
+
+        let attributes = ((num_attributes as u32 - 1) & 0x1f)
+            | (((speed_id_count as u32 - 1) & 0x0f) << 5);
+
+        let functionality_support = (min_speed_id as u16)
+            | ((min_rx_lanes as u16) << 8)
+            | ((min_tx_lanes as u16) << 12);
+
+        let mut data = [0u8; 9 + 4 * MAX_ATTRIBUTES];
+
+        // data[0] is bReserved
+        data[1..5].copy_from_slice(&attributes.to_le_bytes()); // bmAttributes
+        data[5..7].copy_from_slice(&functionality_support.to_le_bytes()); // wFunctionalitySupport
+        // data[7..9] is wReserved
+
+        for (i, attr) in sublink_speed_attributes.iter().enumerate() {
+            data[9 + i * 4..9 + (i + 1) * 4].copy_from_slice(&attr.to_le_bytes());
+        }
+
+        self.capability(capability_type::SUPERSPEED_PLUS, &data[..9 + 4 * num_attributes])
+    }
+
+    /// Returns true if a Billboard capability has been written.
+    pub(crate) fn has_billboard(&self) -> bool {
+        self.billboard
+    }
+
     pub(crate) fn end_bos(&mut self) {
-        self.num_caps_mark= None;
-        let position = self.writer.position as u16;
-        self.writer.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        self.num_caps_mark = None;
+        self.writer.write_total_length();
     }
 }
\ No newline at end of file
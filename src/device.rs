@@ -1,9 +1,10 @@
-use crate::{Result, UsbDirection};
-use crate::bus::{UsbBusAllocator, UsbBus, PollResult, StringIndex};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{Result, UsbDirection, UsbError};
+use crate::bus::{UsbBusAllocator, UsbBus, UsbSpeed, PollResult, InterfaceNumber, StringIndex};
 use crate::class::{UsbClass, ControlIn, ControlOut};
 use crate::control;
 use crate::control_pipe::ControlPipe;
-use crate::descriptor::{DescriptorWriter, BosWriter, descriptor_type, lang_id};
+use crate::descriptor::{DescriptorWriter, BosWriter, descriptor_type, device_class};
 use crate::endpoint::{EndpointType, EndpointAddress};
 pub use crate::device_builder::{UsbDeviceBuilder, UsbVidPid};
 
@@ -35,9 +36,113 @@ pub struct UsbDevice<'a, B: UsbBus> {
     config: Config<'a>,
     control: ControlPipe<'a, B>,
     device_state: UsbDeviceState,
+    configuration: u8,
     remote_wakeup_enabled: bool,
     self_powered: bool,
     pending_address: u8,
+    system_exit_latency: Option<SystemExitLatency>,
+    isoch_delay: Option<u16>,
+    state_before_suspend: UsbDeviceState,
+    control_fallback: ControlFallback<B>,
+    class_error: Option<ClassError>,
+    // State most recently passed to UsbClass::device_state_changed, None before the first call
+    reported_state: Option<UsbDeviceState>,
+    // Index of the class streaming the current control IN response and the request
+    in_stream: Option<(usize, control::Request)>,
+    // Speed of the configuration descriptor being sent in windows, if it's the other speed one
+    window_other_speed: Option<UsbSpeed>,
+    // bConfigurationValue of the configuration descriptor being sent in windows
+    window_configuration: u8,
+    // Masks of the OUT and IN endpoints allocated before the device was built
+    ep_masks: (u16, u16),
+    // Zero-length packets queued by EndpointIn::write_packet_terminated, indexed by endpoint number
+    zlp_pending: &'a [AtomicBool; 16],
+}
+
+// Handlers for control requests that no class or the device itself handles, set with
+// UsbDeviceBuilder::control_in_fallback and control_out_fallback.
+pub(crate) struct ControlFallback<B: UsbBus> {
+    pub control_in: Option<fn(ControlIn<B>)>,
+    pub control_out: Option<fn(ControlOut<B>)>,
+}
+
+/// The point in [`UsbDevice::poll`] at which the hook set with
+/// [`UsbDeviceBuilder::poll_hook`] is called.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PollPhase {
+    /// Called before the bus is polled.
+    Start,
+    /// Called after all events have been processed, just before `poll` returns.
+    End,
+}
+
+/// A summary of what happened during a call to [`UsbDevice::poll_events`], for example to decide
+/// whether it's safe to enter a low power state.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct PollEvents {
+    /// Endpoint 0 received a SETUP or OUT packet or finished sending an IN packet. Control
+    /// requests may have been passed to [`control_in`](crate::class::UsbClass::control_in),
+    /// [`control_out`](crate::class::UsbClass::control_out) and the related callbacks.
+    pub control: bool,
+
+    /// Another endpoint received data, and
+    /// [`endpoint_out`](crate::class::UsbClass::endpoint_out) was called.
+    pub endpoint_out: bool,
+
+    /// Another endpoint finished sending data, and
+    /// [`endpoint_in_complete`](crate::class::UsbClass::endpoint_in_complete) was called.
+    pub endpoint_in_complete: bool,
+
+    /// Another endpoint received a SETUP packet, and
+    /// [`endpoint_setup`](crate::class::UsbClass::endpoint_setup) was called.
+    pub endpoint_setup: bool,
+
+    /// The bus was reset, and [`reset`](crate::class::UsbClass::reset) was called.
+    pub reset: bool,
+
+    /// The bus was suspended, or put into the L1 sleep state with Link Power Management.
+    pub suspend: bool,
+
+    /// The bus was resumed from suspend.
+    pub resume: bool,
+}
+
+impl PollEvents {
+    /// Returns true if there was endpoint activity of any kind, which is what
+    /// [`UsbDevice::poll`] returns. [`poll`](crate::class::UsbClass::poll) is only called on the
+    /// classes in that case.
+    pub fn has_data(&self) -> bool {
+        self.control || self.endpoint_out || self.endpoint_in_complete || self.endpoint_setup
+    }
+
+    /// Returns true if nothing at all happened during the poll.
+    pub fn is_idle(&self) -> bool {
+        *self == PollEvents::default()
+    }
+}
+
+/// System exit latency values sent by the host with a SET_SEL request. All values are in
+/// microseconds.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SystemExitLatency {
+    /// Time from initiating an exit from U1 until the host can receive a packet (U1SEL).
+    pub u1_sel: u8,
+    /// Time from initiating an exit from U1 until the device can receive a packet (U1PEL).
+    pub u1_pel: u8,
+    /// Time from initiating an exit from U2 until the host can receive a packet (U2SEL).
+    pub u2_sel: u16,
+    /// Time from initiating an exit from U2 until the device can receive a packet (U2PEL).
+    pub u2_pel: u16,
+}
+
+/// An error returned by [`UsbClass::try_poll`], as reported by [`UsbDevice::take_class_error`].
+#[derive(Debug)]
+pub struct ClassError {
+    /// The index of the class in the list passed to [`UsbDevice::poll`].
+    pub class_index: usize,
+
+    /// The error returned by the class.
+    pub error: UsbError,
 }
 
 pub(crate) struct Config<'a> {
@@ -51,25 +156,37 @@ pub(crate) struct Config<'a> {
     pub manufacturer: Option<&'a str>,
     pub product: Option<&'a str>,
     pub serial_number: Option<&'a str>,
+    pub supported_languages: &'a [u16],
     pub self_powered: bool,
     pub supports_remote_wakeup: bool,
     pub max_power: u8,
+    pub high_speed_capable: bool,
+    pub control_buffer_size: usize,
+    pub num_configurations: u8,
+    pub reverse_poll_order: bool,
+    pub poll_hook: Option<fn(PollPhase)>,
 }
 
 /// The bConfiguration value for the not configured state.
 pub const CONFIGURATION_NONE: u8 = 0;
 
-/// The bConfiguration value for the single configuration supported by this device.
+/// The bConfiguration value for the first configuration of the device. Further configurations set
+/// up with [`UsbDeviceBuilder::num_configurations`] are numbered consecutively after it.
 pub const CONFIGURATION_VALUE: u8 = 1;
 
+/// The default number of configurations supported by the device.
+pub const NUM_CONFIGURATIONS: u8 = 1;
+
 /// The default value for bAlternateSetting for all interfaces.
 pub const DEFAULT_ALTERNATE_SETTING: u8 = 0;
 
 type ClassList<'a, B> = [&'a mut dyn UsbClass<B>];
 
 impl<B: UsbBus> UsbDevice<'_, B> {
-    pub(crate) fn build<'a>(alloc: &'a UsbBusAllocator<B>, config: Config<'a>)
-        -> UsbDevice<'a, B>
+    pub(crate) fn build<'a>(
+        alloc: &'a UsbBusAllocator<B>,
+        config: Config<'a>,
+        control_fallback: ControlFallback<B>) -> UsbDevice<'a, B>
     {
         let control_out = alloc.alloc(Some(0x00.into()), EndpointType::Control,
             config.max_packet_size_0 as u16, 0).expect("failed to alloc control endpoint");
@@ -77,16 +194,32 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         let control_in = alloc.alloc(Some(0x80.into()), EndpointType::Control,
             config.max_packet_size_0 as u16, 0).expect("failed to alloc control endpoint");
 
+        let ep_masks = alloc.endpoint_masks();
+        let zlp_pending = alloc.zlp_pending();
         let bus = alloc.freeze();
+        let self_powered = config.self_powered;
+        let control = ControlPipe::new(control_out, control_in, config.control_buffer_size);
 
         UsbDevice {
             bus,
             config,
-            control: ControlPipe::new(control_out, control_in),
+            control,
             device_state: UsbDeviceState::Default,
+            configuration: CONFIGURATION_NONE,
             remote_wakeup_enabled: false,
-            self_powered: false,
+            self_powered,
             pending_address: 0,
+            system_exit_latency: None,
+            isoch_delay: None,
+            state_before_suspend: UsbDeviceState::Default,
+            control_fallback,
+            class_error: None,
+            reported_state: None,
+            in_stream: None,
+            window_other_speed: None,
+            window_configuration: CONFIGURATION_VALUE,
+            ep_masks,
+            zlp_pending,
         }
     }
 
@@ -99,6 +232,15 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         self.bus
     }
 
+    /// Gets the speed the device is operating at, as determined by the bus during the last reset.
+    /// This is [`UsbSpeed::Full`] for buses that don't distinguish speeds. See [`UsbBus::speed`].
+    ///
+    /// Classes can get the same value from any of their endpoints with
+    /// [`Endpoint::speed`](crate::endpoint::Endpoint::speed).
+    pub fn speed(&self) -> UsbSpeed {
+        self.bus.speed()
+    }
+
     /// Gets the current state of the device.
     ///
     /// In general class traffic is only possible in the `Configured` state.
@@ -106,21 +248,160 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         self.device_state
     }
 
+    /// Gets whether the device is currently suspended by the host. This is the same as checking
+    /// whether [`state`](UsbDevice::state) is `Suspend`. When the device resumes it returns to the
+    /// state it was in before it was suspended, unless the resume was caused by a bus reset.
+    pub fn is_suspended(&self) -> bool {
+        self.device_state == UsbDeviceState::Suspend
+    }
+
+    /// Gets the `bConfigurationValue` of the configuration selected by the host, or `None` if the
+    /// device is not configured. The configuration is kept while the device is suspended.
+    pub fn configuration(&self) -> Option<u8> {
+        match self.configuration {
+            CONFIGURATION_NONE => None,
+            value => Some(value),
+        }
+    }
+
+    /// Gets the alternate setting of an interface as the host would see it with GET_INTERFACE, or
+    /// `None` if the device is not configured. The value comes from the first class that reports
+    /// it with [`UsbClass::get_alternate_setting`], or is the default alternate setting 0 if no
+    /// class does.
+    pub fn alternate_setting(&self, classes: &ClassList<'_, B>, interface: InterfaceNumber)
+        -> Option<u8>
+    {
+        self.configuration()?;
+
+        Some(Self::active_alternate_setting(classes, interface))
+    }
+
     /// Gets whether host remote wakeup has been enabled by the host.
     pub fn remote_wakeup_enabled(&self) -> bool {
         self.remote_wakeup_enabled
     }
 
-    /// Gets whether the device is currently self powered.
+    /// Asks the host to resume the bus while the device is suspended, for example in response to
+    /// user input. This is only allowed if the host has enabled remote wakeup with
+    /// SET_FEATURE(DEVICE_REMOTE_WAKEUP), and it may disable it again with CLEAR_FEATURE at any
+    /// time. Remote wakeup must also be advertised with
+    /// [`UsbDeviceBuilder::supports_remote_wakeup`] for the host to enable it.
+    ///
+    /// The device stays suspended until the host resumes the bus and the resume is processed by
+    /// [`poll`](Self::poll).
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - The device is not suspended, or the
+    ///   host has not enabled remote wakeup.
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The bus doesn't support remote wakeup.
+    pub fn remote_wakeup(&mut self) -> Result<()> {
+        if !self.remote_wakeup_enabled || self.device_state != UsbDeviceState::Suspend {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.bus.remote_wakeup()
+    }
+
+    /// Gets the current USB frame number as reported by the bus. The value wraps at 2047, and may
+    /// not advance on buses that don't track start-of-frame packets. See
+    /// [`UsbBus::frame_number`].
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The bus doesn't provide the frame number.
+    pub fn frame_number(&self) -> Result<u16> {
+        self.bus.frame_number()
+    }
+
+    /// Gets the system exit latency values most recently sent by the host with a SET_SEL request,
+    /// or `None` if none have been received since the last reset. SET_SEL is only accepted when
+    /// the bus is operating at SuperSpeed.
+    pub fn system_exit_latency(&self) -> Option<SystemExitLatency> {
+        self.system_exit_latency
+    }
+
+    /// Gets the isochronous delay in nanoseconds most recently sent by the host with a
+    /// SET_ISOCH_DELAY request, or `None` if none has been received since the last reset. This is
+    /// the time from the host starting a transfer until the first bit reaches the device.
+    /// SET_ISOCH_DELAY is only accepted when the bus is operating at SuperSpeed.
+    pub fn isoch_delay(&self) -> Option<u16> {
+        self.isoch_delay
+    }
+
+    /// Gets and clears the first error returned by [`UsbClass::try_poll`] since the last call, or
+    /// `None` if all classes polled successfully. Errors returned while a previous error hasn't
+    /// been taken yet are dropped, so that the first fault is the one reported.
+    ///
+    /// A class error doesn't affect the device itself, which keeps polling the classes as usual.
+    /// It is up to the application to recover, for example by resetting the hardware used by the
+    /// class or by calling [`force_reset`](Self::force_reset).
+    pub fn take_class_error(&mut self) -> Option<ClassError> {
+        self.class_error.take()
+    }
+
+    /// Gets the number of bytes of the data stage of the current control IN transfer that have been
+    /// written to endpoint 0 so far, or `None` if no control IN data stage is in progress. The last
+    /// packet counts as sent once it is written, before the host has read it.
+    pub fn control_in_sent_len(&self) -> Option<usize> {
+        self.control.in_sent_len()
+    }
+
+    pub(crate) fn complete_deferred_status(&mut self, id: u16, accept: bool) -> Result<()> {
+        self.control.complete_deferred(id, accept)
+    }
+
+    /// Gets whether the device is currently self powered, as reported to the host in the
+    /// GET_STATUS response. This starts out as the value passed to
+    /// [`UsbDeviceBuilder::self_powered`](crate::device::UsbDeviceBuilder::self_powered).
     pub fn self_powered(&self) -> bool {
         self.self_powered
     }
 
-    /// Sets whether the device is currently self powered.
+    /// Changes the vendor ID and product ID reported in the device descriptor, for example to select
+    /// an identity based on a jumper at boot.
+    ///
+    /// The new IDs only take effect when the host next enumerates the device, so this may only be
+    /// called in the `Default` state, before the device is first enumerated or after a bus reset.
+    /// To change the identity of a device that is already enumerated, call this after
+    /// [`force_reset`](UsbDevice::force_reset) or a disconnect.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - The device is not in the `Default`
+    ///   state.
+    pub fn set_vid_pid(&mut self, vid_pid: UsbVidPid) -> Result<()> {
+        if self.device_state != UsbDeviceState::Default {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.config.vendor_id = vid_pid.0;
+        self.config.product_id = vid_pid.1;
+
+        Ok(())
+    }
+
+    /// Sets whether the device is currently self powered. This only changes the GET_STATUS
+    /// response; the `bmAttributes` field of the configuration descriptor always reflects the
+    /// value passed to the builder.
     pub fn set_self_powered(&mut self, is_self_powered: bool) {
         self.self_powered = is_self_powered;
     }
 
+    /// Sets whether the device accepts Link Power Management (LPM) requests to enter the L1 sleep
+    /// state, for hosts that support it. LPM must also be advertised in the BOS descriptor with
+    /// [`BosWriter::lpm`], otherwise hosts don't use it. While in L1 the device is in the `Suspend`
+    /// state, and classes are told about it with [`UsbClass::lpm_suspend`].
+    ///
+    /// LPM is disabled by default, in which case the host only uses regular suspend.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The bus doesn't support LPM.
+    pub fn set_lpm_enabled(&mut self, enabled: bool) -> Result<()> {
+        self.bus.set_lpm_enabled(enabled)
+    }
+
     /// Simulates a disconnect from the USB bus, causing the host to reset and re-enumerate the
     /// device.
     ///
@@ -130,6 +411,42 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         self.bus.force_reset()
     }
 
+    /// Disconnects from the USB bus like [`force_reset`](Self::force_reset), but only once the
+    /// control transfer in progress, if any, has completed its status stage. This makes the host
+    /// re-enumerate the device, for example after a firmware or DFU mode switch that changes the
+    /// descriptors. Classes can do the same from [`UsbClass::control_out`] with
+    /// [`ControlOut::accept_and_reenumerate`](crate::class::ControlOut::accept_and_reenumerate).
+    ///
+    /// If the control pipe is idle, the device is disconnected immediately. Otherwise the
+    /// disconnect happens during the [`poll`](Self::poll) that completes the transfer, and is
+    /// dropped if the transfer is aborted instead. Errors from a delayed disconnect can't be
+    /// reported, so on a bus that doesn't support [`UsbBus::force_reset`] nothing happens.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The control pipe is idle and the
+    ///   `UsbBus` implementation doesn't support simulating a disconnect.
+    pub fn force_reenumerate(&mut self) -> Result<()> {
+        if self.control.is_idle() {
+            return self.bus.force_reset();
+        }
+
+        self.control.request_reenumerate();
+        Ok(())
+    }
+
+    /// Forces the control pipe back to the idle state and clears any STALL condition on endpoint 0,
+    /// without a bus reset. This can be used to recover from a control transfer that is stuck, for
+    /// example because a [`DeferredStatus`](crate::class::DeferredStatus) was never completed.
+    ///
+    /// Any control transfer in progress is aborted. A status stage that was deferred can no longer
+    /// be completed, and a data packet already handed to the peripheral may still be sent to the
+    /// host, which then sees the transfer fail. The host starts over with a new SETUP packet, so the
+    /// next transfer is processed normally.
+    pub fn reset_control_pipe(&mut self) {
+        self.control.abort();
+    }
+
     /// Polls the [`UsbBus`] for new events and dispatches them to the provided classes. Returns
     /// true if one of the classes may have data available for reading or be ready for writing,
     /// false otherwise. This should be called periodically as often as possible for the best data
@@ -148,28 +465,73 @@ impl<B: UsbBus> UsbDevice<'_, B> {
     /// Strictly speaking the list of classes is allowed to change between polls if the device has
     /// been reset, which is indicated by `state` being equal to [`UsbDeviceState::Default`].
     pub fn poll(&mut self, classes: &mut ClassList<'_, B>) -> bool {
+        self.poll_events(classes).has_data()
+    }
+
+    /// Works like [`poll`](UsbDevice::poll), but returns a summary of what happened during the
+    /// poll instead of just whether there was endpoint activity.
+    pub fn poll_events(&mut self, classes: &mut ClassList<'_, B>) -> PollEvents {
+        if let Some(hook) = self.config.poll_hook {
+            hook(PollPhase::Start);
+        }
+
+        let res = self.poll_bus(classes);
+
+        if let Some(hook) = self.config.poll_hook {
+            hook(PollPhase::End);
+        }
+
+        res
+    }
+
+    fn poll_bus(&mut self, classes: &mut ClassList<'_, B>) -> PollEvents {
         let pr = self.bus.poll();
+        let mut events = PollEvents::default();
 
         if self.device_state == UsbDeviceState::Suspend {
             match pr {
-                PollResult::Suspend | PollResult::None => { return false; },
+                PollResult::Suspend | PollResult::Sleep { .. } | PollResult::None => {
+                    return events;
+                },
+                PollResult::Reset => {
+                    // The peripheral leaves suspend mode, but the device goes straight to the
+                    // Default state below instead of resuming the state it was suspended in.
+                    self.bus.resume();
+                },
                 _ => {
                     self.bus.resume();
-                    self.device_state = UsbDeviceState::Default;
+                    self.device_state = self.state_before_suspend;
+
+                    if self.device_state == UsbDeviceState::Configured {
+                        for cls in classes.iter_mut() {
+                            cls.resume();
+                        }
+                    }
+
+                    self.report_state(classes);
+                    events.resume = true;
                 },
             }
         }
 
         match pr {
             PollResult::None => { }
-            PollResult::Reset => self.reset(classes),
+            PollResult::Reset => {
+                self.reset(classes);
+                events.reset = true;
+            },
             PollResult::Data { ep_out, ep_in_complete, ep_setup } => {
                 // Combine bit fields for quick tests
                 let mut eps = ep_out | ep_in_complete | ep_setup;
 
+                events.control = (eps & 1) != 0;
+                events.endpoint_out = (ep_out & !ep_setup & !1) != 0;
+                events.endpoint_in_complete = (ep_in_complete & !1) != 0;
+                events.endpoint_setup = (ep_setup & !1) != 0;
+
                 // Pending events for endpoint 0?
                 if (eps & 1) != 0 {
-                    let req = if (ep_setup & 1) != 0 {
+                    let mut req = if (ep_setup & 1) != 0 {
                         self.control.handle_setup()
                     } else if (ep_out & 1) != 0 {
                         self.control.handle_out()
@@ -177,6 +539,18 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                         None
                     };
 
+                    if let Some((chunk_req, offset, chunk)) = self.control.take_out_chunk() {
+                        // Stops at the first class that handles the chunk or fails
+                        let handled = classes.iter_mut()
+                            .map(|cls| cls.control_out_chunk(&chunk_req, offset, chunk))
+                            .find(|res| !matches!(res, Ok(false)));
+
+                        if !matches!(handled, Some(Ok(true))) {
+                            self.control.abort_out_stream();
+                            req = None;
+                        }
+                    }
+
                     match req {
                         Some(req) if req.direction == UsbDirection::In
                             => self.control_in(classes, req),
@@ -188,6 +562,10 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                     if (ep_in_complete & 1) != 0 {
                         let completed = self.control.handle_in_complete();
 
+                        if self.control.window_pending() {
+                            self.fill_in_window(classes);
+                        }
+
                         if !B::QUIRK_SET_ADDRESS_BEFORE_STATUS {
                             if completed && self.pending_address != 0 {
                                 self.bus.set_device_address(self.pending_address);
@@ -198,6 +576,18 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                         }
                     }
 
+                    if self.control.take_completed() {
+                        for cls in classes.iter_mut() {
+                            cls.control_idle();
+                        }
+
+                        if self.control.take_reenumerate() {
+                            self.bus.force_reset().ok();
+                        }
+                    }
+
+                    self.report_state(classes);
+
                     eps &= !1;
                 }
 
@@ -207,21 +597,35 @@ impl<B: UsbBus> UsbDevice<'_, B> {
 
                     for i in 1..MAX_ENDPOINTS {
                         if (ep_setup & bit) != 0 {
-                            for cls in classes.iter_mut() {
-                                cls.endpoint_setup(
-                                    EndpointAddress::from_parts(i, UsbDirection::Out));
+                            let addr = EndpointAddress::from_parts(i, UsbDirection::Out);
+
+                            self.for_each_class(classes, |cls| cls.endpoint_setup(addr));
+
+                            // No class can own an endpoint that wasn't allocated, so only
+                            // allocated endpoints are left to the classes.
+                            let allocated = (self.ep_masks.0 & bit) != 0;
+
+                            if !allocated
+                                || !classes.iter().any(|cls| cls.endpoint_setup_handled(addr))
+                            {
+                                // Nobody is going to respond, so discard the SETUP packet and
+                                // stall the endpoint to avoid leaving the host waiting.
+                                let _ = self.bus.read(addr, &mut [0u8; 8]);
+                                self.bus.set_stalled(addr, true);
+                                self.bus.set_stalled(
+                                    EndpointAddress::from_parts(i, UsbDirection::In), true);
                             }
                         } else if (ep_out & bit) != 0 {
-                            for cls in classes.iter_mut() {
-                                cls.endpoint_out(
-                                    EndpointAddress::from_parts(i, UsbDirection::Out));
-                            }
+                            self.for_each_class(classes, |cls| cls.endpoint_out(
+                                EndpointAddress::from_parts(i, UsbDirection::Out)));
                         }
 
                         if (ep_in_complete & bit) != 0 {
-                            for cls in classes.iter_mut() {
-                                cls.endpoint_in_complete(
-                                    EndpointAddress::from_parts(i, UsbDirection::In));
+                            let addr = EndpointAddress::from_parts(i, UsbDirection::In);
+
+                            // The transfer isn't complete until its zero-length packet is sent
+                            if !self.write_pending_zlp(addr) {
+                                self.for_each_class(classes, |cls| cls.endpoint_in_complete(addr));
                             }
                         }
 
@@ -236,29 +640,144 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                     }
                 }
 
-                for cls in classes.iter_mut() {
-                    cls.poll();
+                let count = classes.len();
+
+                for i in 0..count {
+                    let i = if self.config.reverse_poll_order { count - 1 - i } else { i };
+
+                    if let Err(error) = classes[i].try_poll() {
+                        if self.class_error.is_none() {
+                            self.class_error = Some(ClassError { class_index: i, error });
+                        }
+                    }
                 }
 
-                return true;
             },
             PollResult::Resume => { }
             PollResult::Suspend => {
                 self.bus.suspend();
+                self.state_before_suspend = self.device_state;
+                self.device_state = UsbDeviceState::Suspend;
+                self.suspend_classes(classes);
+                self.report_state(classes);
+                events.suspend = true;
+            }
+            PollResult::Sleep { besl } => {
+                // L1 sleep is handled like a suspend, except that classes are told the latency
+                self.bus.suspend();
+                self.state_before_suspend = self.device_state;
                 self.device_state = UsbDeviceState::Suspend;
+
+                for cls in classes.iter_mut() {
+                    cls.lpm_suspend(besl);
+                }
+
+                self.suspend_classes(classes);
+                self.report_state(classes);
+                events.suspend = true;
             }
         }
 
-        return false;
+        events
+    }
+
+    // Tells the classes that the bus has been suspended. Classes are only told about suspends of
+    // the configured device, since they have nothing to stop before that.
+    fn suspend_classes(&self, classes: &mut ClassList<'_, B>) {
+        if self.state_before_suspend == UsbDeviceState::Configured {
+            for cls in classes.iter_mut() {
+                cls.suspend();
+            }
+        }
+    }
+
+    // Tells the classes about the current state if it has changed since they were last told.
+    fn report_state(&mut self, classes: &mut ClassList<'_, B>) {
+        let new = self.device_state;
+
+        if self.reported_state == Some(new) {
+            return;
+        }
+
+        let old = self.reported_state.replace(new).unwrap_or(UsbDeviceState::Default);
+
+        for cls in classes.iter_mut() {
+            cls.device_state_changed(old, new);
+        }
+    }
+
+    // Writes the zero-length packet queued for an IN endpoint by
+    // EndpointIn::write_packet_terminated, and returns true if one was queued. If the endpoint is
+    // still busy, the packet stays queued until the endpoint completes another packet.
+    fn write_pending_zlp(&self, ep_addr: EndpointAddress) -> bool {
+        let pending = &self.zlp_pending[ep_addr.index()];
+
+        if !pending.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        match self.bus.write(ep_addr, &[]) {
+            Err(UsbError::WouldBlock) => true,
+            res => {
+                // If the packet can't be written at all, the transfer ends without it
+                pending.store(false, Ordering::SeqCst);
+                res.is_ok()
+            },
+        }
+    }
+
+    fn for_each_class(&self, classes: &mut ClassList<'_, B>, mut f: impl FnMut(&mut dyn UsbClass<B>)) {
+        if self.config.reverse_poll_order {
+            for cls in classes.iter_mut().rev() {
+                f(*cls);
+            }
+        } else {
+            for cls in classes.iter_mut() {
+                f(*cls);
+            }
+        }
+    }
+
+    // Fills the next part of a control IN response that doesn't fit in the control buffer, either
+    // from the class that is streaming it or from the configuration descriptor.
+    fn fill_in_window(&mut self, classes: &mut ClassList<'_, B>) {
+        let control = &mut self.control;
+
+        match self.in_stream {
+            Some((i, req)) => {
+                control.fill_stream(|buf, offset| match classes.get_mut(i) {
+                    Some(cls) => cls.control_in_chunk(&req, offset, buf),
+                    None => Err(UsbError::InvalidState),
+                }).ok();
+            },
+            None => {
+                let config = &self.config;
+                let bus = self.bus;
+                let speed = self.window_other_speed.unwrap_or_else(|| bus.speed());
+                let other_speed = self.window_other_speed.is_some();
+                let value = self.window_configuration;
+
+                control.fill_window(|buf, start| Self::write_configuration(
+                    config, classes, buf, start, value, speed, other_speed)).ok();
+            },
+        }
     }
 
     fn control_in(&mut self, classes: &mut ClassList<'_, B>, req: control::Request) {
         use crate::control::{Request, Recipient};
 
-        for cls in classes.iter_mut() {
-            cls.control_in(ControlIn::new(&mut self.control, &req));
+        self.in_stream = None;
+
+        for i in 0..classes.len() {
+            classes[i].control_in(ControlIn::new(&mut self.control, &req));
 
             if !self.control.waiting_for_response() {
+                if self.control.window_pending() {
+                    // The class accepted the transfer with accept_stream
+                    self.in_stream = Some((i, req));
+                    self.fill_in_window(classes);
+                }
+
                 return;
             }
         }
@@ -267,59 +786,88 @@ impl<B: UsbBus> UsbDevice<'_, B> {
             let xfer = ControlIn::new(&mut self.control, &req);
 
             match (req.recipient, req.request) {
+                _ if !Self::standard_request_allowed(self.device_state, &req) => {
+                    xfer.reject().ok();
+                },
+
                 (Recipient::Device, Request::GET_STATUS) => {
                     let status: u16 = 0x0000
                         | if self.self_powered { 0x0001 } else { 0x0000 }
                         | if self.remote_wakeup_enabled { 0x0002 } else { 0x0000 };
 
-                    xfer.accept_with(&status.to_le_bytes()).ok();
+                    xfer.accept_u16(status).ok();
                 },
 
                 (Recipient::Interface, Request::GET_STATUS) => {
                     let status: u16 = 0x0000;
 
-                    xfer.accept_with(&status.to_le_bytes()).ok();
+                    xfer.accept_u16(status).ok();
                 },
 
                 (Recipient::Endpoint, Request::GET_STATUS) => {
-                    let ep_addr = ((req.index as u8) & 0x8f).into();
-
-                    let status: u16 = 0x0000
-                        | if self.bus.is_stalled(ep_addr) { 0x0001 } else { 0x0000 };
+                    match Self::request_endpoint(self.ep_masks, &req) {
+                        Some(ep_addr) => {
+                            let status: u16 = 0x0000
+                                | if self.bus.is_stalled(ep_addr) { 0x0001 } else { 0x0000 };
 
-                    xfer.accept_with(&status.to_le_bytes()).ok();
+                            xfer.accept_u16(status).ok();
+                        },
+                        None => { xfer.reject().ok(); },
+                    }
                 },
 
-                (Recipient::Device, Request::GET_DESCRIPTOR)
-                    => UsbDevice::get_descriptor(&self.config, classes, xfer),
-
-                (Recipient::Device, Request::GET_CONFIGURATION) => {
-                    let config = match self.device_state {
-                        UsbDeviceState::Configured => CONFIGURATION_VALUE,
-                        _ => CONFIGURATION_NONE,
+                (Recipient::Device, Request::GET_DESCRIPTOR) => {
+                    let speed = self.bus.speed();
+                    let other_speed = match speed {
+                        _ if !self.config.high_speed_capable => None,
+                        UsbSpeed::Full => Some(UsbSpeed::High),
+                        UsbSpeed::High => Some(UsbSpeed::Full),
+                        _ => None,
                     };
 
-                    xfer.accept_with(&config.to_le_bytes()).ok();
+                    self.window_other_speed = other_speed
+                        .filter(|_| req.descriptor_type_index().0
+                            == descriptor_type::OTHER_SPEED_CONFIGURATION);
+                    self.window_configuration =
+                        req.descriptor_type_index().1.wrapping_add(CONFIGURATION_VALUE);
+
+                    UsbDevice::get_descriptor(&self.config, classes, xfer, speed, other_speed)
+                },
+
+                (Recipient::Device, Request::GET_CONFIGURATION) => {
+                    xfer.accept_u8(self.configuration).ok();
                 },
 
                 (Recipient::Interface, Request::GET_INTERFACE) => {
-                    // TODO: change when alternate settings are implemented
-                    xfer.accept_with(&DEFAULT_ALTERNATE_SETTING.to_le_bytes()).ok();
+                    let iface = InterfaceNumber::new(req.index as u8);
+
+                    xfer.accept_u8(Self::active_alternate_setting(classes, iface)).ok();
                 },
 
                 _ => (),
             };
         }
 
+        if self.control.waiting_for_response() {
+            if let Some(fallback) = self.control_fallback.control_in {
+                fallback(ControlIn::new(&mut self.control, &req));
+            }
+        }
+
+        if self.control.window_pending() {
+            // Only classes can stream a response, so this stalls the transfer
+            self.fill_in_window(classes);
+        }
+
         if self.control.waiting_for_response() {
             self.control.reject().ok();
         }
     }
 
     fn control_out(&mut self, classes: &mut ClassList<'_, B>, req: control::Request) {
-        use crate::control::{Request, Recipient};
+        use crate::control::{Feature, Request, Recipient};
 
-        for cls in classes {
+        for cls in classes.iter_mut() {
             cls.control_out(ControlOut::new(&mut self.control, &req));
 
             if !self.control.waiting_for_response() {
@@ -331,28 +879,31 @@ impl<B: UsbBus> UsbDevice<'_, B> {
             let xfer = ControlOut::new(&mut self.control, &req);
 
             const CONFIGURATION_NONE_U16: u16 = CONFIGURATION_NONE as u16;
-            const CONFIGURATION_VALUE_U16: u16 = CONFIGURATION_VALUE as u16;
-            const DEFAULT_ALTERNATE_SETTING_U16: u16 = DEFAULT_ALTERNATE_SETTING as u16;
+            let num_configurations = self.config.num_configurations as u16;
 
             match (req.recipient, req.request, req.value) {
-                (Recipient::Device, Request::CLEAR_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
-                    self.remote_wakeup_enabled = false;
-                    xfer.accept().ok();
+                _ if !Self::standard_request_allowed(self.device_state, &req) => {
+                    xfer.reject().ok();
+                    return;
                 },
 
-                (Recipient::Endpoint, Request::CLEAR_FEATURE, Request::FEATURE_ENDPOINT_HALT) => {
-                    self.bus.set_stalled(((req.index as u8) & 0x8f).into(), false);
-                    xfer.accept().ok();
-                },
+                (_, Request::SET_FEATURE, _) | (_, Request::CLEAR_FEATURE, _) => {
+                    let set = req.request == Request::SET_FEATURE;
 
-                (Recipient::Device, Request::SET_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
-                    self.remote_wakeup_enabled = true;
-                    xfer.accept().ok();
-                },
-
-                (Recipient::Endpoint, Request::SET_FEATURE, Request::FEATURE_ENDPOINT_HALT) => {
-                    self.bus.set_stalled(((req.index as u8) & 0x8f).into(), true);
-                    xfer.accept().ok();
+                    match req.feature() {
+                        Some(Feature::DeviceRemoteWakeup) => {
+                            self.remote_wakeup_enabled = set;
+                            xfer.accept().ok();
+                        },
+                        Some(Feature::EndpointHalt) => match Self::request_endpoint(self.ep_masks, &req) {
+                            Some(ep_addr) => {
+                                self.bus.set_stalled(ep_addr, set);
+                                xfer.accept().ok();
+                            },
+                            None => { xfer.reject().ok(); },
+                        },
+                        _ => { xfer.reject().ok(); },
+                    }
                 },
 
                 (Recipient::Device, Request::SET_ADDRESS, 1..=127) => {
@@ -365,8 +916,20 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                     xfer.accept().ok();
                 },
 
-                (Recipient::Device, Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
+                (Recipient::Device, Request::SET_CONFIGURATION, value)
+                    if value != CONFIGURATION_NONE_U16 && value <= num_configurations =>
+                {
+                    let value = value as u8;
+
                     self.device_state = UsbDeviceState::Configured;
+                    self.configuration = value;
+                    Self::set_endpoints_stalled(self.bus, self.ep_masks, false);
+
+                    for cls in classes.iter_mut() {
+                        cls.configuration_changed(value);
+                        cls.configure();
+                    }
+
                     xfer.accept().ok();
                 },
 
@@ -376,18 +939,87 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                             xfer.reject().ok();
                         },
                         _ => {
+                            // Only endpoint 0 may be used while the device is not configured
                             self.device_state = UsbDeviceState::Addressed;
+                            self.configuration = CONFIGURATION_NONE;
+                            Self::set_endpoints_stalled(self.bus, self.ep_masks, true);
+
+                            for cls in classes.iter_mut() {
+                                cls.configuration_changed(CONFIGURATION_NONE);
+                            }
+
                             xfer.accept().ok();
                         },
                     }
                 },
 
-                (Recipient::Interface, Request::SET_INTERFACE, DEFAULT_ALTERNATE_SETTING_U16) => {
-                    // TODO: do something when alternate settings are implemented
+                (Recipient::Device, Request::SET_SEL, 0)
+                    if self.bus.speed() == UsbSpeed::Super && xfer.data().len() == 6 =>
+                {
+                    let data = xfer.data();
+
+                    self.system_exit_latency = Some(SystemExitLatency {
+                        u1_sel: data[0],
+                        u1_pel: data[1],
+                        u2_sel: u16::from_le_bytes([data[2], data[3]]),
+                        u2_pel: u16::from_le_bytes([data[4], data[5]]),
+                    });
+
+                    xfer.accept().ok();
+                },
+
+                (Recipient::Device, Request::SET_ISOCH_DELAY, delay)
+                    if self.bus.speed() == UsbSpeed::Super && req.index == 0 =>
+                {
+                    self.isoch_delay = Some(delay);
                     xfer.accept().ok();
                 },
 
-                _ => { xfer.reject().ok(); return; },
+                (Recipient::Device, Request::SET_DESCRIPTOR, _)
+                    if self.device_state != UsbDeviceState::Default
+                        && xfer.data().len() == req.length as usize =>
+                {
+                    let (dtype, index) = req.descriptor_type_index();
+                    let data = xfer.data();
+
+                    let accepted = classes.iter_mut()
+                        .any(|cls| cls.set_descriptor(dtype, index, req.index, data).is_ok());
+
+                    if accepted {
+                        xfer.accept().ok();
+                    } else {
+                        xfer.reject().ok();
+                    }
+                },
+
+                (Recipient::Interface, Request::SET_INTERFACE, 0..=255) => {
+                    let iface = InterfaceNumber::new(req.index as u8);
+                    let alt = req.value as u8;
+
+                    let mut accepted = false;
+
+                    for cls in classes.iter_mut() {
+                        if cls.set_alternate_setting(iface, alt) {
+                            accepted = true;
+                            break;
+                        }
+                    }
+
+                    // The default alternate setting is always valid even if no class handles it
+                    if accepted || alt == DEFAULT_ALTERNATE_SETTING {
+                        xfer.accept().ok();
+                    } else {
+                        xfer.reject().ok();
+                    }
+                },
+
+                _ => (),
+            }
+        }
+
+        if self.control.waiting_for_response() {
+            if let Some(fallback) = self.control_fallback.control_out {
+                fallback(ControlOut::new(&mut self.control, &req));
             }
         }
 
@@ -396,7 +1028,49 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         }
     }
 
-    fn get_descriptor(config: &Config, classes: &mut ClassList<'_, B>, xfer: ControlIn<B>) {
+    fn active_alternate_setting(classes: &ClassList<'_, B>, interface: InterfaceNumber) -> u8 {
+        classes.iter()
+            .find_map(|cls| cls.get_alternate_setting(interface))
+            .unwrap_or(DEFAULT_ALTERNATE_SETTING)
+    }
+
+    // Gets the endpoint addressed by the index field of an endpoint request, if it is allocated.
+    fn request_endpoint(ep_masks: (u16, u16), req: &control::Request) -> Option<EndpointAddress> {
+        let ep_addr = EndpointAddress::from((req.index as u8) & 0x8f);
+
+        let mask = match ep_addr.direction() {
+            UsbDirection::Out => ep_masks.0,
+            UsbDirection::In => ep_masks.1,
+        };
+
+        if mask & (1 << ep_addr.index()) != 0 {
+            Some(ep_addr)
+        } else {
+            None
+        }
+    }
+
+    // Interface requests and requests for endpoints other than endpoint 0 are only valid in the
+    // Configured state (USB 2.0 spec, 9.4).
+    fn standard_request_allowed(state: UsbDeviceState, req: &control::Request) -> bool {
+        use crate::control::Recipient;
+
+        match req.recipient {
+            Recipient::Interface => state == UsbDeviceState::Configured,
+            Recipient::Endpoint =>
+                state == UsbDeviceState::Configured || (req.index & 0x0f) == 0,
+            _ => true,
+        }
+    }
+
+    // `other_speed` is the speed the device could also operate at, if it is high speed capable.
+    fn get_descriptor(
+        config: &Config,
+        classes: &mut ClassList<'_, B>,
+        xfer: ControlIn<B>,
+        speed: UsbSpeed,
+        other_speed: Option<UsbSpeed>)
+    {
         let req = *xfer.request();
 
         let (dtype, index) = req.descriptor_type_index();
@@ -421,6 +1095,12 @@ impl<B: UsbBus> UsbDevice<'_, B> {
                     cls.get_bos_descriptors(&mut bw)?;
                 }
 
+                // A Billboard device has to describe its alternate modes with a Billboard
+                // capability, so don't send a BOS descriptor that the host would reject anyway
+                if config.device_class == device_class::BILLBOARD && !bw.has_billboard() {
+                    return Err(UsbError::MalformedDescriptor);
+                }
+
                 bw.end_bos();
 
                 Ok(())
@@ -428,38 +1108,83 @@ impl<B: UsbBus> UsbDevice<'_, B> {
 
             descriptor_type::DEVICE => accept_writer(xfer, |w| w.device(config)),
 
-            descriptor_type::CONFIGURATION => accept_writer(xfer, |w| {
-                w.configuration(config)?;
+            // Configuration descriptor indices are zero-based, so anything past the last supported
+            // configuration is an invalid request. The configuration descriptor may be larger than
+            // the control buffer, in which case it is generated again for each part that is sent.
+            descriptor_type::CONFIGURATION if index < config.num_configurations => {
+                let value = index + CONFIGURATION_VALUE;
 
-                for cls in classes {
-                    cls.get_configuration_descriptors(w)?;
-                    w.end_class();
-                }
+                xfer.accept_windowed(|buf|
+                    Self::write_configuration(config, classes, buf, 0, value, speed, false)).ok();
+            },
 
-                w.end_configuration();
+            // These are only defined for devices that can operate at both full and high speed
+            descriptor_type::DEVICE_QUALIFIER => match other_speed {
+                Some(speed) => accept_writer(xfer, |w| w.device_qualifier(config, speed)),
+                None => { xfer.reject().ok(); },
+            },
 
-                Ok(())
-            }),
+            descriptor_type::OTHER_SPEED_CONFIGURATION if index < config.num_configurations => {
+                let value = index + CONFIGURATION_VALUE;
+
+                match other_speed {
+                    Some(speed) => {
+                        xfer.accept_windowed(|buf|
+                            Self::write_configuration(config, classes, buf, 0, value, speed, true))
+                            .ok();
+                    },
+                    None => { xfer.reject().ok(); },
+                }
+            },
 
             descriptor_type::STRING => {
                 if index == 0 {
-                    accept_writer(xfer, |w|
-                        w.write(
-                            descriptor_type::STRING,
-                            &lang_id::ENGLISH_US.to_le_bytes()))
+                    accept_writer(xfer, |w| {
+                        let mut buf = [0u8; 252];
+                        let len = config.supported_languages.len() * 2;
+
+                        for (b, lang) in buf.chunks_exact_mut(2)
+                            .zip(config.supported_languages)
+                        {
+                            b.copy_from_slice(&lang.to_le_bytes());
+                        }
+
+                        w.write(descriptor_type::STRING, &buf[..len])
+                    })
+                } else if !config.supported_languages.contains(&req.index) {
+                    // Strings are only available in the languages listed in string descriptor 0
+                    xfer.reject().ok();
                 } else {
-                    let s = match index {
-                        1 => config.manufacturer,
-                        2 => config.product,
-                        3 => config.serial_number,
-                        _ => {
-                            let index = StringIndex::new(index);
-                            let lang_id = req.index;
+                    let string_index = StringIndex::new(index);
+                    let lang_id = req.index;
+
+                    // Class strings are only requested from the class that references them in
+                    // its descriptors, if there is one, so that classes can't shadow each other.
+                    let owner = match index {
+                        1..=3 => None,
+                        _ => Self::string_owner(config, classes, index),
+                    };
 
-                            classes.iter()
-                                .filter_map(|cls| cls.get_string(index, lang_id))
-                                .nth(0)
-                        },
+                    let class_string = match owner {
+                        Some(i) => classes[i].get_string(string_index, lang_id),
+                        None => classes.iter()
+                            .filter_map(|cls| cls.get_string(string_index, lang_id))
+                            .nth(0),
+                    };
+
+                    let device_string = match index {
+                        1 => Some(config.manufacturer),
+                        2 => Some(config.product),
+                        3 => Some(config.serial_number),
+                        _ => None,
+                    };
+
+                    // Classes can override the device strings, for example to localize them, but
+                    // they are only advertised in the device descriptor if set in the builder.
+                    let s = match device_string {
+                        Some(Some(default)) => Some(class_string.unwrap_or(default)),
+                        Some(None) => None,
+                        None => class_string,
                     };
 
                     if let Some(s) = s {
@@ -474,17 +1199,154 @@ impl<B: UsbBus> UsbDevice<'_, B> {
         }
     }
 
+    // Writes the part of the configuration descriptor starting at `window_start` that fits in
+    // `buf`, and returns the full length of the descriptor. If `other_speed` is set, the other
+    // speed configuration descriptor for that speed is written instead.
+    fn write_configuration(
+        config: &Config,
+        classes: &mut ClassList<'_, B>,
+        buf: &mut [u8],
+        window_start: usize,
+        value: u8,
+        speed: UsbSpeed,
+        other_speed: bool) -> Result<usize>
+    {
+        let mut w = if other_speed {
+            DescriptorWriter::new_window_other_speed(buf, window_start, speed)
+        } else {
+            DescriptorWriter::new_window_speed(buf, window_start, speed)
+        };
+
+        w.configuration(config, value)?;
+
+        for cls in classes {
+            if let Some(info) = cls.function_descriptor() {
+                w.begin_function(&info)?;
+            }
+
+            cls.get_configuration_descriptors(&mut w)?;
+            w.end_class()?;
+        }
+
+        w.end_configuration();
+
+        debug_assert!(
+            !w.has_functions() || (config.device_class, config.device_sub_class,
+                config.device_protocol) == (device_class::MISCELLANEOUS, 0x02, 0x01),
+            "interface association descriptors require UsbDeviceBuilder::composite_with_iads");
+
+        Ok(w.position())
+    }
+
+    // Finds the first class that references the string with the specified index in its part of
+    // any of the configuration descriptors.
+    fn string_owner(config: &Config, classes: &ClassList<'_, B>, index: u8) -> Option<usize> {
+        (0..config.num_configurations).find_map(|n| {
+            let mut w = DescriptorWriter::new_string_probe(&mut [], index);
+
+            w.configuration(config, n + CONFIGURATION_VALUE).ok()?;
+
+            for (i, cls) in classes.iter().enumerate() {
+                if let Some(info) = cls.function_descriptor() {
+                    w.begin_function(&info).ok()?;
+                }
+
+                let res = cls.get_configuration_descriptors(&mut w);
+
+                if w.take_string_referenced() {
+                    return Some(i);
+                }
+
+                res.ok()?;
+                w.end_class().ok()?;
+            }
+
+            None
+        })
+    }
+
+    // Sets or clears the halt feature of all endpoints other than endpoint 0 that were allocated
+    // before the device was built.
+    fn set_endpoints_stalled(bus: &B, ep_masks: (u16, u16), stalled: bool) {
+        for index in 1..MAX_ENDPOINTS {
+            if ep_masks.0 & (1 << index) != 0 {
+                bus.set_stalled(EndpointAddress::from_parts(index, UsbDirection::Out), stalled);
+            }
+
+            if ep_masks.1 & (1 << index) != 0 {
+                bus.set_stalled(EndpointAddress::from_parts(index, UsbDirection::In), stalled);
+            }
+        }
+    }
+
     fn reset(&mut self, classes: &mut ClassList<'_, B>) {
         self.bus.reset();
 
         self.device_state = UsbDeviceState::Default;
+        self.configuration = CONFIGURATION_NONE;
         self.remote_wakeup_enabled = false;
         self.pending_address = 0;
+        self.system_exit_latency = None;
+        self.isoch_delay = None;
+
+        for pending in self.zlp_pending.iter() {
+            pending.store(false, Ordering::SeqCst);
+        }
 
         self.control.reset();
 
-        for cls in classes {
+        for cls in classes.iter_mut() {
             cls.reset();
         }
+
+        self.report_state(classes);
+    }
+}
+
+/// A [`UsbDevice`] bundled with the classes it polls, created with
+/// [`UsbDeviceBuilder::build_with_classes`].
+///
+/// This is a convenience for devices where the classes don't need to be accessed directly by the
+/// application after creation, for example because they communicate through shared buffers or
+/// only respond to control requests. Because the classes are borrowed as trait objects for the
+/// lifetime of this struct, their own methods such as reading or writing data can't be called
+/// while it exists. If the application needs to call class-specific methods, keep using
+/// [`UsbDevice`] directly and pass the classes to [`UsbDevice::poll`] instead.
+///
+/// The classes are borrowed instead of owned so that no allocator is required.
+pub struct UsbDeviceWithClasses<'a, 'c, B: UsbBus> {
+    device: UsbDevice<'a, B>,
+    classes: &'c mut ClassList<'c, B>,
+}
+
+impl<'a, 'c, B: UsbBus> UsbDeviceWithClasses<'a, 'c, B> {
+    pub(crate) fn new(device: UsbDevice<'a, B>, classes: &'c mut ClassList<'c, B>) -> Self {
+        UsbDeviceWithClasses { device, classes }
+    }
+
+    /// Polls the device and its classes. See [`UsbDevice::poll`] for more information.
+    pub fn poll(&mut self) -> bool {
+        self.device.poll(self.classes)
+    }
+
+    /// Polls the device and its classes and returns a summary of what happened. See
+    /// [`UsbDevice::poll_events`] for more information.
+    pub fn poll_events(&mut self) -> PollEvents {
+        self.device.poll_events(self.classes)
+    }
+
+    /// Gets a reference to the device.
+    pub fn device(&self) -> &UsbDevice<'a, B> {
+        &self.device
+    }
+
+    /// Gets a mutable reference to the device.
+    pub fn device_mut(&mut self) -> &mut UsbDevice<'a, B> {
+        &mut self.device
+    }
+
+    /// Gets the list of classes in the order they are polled.
+    pub fn classes(&mut self) -> &mut ClassList<'c, B> {
+        self.classes
     }
 }
@@ -1,5 +1,10 @@
 use crate::bus::{UsbBusAllocator, UsbBus};
-use crate::device::{UsbDevice, Config};
+use crate::class::{UsbClass, ControlIn, ControlOut};
+use crate::control_pipe::CONTROL_BUF_LEN;
+use crate::descriptor::{device_class, lang_id};
+use crate::device::{
+    UsbDevice, UsbDeviceWithClasses, Config, ControlFallback, PollPhase, NUM_CONFIGURATIONS,
+};
 
 /// A USB vendor ID and product ID pair.
 pub struct UsbVidPid(pub u16, pub u16);
@@ -8,6 +13,7 @@ pub struct UsbVidPid(pub u16, pub u16);
 pub struct UsbDeviceBuilder<'a, B: UsbBus> {
     alloc: &'a UsbBusAllocator<B>,
     config: Config<'a>,
+    control_fallback: ControlFallback<B>,
 }
 
 macro_rules! builder_fields {
@@ -41,22 +47,49 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
                 manufacturer: None,
                 product: None,
                 serial_number: None,
+                supported_languages: &[lang_id::ENGLISH_US],
                 self_powered: false,
                 supports_remote_wakeup: false,
                 max_power: 50,
-            }
+                high_speed_capable: false,
+                control_buffer_size: CONTROL_BUF_LEN,
+                num_configurations: NUM_CONFIGURATIONS,
+                reverse_poll_order: false,
+                poll_hook: None,
+            },
+            control_fallback: ControlFallback {
+                control_in: None,
+                control_out: None,
+            },
         }
     }
 
     /// Creates the [`UsbDevice`] instance with the configuration in this builder.
     pub fn build(self) -> UsbDevice<'a, B> {
-        UsbDevice::build(self.alloc, self.config)
+        UsbDevice::build(self.alloc, self.config, self.control_fallback)
+    }
+
+    /// Creates a [`UsbDeviceWithClasses`] instance with the configuration in this builder that
+    /// polls the specified classes itself, so that they don't have to be passed to every `poll`
+    /// call.
+    ///
+    /// See [`UsbDeviceWithClasses`] for the tradeoffs compared to [`build`](Self::build).
+    pub fn build_with_classes<'c>(self, classes: &'c mut [&'c mut dyn UsbClass<B>])
+        -> UsbDeviceWithClasses<'a, 'c, B>
+    {
+        UsbDeviceWithClasses::new(self.build(), classes)
     }
 
     builder_fields! {
         /// Sets the device class code assigned by USB.org. Set to `0xff` for vendor-specific
         /// devices that do not conform to any class.
         ///
+        /// The class is written to the device descriptor as is, so classes that are only valid at
+        /// the device level, such as the ones in [`device_class`](crate::descriptor::device_class),
+        /// can be used as well. A [`BILLBOARD`](crate::descriptor::device_class::BILLBOARD)
+        /// device must also write a Billboard capability in
+        /// [`get_bos_descriptors`](crate::class::UsbClass::get_bos_descriptors).
+        ///
         /// Default: `0x00` (class code specified by interfaces)
         device_class: u8,
 
@@ -80,6 +113,10 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
         /// This should be set to `true` even if the device is sometimes self-powered and may not
         /// always draw power from the USB bus.
         ///
+        /// This sets the self-powered bit in the configuration descriptor and the initial value of
+        /// the self-powered bit in the GET_STATUS response, which can later be changed with
+        /// [`UsbDevice::set_self_powered`].
+        ///
         /// Default: `false`
         ///
         /// See also: `max_power`
@@ -89,6 +126,41 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
         ///
         /// Default: `false`
         supports_remote_wakeup: bool,
+
+        /// Sets whether class callbacks for polling and endpoint events are dispatched to the
+        /// classes in reverse order, starting from the last class in the list passed to
+        /// [`UsbDevice::poll`]. This only affects [`UsbClass::poll`](crate::class::UsbClass::poll)
+        /// and the `endpoint_*` callbacks. Control requests are always offered to classes in list
+        /// order, so the first class in the list still takes precedence for those.
+        ///
+        /// Default: `false`
+        reverse_poll_order: bool,
+
+        /// Sets whether the device can operate at both full speed and high speed. Such devices
+        /// must answer GET_DESCRIPTOR requests for the device qualifier descriptor and the other
+        /// speed configuration descriptor, which describe how the device would work at the speed
+        /// it is not currently operating at. Both are generated from the device descriptor and
+        /// the class configuration descriptors, with endpoint packet sizes limited to the ones
+        /// allowed at the other speed. Classes can check
+        /// [`DescriptorWriter::other_speed`](crate::descriptor::DescriptorWriter::other_speed) to
+        /// adjust other fields.
+        ///
+        /// When this is `false`, both requests are stalled, as required for full speed only
+        /// devices.
+        ///
+        /// Default: `false`
+        high_speed_capable: bool,
+    }
+
+    /// Sets the device class to vendor-specific (`0xff`) with the specified sub-class and protocol.
+    /// This is meant for devices with a single vendor-specific interface, where the device
+    /// descriptor usually carries the same codes as the interface so that drivers can match on
+    /// either.
+    pub fn vendor_specific(mut self, sub_class: u8, protocol: u8) -> Self {
+        self.config.device_class = 0xff;
+        self.config.device_sub_class = sub_class;
+        self.config.device_protocol = protocol;
+        self
     }
 
     /// Sets the manufacturer name string descriptor.
@@ -109,12 +181,77 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
 
     /// Sets the serial number string descriptor.
     ///
+    /// The string doesn't have to be `'static`, only outlive the device, so it can be formatted at
+    /// runtime into a buffer on the stack, for example from a hardware unique ID with
+    /// [`format_serial_hex`](crate::descriptor::format_serial_hex). It is encoded as UTF-16 when
+    /// the host requests it.
+    ///
     /// Default: (none)
     pub fn serial_number(mut self, serial_number: &'a str) -> Self {
         self.config.serial_number = Some(serial_number);
         self
     }
 
+    /// Sets the language IDs in which the device provides its string descriptors. They are listed in
+    /// string descriptor 0 in this order, and hosts generally use the first one unless the user has
+    /// selected another language. Requests for strings in other languages are rejected, so
+    /// [`UsbClass::get_string`] is only called with one of these.
+    ///
+    /// The manufacturer, product and serial number strings set in the builder are returned for
+    /// every language, unless a class overrides them for a specific language.
+    ///
+    /// Default: `[0x0409]` (English (US))
+    ///
+    /// # Panics
+    ///
+    /// Panics if the list is empty or contains more than 126 language IDs, which don't fit in a
+    /// string descriptor.
+    pub fn supported_languages(mut self, supported_languages: &'a [u16]) -> Self {
+        if supported_languages.is_empty() || supported_languages.len() > 126 {
+            panic!("invalid number of supported languages");
+        }
+
+        self.config.supported_languages = supported_languages;
+        self
+    }
+
+    /// Sets a function that is called at the start and at the end of every
+    /// [`UsbDevice::poll`] call, for example to toggle a GPIO pin or read a timer to measure how
+    /// much time is spent processing USB events.
+    ///
+    /// The hook is called from within `poll`, so it must be cheap and must not block. When no hook
+    /// is set the only cost is checking for it.
+    ///
+    /// Default: (none)
+    pub fn poll_hook(mut self, hook: fn(PollPhase)) -> Self {
+        self.config.poll_hook = Some(hook);
+        self
+    }
+
+    /// Sets a function that handles control IN requests that weren't handled by any class or by
+    /// the device itself, for example to keep the handling of many vendor requests in one place.
+    ///
+    /// Requests are first offered to the classes in the order they are passed to
+    /// [`UsbDevice::poll`], then standard requests are handled by the device, and only then is the
+    /// fallback called. If the fallback doesn't accept or reject the request either, it is
+    /// rejected with a STALL as usual.
+    ///
+    /// Default: (none)
+    pub fn control_in_fallback(mut self, fallback: fn(ControlIn<B>)) -> Self {
+        self.control_fallback.control_in = Some(fallback);
+        self
+    }
+
+    /// Sets a function that handles control OUT requests that weren't handled by any class or by
+    /// the device itself. See [`control_in_fallback`](Self::control_in_fallback) for the order in
+    /// which requests are handled.
+    ///
+    /// Default: (none)
+    pub fn control_out_fallback(mut self, fallback: fn(ControlOut<B>)) -> Self {
+        self.control_fallback.control_out = Some(fallback);
+        self
+    }
+
     /// Sets the maximum packet size in bytes for the control endpoint 0.
     ///
     /// Valid values are 8, 16, 32 and 64. There's generally no need to change this from the default
@@ -132,6 +269,74 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
         self
     }
 
+    /// Sets the device class, sub-class and protocol to `0xef`, `0x02` and `0x01`, which tells the
+    /// host that the device is a composite device that groups its interfaces into functions with
+    /// interface association descriptors (IADs). This is required if any class returns a
+    /// [`function_descriptor`](UsbClass::function_descriptor) or writes an
+    /// [`iad`](crate::descriptor::DescriptorWriter::iad), otherwise hosts may ignore the IADs.
+    ///
+    /// In debug builds, the device panics when writing a configuration descriptor with IADs if
+    /// the device class hasn't been set this way.
+    pub fn composite_with_iads(mut self) -> Self {
+        self.config.device_class = device_class::MISCELLANEOUS;
+        self.config.device_sub_class = 0x02;
+        self.config.device_protocol = 0x01;
+        self
+    }
+
+    /// Sets the number of configurations the host can choose between with SET_CONFIGURATION. The
+    /// configurations have the `bConfigurationValue`s 1 to `count`, and the descriptors of each
+    /// are generated by calling
+    /// [`get_configuration_descriptors`](crate::class::UsbClass::get_configuration_descriptors)
+    /// on the classes, which can check
+    /// [`DescriptorWriter::configuration_value`](crate::descriptor::DescriptorWriter::configuration_value)
+    /// to only describe their interfaces in the configurations they are part of. Classes are told
+    /// about the selected configuration with
+    /// [`UsbClass::configuration_changed`](crate::class::UsbClass::configuration_changed).
+    ///
+    /// Interface numbers and endpoints are allocated for the device as a whole, so they are not
+    /// shared between configurations.
+    ///
+    /// Default: 1
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is 0.
+    pub fn num_configurations(mut self, count: u8) -> Self {
+        if count == 0 {
+            panic!("invalid num_configurations");
+        }
+
+        self.config.num_configurations = count;
+        self
+    }
+
+    /// Sets the size of the control pipe buffer in bytes, which limits the length of control
+    /// transfer data stages that are buffered as a whole. This can be used to check that a device
+    /// works with a smaller buffer, and reduces the memory touched by each transfer.
+    ///
+    /// The memory for the buffer is always reserved for the maximum size, which is 128 bytes, or
+    /// 256 bytes with the `control-buffer-256` feature. OUT data stages longer than the buffer are
+    /// stalled unless a class streams them with [`UsbClass::control_out_chunk`], and IN responses
+    /// longer than the buffer fail with [`BufferOverflow`](crate::UsbError::BufferOverflow) unless
+    /// they are sent with one of the windowed, streamed or static `accept` methods of
+    /// [`ControlIn`].
+    ///
+    /// Default: the maximum size
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is smaller than 64 bytes, the largest endpoint 0 packet size, or larger
+    /// than the maximum size.
+    pub fn control_buffer_size(mut self, size: usize) -> Self {
+        if !(64..=CONTROL_BUF_LEN).contains(&size) {
+            panic!("invalid control_buffer_size");
+        }
+
+        self.config.control_buffer_size = size;
+        self
+    }
+
     /// Sets the maximum current drawn from the USB bus by the device in milliamps.
     ///
     /// The default is 100 mA. If your device always uses an external power source and never draws
@@ -140,6 +345,11 @@ impl<'a, B: UsbBus> UsbDeviceBuilder<'a, B> {
     /// See also: `self_powered`
     ///
     /// Default: 100mA
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_power_ma` is more than 500 mA, the most that can be encoded in the
+    /// configuration descriptor.
     pub fn max_power(mut self, max_power_ma: usize) -> Self {
         if max_power_ma > 500 {
             panic!("max_power is too much")
@@ -1,8 +1,10 @@
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, Ordering};
+#[cfg(feature = "resend-last-packet")]
+use core::sync::atomic::{AtomicU8, AtomicUsize};
 use core::ptr;
-use crate::{Result, UsbDirection};
-use crate::bus::UsbBus;
+use crate::{Result, UsbDirection, UsbError};
+use crate::bus::{UsbBus, UsbSpeed};
 
 /// Trait for endpoint type markers.
 pub trait EndpointDirection {
@@ -38,7 +40,9 @@ pub enum EndpointType {
     /// Control endpoint. Used for device management. Only the host can initiate requests. Usually
     /// used only endpoint 0.
     Control = 0b00,
-    /// Isochronous endpoint. Used for time-critical unreliable data. Not implemented yet.
+    /// Isochronous endpoint. Used for time-critical unreliable data. See
+    /// [`UsbBusAllocator::isochronous`](crate::bus::UsbBusAllocator::isochronous) for allocating
+    /// one with a synchronization and usage type.
     Isochronous = 0b01,
     /// Bulk endpoint. Used for large amounts of best-effort reliable data.
     Bulk = 0b10,
@@ -46,31 +50,87 @@ pub enum EndpointType {
     Interrupt = 0b11,
 }
 
+/// Synchronization type of an isochronous endpoint. The values of this enum can be directly cast
+/// into `u8` and shifted left by 2 to get the synchronization type bits of bmAttributes.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IsochronousSynchronizationType {
+    /// No synchronization.
+    NoSynchronization = 0b00,
+    /// Asynchronous. The device has its own clock and usually provides feedback to the host.
+    Asynchronous = 0b01,
+    /// Adaptive. The device adapts to the data rate of the host.
+    Adaptive = 0b10,
+    /// Synchronous. The device clock is synchronized to the USB start-of-frame.
+    Synchronous = 0b11,
+}
+
+/// Usage type of an isochronous endpoint. The values of this enum can be directly cast into `u8`
+/// and shifted left by 4 to get the usage type bits of bmAttributes.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IsochronousUsageType {
+    /// Data endpoint.
+    Data = 0b00,
+    /// Explicit feedback endpoint, which tells the host the data rate of an asynchronous data
+    /// endpoint.
+    Feedback = 0b01,
+    /// Data endpoint that also provides implicit feedback for another endpoint.
+    ImplicitFeedbackData = 0b10,
+}
+
+// Maximum length of a packet remembered for EndpointIn::resend_last.
+#[cfg(feature = "resend-last-packet")]
+const LAST_PACKET_LEN: usize = 64;
+
+// Value of Endpoint::last_packet_len when no packet is remembered.
+#[cfg(feature = "resend-last-packet")]
+const NO_LAST_PACKET: usize = usize::MAX;
+
 /// Handle for a USB endpoint. The endpoint direction is constrained by the `D` type argument, which
 /// must be either `In` or `Out`.
 pub struct Endpoint<'a, B: UsbBus, D: EndpointDirection> {
     bus_ptr: &'a AtomicPtr<B>,
+    // Set while a zero-length packet queued by write_packet_terminated hasn't been written by the
+    // device. Only used by IN endpoints.
+    zlp_pending: &'a AtomicBool,
     address: EndpointAddress,
     ep_type: EndpointType,
+    attributes: u8,
     max_packet_size: u16,
     interval: u8,
+    // Packet size currently used by the peripheral, changed with set_max_packet_size
+    packet_size: AtomicU16,
+    // Atomics are used to keep the endpoint Sync
+    #[cfg(feature = "resend-last-packet")]
+    last_packet: [AtomicU8; LAST_PACKET_LEN],
+    #[cfg(feature = "resend-last-packet")]
+    last_packet_len: AtomicUsize,
     _marker: PhantomData<D>
 }
 
 impl<B: UsbBus, D: EndpointDirection> Endpoint<'_, B, D> {
     pub(crate) fn new<'a>(
         bus_ptr: &'a AtomicPtr<B>,
+        zlp_pending: &'a AtomicBool,
         address: EndpointAddress,
         ep_type: EndpointType,
         max_packet_size: u16,
-        interval: u8) -> Endpoint<'_, B, D>
+        interval: u8) -> Endpoint<'a, B, D>
     {
         Endpoint {
             bus_ptr,
+            zlp_pending,
             address,
             ep_type,
+            attributes: ep_type as u8,
             max_packet_size,
             interval,
+            packet_size: AtomicU16::new(max_packet_size),
+            #[cfg(feature = "resend-last-packet")]
+            last_packet: [(); LAST_PACKET_LEN].map(|_| AtomicU8::new(0)),
+            #[cfg(feature = "resend-last-packet")]
+            last_packet_len: AtomicUsize::new(NO_LAST_PACKET),
             _marker: PhantomData
         }
     }
@@ -90,7 +150,22 @@ impl<B: UsbBus, D: EndpointDirection> Endpoint<'_, B, D> {
     /// Gets the endpoint transfer type.
     pub fn ep_type(&self) -> EndpointType { self.ep_type }
 
-    /// Gets the maximum packet size for the endpoint.
+    /// Gets the bmAttributes value of the endpoint descriptor, which is the transfer type with the
+    /// synchronization and usage type bits for isochronous endpoints.
+    pub fn attributes(&self) -> u8 { self.attributes }
+
+    pub(crate) fn set_isochronous_types(
+        &mut self,
+        synchronization: IsochronousSynchronizationType,
+        usage: IsochronousUsageType)
+    {
+        self.attributes = self.ep_type as u8 | ((synchronization as u8) << 2) | ((usage as u8) << 4);
+    }
+
+    /// Gets the maximum packet size for the endpoint. This is the size the bus actually allocated,
+    /// which may be larger or smaller than the requested size if the peripheral only supports
+    /// certain sizes, and is the size to use for example to decide whether a bulk transfer must be
+    /// terminated with a zero-length packet.
     pub fn max_packet_size(&self) -> u16 { self.max_packet_size }
 
     /// Gets the poll interval for interrupt endpoints.
@@ -101,10 +176,66 @@ impl<B: UsbBus, D: EndpointDirection> Endpoint<'_, B, D> {
         self.bus().set_stalled(self.address, true);
     }
 
-    /// Clears the STALL condition of the endpoint.
+    /// Clears the STALL condition of the endpoint. This also resets the data toggle of the
+    /// endpoint like a CLEAR_FEATURE(ENDPOINT_HALT) request from the host does.
     pub fn unstall(&self) {
         self.bus().set_stalled(self.address, false);
     }
+
+    /// Sets or clears the STALL condition for the endpoint, for example to signal an error to the
+    /// host from a class when a bulk transfer can't be processed. The host clears the condition
+    /// with a CLEAR_FEATURE(ENDPOINT_HALT) request, which is handled by the device.
+    pub fn set_stalled(&self, stalled: bool) {
+        self.bus().set_stalled(self.address, stalled);
+    }
+
+    /// Gets whether the STALL condition is set for the endpoint, either by the class or by the host
+    /// with a SET_FEATURE(ENDPOINT_HALT) request.
+    pub fn is_stalled(&self) -> bool {
+        self.bus().is_stalled(self.address)
+    }
+
+    /// Gets the speed the bus is currently operating at, for example to check the packet size
+    /// against [`UsbSpeed::max_packet_size`]. See [`UsbBus::speed`].
+    pub fn speed(&self) -> UsbSpeed {
+        self.bus().speed()
+    }
+
+    /// Gets whether the peripheral double buffers the endpoint. A class can use this to keep two
+    /// packets queued for higher throughput, by writing the next packet right away instead of
+    /// waiting for the previous one to be sent. See [`UsbBus::is_double_buffered`].
+    pub fn is_double_buffered(&self) -> bool {
+        self.bus().is_double_buffered(self.address)
+    }
+
+    /// Gets the offset of the endpoint's buffer in the packet memory of the peripheral, if the bus
+    /// reports it. This is only meant for debugging, and the meaning of the offset depends on the
+    /// bus implementation. See [`UsbBus::ep_fifo_offset`].
+    pub fn fifo_offset(&self) -> Option<u16> {
+        self.bus().ep_fifo_offset(self.address)
+    }
+
+    /// Changes the packet size used by the peripheral for the endpoint, for example when an
+    /// alternate setting that uses a smaller packet size is selected. The size the endpoint was
+    /// allocated with stays reserved and is still returned by
+    /// [`max_packet_size`](Self::max_packet_size).
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - `max_packet_size` is larger than
+    ///   the size the endpoint was allocated with.
+    /// * [`Unsupported`](crate::UsbError::Unsupported) - The peripheral doesn't support changing
+    ///   the packet size. The allocated size still limits the packets in that case.
+    pub fn set_max_packet_size(&self, max_packet_size: u16) -> Result<()> {
+        if max_packet_size > self.max_packet_size {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        self.bus().set_ep_packet_size(self.address, max_packet_size)?;
+        self.packet_size.store(max_packet_size, Ordering::SeqCst);
+
+        Ok(())
+    }
 }
 
 impl<B: UsbBus> Endpoint<'_, B, In> {
@@ -124,7 +255,90 @@ impl<B: UsbBus> Endpoint<'_, B, In> {
     ///   `max_packet_size` specified when allocating the endpoint. This is generally an error in
     ///   the class implementation.
     pub fn write(&self, data: &[u8]) -> Result<usize> {
-        self.bus().write(self.address, data)
+        let count = self.bus().write(self.address, data)?;
+
+        #[cfg(feature = "resend-last-packet")]
+        {
+            self.last_packet_len.store(NO_LAST_PACKET, Ordering::SeqCst);
+
+            if data.len() <= LAST_PACKET_LEN {
+                for (stored, &b) in self.last_packet.iter().zip(data) {
+                    stored.store(b, Ordering::Relaxed);
+                }
+
+                self.last_packet_len.store(data.len(), Ordering::SeqCst);
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Writes the last packet of a transfer, and remembers to end the transfer with a zero-length
+    /// packet if `data` is exactly as long as the current packet size. The current packet size is
+    /// `max_packet_size` unless it was changed with
+    /// [`set_max_packet_size`](Self::set_max_packet_size). Without the zero-length packet the host
+    /// can't tell that the transfer has ended, and keeps waiting for more data.
+    ///
+    /// The packets before the last one are written with [`write`](Self::write) as usual. The
+    /// zero-length packet is written by [`UsbDevice::poll`](crate::device::UsbDevice::poll) once
+    /// this packet has been sent, and
+    /// [`UsbClass::endpoint_in_complete`](crate::class::UsbClass::endpoint_in_complete) is only
+    /// called for the endpoint after the zero-length packet has been sent as well, so it always
+    /// means that the whole transfer is complete. No other packets should be written before
+    /// that.
+    ///
+    /// # Errors
+    ///
+    /// The same errors as [`write`](Self::write) are returned, in which case no zero-length packet
+    /// is queued.
+    pub fn write_packet_terminated(&self, data: &[u8]) -> Result<usize> {
+        let count = self.write(data)?;
+
+        let packet_size = self.packet_size.load(Ordering::SeqCst) as usize;
+        self.zlp_pending.store(count == packet_size, Ordering::SeqCst);
+
+        Ok(count)
+    }
+
+    /// Returns true if a zero-length packet queued by
+    /// [`write_packet_terminated`](Self::write_packet_terminated) hasn't been written yet, in
+    /// which case the transfer isn't complete.
+    pub fn is_zlp_pending(&self) -> bool {
+        self.zlp_pending.load(Ordering::SeqCst)
+    }
+
+    /// Writes the packet most recently written with [`write`](Self::write) again, for example to
+    /// make sure the host has the latest state of a HID report after recovering from an error.
+    ///
+    /// The packet is sent as a new transaction with the next data toggle, so the host sees it as a
+    /// new packet and not a retransmission. It should only be used with data where receiving the
+    /// same packet twice is harmless, such as reports that describe the current state.
+    ///
+    /// Only available with the `resend-last-packet` feature. Like with `write`, the endpoint should
+    /// only be written to from one context at a time, otherwise the packet that is sent again may
+    /// be a mix of two packets.
+    ///
+    /// # Errors
+    ///
+    /// * [`InvalidState`](crate::UsbError::InvalidState) - No packet has been written yet, or the
+    ///   last packet was longer than 64 bytes and was not remembered.
+    ///
+    /// Otherwise the same errors as [`write`](Self::write) are returned.
+    #[cfg(feature = "resend-last-packet")]
+    pub fn resend_last(&self) -> Result<usize> {
+        let len = self.last_packet_len.load(Ordering::SeqCst);
+
+        if len == NO_LAST_PACKET {
+            return Err(UsbError::InvalidState);
+        }
+
+        let mut packet = [0u8; LAST_PACKET_LEN];
+
+        for (b, stored) in packet.iter_mut().zip(&self.last_packet) {
+            *b = stored.load(Ordering::Relaxed);
+        }
+
+        self.bus().write(self.address, &packet[..len])
     }
 }
 
@@ -143,11 +357,89 @@ impl<B: UsbBus> Endpoint<'_, B, Out> {
     ///   USB. A zero-length packet will return `Ok(0)`.
     /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The received packet is too long to
     ///   fit in `data`. This is generally an error in the class implementation.
+    /// * [`Overflow`](crate::UsbError::Overflow) - The host sent a packet longer than the maximum
+    ///   packet size of the endpoint, and the peripheral discarded it. The endpoint is ready to
+    ///   receive the next packet, but any transfer the packet was part of is now incomplete, so the
+    ///   class should drop the data it has received for that transfer so far and get back in sync
+    ///   with the host the same way it would after a reset of the class protocol.
     pub fn read(&self, data: &mut [u8]) -> Result<usize> {
         self.bus().read(self.address, data)
     }
 }
 
+/// Holds the most recent report for an interrupt IN endpoint, so that a class can update its state
+/// as often as it likes but only send one report per poll.
+///
+/// Each call to [`update`](Self::update) replaces the pending report, and
+/// [`flush_on_poll`](Self::flush_on_poll) writes whatever report is pending at that point. Reports
+/// that were replaced before they could be sent are dropped, which is what the host wants for
+/// reports that describe the current state, such as HID input reports.
+pub struct LatestReport<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    pending: bool,
+}
+
+impl<'a> LatestReport<'a> {
+    /// Creates a new `LatestReport` that stores reports in `buf`. The buffer should be as long as
+    /// the longest report that will be sent.
+    pub fn new(buf: &'a mut [u8]) -> LatestReport<'a> {
+        LatestReport {
+            buf,
+            len: 0,
+            pending: false,
+        }
+    }
+
+    /// Replaces the pending report with `report`.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](crate::UsbError::BufferOverflow) - The report is longer than the
+    ///   buffer given to [`new`](Self::new). The previously pending report is kept.
+    pub fn update(&mut self, report: &[u8]) -> Result<()> {
+        if report.len() > self.buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.buf[..report.len()].copy_from_slice(report);
+        self.len = report.len();
+        self.pending = true;
+
+        Ok(())
+    }
+
+    /// Returns true if a report has been updated but not sent yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Writes the pending report to `ep`, if there is one. This should be called once per poll of
+    /// the device. Returns true if a report was written.
+    ///
+    /// If the endpoint is still busy with the previous report, the pending report is kept and
+    /// `Ok(false)` is returned, so a later update can still replace it before the next try.
+    ///
+    /// # Errors
+    ///
+    /// Errors from [`Endpoint::write`] other than `WouldBlock` are passed through, and the report
+    /// stays pending.
+    pub fn flush_on_poll<B: UsbBus>(&mut self, ep: &EndpointIn<B>) -> Result<bool> {
+        if !self.pending {
+            return Ok(false);
+        }
+
+        match ep.write(&self.buf[..self.len]) {
+            Ok(_) => {
+                self.pending = false;
+                Ok(true)
+            },
+            Err(UsbError::WouldBlock) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
 /// Type-safe endpoint address.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct EndpointAddress(u8);
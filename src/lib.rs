@@ -36,6 +36,9 @@
 #![no_std]
 #![warn(missing_docs)]
 
+#[cfg(feature = "test-util")]
+extern crate std;
+
 /// A USB stack error.
 #[derive(Debug)]
 pub enum UsbError {
@@ -66,8 +69,39 @@ pub enum UsbError {
 
     /// Operation is not valid in the current state of the object.
     InvalidState,
+
+    /// A descriptor could not be written because the result would not be a valid descriptor, for
+    /// example because a count field would overflow.
+    MalformedDescriptor,
+
+    /// The host sent a packet longer than the maximum packet size of the endpoint (babble). The
+    /// packet has been discarded. This is different from `BufferOverflow`, which means that a
+    /// valid packet did not fit in the buffer passed in.
+    Overflow,
+}
+
+impl core::fmt::Display for UsbError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            UsbError::WouldBlock => "operation would block",
+            UsbError::ParseError => "failed to parse input",
+            UsbError::BufferOverflow => "data does not fit in the buffer or length limit",
+            UsbError::EndpointOverflow => "no endpoints left to allocate",
+            UsbError::EndpointMemoryOverflow => "not enough endpoint packet buffer memory",
+            UsbError::InvalidEndpoint => "invalid or already used endpoint address",
+            UsbError::Unsupported => "operation not supported",
+            UsbError::InvalidState => "operation not valid in the current state",
+            UsbError::MalformedDescriptor => "descriptor would be malformed",
+            UsbError::Overflow => "received packet longer than the maximum packet size",
+        })
+    }
 }
 
+/// Requires the `core-error` feature, since `core::error::Error` is only available since Rust
+/// 1.81.
+#[cfg(feature = "core-error")]
+impl core::error::Error for UsbError { }
+
 /// Direction of USB traffic. Note that in the USB standard the direction is always indicated from
 /// the perspective of the host, which is backward for devices, but the standard directions are used
 /// for consistency.
@@ -165,6 +199,12 @@ pub mod endpoint;
 pub mod device;
 
 /// Creating USB descriptors
+///
+/// Descriptors that don't fit in the control buffer or would be invalid result in an error, which
+/// causes the request for the descriptor to be rejected. Descriptor generation never panics, with
+/// one exception in debug builds: writing interface association descriptors without
+/// [`UsbDeviceBuilder::composite_with_iads`](crate::device::UsbDeviceBuilder::composite_with_iads)
+/// fails a debug assertion, because the host would otherwise ignore them.
 pub mod descriptor;
 
 /// Test USB class for testing USB driver implementations. Peripheral driver implementations should
@@ -172,6 +212,31 @@ pub mod descriptor;
 /// driver to be tested with the test_class_host example in this crate.
 pub mod test_class;
 
+/// Emulated USB bus and host for testing classes and devices without hardware.
+///
+/// This module is only available with the `test-util` feature, which requires `std`. The
+/// [`EmulatedUsbBus`](test_util::EmulatedUsbBus) can be used like any other bus to build a
+/// [`UsbDevice`](device::UsbDevice), and the functions in this module act as the host to perform
+/// control transfers or a complete enumeration:
+///
+/// ``` ignore
+/// use usb_device::bus::UsbBusAllocator;
+/// use usb_device::test_util::{self, EmulatedUsbBus};
+///
+/// let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+/// let mut class = MyClass::new(&usb_bus);
+/// let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x5824, 0x27dd)).build();
+///
+/// let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).unwrap();
+/// assert_eq!(result.configuration.interfaces[0].interface_class, 0xff);
+/// ```
+///
+/// For lower level tests, the `host_*` methods of the bus inject SETUP and OUT packets and read
+/// back the IN packets written by the device, for example to check the exact bytes a class sends
+/// from its `endpoint_out` handler. The device only processes them when it is polled.
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
 mod control_pipe;
 
 mod device_builder;
@@ -179,17 +244,21 @@ mod device_builder;
 /// Prelude for device implementors.
 pub mod prelude {
     pub use crate::UsbError;
-    pub use crate::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+    pub use crate::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbDeviceWithClasses,
+        UsbVidPid};
 }
 
 /// Prelude for class implementors.
 pub mod class_prelude {
     pub use crate::UsbError;
-    pub use crate::bus::{UsbBus, UsbBusAllocator, InterfaceNumber, StringIndex};
-    pub use crate::descriptor::{DescriptorWriter, BosWriter};
-    pub use crate::endpoint::{EndpointType, EndpointIn, EndpointOut, EndpointAddress};
-    pub use crate::class::{UsbClass, ControlIn, ControlOut};
+    pub use crate::bus::{UsbBus, UsbBusAllocator, UsbSpeed, InterfaceNumber, StringIndex,
+        EndpointSet};
+    pub use crate::descriptor::{DescriptorWriter, BosWriter, FunctionInfo};
+    pub use crate::endpoint::{EndpointType, EndpointIn, EndpointOut, EndpointAddress,
+        IsochronousSynchronizationType, IsochronousUsageType, LatestReport};
+    pub use crate::class::{UsbClass, ControlIn, ControlOut, DeferredStatus, RecipientTarget};
     pub use crate::control;
+    pub use crate::device::UsbDeviceState;
 }
 
 fn _ensure_sync() {
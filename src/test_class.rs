@@ -10,6 +10,7 @@ use crate::descriptor;
 /// requests for testing USB peripheral drivers on actual hardware.
 pub struct TestClass<'a, B: UsbBus> {
     custom_string: StringIndex,
+    empty_string: StringIndex,
     iface: InterfaceNumber,
     ep_bulk_in: EndpointIn<'a, B>,
     ep_bulk_out: EndpointOut<'a, B>,
@@ -48,6 +49,7 @@ impl<B: UsbBus> TestClass<'_, B> {
     pub fn new(alloc: &UsbBusAllocator<B>) -> TestClass<'_, B> {
         TestClass {
             custom_string: alloc.string(),
+            empty_string: alloc.string(),
             iface: alloc.interface(),
             ep_bulk_in: alloc.bulk(64),
             ep_bulk_out: alloc.bulk(64),
@@ -175,8 +177,14 @@ impl<B: UsbBus> UsbClass<B> for TestClass<'_, B> {
     }
 
     fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
-        if index == self.custom_string && lang_id == descriptor::lang_id::ENGLISH_US {
+        if lang_id != descriptor::lang_id::ENGLISH_US {
+            return None;
+        }
+
+        if index == self.custom_string {
             Some(CUSTOM_STRING)
+        } else if index == self.empty_string {
+            Some("")
         } else {
             None
         }
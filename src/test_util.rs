@@ -0,0 +1,1208 @@
+use std::collections::VecDeque;
+use std::string::String;
+use std::sync::Mutex;
+use std::vec::Vec;
+use crate::{Result, UsbDirection, UsbError};
+use crate::bus::{UsbBus, UsbSpeed, PollResult, StringIndex, DEFAULT_BESL};
+use crate::class::UsbClass;
+use crate::control::{Request, RequestType, Recipient};
+use crate::descriptor::descriptor_type;
+use crate::device::UsbDevice;
+use crate::endpoint::{EndpointAddress, EndpointType};
+
+// Number of endpoints in each direction emulated by EmulatedUsbBus.
+const NUM_ENDPOINTS: usize = 16;
+
+// Maximum number of times the device is polled while waiting for it to respond to the host.
+const MAX_POLLS: usize = 100;
+
+#[derive(Default)]
+struct EndpointState {
+    ep_type: Option<EndpointType>,
+    max_packet_size: u16,
+    fifo_offset: u16,
+    stalled: bool,
+    data_toggle: bool,
+    setup: Option<[u8; 8]>,
+    double_buffered: bool,
+    out_packets: VecDeque<Vec<u8>>,
+    in_packets: VecDeque<Vec<u8>>,
+    in_complete: bool,
+}
+
+impl EndpointState {
+    fn clear(&mut self) {
+        self.stalled = false;
+        self.data_toggle = false;
+        self.setup = None;
+        self.out_packets.clear();
+        self.in_packets.clear();
+        self.in_complete = false;
+    }
+
+    // Isochronous transfers always use DATA0 at full speed and have no handshake to keep in sync.
+    fn toggle(&mut self) {
+        if self.ep_type != Some(EndpointType::Isochronous) {
+            self.data_toggle = !self.data_toggle;
+        }
+    }
+}
+
+enum BusEvent {
+    Reset,
+    Suspend,
+    Resume,
+    Sleep(u8),
+}
+
+#[derive(Default)]
+struct BusState {
+    enabled: bool,
+    suspended: bool,
+    remote_wakeup: bool,
+    lpm_enabled: bool,
+    besl_unsupported: bool,
+    disconnected: bool,
+    frame_number: u16,
+    address: u8,
+    // None means the default of full speed
+    speed: Option<UsbSpeed>,
+    ep_out: [EndpointState; NUM_ENDPOINTS],
+    ep_in: [EndpointState; NUM_ENDPOINTS],
+    // Packet memory used by the endpoints allocated so far
+    fifo_used: u16,
+    events: VecDeque<BusEvent>,
+}
+
+impl BusState {
+    fn endpoint(&mut self, ep_addr: EndpointAddress) -> Result<&mut EndpointState> {
+        let eps = match ep_addr.direction() {
+            UsbDirection::Out => &mut self.ep_out,
+            UsbDirection::In => &mut self.ep_in,
+        };
+
+        match eps.get_mut(ep_addr.index()) {
+            Some(ep) if ep.ep_type.is_some() => Ok(ep),
+            _ => Err(UsbError::InvalidEndpoint),
+        }
+    }
+}
+
+/// An in-memory [`UsbBus`] implementation for testing classes and devices on the host.
+///
+/// Packets written by the device are kept in per-endpoint buffers until they are read by the
+/// emulated host with the `host_*` methods, and packets sent by the host are queued until the
+/// device reads them. The higher level functions in this module such as [`enumerate`],
+/// [`control_in`] and [`control_out`] use these methods to drive a [`UsbDevice`] the way a real
+/// host would.
+///
+/// A received packet that does not fit in the buffer passed to [`UsbBus::read`] is discarded after
+/// returning [`BufferOverflow`](crate::UsbError::BufferOverflow).
+///
+/// Endpoint buffers are laid out consecutively in allocation order, and
+/// [`UsbBus::ep_fifo_offset`] returns their byte offsets. Memory of freed endpoints is not reused.
+pub struct EmulatedUsbBus {
+    state: Mutex<BusState>,
+}
+
+impl EmulatedUsbBus {
+    /// Creates a new emulated bus with 16 endpoints in each direction.
+    pub fn new() -> EmulatedUsbBus {
+        EmulatedUsbBus {
+            state: Mutex::new(BusState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BusState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Gets the device address most recently set by the device.
+    pub fn device_address(&self) -> u8 {
+        self.state().address
+    }
+
+    /// Gets the addresses of the endpoints allocated by classes, i.e. all endpoints except the
+    /// control endpoint 0, in ascending order.
+    pub fn allocated_endpoints(&self) -> Vec<u8> {
+        let state = self.state();
+
+        let mut addrs: Vec<u8> = state.ep_out.iter()
+            .enumerate()
+            .map(|(i, ep)| (EndpointAddress::from_parts(i, UsbDirection::Out), ep))
+            .chain(state.ep_in.iter()
+                .enumerate()
+                .map(|(i, ep)| (EndpointAddress::from_parts(i, UsbDirection::In), ep)))
+            .filter(|(addr, ep)| addr.index() != 0 && ep.ep_type.is_some())
+            .map(|(addr, _)| addr.into())
+            .collect();
+
+        addrs.sort_unstable();
+        addrs
+    }
+
+    /// Gets whether the device has put the bus into suspend mode.
+    pub fn is_suspended(&self) -> bool {
+        self.state().suspended
+    }
+
+    /// Sets whether an allocated endpoint is double buffered, so that it holds up to two IN packets
+    /// at a time. See [`UsbBus::is_double_buffered`]. Endpoints are single buffered by default.
+    pub fn set_double_buffered(&self, ep_addr: EndpointAddress, double_buffered: bool) {
+        if let Ok(ep) = self.state().endpoint(ep_addr) {
+            ep.double_buffered = double_buffered;
+        }
+    }
+
+    /// Sets the speed reported by [`UsbBus::speed`]. The default is full speed.
+    pub fn set_speed(&self, speed: UsbSpeed) {
+        self.state().speed = Some(speed);
+    }
+
+    /// Signals a USB reset to the device on the next poll.
+    pub fn host_reset(&self) {
+        self.state().events.push_back(BusEvent::Reset);
+    }
+
+    /// Signals a USB suspend condition to the device on the next poll.
+    pub fn host_suspend(&self) {
+        self.state().events.push_back(BusEvent::Suspend);
+    }
+
+    /// Sets whether the bus can read the BESL field of LPM transactions. A bus that can't reports
+    /// [`DEFAULT_BESL`] instead of the value sent by the host. The field is read by default.
+    pub fn set_besl_supported(&self, supported: bool) {
+        self.state().besl_unsupported = !supported;
+    }
+
+    /// Sends a Link Power Management transaction that asks the device to enter the L1 sleep state
+    /// with the specified BESL value, and returns whether the device acknowledged it. The device
+    /// only acknowledges it if LPM has been enabled with [`UsbBus::set_lpm_enabled`], and then sees
+    /// it on the next poll. Call [`host_resume`](Self::host_resume) to return to L0.
+    pub fn host_lpm_sleep(&self, besl: u8) -> bool {
+        let mut state = self.state();
+
+        if state.lpm_enabled {
+            let besl = if state.besl_unsupported { DEFAULT_BESL } else { besl };
+            state.events.push_back(BusEvent::Sleep(besl));
+        }
+
+        state.lpm_enabled
+    }
+
+    /// Gets and clears whether the device has signaled remote wakeup since the last call. Like a
+    /// real host, the emulated host doesn't resume the bus by itself, so call
+    /// [`host_resume`](Self::host_resume) to do so.
+    pub fn host_take_remote_wakeup(&self) -> bool {
+        core::mem::replace(&mut self.state().remote_wakeup, false)
+    }
+
+    /// Gets and clears whether the device has disconnected from the bus with
+    /// [`UsbBus::force_reset`] since the last call. Like a real host, the emulated host doesn't
+    /// reset the device by itself, so call [`host_reset`](Self::host_reset) to do so.
+    pub fn host_take_disconnect(&self) -> bool {
+        core::mem::replace(&mut self.state().disconnected, false)
+    }
+
+    /// Sends a start-of-frame packet, which advances the frame number returned by
+    /// [`UsbBus::frame_number`] and wraps it from 2047 to 0 like the 11-bit counter of a real bus.
+    pub fn host_sof(&self) {
+        let mut state = self.state();
+        state.frame_number = (state.frame_number + 1) & 0x7ff;
+    }
+
+    /// Signals a USB resume condition to the device on the next poll.
+    pub fn host_resume(&self) {
+        self.state().events.push_back(BusEvent::Resume);
+    }
+
+    /// Sends a SETUP packet to the OUT endpoint with the specified index. Like with real hardware,
+    /// receiving a SETUP packet clears the STALL condition and any pending data of the endpoint in
+    /// both directions.
+    pub fn host_setup(&self, index: usize, packet: [u8; 8]) {
+        let mut state = self.state();
+
+        state.ep_in[index].clear();
+        state.ep_out[index].clear();
+        state.ep_out[index].setup = Some(packet);
+    }
+
+    /// Sends an OUT packet to the endpoint with the specified index. A packet longer than the
+    /// maximum packet size of the endpoint simulates babble and makes the device side read fail
+    /// with [`UsbError::Overflow`].
+    pub fn host_write(&self, index: usize, data: &[u8]) -> HostResult<()> {
+        let mut state = self.state();
+        let ep = &mut state.ep_out[index];
+
+        if ep.stalled {
+            return Err(HostError::Stall);
+        }
+
+        ep.out_packets.push_back(data.to_vec());
+        ep.toggle();
+
+        Ok(())
+    }
+
+    /// Reads the IN packet pending on the endpoint with the specified index, if any. Reading a
+    /// packet causes the device to be notified that the transfer has completed on the next poll.
+    pub fn host_read(&self, index: usize) -> HostResult<Option<Vec<u8>>> {
+        let mut state = self.state();
+        let ep = &mut state.ep_in[index];
+
+        if ep.stalled {
+            return Err(HostError::Stall);
+        }
+
+        let packet = ep.in_packets.pop_front();
+
+        if packet.is_some() {
+            ep.in_complete = true;
+            ep.toggle();
+        }
+
+        Ok(packet)
+    }
+
+    /// Gets whether a SETUP or OUT packet sent to the endpoint with the specified index is still
+    /// waiting to be read by the device.
+    pub fn host_out_pending(&self, index: usize) -> bool {
+        let state = self.state();
+        let ep = &state.ep_out[index];
+
+        ep.setup.is_some() || !ep.out_packets.is_empty()
+    }
+
+    /// Gets the data toggle of the endpoint with the specified address, i.e. whether its next data
+    /// packet is a DATA1 packet. The toggle alternates with every packet transferred, and is reset
+    /// to DATA0 when the STALL condition is cleared or the bus is reset. Isochronous endpoints
+    /// don't use the data toggle and always stay at DATA0.
+    pub fn host_data_toggle(&self, ep_addr: EndpointAddress) -> bool {
+        self.state().endpoint(ep_addr).map(|ep| ep.data_toggle).unwrap_or(false)
+    }
+
+    /// Gets whether the endpoint with the specified address is stalled.
+    pub fn host_is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.state().endpoint(ep_addr).map(|ep| ep.stalled).unwrap_or(false)
+    }
+
+    fn max_packet_size_0(&self) -> usize {
+        self.state().ep_in[0].max_packet_size as usize
+    }
+}
+
+impl Default for EmulatedUsbBus {
+    fn default() -> EmulatedUsbBus {
+        EmulatedUsbBus::new()
+    }
+}
+
+impl UsbBus for EmulatedUsbBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8) -> Result<EndpointAddress>
+    {
+        let state = self.state.get_mut().unwrap();
+
+        let eps = match ep_dir {
+            UsbDirection::Out => &mut state.ep_out,
+            UsbDirection::In => &mut state.ep_in,
+        };
+
+        let index = match ep_addr {
+            Some(addr) => {
+                let index = addr.index();
+
+                if index >= NUM_ENDPOINTS || eps[index].ep_type.is_some() {
+                    return Err(UsbError::InvalidEndpoint);
+                }
+
+                index
+            },
+            None => (1..NUM_ENDPOINTS)
+                .find(|&i| eps[i].ep_type.is_none())
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+
+        eps[index].ep_type = Some(ep_type);
+        eps[index].max_packet_size = max_packet_size;
+        eps[index].fifo_offset = state.fifo_used;
+
+        state.fifo_used = state.fifo_used.saturating_add(max_packet_size);
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn free_ep(&mut self, ep_addr: EndpointAddress) -> Result<()> {
+        let ep = self.state.get_mut().unwrap().endpoint(ep_addr)?;
+
+        *ep = EndpointState::default();
+
+        Ok(())
+    }
+
+    fn enable(&mut self) {
+        self.state.get_mut().unwrap().enabled = true;
+    }
+
+    fn reset(&self) {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        for ep in state.ep_out.iter_mut().chain(state.ep_in.iter_mut()) {
+            ep.clear();
+        }
+
+        state.address = 0;
+        state.suspended = false;
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        if !ep_addr.is_in() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let mut state = self.state();
+        let ep = state.endpoint(ep_addr)?;
+
+        if buf.len() > ep.max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let capacity = if ep.double_buffered { 2 } else { 1 };
+
+        if ep.in_packets.len() >= capacity {
+            return Err(UsbError::WouldBlock);
+        }
+
+        ep.in_packets.push_back(buf.to_vec());
+
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        if !ep_addr.is_out() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let mut state = self.state();
+        let ep = state.endpoint(ep_addr)?;
+
+        let packet = match ep.setup.take() {
+            Some(setup) => setup.to_vec(),
+            None => ep.out_packets.pop_front().ok_or(UsbError::WouldBlock)?,
+        };
+
+        if packet.len() > ep.max_packet_size as usize {
+            return Err(UsbError::Overflow);
+        }
+
+        if packet.len() > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        buf[..packet.len()].copy_from_slice(&packet);
+
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if let Ok(ep) = self.state().endpoint(ep_addr) {
+            ep.stalled = stalled;
+
+            if !stalled {
+                ep.data_toggle = false;
+            }
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.host_is_stalled(ep_addr)
+    }
+
+    fn is_double_buffered(&self, ep_addr: EndpointAddress) -> bool {
+        self.state().endpoint(ep_addr).map(|ep| ep.double_buffered).unwrap_or(false)
+    }
+
+    fn ep_fifo_offset(&self, ep_addr: EndpointAddress) -> Option<u16> {
+        self.state().endpoint(ep_addr).ok().map(|ep| ep.fifo_offset)
+    }
+
+    fn set_ep_packet_size(&self, ep_addr: EndpointAddress, max_packet_size: u16) -> Result<()> {
+        self.state().endpoint(ep_addr)?.max_packet_size = max_packet_size;
+
+        Ok(())
+    }
+
+    fn suspend(&self) {
+        self.state().suspended = true;
+    }
+
+    fn resume(&self) {
+        self.state().suspended = false;
+    }
+
+    fn remote_wakeup(&self) -> Result<()> {
+        let mut state = self.state();
+
+        if !state.suspended {
+            return Err(UsbError::InvalidState);
+        }
+
+        state.remote_wakeup = true;
+
+        Ok(())
+    }
+
+    fn frame_number(&self) -> Result<u16> {
+        Ok(self.state().frame_number)
+    }
+
+    fn set_lpm_enabled(&self, enabled: bool) -> Result<()> {
+        self.state().lpm_enabled = enabled;
+        Ok(())
+    }
+
+    fn force_reset(&self) -> Result<()> {
+        self.state().disconnected = true;
+        Ok(())
+    }
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state();
+
+        if !state.enabled {
+            return PollResult::None;
+        }
+
+        if let Some(event) = state.events.pop_front() {
+            return match event {
+                BusEvent::Reset => PollResult::Reset,
+                BusEvent::Suspend => PollResult::Suspend,
+                BusEvent::Resume => PollResult::Resume,
+                BusEvent::Sleep(besl) => PollResult::Sleep { besl },
+            };
+        }
+
+        let mut ep_out = 0;
+        let mut ep_in_complete = 0;
+        let mut ep_setup = 0;
+
+        for i in 0..NUM_ENDPOINTS {
+            let bit = 1 << i;
+
+            if state.ep_out[i].setup.is_some() {
+                ep_setup |= bit;
+            }
+
+            if !state.ep_out[i].out_packets.is_empty() {
+                ep_out |= bit;
+            }
+
+            if state.ep_in[i].in_complete {
+                state.ep_in[i].in_complete = false;
+                ep_in_complete |= bit;
+            }
+        }
+
+        if (ep_out | ep_in_complete | ep_setup) == 0 {
+            return PollResult::None;
+        }
+
+        PollResult::Data { ep_out, ep_in_complete, ep_setup }
+    }
+
+    fn speed(&self) -> UsbSpeed {
+        self.state().speed.unwrap_or(UsbSpeed::Full)
+    }
+}
+
+/// An error returned by the emulated host operations.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum HostError {
+    /// The device responded with a STALL.
+    Stall,
+
+    /// The device did not respond within a reasonable number of polls.
+    Timeout,
+
+    /// Data returned by the device could not be parsed.
+    ParseError,
+}
+
+/// Result for emulated host operations.
+pub type HostResult<T> = core::result::Result<T, HostError>;
+
+type Device<'d, 'a> = &'d mut UsbDevice<'a, EmulatedUsbBus>;
+
+type Classes<'c, 'x> = &'c mut [&'x mut dyn UsbClass<EmulatedUsbBus>];
+
+fn poll_until(
+    dev: Device,
+    classes: Classes,
+    mut done: impl FnMut(&EmulatedUsbBus) -> HostResult<bool>) -> HostResult<()>
+{
+    for _ in 0..MAX_POLLS {
+        dev.poll(classes);
+
+        if done(dev.bus())? {
+            return Ok(());
+        }
+    }
+
+    Err(HostError::Timeout)
+}
+
+fn setup_packet(req: &Request, length: u16) -> [u8; 8] {
+    let value = req.value.to_le_bytes();
+    let index = req.index.to_le_bytes();
+    let length = length.to_le_bytes();
+
+    [
+        (req.direction as u8) | ((req.request_type as u8) << 5) | (req.recipient as u8),
+        req.request,
+        value[0], value[1],
+        index[0], index[1],
+        length[0], length[1],
+    ]
+}
+
+/// Signals a USB reset and polls the device once to let it process it.
+pub fn bus_reset(dev: Device, classes: Classes) {
+    dev.bus().host_reset();
+    dev.poll(classes);
+}
+
+/// Performs a control IN transfer on endpoint 0 and returns the data sent by the device.
+///
+/// The `direction` field of `req` is ignored.
+pub fn control_in(dev: Device, classes: Classes, req: Request) -> HostResult<Vec<u8>> {
+    let max_packet_size = dev.bus().max_packet_size_0();
+    let req = Request { direction: UsbDirection::In, ..req };
+
+    dev.bus().host_setup(0, setup_packet(&req, req.length));
+
+    let mut data = Vec::new();
+
+    loop {
+        let mut packet = None;
+        poll_until(dev, classes, |bus| {
+            packet = bus.host_read(0)?;
+            Ok(packet.is_some())
+        })?;
+
+        let packet = packet.unwrap();
+        data.extend_from_slice(&packet);
+
+        if packet.len() < max_packet_size || data.len() >= req.length as usize {
+            break;
+        }
+    }
+
+    // Let the device process the completion of the last packet before the status stage
+    dev.poll(classes);
+
+    dev.bus().host_write(0, &[])?;
+    poll_until(dev, classes, |bus| Ok(!bus.host_out_pending(0)))?;
+
+    if dev.bus().host_is_stalled(EndpointAddress::from_parts(0, UsbDirection::Out)) {
+        return Err(HostError::Stall);
+    }
+
+    Ok(data)
+}
+
+/// Performs a control OUT transfer on endpoint 0 with the specified data stage.
+///
+/// The `direction` and `length` fields of `req` are ignored, and the length of `data` is used
+/// instead.
+pub fn control_out(dev: Device, classes: Classes, req: Request, data: &[u8]) -> HostResult<()> {
+    let max_packet_size = dev.bus().max_packet_size_0();
+    let req = Request { direction: UsbDirection::Out, ..req };
+
+    dev.bus().host_setup(0, setup_packet(&req, data.len() as u16));
+    poll_until(dev, classes, |bus| Ok(!bus.host_out_pending(0)))?;
+
+    for chunk in data.chunks(max_packet_size) {
+        dev.bus().host_write(0, chunk)?;
+        poll_until(dev, classes, |bus| Ok(!bus.host_out_pending(0)))?;
+    }
+
+    let mut status = None;
+    poll_until(dev, classes, |bus| {
+        status = bus.host_read(0)?;
+        Ok(status.is_some())
+    })?;
+
+    // Let the device process the completion of the status stage
+    dev.poll(classes);
+
+    Ok(())
+}
+
+fn standard_request(recipient: Recipient, request: u8, value: u16, index: u16, length: u16)
+    -> Request
+{
+    Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient,
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+/// Performs a standard GET_DESCRIPTOR request and returns the raw descriptor data.
+pub fn get_descriptor(
+    dev: Device,
+    classes: Classes,
+    descriptor_type: u8,
+    index: u8,
+    lang_id: u16,
+    length: u16) -> HostResult<Vec<u8>>
+{
+    control_in(dev, classes, standard_request(
+        Recipient::Device,
+        Request::GET_DESCRIPTOR,
+        ((descriptor_type as u16) << 8) | (index as u16),
+        lang_id,
+        length))
+}
+
+/// Performs a standard SET_INTERFACE request.
+pub fn set_interface(dev: Device, classes: Classes, interface: u8, alternate_setting: u8)
+    -> HostResult<()>
+{
+    control_out(dev, classes, standard_request(
+        Recipient::Interface,
+        Request::SET_INTERFACE,
+        alternate_setting as u16,
+        interface as u16,
+        0), &[])
+}
+
+/// Performs a standard GET_INTERFACE request and returns the current alternate setting.
+pub fn get_interface(dev: Device, classes: Classes, interface: u8) -> HostResult<u8> {
+    let data = control_in(dev, classes, standard_request(
+        Recipient::Interface,
+        Request::GET_INTERFACE,
+        0,
+        interface as u16,
+        1))?;
+
+    data.first().copied().ok_or(HostError::ParseError)
+}
+
+fn get_full_descriptor(dev: Device, classes: Classes, descriptor_type: u8)
+    -> HostResult<Vec<u8>>
+{
+    let header = get_descriptor(dev, classes, descriptor_type, 0, 0, 4)?;
+
+    if header.len() < 4 {
+        return Err(HostError::ParseError);
+    }
+
+    let total_length = u16::from_le_bytes([header[2], header[3]]);
+
+    get_descriptor(dev, classes, descriptor_type, 0, 0, total_length)
+}
+
+/// The result of a successful [`enumerate`] call.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct EnumerationResult {
+    /// The device descriptor.
+    pub device: DeviceDescriptor,
+
+    /// The configuration descriptor including all interfaces and endpoints.
+    pub configuration: ConfigurationDescriptor,
+
+    /// The BOS descriptor, if the device reports USB version 2.1 or later.
+    pub bos: Option<BosDescriptor>,
+
+    /// The language IDs from string descriptor 0.
+    pub languages: Vec<u16>,
+
+    /// The manufacturer string in the first language, if any.
+    pub manufacturer: Option<String>,
+
+    /// The product string in the first language, if any.
+    pub product: Option<String>,
+
+    /// The serial number string in the first language, if any.
+    pub serial_number: Option<String>,
+}
+
+/// Enumerates the device like a host would, and returns the parsed descriptors.
+///
+/// The device is reset, its device, configuration, BOS and string descriptors are read, and it is
+/// assigned an address and put into the configured state.
+pub fn enumerate(dev: Device, classes: Classes) -> HostResult<EnumerationResult> {
+    bus_reset(dev, classes);
+
+    let device = parse_device_descriptor(
+        &get_descriptor(dev, classes, descriptor_type::DEVICE, 0, 0, 64)?)?;
+
+    control_out(dev, classes, standard_request(
+        Recipient::Device, Request::SET_ADDRESS, 1, 0, 0), &[])?;
+
+    let configuration = parse_configuration_descriptor(
+        &get_full_descriptor(dev, classes, descriptor_type::CONFIGURATION)?)?;
+
+    let bos = if device.usb_release >= 0x0201 {
+        Some(parse_bos_descriptor(&get_full_descriptor(dev, classes, descriptor_type::BOS)?)?)
+    } else {
+        None
+    };
+
+    let languages = parse_language_ids(
+        &get_descriptor(dev, classes, descriptor_type::STRING, 0, 0, 255)?)?;
+
+    let lang_id = *languages.first().ok_or(HostError::ParseError)?;
+
+    let mut read_string = |index: u8| -> HostResult<Option<String>> {
+        if index == 0 {
+            return Ok(None);
+        }
+
+        let data = get_descriptor(dev, classes, descriptor_type::STRING, index, lang_id, 255)?;
+
+        parse_string_descriptor(&data).map(Some)
+    };
+
+    let manufacturer = read_string(device.manufacturer_index)?;
+    let product = read_string(device.product_index)?;
+    let serial_number = read_string(device.serial_number_index)?;
+
+    control_out(dev, classes, standard_request(
+        Recipient::Device,
+        Request::SET_CONFIGURATION,
+        configuration.configuration_value as u16,
+        0,
+        0), &[])?;
+
+    Ok(EnumerationResult {
+        device,
+        configuration,
+        bos,
+        languages,
+        manufacturer,
+        product,
+        serial_number,
+    })
+}
+
+/// Gets the string indices from 1 to `max_index` for which the class returns a string in the
+/// specified language.
+pub fn class_string_indices(class: &dyn UsbClass<EmulatedUsbBus>, max_index: u8, lang_id: u16)
+    -> Vec<u8>
+{
+    (1..=max_index)
+        .filter(|&index| class.get_string(StringIndex::new(index), lang_id).is_some())
+        .collect()
+}
+
+/// Gets the non-zero string indices referenced by the device and interface descriptors of an
+/// enumerated device, in ascending order without duplicates.
+pub fn referenced_string_indices(result: &EnumerationResult) -> Vec<u8> {
+    let mut indices: Vec<u8> = [
+            result.device.manufacturer_index,
+            result.device.product_index,
+            result.device.serial_number_index,
+        ].iter()
+        .copied()
+        .chain(result.configuration.interfaces.iter().map(|i| i.interface_index))
+        .filter(|&index| index != 0)
+        .collect();
+
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Enumerates the device and returns the string indices referenced by its descriptors that don't
+/// resolve to a string descriptor in the first supported language. An empty result means every
+/// reference is valid.
+pub fn dangling_string_indices(dev: Device, classes: Classes) -> HostResult<Vec<u8>> {
+    let result = enumerate(dev, classes)?;
+    let lang_id = *result.languages.first().ok_or(HostError::ParseError)?;
+
+    let mut dangling = Vec::new();
+
+    for index in referenced_string_indices(&result) {
+        match get_descriptor(dev, classes, descriptor_type::STRING, index, lang_id, 255) {
+            Ok(_) => { },
+            Err(HostError::Stall) => dangling.push(index),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(dangling)
+}
+
+/// Gets the endpoint addresses referenced by the endpoint descriptors of a configuration, in
+/// ascending order without duplicates. Endpoints used by several alternate settings are only
+/// listed once.
+pub fn referenced_endpoints(config: &ConfigurationDescriptor) -> Vec<u8> {
+    let mut addrs: Vec<u8> = config.interfaces.iter()
+        .flat_map(|iface| iface.endpoints.iter().map(|ep| ep.address))
+        .collect();
+
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs
+}
+
+/// Endpoints that are allocated but not described in the configuration descriptor, or the other
+/// way around. See [`endpoint_mismatches`].
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct EndpointMismatches {
+    /// Addresses of allocated endpoints that no endpoint descriptor refers to.
+    pub undescribed: Vec<u8>,
+
+    /// Addresses in endpoint descriptors that weren't allocated.
+    pub unallocated: Vec<u8>,
+}
+
+impl EndpointMismatches {
+    /// Returns true if every allocated endpoint is described and vice versa.
+    pub fn is_empty(&self) -> bool {
+        self.undescribed.is_empty() && self.unallocated.is_empty()
+    }
+}
+
+/// Enumerates the device and cross-references the endpoints allocated on the bus against the
+/// endpoint descriptors in its configuration descriptor.
+///
+/// Classes that intentionally leave out their descriptors, such as optional functions whose
+/// hardware is not present, show up as undescribed endpoints.
+pub fn endpoint_mismatches(dev: Device, classes: Classes) -> HostResult<EndpointMismatches> {
+    let result = enumerate(dev, classes)?;
+
+    let allocated = dev.bus().allocated_endpoints();
+    let referenced = referenced_endpoints(&result.configuration);
+
+    Ok(EndpointMismatches {
+        undescribed: allocated.iter().filter(|a| !referenced.contains(a)).copied().collect(),
+        unallocated: referenced.iter().filter(|a| !allocated.contains(a)).copied().collect(),
+    })
+}
+
+/// A descriptor that is not otherwise parsed, such as a class-specific descriptor.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RawDescriptor {
+    /// The bDescriptorType field.
+    pub descriptor_type: u8,
+
+    /// The descriptor data following the bDescriptorType field.
+    pub data: Vec<u8>,
+}
+
+/// A parsed device descriptor.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[allow(missing_docs)]
+pub struct DeviceDescriptor {
+    pub usb_release: u16,
+    pub device_class: u8,
+    pub device_sub_class: u8,
+    pub device_protocol: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_release: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+/// A parsed configuration descriptor with its interfaces.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[allow(missing_docs)]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub configuration_value: u8,
+    pub configuration_index: u8,
+    pub attributes: u8,
+    pub max_power: u8,
+
+    /// The interface descriptors in the order they appear, including alternate settings.
+    pub interfaces: Vec<InterfaceDescriptor>,
+
+    /// Other descriptors that appear before the first interface descriptor.
+    pub extra: Vec<RawDescriptor>,
+}
+
+/// A parsed interface descriptor with its endpoints.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[allow(missing_docs)]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub interface_class: u8,
+    pub interface_sub_class: u8,
+    pub interface_protocol: u8,
+    pub interface_index: u8,
+
+    /// The endpoint descriptors following this interface descriptor.
+    pub endpoints: Vec<EndpointDescriptor>,
+
+    /// Other descriptors that appear after this interface descriptor but before the first endpoint
+    /// descriptor.
+    pub extra: Vec<RawDescriptor>,
+}
+
+/// A parsed endpoint descriptor.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[allow(missing_docs)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+
+    /// Extra bytes past the standard 7 byte endpoint descriptor, such as audio class fields.
+    pub extension: Vec<u8>,
+
+    /// Other descriptors that appear after this endpoint descriptor.
+    pub extra: Vec<RawDescriptor>,
+}
+
+/// A parsed BOS descriptor with its device capabilities.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct BosDescriptor {
+    /// The wTotalLength field.
+    pub total_length: u16,
+
+    /// The capability descriptors in the order they appear.
+    pub capabilities: Vec<Capability>,
+}
+
+/// A device capability descriptor from a BOS descriptor.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Capability {
+    /// The bDevCapabilityType field.
+    pub capability_type: u8,
+
+    /// The capability data following the bDevCapabilityType field.
+    pub data: Vec<u8>,
+}
+
+// Splits a buffer into (descriptor type, descriptor data) pairs.
+fn descriptors(buf: &[u8]) -> HostResult<Vec<(u8, &[u8])>> {
+    let mut result = Vec::new();
+    let mut i = 0;
+
+    while i < buf.len() {
+        let length = buf[i] as usize;
+
+        if length < 2 || i + length > buf.len() {
+            return Err(HostError::ParseError);
+        }
+
+        result.push((buf[i + 1], &buf[i + 2..i + length]));
+        i += length;
+    }
+
+    Ok(result)
+}
+
+fn expect(descriptor: Option<&(u8, &[u8])>, descriptor_type: u8, min_length: usize)
+    -> HostResult<Vec<u8>>
+{
+    match descriptor {
+        Some((t, data)) if *t == descriptor_type && data.len() >= min_length => Ok(data.to_vec()),
+        _ => Err(HostError::ParseError),
+    }
+}
+
+fn le16(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+/// Parses a device descriptor.
+pub fn parse_device_descriptor(buf: &[u8]) -> HostResult<DeviceDescriptor> {
+    let d = expect(descriptors(buf)?.first(), descriptor_type::DEVICE, 16)?;
+
+    Ok(DeviceDescriptor {
+        usb_release: le16(&d, 0),
+        device_class: d[2],
+        device_sub_class: d[3],
+        device_protocol: d[4],
+        max_packet_size_0: d[5],
+        vendor_id: le16(&d, 6),
+        product_id: le16(&d, 8),
+        device_release: le16(&d, 10),
+        manufacturer_index: d[12],
+        product_index: d[13],
+        serial_number_index: d[14],
+        num_configurations: d[15],
+    })
+}
+
+/// Parses a complete configuration descriptor including the interface, endpoint and other
+/// descriptors following it.
+pub fn parse_configuration_descriptor(buf: &[u8]) -> HostResult<ConfigurationDescriptor> {
+    let descs = descriptors(buf)?;
+    let c = expect(descs.first(), descriptor_type::CONFIGURATION, 7)?;
+
+    let mut config = ConfigurationDescriptor {
+        total_length: le16(&c, 0),
+        num_interfaces: c[2],
+        configuration_value: c[3],
+        configuration_index: c[4],
+        attributes: c[5],
+        max_power: c[6],
+        interfaces: Vec::new(),
+        extra: Vec::new(),
+    };
+
+    for desc in &descs[1..] {
+        match desc.0 {
+            descriptor_type::INTERFACE => {
+                let d = expect(Some(desc), descriptor_type::INTERFACE, 7)?;
+
+                config.interfaces.push(InterfaceDescriptor {
+                    interface_number: d[0],
+                    alternate_setting: d[1],
+                    num_endpoints: d[2],
+                    interface_class: d[3],
+                    interface_sub_class: d[4],
+                    interface_protocol: d[5],
+                    interface_index: d[6],
+                    endpoints: Vec::new(),
+                    extra: Vec::new(),
+                });
+            },
+            descriptor_type::ENDPOINT => {
+                let d = expect(Some(desc), descriptor_type::ENDPOINT, 5)?;
+                let iface = config.interfaces.last_mut().ok_or(HostError::ParseError)?;
+
+                iface.endpoints.push(EndpointDescriptor {
+                    address: d[0],
+                    attributes: d[1],
+                    max_packet_size: le16(&d, 2),
+                    interval: d[4],
+                    extension: d[5..].to_vec(),
+                    extra: Vec::new(),
+                });
+            },
+            _ => {
+                let raw = RawDescriptor { descriptor_type: desc.0, data: desc.1.to_vec() };
+
+                match config.interfaces.last_mut() {
+                    Some(iface) => match iface.endpoints.last_mut() {
+                        Some(ep) => ep.extra.push(raw),
+                        None => iface.extra.push(raw),
+                    },
+                    None => config.extra.push(raw),
+                }
+            },
+        }
+    }
+
+    Ok(config)
+}
+
+/// Gets the number of bytes of periodic (isochronous and interrupt) endpoint data that fit in a
+/// single frame at the specified speed, or a single microframe at high speed. This is the limit for
+/// one endpoint, and a configuration that exceeds it is rejected by host bandwidth allocators
+/// whatever else is on the bus.
+pub fn periodic_bandwidth_budget(speed: UsbSpeed) -> usize {
+    match speed {
+        UsbSpeed::Low | UsbSpeed::Full => 1023,
+        UsbSpeed::High => 3 * 1024,
+        // Burst and mult fields are in the companion descriptors, which are not parsed
+        UsbSpeed::Super => 48 * 1024,
+    }
+}
+
+/// Sums the bytes per (micro)frame reserved by the periodic endpoints of a configuration in the
+/// worst case where all of them are serviced in the same frame.
+///
+/// For interfaces with alternate settings, the alternate setting with the largest bandwidth is
+/// counted. At high speed the additional transactions per microframe encoded in bits 12..11 of
+/// `wMaxPacketSize` are included.
+pub fn periodic_bandwidth(config: &ConfigurationDescriptor, speed: UsbSpeed) -> usize {
+    let mut per_interface: Vec<(u8, usize)> = Vec::new();
+
+    for iface in &config.interfaces {
+        let bytes: usize = iface.endpoints.iter()
+            .filter(|ep| matches!(ep.attributes & 0b11, 0b01 | 0b11))
+            .map(|ep| {
+                let size = (ep.max_packet_size & 0x7ff) as usize;
+
+                match speed {
+                    UsbSpeed::High => size * (((ep.max_packet_size >> 11) & 0b11) as usize + 1),
+                    _ => size,
+                }
+            })
+            .sum();
+
+        match per_interface.iter_mut().find(|(n, _)| *n == iface.interface_number) {
+            Some((_, max)) => *max = (*max).max(bytes),
+            None => per_interface.push((iface.interface_number, bytes)),
+        }
+    }
+
+    per_interface.iter().map(|(_, bytes)| bytes).sum()
+}
+
+/// Checks whether the periodic endpoints of a configuration fit in the bandwidth budget for the
+/// specified speed. See [`periodic_bandwidth`] and [`periodic_bandwidth_budget`].
+pub fn fits_periodic_bandwidth(config: &ConfigurationDescriptor, speed: UsbSpeed) -> bool {
+    periodic_bandwidth(config, speed) <= periodic_bandwidth_budget(speed)
+}
+
+/// Parses a complete BOS descriptor including the device capability descriptors following it.
+pub fn parse_bos_descriptor(buf: &[u8]) -> HostResult<BosDescriptor> {
+    let descs = descriptors(buf)?;
+    let b = expect(descs.first(), descriptor_type::BOS, 3)?;
+
+    let mut capabilities = Vec::new();
+
+    for desc in &descs[1..] {
+        let d = expect(Some(desc), descriptor_type::CAPABILITY, 1)?;
+
+        capabilities.push(Capability {
+            capability_type: d[0],
+            data: d[1..].to_vec(),
+        });
+    }
+
+    Ok(BosDescriptor {
+        total_length: le16(&b, 0),
+        capabilities,
+    })
+}
+
+fn parse_utf16_descriptor(buf: &[u8]) -> HostResult<Vec<u16>> {
+    let s = expect(descriptors(buf)?.first(), descriptor_type::STRING, 0)?;
+
+    if s.len() % 2 != 0 {
+        return Err(HostError::ParseError);
+    }
+
+    Ok(s.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect())
+}
+
+/// Parses string descriptor 0 into a list of language IDs.
+pub fn parse_language_ids(buf: &[u8]) -> HostResult<Vec<u16>> {
+    parse_utf16_descriptor(buf)
+}
+
+/// Parses a string descriptor.
+pub fn parse_string_descriptor(buf: &[u8]) -> HostResult<String> {
+    String::from_utf16(&parse_utf16_descriptor(buf)?).map_err(|_| HostError::ParseError)
+}
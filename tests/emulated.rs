@@ -0,0 +1,4839 @@
+//! Tests that run the device and TestClass against the emulated bus and host in
+//! `usb_device::test_util`, without requiring hardware.
+
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use usb_device::{Result, UsbDirection, UsbError};
+use usb_device::bus::{
+    BusChooses, DEFAULT_BESL, EndpointAllocationStrategy, LowestAvailable, PollResult,
+};
+use usb_device::class_prelude::*;
+use usb_device::control::{Feature, Recipient, Request, RequestType};
+use usb_device::descriptor::{self, capability_type, cdc, descriptor_type};
+use usb_device::device::{
+    ClassError, PollEvents, PollPhase, SystemExitLatency, UsbDevice, UsbDeviceBuilder,
+    UsbDeviceState, UsbVidPid};
+use usb_device::endpoint::In;
+use usb_device::test_class::{self, TestClass};
+use usb_device::test_util::{self, EmulatedUsbBus, HostError};
+
+#[test]
+fn enumerate_test_class() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TestClass::new(&usb_bus);
+    let mut usb_dev = class.make_device(&usb_bus);
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    assert_eq!(result.device.vendor_id, test_class::VID);
+    assert_eq!(result.device.product_id, test_class::PID);
+    assert_eq!(result.languages, [0x0409]);
+    assert_eq!(result.manufacturer.as_deref(), Some(test_class::MANUFACTURER));
+    assert_eq!(result.product.as_deref(), Some(test_class::PRODUCT));
+    assert_eq!(result.serial_number.as_deref(), Some(test_class::SERIAL_NUMBER));
+
+    let config = &result.configuration;
+    assert_eq!(config.num_interfaces, 1);
+    assert_eq!(config.interfaces.len(), 1);
+    assert_eq!(config.interfaces[0].interface_class, 0xff);
+    assert_eq!(config.interfaces[0].endpoints.len(), 4);
+    // Configuration, interface and four endpoint descriptors
+    assert_eq!(config.total_length, 9 + 9 + 4 * 7);
+
+    assert_eq!(usb_dev.bus().device_address(), 1);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Configured);
+}
+
+#[test]
+fn allocation_rollback() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let checkpoint = usb_bus.checkpoint();
+
+    let iface = usb_bus.interface();
+    let eps: Vec<EndpointIn<_>> = (0..15).map(|_| usb_bus.bulk(64)).collect();
+
+    let res = usb_bus.alloc::<In>(None, EndpointType::Bulk, 64, 0);
+    assert!(matches!(res, Err(UsbError::EndpointOverflow)));
+
+    drop(eps);
+    usb_bus.rollback(checkpoint).expect("rollback");
+
+    assert!(usb_bus.interface() == iface);
+    assert!(usb_bus.alloc::<In>(None, EndpointType::Bulk, 64, 0).is_ok());
+}
+
+struct ModeClass {
+    iface: InterfaceNumber,
+    protocol: u8,
+}
+
+impl<B: UsbBus> UsbClass<B> for ModeClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0x03, 0x01, self.protocol)
+    }
+}
+
+#[test]
+fn descriptors_follow_class_state() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = ModeClass { iface: usb_bus.interface(), protocol: 0x01 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(result.configuration.interfaces[0].interface_protocol, 0x01);
+
+    class.protocol = 0x02;
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("re-enumerate");
+    assert_eq!(result.configuration.interfaces[0].interface_protocol, 0x02);
+}
+
+struct SizeClass {
+    iface: InterfaceNumber,
+    string: StringIndex,
+    extra_len: usize,
+    string_value: String,
+}
+
+impl<B: UsbBus> UsbClass<B> for SizeClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.write(0x41, &vec![0xaa; self.extra_len])
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.string {
+            Some(&self.string_value)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn oversized_descriptors_stall() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SizeClass {
+        iface: usb_bus.interface(),
+        string: usb_bus.string(),
+        extra_len: 0,
+        string_value: String::new(),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+
+    for len in 0..300 {
+        class.extra_len = len;
+
+        let res = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut class],
+            descriptor_type::CONFIGURATION, 0, 0, 0xffff);
+
+        match res {
+            Ok(data) => assert_eq!(data.len(), 9 + 9 + 2 + len),
+            // Configuration descriptors larger than the control buffer are sent in parts, so only
+            // the 255 byte limit of a single descriptor applies
+            Err(HostError::Stall) => assert!(2 + len > 255),
+            Err(err) => panic!("configuration descriptor with {} extra bytes: {:?}", len, err),
+        }
+    }
+
+    let string_index = u8::from(class.string);
+
+    for len in 0..300 {
+        class.string_value = "x".repeat(len);
+
+        let res = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut class],
+            descriptor_type::STRING, string_index, 0x0409, 0xffff);
+
+        match res {
+            Ok(data) => assert_eq!(data.len(), 2 + len * 2),
+            Err(HostError::Stall) => assert!(2 + len * 2 > 128),
+            Err(err) => panic!("string descriptor of length {}: {:?}", len, err),
+        }
+    }
+}
+
+struct PlacementClass<'a> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, EmulatedUsbBus>,
+    layout: fn(&PlacementClass<'a>, &mut DescriptorWriter) -> Result<()>,
+    malformed: Cell<bool>,
+}
+
+impl UsbClass<EmulatedUsbBus> for PlacementClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        let res = (self.layout)(self, writer);
+        self.malformed.set(matches!(res, Err(UsbError::MalformedDescriptor)));
+        res
+    }
+}
+
+/// Returns whether the layout was rejected as malformed, checking that the host saw a STALL if so.
+fn is_malformed(layout: fn(&PlacementClass, &mut DescriptorWriter) -> Result<()>) -> bool {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = PlacementClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.interrupt(8, 1),
+        layout,
+        malformed: Cell::new(false),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class],
+        descriptor_type::CONFIGURATION, 0, 0, 0xffff);
+
+    assert_eq!(res.is_err(), class.malformed.get());
+
+    class.malformed.get()
+}
+
+#[test]
+fn class_specific_descriptor_placement() {
+    use descriptor_type::{CS_INTERFACE, CS_ENDPOINT};
+
+    assert!(!is_malformed(|c, w| {
+        w.interface(c.iface, 0x01, 0x02, 0x00)?;
+        w.write(CS_INTERFACE, &[0x01, 0x00, 0x01])?;
+        w.write(CS_INTERFACE, &[0x02, 0x01])?;
+        w.endpoint(&c.ep)?;
+        w.write(CS_ENDPOINT, &[0x01, 0x00, 0x00, 0x00])
+    }));
+
+    // CS_INTERFACE before the interface
+    assert!(is_malformed(|c, w| {
+        w.write(CS_INTERFACE, &[0x01, 0x00, 0x01])?;
+        w.interface(c.iface, 0x01, 0x02, 0x00)
+    }));
+
+    // CS_INTERFACE after the interface's endpoints
+    assert!(is_malformed(|c, w| {
+        w.interface(c.iface, 0x01, 0x02, 0x00)?;
+        w.endpoint(&c.ep)?;
+        w.write(CS_INTERFACE, &[0x01, 0x00, 0x01])
+    }));
+
+    // CS_ENDPOINT before any endpoint
+    assert!(is_malformed(|c, w| {
+        w.interface(c.iface, 0x01, 0x02, 0x00)?;
+        w.write(CS_ENDPOINT, &[0x01, 0x00, 0x00, 0x00])?;
+        w.endpoint(&c.ep)
+    }));
+}
+
+struct FeedbackClass<'a> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for FeedbackClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0x01, 0x02, 0x00)?;
+
+        assert!(matches!(writer.endpoint_feedback(&self.ep, 0), Err(UsbError::InvalidEndpoint)));
+        assert!(matches!(writer.endpoint_feedback(&self.ep, 10), Err(UsbError::InvalidEndpoint)));
+
+        writer.endpoint_feedback(&self.ep, 3)
+    }
+}
+
+#[test]
+fn feedback_endpoint_refresh() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = FeedbackClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.alloc(None, EndpointType::Isochronous, 4, 1).expect("alloc"),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let endpoints = &result.configuration.interfaces[0].endpoints;
+    assert_eq!(endpoints.len(), 1);
+    assert_eq!(endpoints[0].attributes, EndpointType::Isochronous as u8);
+    assert_eq!(endpoints[0].extension, [3, 0]);
+}
+
+struct IsochronousClass<'a> {
+    iface: InterfaceNumber,
+    data: EndpointOut<'a, EmulatedUsbBus>,
+    feedback: EndpointIn<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for IsochronousClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.data)?;
+        writer.endpoint(&self.feedback)
+    }
+}
+
+#[test]
+fn isochronous_endpoint_types() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+
+    for interval in &[0, 17] {
+        assert!(matches!(
+            usb_bus.alloc::<In>(None, EndpointType::Isochronous, 64, *interval),
+            Err(UsbError::InvalidEndpoint)));
+    }
+
+    let mut class = IsochronousClass {
+        iface: usb_bus.interface(),
+        data: usb_bus.isochronous(
+            IsochronousSynchronizationType::Asynchronous,
+            IsochronousUsageType::Data,
+            192, 1),
+        feedback: usb_bus.alloc_isochronous(
+            None,
+            IsochronousSynchronizationType::NoSynchronization,
+            IsochronousUsageType::Feedback,
+            4, 4).expect("alloc"),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let endpoints = &result.configuration.interfaces[0].endpoints;
+    assert_eq!(endpoints.len(), 2);
+    assert_eq!((endpoints[0].attributes, endpoints[0].interval), (0b00_01_01, 1));
+    assert_eq!((endpoints[1].attributes, endpoints[1].interval), (0b01_00_01, 4));
+    assert_eq!(class.data.ep_type(), EndpointType::Isochronous);
+
+    // Isochronous endpoints don't toggle between DATA0 and DATA1
+    let addr = class.data.address();
+    usb_dev.bus().host_write(addr.index(), &[1, 2, 3]).expect("write");
+    let mut buf = [0u8; 192];
+    assert_eq!(class.data.read(&mut buf).expect("read"), 3);
+    assert!(!usb_dev.bus().host_data_toggle(addr));
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Callback {
+    Reset,
+    Configure,
+    SetAlternateSetting(u8),
+}
+
+struct SequenceClass {
+    iface: InterfaceNumber,
+    alt: u8,
+    callbacks: Vec<Callback>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SequenceClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)
+    }
+
+    fn reset(&mut self) {
+        self.alt = 0;
+        self.callbacks.push(Callback::Reset);
+    }
+
+    fn configure(&mut self) {
+        self.alt = 0;
+        self.callbacks.push(Callback::Configure);
+    }
+
+    fn set_alternate_setting(&mut self, interface: InterfaceNumber, alternate_setting: u8)
+        -> bool
+    {
+        if interface != self.iface || alternate_setting > 1 {
+            return false;
+        }
+
+        self.alt = alternate_setting;
+        self.callbacks.push(Callback::SetAlternateSetting(alternate_setting));
+        true
+    }
+
+    fn get_alternate_setting(&self, interface: InterfaceNumber) -> Option<u8> {
+        if interface == self.iface { Some(self.alt) } else { None }
+    }
+}
+
+#[test]
+fn callback_sequence() {
+    use Callback::*;
+
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SequenceClass { iface: usb_bus.interface(), alt: 0, callbacks: Vec::new() };
+    let iface = u8::from(class.iface);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+
+    // SET_INTERFACE is not valid before the device is configured
+    assert_eq!(
+        test_util::set_interface(&mut usb_dev, &mut [&mut class], iface, 0),
+        Err(HostError::Stall));
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    test_util::set_interface(&mut usb_dev, &mut [&mut class], iface, 1).expect("set interface");
+    assert_eq!(test_util::get_interface(&mut usb_dev, &mut [&mut class], iface), Ok(1));
+
+    assert_eq!(
+        test_util::set_interface(&mut usb_dev, &mut [&mut class], iface, 2),
+        Err(HostError::Stall));
+    assert_eq!(test_util::get_interface(&mut usb_dev, &mut [&mut class], iface), Ok(1));
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("re-enumerate");
+    assert_eq!(test_util::get_interface(&mut usb_dev, &mut [&mut class], iface), Ok(0));
+
+    assert_eq!(
+        class.callbacks,
+        [Reset, Reset, Configure, SetAlternateSetting(1), Reset, Configure]);
+}
+
+#[test]
+fn current_configuration_and_alternate_settings() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SequenceClass { iface: usb_bus.interface(), alt: 0, callbacks: Vec::new() };
+    let iface = class.iface;
+    let other = usb_bus.interface();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    // Nothing is selected before the device is configured
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+    assert_eq!(usb_dev.configuration(), None);
+    assert_eq!(usb_dev.alternate_setting(&[&mut class], iface), None);
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(usb_dev.configuration(), Some(1));
+    assert_eq!(usb_dev.alternate_setting(&[&mut class], iface), Some(0));
+
+    test_util::set_interface(&mut usb_dev, &mut [&mut class], u8::from(iface), 1)
+        .expect("set interface");
+    assert_eq!(usb_dev.alternate_setting(&[&mut class], iface), Some(1));
+
+    // Interfaces that no class reports are in the default alternate setting
+    assert_eq!(usb_dev.alternate_setting(&[&mut class], other), Some(0));
+
+    // Kept while suspended
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(usb_dev.configuration(), Some(1));
+    assert_eq!(usb_dev.alternate_setting(&[&mut class], iface), Some(1));
+
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+    assert_eq!(usb_dev.configuration(), None);
+}
+
+const REQ_READ_VENDOR_DATA: u8 = 0x01;
+
+struct VendorDataClass;
+
+impl VendorDataClass {
+    fn data() -> Vec<u8> {
+        (0..128).map(|i| i as u8).collect()
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for VendorDataClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_READ_VENDOR_DATA {
+            xfer.accept_with(&Self::data()).expect("accept");
+        }
+    }
+}
+
+#[test]
+fn multi_packet_vendor_response() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = VendorDataClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .max_packet_size_0(64)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    for &length in &[64, 100, 128, 255] {
+        let req = Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: REQ_READ_VENDOR_DATA,
+            value: 0,
+            index: 0,
+            length,
+        };
+
+        let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req)
+            .expect("vendor request");
+
+        assert_eq!(data, &VendorDataClass::data()[..(length as usize).min(128)]);
+    }
+}
+
+struct OptionalClass {
+    iface: InterfaceNumber,
+    present: bool,
+}
+
+impl<B: UsbBus> UsbClass<B> for OptionalClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        if self.present {
+            writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn composite_interface_numbers() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class1 = OptionalClass { iface: usb_bus.interface(), present: true };
+    let mut class2 = OptionalClass { iface: usb_bus.interface(), present: true };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    assert!(class1.iface != class2.iface);
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class1, &mut class2])
+        .expect("enumerate");
+
+    let numbers: Vec<u8> = result.configuration.interfaces.iter()
+        .map(|iface| iface.interface_number)
+        .collect();
+
+    assert_eq!(numbers, [u8::from(class1.iface), u8::from(class2.iface)]);
+    assert_eq!(numbers, [0, 1]);
+}
+
+#[test]
+fn optional_class_interfaces() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut first = OptionalClass { iface: usb_bus.interface(), present: true };
+    let mut second = OptionalClass { iface: usb_bus.interface(), present: true };
+    // Optional classes must allocate their interfaces last
+    let mut optional = OptionalClass { iface: usb_bus.interface(), present: false };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    for &present in &[false, true] {
+        optional.present = present;
+
+        let result = test_util::enumerate(
+            &mut usb_dev,
+            &mut [&mut first, &mut second, &mut optional]).expect("enumerate");
+
+        let numbers: Vec<u8> = result.configuration.interfaces.iter()
+            .map(|iface| iface.interface_number)
+            .collect();
+
+        if present {
+            assert_eq!(result.configuration.num_interfaces, 3);
+            assert_eq!(numbers, [0, 1, 2]);
+        } else {
+            assert_eq!(result.configuration.num_interfaces, 2);
+            assert_eq!(numbers, [0, 1]);
+        }
+    }
+}
+
+#[test]
+fn set_isoch_delay() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_ISOCH_DELAY,
+        value: 40,
+        index: 0,
+        length: 0,
+    };
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    // SET_ISOCH_DELAY is only valid at SuperSpeed
+    assert_eq!(test_util::control_out(&mut usb_dev, &mut [], req, &[]), Err(HostError::Stall));
+    assert_eq!(usb_dev.isoch_delay(), None);
+
+    usb_dev.bus().set_speed(UsbSpeed::Super);
+    test_util::bus_reset(&mut usb_dev, &mut []);
+
+    // Valid in the Default state too
+    test_util::control_out(&mut usb_dev, &mut [], req, &[]).expect("set isoch delay");
+    assert_eq!(usb_dev.isoch_delay(), Some(40));
+
+    test_util::bus_reset(&mut usb_dev, &mut []);
+    assert_eq!(usb_dev.isoch_delay(), None);
+}
+
+#[test]
+fn set_sel() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_SEL,
+        value: 0,
+        index: 0,
+        length: 6,
+    };
+    let data = [0x10, 0x20, 0x34, 0x12, 0x78, 0x56];
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    // SET_SEL is only valid at SuperSpeed
+    assert_eq!(test_util::control_out(&mut usb_dev, &mut [], req, &data), Err(HostError::Stall));
+    assert_eq!(usb_dev.system_exit_latency(), None);
+
+    usb_dev.bus().set_speed(UsbSpeed::Super);
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    test_util::control_out(&mut usb_dev, &mut [], req, &data).expect("set sel");
+    assert_eq!(
+        usb_dev.system_exit_latency(),
+        Some(SystemExitLatency { u1_sel: 0x10, u1_pel: 0x20, u2_sel: 0x1234, u2_pel: 0x5678 }));
+
+    test_util::bus_reset(&mut usb_dev, &mut []);
+    assert_eq!(usb_dev.system_exit_latency(), None);
+}
+
+struct CountingClass<'r> {
+    iface: InterfaceNumber,
+    resets: &'r Cell<u32>,
+}
+
+impl<B: UsbBus> UsbClass<B> for CountingClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)
+    }
+
+    fn reset(&mut self) {
+        self.resets.set(self.resets.get() + 1);
+    }
+}
+
+#[test]
+fn device_with_classes() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let (resets1, resets2) = (Cell::new(0), Cell::new(0));
+    let mut class1 = CountingClass { iface: usb_bus.interface(), resets: &resets1 };
+    let mut class2 = CountingClass { iface: usb_bus.interface(), resets: &resets2 };
+    let mut classes: [&mut dyn UsbClass<_>; 2] = [&mut class1, &mut class2];
+
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .build_with_classes(&mut classes);
+
+    usb_dev.device().bus().host_reset();
+    usb_dev.poll();
+
+    assert_eq!((resets1.get(), resets2.get()), (1, 1));
+    assert_eq!(usb_dev.device().state(), UsbDeviceState::Default);
+
+    // GET_DESCRIPTOR(CONFIGURATION) with wLength 9
+    usb_dev.device().bus().host_setup(0, [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00]);
+
+    let mut packet = None;
+    for _ in 0..10 {
+        usb_dev.poll();
+        packet = usb_dev.device().bus().host_read(0).expect("read");
+
+        if packet.is_some() {
+            break;
+        }
+    }
+
+    // bNumInterfaces includes the interfaces of both classes
+    assert_eq!(packet.expect("no response")[4], 2);
+}
+
+#[test]
+fn requests_rejected_before_configured() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SequenceClass { iface: usb_bus.interface(), alt: 0, callbacks: Vec::new() };
+    let _ep = usb_bus.bulk::<In>(64);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let request = |recipient, request, value, index, length| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient,
+        request,
+        value,
+        index,
+        length,
+    };
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // SET_CONFIGURATION(0) returns the device to the Addressed state
+    test_util::control_out(
+        &mut usb_dev, &mut [&mut class],
+        request(Recipient::Device, Request::SET_CONFIGURATION, 0, 0, 0), &[])
+        .expect("deconfigure");
+    assert_eq!(usb_dev.state(), UsbDeviceState::Addressed);
+
+    let iface = u8::from(class.iface);
+
+    assert_eq!(
+        test_util::set_interface(&mut usb_dev, &mut [&mut class], iface, 0),
+        Err(HostError::Stall));
+    assert_eq!(
+        test_util::get_interface(&mut usb_dev, &mut [&mut class], iface),
+        Err(HostError::Stall));
+    assert_eq!(
+        test_util::control_in(
+            &mut usb_dev, &mut [&mut class],
+            request(Recipient::Endpoint, Request::GET_STATUS, 0, 0x81, 2)),
+        Err(HostError::Stall));
+
+    // Endpoint 0 requests are still valid
+    assert_eq!(
+        test_util::control_in(
+            &mut usb_dev, &mut [&mut class],
+            request(Recipient::Endpoint, Request::GET_STATUS, 0, 0x80, 2)),
+        Ok(vec![0, 0]));
+
+    assert!(!class.callbacks.contains(&Callback::SetAlternateSetting(0)));
+}
+
+#[test]
+fn endpoint_halt_status() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep: EndpointIn<_> = usb_bus.bulk(64);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let request = |request, index| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Endpoint,
+        request,
+        value: 0,
+        index,
+        length: 2,
+    };
+
+    let ep_index = u8::from(ep.address()) as u16;
+    let halt = request(Request::SET_FEATURE, ep_index);
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    let status = test_util::control_in(&mut usb_dev, &mut [],
+        request(Request::GET_STATUS, ep_index));
+    assert_eq!(status, Ok(vec![0, 0]));
+
+    test_util::control_out(&mut usb_dev, &mut [], halt, &[]).expect("set halt");
+    let status = test_util::control_in(&mut usb_dev, &mut [],
+        request(Request::GET_STATUS, ep_index));
+    assert_eq!(status, Ok(vec![1, 0]));
+
+    test_util::control_out(&mut usb_dev, &mut [],
+        Request { request: Request::CLEAR_FEATURE, ..halt }, &[]).expect("clear halt");
+    let status = test_util::control_in(&mut usb_dev, &mut [],
+        request(Request::GET_STATUS, ep_index));
+    assert_eq!(status, Ok(vec![0, 0]));
+
+    // Endpoints that aren't allocated, including the other direction of an allocated one, stall
+    for &index in &[0x82, 0x01, 0x0f] {
+        let status = test_util::control_in(&mut usb_dev, &mut [],
+            request(Request::GET_STATUS, index));
+        assert_eq!(status, Err(HostError::Stall));
+
+        let res = test_util::control_out(&mut usb_dev, &mut [],
+            Request { index, ..halt }, &[]);
+        assert_eq!(res, Err(HostError::Stall));
+    }
+}
+
+static POLL_PHASES: std::sync::Mutex<Vec<PollPhase>> = std::sync::Mutex::new(Vec::new());
+
+fn record_poll_phase(phase: PollPhase) {
+    POLL_PHASES.lock().unwrap().push(phase);
+}
+
+#[test]
+fn poll_hook() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .poll_hook(record_poll_phase)
+        .build();
+
+    // A poll with nothing to do and one that handles a reset
+    usb_dev.poll(&mut []);
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut []);
+
+    assert_eq!(
+        *POLL_PHASES.lock().unwrap(),
+        [PollPhase::Start, PollPhase::End, PollPhase::Start, PollPhase::End]);
+}
+
+struct LocalizedClass;
+
+impl<B: UsbBus> UsbClass<B> for LocalizedClass {
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        match (index, lang_id) {
+            (StringIndex::PRODUCT, 0x040c) => Some("Produit"),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn localized_product_string() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = LocalizedClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .product("Product")
+        .supported_languages(&[0x0409, 0x040c])
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(result.languages, [0x0409, 0x040c]);
+    assert_eq!(result.product.as_deref(), Some("Product"));
+
+    let product_index = result.device.product_index;
+
+    for &(lang_id, expected) in &[(0x0409, "Product"), (0x040c, "Produit")] {
+        let data = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut class],
+            descriptor_type::STRING, product_index, lang_id, 255).expect("product string");
+
+        assert_eq!(test_util::parse_string_descriptor(&data).as_deref(), Ok(expected));
+    }
+
+    // Languages that aren't listed are rejected without asking the classes
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class],
+        descriptor_type::STRING, product_index, 0x0407, 255);
+
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+#[test]
+fn default_supported_languages() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = LocalizedClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .product("Product")
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(result.languages, [0x0409]);
+
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class],
+        descriptor_type::STRING, result.device.product_index, 0x040c, 255);
+
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+struct SerialClass {
+    serial: Option<&'static str>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SerialClass {
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        match index {
+            StringIndex::SERIAL_NUMBER => self.serial,
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn serial_number_override() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut first = SerialClass { serial: None };
+    let mut second = SerialClass { serial: None };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .serial_number("1234")
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut first, &mut second])
+        .expect("enumerate");
+    assert_eq!(result.serial_number.as_deref(), Some("1234"));
+
+    // Switch to a test mode identity at runtime
+    second.serial = Some("TEST-0002");
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut first, &mut second])
+        .expect("re-enumerate");
+    assert_eq!(result.serial_number.as_deref(), Some("TEST-0002"));
+
+    // The first class that returns a string wins
+    first.serial = Some("TEST-0001");
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut first, &mut second])
+        .expect("re-enumerate");
+    assert_eq!(result.serial_number.as_deref(), Some("TEST-0001"));
+}
+
+const REQ_DEFERRED_WRITE: u8 = 0x02;
+
+#[derive(Default)]
+struct DeferredClass {
+    data: Vec<u8>,
+    pending: Option<DeferredStatus>,
+}
+
+impl<B: UsbBus> UsbClass<B> for DeferredClass {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_DEFERRED_WRITE {
+            self.data = xfer.data().to_vec();
+            self.pending = Some(xfer.accept_deferred().expect("defer"));
+        }
+    }
+}
+
+#[test]
+fn deferred_out_status() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = DeferredClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    usb_dev.bus().host_setup(0, [0x40, REQ_DEFERRED_WRITE, 0, 0, 0, 0, 3, 0]);
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_write(0, &[1, 2, 3]).expect("write data");
+
+    // The status stage is NAKed while the class is busy
+    for _ in 0..5 {
+        usb_dev.poll(&mut [&mut class]);
+        assert_eq!(usb_dev.bus().host_read(0), Ok(None));
+    }
+
+    assert_eq!(class.data, [1, 2, 3]);
+
+    class.pending.take().expect("not deferred").complete(&mut usb_dev).expect("complete");
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(usb_dev.bus().host_read(0), Ok(Some(Vec::new())));
+}
+
+#[test]
+fn reset_control_pipe() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = DeferredClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // An OUT packet outside of a transfer stalls the pipe
+    usb_dev.bus().host_write(0, &[]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    assert!(usb_dev.bus().host_is_stalled(EndpointAddress::from(0x80)));
+
+    usb_dev.reset_control_pipe();
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x00)));
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x80)));
+
+    // A deferred status stage that is never completed
+    usb_dev.bus().host_setup(0, [0x40, REQ_DEFERRED_WRITE, 0, 0, 0, 0, 1, 0]);
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_write(0, &[1]).expect("write data");
+    usb_dev.poll(&mut [&mut class]);
+    let stale = class.pending.take().expect("not deferred");
+
+    usb_dev.reset_control_pipe();
+
+    // The aborted transfer can no longer be completed
+    assert!(matches!(stale.complete(&mut usb_dev), Err(UsbError::InvalidState)));
+    assert_eq!(usb_dev.bus().host_read(0), Ok(None));
+
+    // The next transfer works normally
+    let data = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::DEVICE, 0, 0, 18).expect("device");
+    assert_eq!(data.len(), 18);
+}
+
+#[test]
+fn back_to_back_setup_and_out() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TestClass::new(&usb_bus);
+    let mut usb_dev = class.make_device(&usb_bus);
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // The SETUP packet and the data stage both arrive before the device is polled
+    usb_dev.bus().host_setup(0, [0x40, test_class::REQ_WRITE_BUFFER, 0, 0, 0, 0, 4, 0]);
+    usb_dev.bus().host_write(0, &[1, 2, 3, 4]).expect("write data");
+
+    let mut status = None;
+    for _ in 0..10 {
+        usb_dev.poll(&mut [&mut class]);
+        status = usb_dev.bus().host_read(0).expect("status stage");
+
+        if status.is_some() {
+            break;
+        }
+    }
+
+    assert_eq!(status, Some(Vec::new()));
+    usb_dev.poll(&mut [&mut class]);
+
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: test_class::REQ_READ_BUFFER,
+        value: 0,
+        index: 0,
+        length: 4,
+    };
+
+    assert_eq!(test_util::control_in(&mut usb_dev, &mut [&mut class], req), Ok(vec![1, 2, 3, 4]));
+}
+
+struct CdcClass {
+    comm: InterfaceNumber,
+    data: InterfaceNumber,
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.comm, 0x02, 0x02, 0x00)?;
+
+        assert!(matches!(
+            cdc::union(writer, self.comm, &[]),
+            Err(UsbError::MalformedDescriptor)));
+        assert!(matches!(
+            cdc::union(writer, self.comm, &[self.data, self.comm]),
+            Err(UsbError::MalformedDescriptor)));
+
+        cdc::header(writer, 0x0110)?;
+        cdc::call_management(writer, 0x00, self.data)?;
+        cdc::acm(writer, 0x02)?;
+        cdc::union(writer, self.comm, &[self.data])?;
+
+        writer.interface(self.data, 0x0a, 0x00, 0x00)
+    }
+}
+
+#[test]
+fn cdc_functional_descriptors() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = CdcClass { comm: usb_bus.interface(), data: usb_bus.interface() };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let extra: Vec<(u8, &[u8])> = result.configuration.interfaces[0].extra.iter()
+        .map(|d| (d.descriptor_type, &d.data[..]))
+        .collect();
+
+    assert_eq!(extra, [
+        (descriptor_type::CS_INTERFACE, &[0x00, 0x10, 0x01][..]),
+        (descriptor_type::CS_INTERFACE, &[0x01, 0x00, 0x01][..]),
+        (descriptor_type::CS_INTERFACE, &[0x02, 0x02][..]),
+        (descriptor_type::CS_INTERFACE, &[0x06, 0x00, 0x01][..]),
+    ]);
+}
+
+struct AltPacketSizeClass<'a> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for AltPacketSizeClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0x01, 0x02, 0x00)?;
+
+        writer.interface_alt(self.iface, 1, 0x01, 0x02, 0x00, None)?;
+        writer.endpoint_with_packet_size(&self.ep, 192)?;
+
+        writer.interface_alt(self.iface, 2, 0x01, 0x02, 0x00, None)?;
+        writer.endpoint_with_packet_size(&self.ep, 196)?;
+
+        assert!(matches!(
+            writer.endpoint_with_packet_size(&self.ep, 197),
+            Err(UsbError::InvalidEndpoint)));
+
+        Ok(())
+    }
+
+    fn set_alternate_setting(&mut self, interface: InterfaceNumber, alternate_setting: u8)
+        -> bool
+    {
+        let size = match alternate_setting {
+            1 => 192,
+            2 => 196,
+            _ => return false,
+        };
+
+        interface == self.iface && self.ep.set_max_packet_size(size).is_ok()
+    }
+}
+
+#[test]
+fn alternate_setting_packet_sizes() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = AltPacketSizeClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.alloc(None, EndpointType::Isochronous, 196, 1).expect("alloc"),
+    };
+    let index = class.ep.address().index();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    let config = &result.configuration;
+
+    assert_eq!(config.num_interfaces, 1);
+    assert_eq!(config.interfaces.len(), 3);
+
+    let alts: Vec<(u8, u8, Vec<u16>)> = config.interfaces.iter()
+        .map(|i| (
+            i.alternate_setting,
+            i.num_endpoints,
+            i.endpoints.iter().map(|e| e.max_packet_size).collect()))
+        .collect();
+
+    assert_eq!(alts, [(0, 0, vec![]), (1, 1, vec![192]), (2, 1, vec![196])]);
+
+    assert!(matches!(class.ep.set_max_packet_size(197), Err(UsbError::InvalidEndpoint)));
+
+    test_util::set_interface(&mut usb_dev, &mut [&mut class], 0, 1).expect("set_interface");
+    assert!(matches!(class.ep.write(&[0; 196]), Err(UsbError::BufferOverflow)));
+    class.ep.write(&[0; 192]).expect("write");
+    assert_eq!(usb_dev.bus().host_read(index).expect("host_read").map(|p| p.len()), Some(192));
+
+    test_util::set_interface(&mut usb_dev, &mut [&mut class], 0, 2).expect("set_interface");
+    class.ep.write(&[0; 196]).expect("write");
+    assert_eq!(usb_dev.bus().host_read(index).expect("host_read").map(|p| p.len()), Some(196));
+
+    // And back to the smaller size
+    test_util::set_interface(&mut usb_dev, &mut [&mut class], 0, 1).expect("set_interface");
+    assert!(matches!(class.ep.write(&[0; 193]), Err(UsbError::BufferOverflow)));
+    assert_eq!(class.ep.max_packet_size(), 196);
+}
+
+#[test]
+fn set_max_packet_size_unsupported() {
+    // LatchingBus doesn't implement set_ep_packet_size
+    let usb_bus = UsbBusAllocator::new(LatchingBus(EmulatedUsbBus::new()));
+    let ep: EndpointIn<_> = usb_bus.alloc(None, EndpointType::Isochronous, 196, 1).expect("alloc");
+    let _usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    assert!(matches!(ep.set_max_packet_size(197), Err(UsbError::InvalidEndpoint)));
+    assert!(matches!(ep.set_max_packet_size(192), Err(UsbError::Unsupported)));
+}
+
+const REQ_READ_STATUS: u8 = 0x02;
+
+struct StatusClass;
+
+impl<B: UsbBus> UsbClass<B> for StatusClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type != RequestType::Vendor || req.request != REQ_READ_STATUS {
+            return;
+        }
+
+        match req.value {
+            1 => xfer.accept_u8(0x12),
+            2 => xfer.accept_u16(0x1234),
+            4 => xfer.accept_u32(0x1234_5678),
+            _ => xfer.reject(),
+        }.expect("accept");
+    }
+}
+
+#[test]
+fn integer_responses_little_endian() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = StatusClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let expected: [(u16, &[u8]); 3] = [
+        (1, &[0x12]),
+        (2, &[0x34, 0x12]),
+        (4, &[0x78, 0x56, 0x34, 0x12]),
+    ];
+
+    for &(value, bytes) in &expected {
+        let req = Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: REQ_READ_STATUS,
+            value,
+            index: 0,
+            length: 8,
+        };
+
+        let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req)
+            .expect("vendor request");
+
+        assert_eq!(data, bytes);
+    }
+}
+
+struct OutClass<'a> {
+    ep: EndpointOut<'a, EmulatedUsbBus>,
+    received: Vec<u8>,
+    overflows: usize,
+}
+
+impl UsbClass<EmulatedUsbBus> for OutClass<'_> {
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.ep.address() {
+            return;
+        }
+
+        let mut buf = [0u8; 16];
+
+        match self.ep.read(&mut buf) {
+            Ok(count) => self.received.extend_from_slice(&buf[..count]),
+            Err(UsbError::Overflow) => {
+                // Drop the partial transfer
+                self.received.clear();
+                self.overflows += 1;
+            },
+            Err(err) => panic!("unexpected read error: {:?}", err),
+        }
+    }
+}
+
+#[test]
+fn oversized_out_packet() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = OutClass { ep: usb_bus.bulk(8), received: Vec::new(), overflows: 0 };
+    let index = class.ep.address().index();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    usb_dev.bus().host_write(index, &[1; 8]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.received, [1; 8]);
+
+    // Babble: longer than the 8 byte max packet size, but would fit in the read buffer
+    usb_dev.bus().host_write(index, &[2; 9]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.overflows, 1);
+    assert!(class.received.is_empty());
+
+    // The endpoint keeps working after the overflow
+    usb_dev.bus().host_write(index, &[3; 4]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.received, [3; 4]);
+}
+
+const REQ_WRITE_REVERSED: u8 = 0x03;
+
+struct ScratchClass {
+    last: Vec<u8>,
+}
+
+impl<B: UsbBus> UsbClass<B> for ScratchClass {
+    fn control_out(&mut self, mut xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.request_type != RequestType::Vendor || req.request != REQ_WRITE_REVERSED {
+            return;
+        }
+
+        let len = xfer.data().len();
+        let buf = xfer.buffer_mut();
+        assert!(buf.len() >= len);
+
+        // Decode in place
+        buf[..len].reverse();
+        self.last = buf[..len].to_vec();
+
+        xfer.accept().expect("accept");
+    }
+}
+
+#[test]
+fn control_out_scratch_buffer() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = ScratchClass { last: Vec::new() };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: REQ_WRITE_REVERSED,
+        value: 0,
+        index: 0,
+        length: 4,
+    };
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], req, &[1, 2, 3, 4])
+        .expect("vendor request");
+    assert_eq!(class.last, [4, 3, 2, 1]);
+
+    // The next transfer sees its own data and not the previously modified buffer
+    test_util::control_out(&mut usb_dev, &mut [&mut class], req, &[5, 6, 7, 8])
+        .expect("vendor request");
+    assert_eq!(class.last, [8, 7, 6, 5]);
+}
+
+#[test]
+fn suspend_and_resume() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TestClass::new(&usb_bus);
+    let mut usb_dev = class.make_device(&usb_bus);
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert!(!usb_dev.is_suspended());
+
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    assert!(usb_dev.is_suspended());
+    assert!(usb_dev.bus().is_suspended());
+    assert_eq!(usb_dev.state(), UsbDeviceState::Suspend);
+
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert!(!usb_dev.is_suspended());
+    assert!(!usb_dev.bus().is_suspended());
+    assert_eq!(usb_dev.state(), UsbDeviceState::Configured);
+
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    assert!(usb_dev.is_suspended());
+
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut [&mut class]);
+    assert!(!usb_dev.is_suspended());
+    assert_eq!(usb_dev.state(), UsbDeviceState::Default);
+}
+
+#[derive(Default)]
+struct SuspendClass {
+    events: Vec<&'static str>,
+    resets: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for SuspendClass {
+    fn reset(&mut self) {
+        self.resets += 1;
+    }
+
+    fn suspend(&mut self) {
+        self.events.push("suspend");
+    }
+
+    fn resume(&mut self) {
+        self.events.push("resume");
+    }
+}
+
+#[test]
+fn suspend_and_resume_callbacks() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SuspendClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    // Not reported before the device is configured
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert!(class.events.is_empty());
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // Once per transition, not on every poll while suspended
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.events, ["suspend"]);
+
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.events, ["suspend", "resume"]);
+
+    // A reset while suspended doesn't resume
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.events, ["suspend", "resume", "suspend"]);
+}
+
+#[test]
+fn reset_while_suspended() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SuspendClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Suspend);
+
+    // The device goes straight from Suspend to Default without passing through Configured
+    let resets = class.resets;
+    usb_dev.bus().host_reset();
+    let events = usb_dev.poll_events(&mut [&mut class]);
+
+    assert_eq!(events, PollEvents { reset: true, ..PollEvents::default() });
+    assert_eq!(class.events, ["suspend"]);
+    assert_eq!(class.resets, resets + 1);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Default);
+    assert_eq!(usb_dev.configuration(), None);
+    assert!(!usb_dev.bus().is_suspended());
+
+    // A suspend after the reset isn't reported until the device is configured again
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.events, ["suspend"]);
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.events, ["suspend", "suspend", "resume"]);
+}
+
+#[test]
+fn short_device_descriptor_request_before_address() {
+    for &max_packet_size_0 in &[8, 64] {
+        let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+            .max_packet_size_0(max_packet_size_0)
+            .build();
+
+        test_util::bus_reset(&mut usb_dev, &mut []);
+        assert_eq!(usb_dev.state(), UsbDeviceState::Default);
+
+        // Hosts only read the first packet to learn bMaxPacketSize0 before assigning an address
+        let short = test_util::get_descriptor(
+            &mut usb_dev, &mut [], descriptor_type::DEVICE, 0, 0, 8).expect("short descriptor");
+        assert_eq!(short.len(), 8);
+        assert_eq!(short[..2], [18, descriptor_type::DEVICE]);
+        assert_eq!(short[7], max_packet_size_0);
+
+        // Nothing left over for the next transfer
+        assert_eq!(usb_dev.bus().host_read(0), Ok(None));
+
+        let full = test_util::get_descriptor(
+            &mut usb_dev, &mut [], descriptor_type::DEVICE, 0, 0, 18).expect("full descriptor");
+        assert_eq!(full.len(), 18);
+        assert_eq!(full[..8], short[..]);
+    }
+}
+
+#[test]
+fn feature_selectors() {
+    let features = [
+        (Recipient::Endpoint, 0, Feature::EndpointHalt),
+        (Recipient::Interface, 0, Feature::FunctionSuspend),
+        (Recipient::Device, 1, Feature::DeviceRemoteWakeup),
+        (Recipient::Device, 2, Feature::TestMode),
+        (Recipient::Device, 48, Feature::U1Enable),
+        (Recipient::Device, 49, Feature::U2Enable),
+        (Recipient::Device, 50, Feature::LtmEnable),
+    ];
+
+    for &(recipient, selector, feature) in &features {
+        assert_eq!(Feature::try_from((recipient, selector)).ok(), Some(feature));
+        assert_eq!((feature.recipient(), feature.selector()), (recipient, selector));
+    }
+
+    for &(recipient, selector) in &[
+        (Recipient::Device, 0),
+        (Recipient::Endpoint, 1),
+        (Recipient::Interface, 2),
+        (Recipient::Device, 3),
+        (Recipient::Other, 0),
+    ] {
+        assert!(matches!(Feature::try_from((recipient, selector)), Err(UsbError::ParseError)));
+    }
+
+    let mut req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_FEATURE,
+        value: 1,
+        index: 0,
+        length: 0,
+    };
+    assert_eq!(req.feature(), Some(Feature::DeviceRemoteWakeup));
+
+    req.request = Request::SET_CONFIGURATION;
+    assert_eq!(req.feature(), None);
+
+    req.request = Request::CLEAR_FEATURE;
+    req.request_type = RequestType::Vendor;
+    assert_eq!(req.feature(), None);
+}
+
+#[test]
+fn set_and_clear_feature() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TestClass::new(&usb_bus);
+    let mut usb_dev = class.make_device(&usb_bus);
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let feature_request = |recipient, request, feature: Feature, index| Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient,
+        request,
+        value: feature.selector(),
+        index,
+        length: 0,
+    };
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], feature_request(
+        Recipient::Device, Request::SET_FEATURE, Feature::DeviceRemoteWakeup, 0), &[])
+        .expect("set remote wakeup");
+    assert!(usb_dev.remote_wakeup_enabled());
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], feature_request(
+        Recipient::Device, Request::CLEAR_FEATURE, Feature::DeviceRemoteWakeup, 0), &[])
+        .expect("clear remote wakeup");
+    assert!(!usb_dev.remote_wakeup_enabled());
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], feature_request(
+        Recipient::Endpoint, Request::SET_FEATURE, Feature::EndpointHalt, 0x81), &[])
+        .expect("set halt");
+    assert!(usb_dev.bus().host_is_stalled(EndpointAddress::from(0x81)));
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], feature_request(
+        Recipient::Endpoint, Request::CLEAR_FEATURE, Feature::EndpointHalt, 0x81), &[])
+        .expect("clear halt");
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x81)));
+
+    // Known selectors that the device doesn't implement are rejected
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class], feature_request(
+        Recipient::Device, Request::SET_FEATURE, Feature::TestMode, 0), &[]);
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+// SET_ADDRESS(5)
+const SET_ADDRESS_5: [u8; 8] = [0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+#[test]
+fn set_address_after_status() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut []);
+
+    usb_dev.bus().host_setup(0, SET_ADDRESS_5);
+    usb_dev.poll(&mut []);
+
+    // The old address stays in use until the status stage completes
+    assert_eq!(usb_dev.bus().device_address(), 0);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Default);
+
+    assert_eq!(usb_dev.bus().host_read(0), Ok(Some(vec![])));
+    usb_dev.poll(&mut []);
+
+    assert_eq!(usb_dev.bus().device_address(), 5);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Addressed);
+}
+
+// Bus that applies the address itself after the status stage
+struct LatchingBus(EmulatedUsbBus);
+
+impl UsbBus for LatchingBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8) -> Result<EndpointAddress>
+    {
+        self.0.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+    }
+
+    fn enable(&mut self) { self.0.enable() }
+    fn reset(&self) { self.0.reset() }
+    fn set_device_address(&self, addr: u8) { self.0.set_device_address(addr) }
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.0.write(ep_addr, buf)
+    }
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(ep_addr, buf)
+    }
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.0.set_stalled(ep_addr, stalled)
+    }
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool { self.0.is_stalled(ep_addr) }
+    fn suspend(&self) { self.0.suspend() }
+    fn resume(&self) { self.0.resume() }
+    fn poll(&self) -> PollResult { self.0.poll() }
+
+    const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
+}
+
+#[test]
+fn set_address_before_status() {
+    let usb_bus = UsbBusAllocator::new(LatchingBus(EmulatedUsbBus::new()));
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    usb_dev.bus().0.host_reset();
+    usb_dev.poll(&mut []);
+
+    usb_dev.bus().0.host_setup(0, SET_ADDRESS_5);
+    usb_dev.poll(&mut []);
+
+    // The address is handed to the peripheral before the status stage
+    assert_eq!(usb_dev.bus().0.device_address(), 5);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Addressed);
+
+    assert_eq!(usb_dev.bus().0.host_read(0), Ok(Some(vec![])));
+    usb_dev.poll(&mut []);
+
+    assert_eq!(usb_dev.bus().0.device_address(), 5);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Addressed);
+}
+
+struct FunctionClass {
+    comm: InterfaceNumber,
+    data: InterfaceNumber,
+    declared_count: u8,
+}
+
+impl<B: UsbBus> UsbClass<B> for FunctionClass {
+    fn function_descriptor(&self) -> Option<FunctionInfo> {
+        Some(FunctionInfo {
+            first_interface: self.comm,
+            interface_count: self.declared_count,
+            function_class: 0x02,
+            function_sub_class: 0x02,
+            function_protocol: 0x01,
+        })
+    }
+
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.comm, 0x02, 0x02, 0x01)?;
+        writer.interface(self.data, 0x0a, 0x00, 0x00)
+    }
+}
+
+#[test]
+fn interface_association_descriptor() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut function = FunctionClass {
+        comm: usb_bus.interface(),
+        data: usb_bus.interface(),
+        declared_count: 2,
+    };
+    let mut other = SequenceClass { iface: usb_bus.interface(), alt: 0, callbacks: Vec::new() };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .composite_with_iads()
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut function, &mut other])
+        .expect("enumerate");
+    let device = &result.device;
+    assert_eq!((device.device_class, device.device_sub_class, device.device_protocol),
+        (0xef, 0x02, 0x01));
+
+    let config = &result.configuration;
+
+    assert_eq!(config.num_interfaces, 3);
+    assert_eq!(config.extra.len(), 1);
+    assert_eq!(config.extra[0].descriptor_type, descriptor_type::IAD);
+    assert_eq!(config.extra[0].data, [0, 2, 0x02, 0x02, 0x01, 0]);
+
+    // Only the class that declares a function gets an IAD
+    assert!(config.interfaces.iter().all(|i| i.extra.is_empty()));
+
+    for count in &[1, 3] {
+        function.declared_count = *count;
+
+        let res = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut function, &mut other],
+            descriptor_type::CONFIGURATION, 0, 0, 0xff);
+
+        assert!(matches!(res, Err(HostError::Stall)), "interface count {}", count);
+    }
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "composite_with_iads")]
+fn interface_association_requires_composite_class() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut function = FunctionClass {
+        comm: usb_bus.interface(),
+        data: usb_bus.interface(),
+        declared_count: 2,
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut function]).ok();
+}
+
+struct CapabilityClass {
+    capability_type: u8,
+    data: &'static [u8],
+}
+
+impl<B: UsbBus> UsbClass<B> for CapabilityClass {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        writer.capability(self.capability_type, self.data)
+    }
+}
+
+#[test]
+fn bos_capability_order() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut container = CapabilityClass {
+        capability_type: capability_type::CONTAINER_ID,
+        data: &[0x00; 17],
+    };
+    let mut platform = CapabilityClass {
+        capability_type: capability_type::PLATFORM,
+        data: &[0x00; 17],
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .reverse_poll_order(true)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut container, &mut platform])
+        .expect("enumerate");
+    let types: Vec<u8> = result.bos.expect("bos").capabilities.iter()
+        .map(|c| c.capability_type)
+        .collect();
+    assert_eq!(types, [
+        capability_type::USB_2_0_EXTENSION,
+        capability_type::CONTAINER_ID,
+        capability_type::PLATFORM,
+    ]);
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut platform, &mut container])
+        .expect("re-enumerate");
+    let types: Vec<u8> = result.bos.expect("bos").capabilities.iter()
+        .map(|c| c.capability_type)
+        .collect();
+    assert_eq!(types, [
+        capability_type::USB_2_0_EXTENSION,
+        capability_type::PLATFORM,
+        capability_type::CONTAINER_ID,
+    ]);
+}
+
+const REQ_READ_ID: u8 = 0x05;
+
+// Records the callbacks it receives, and answers a vendor request with its id
+struct OrderClass<'a> {
+    id: u8,
+    log: &'a RefCell<Vec<(u8, &'static str)>>,
+}
+
+impl<B: UsbBus> UsbClass<B> for OrderClass<'_> {
+    fn poll(&mut self) {
+        self.log.borrow_mut().push((self.id, "poll"));
+    }
+
+    fn endpoint_out(&mut self, _addr: EndpointAddress) {
+        self.log.borrow_mut().push((self.id, "out"));
+    }
+
+    fn endpoint_in_complete(&mut self, _addr: EndpointAddress) {
+        self.log.borrow_mut().push((self.id, "in"));
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_READ_ID {
+            xfer.accept_u8(self.id).expect("accept");
+        }
+    }
+}
+
+#[test]
+fn reverse_poll_order() {
+    for &reverse in &[false, true] {
+        let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+        let log = RefCell::new(Vec::new());
+        let mut first = OrderClass { id: 1, log: &log };
+        let mut second = OrderClass { id: 2, log: &log };
+        let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+        let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+            .reverse_poll_order(reverse)
+            .build();
+
+        test_util::enumerate(&mut usb_dev, &mut [&mut first, &mut second]).expect("enumerate");
+
+        // Control requests go to the first class in either order
+        let req = Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: REQ_READ_ID,
+            value: 0,
+            index: 0,
+            length: 1,
+        };
+        let data = test_util::control_in(&mut usb_dev, &mut [&mut first, &mut second], req)
+            .expect("vendor request");
+        assert_eq!(data, [1]);
+
+        let order: &[u8] = if reverse { &[2, 1] } else { &[1, 2] };
+        let expected = |event| -> Vec<(u8, &'static str)> {
+            order.iter().map(|&id| (id, event))
+                .chain(order.iter().map(|&id| (id, "poll")))
+                .collect()
+        };
+
+        log.borrow_mut().clear();
+        usb_dev.bus().host_write(ep_out.address().index(), &[1]).expect("write");
+        usb_dev.poll(&mut [&mut first, &mut second]);
+        ep_out.read(&mut [0; 64]).expect("read");
+        assert_eq!(log.take(), expected("out"));
+
+        ep_in.write(&[2]).expect("write");
+        usb_dev.bus().host_read(ep_in.address().index()).expect("read");
+        usb_dev.poll(&mut [&mut first, &mut second]);
+        assert_eq!(log.take(), expected("in"));
+    }
+}
+
+// Billboard capability with one alternate mode: iAdditionalInfoURL, bNumberOfAlternateOrUSB4Modes,
+// bPreferredAlternateOrUSB4Mode, VconnPower, bmConfigured, bcdVersion, bAdditionalFailureInfo,
+// bReserved, and wSVID, bAlternateOrUSB4Mode, iAlternateOrUSB4ModeString for the mode.
+const BILLBOARD_CAPABILITY: [u8; 45] = [
+    0, 1, 0, 0x00, 0x80,
+    0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0x21, 0x01, 0, 0,
+    0x01, 0xff, 0, 0,
+];
+
+#[test]
+fn billboard_device_class() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut billboard = CapabilityClass {
+        capability_type: capability_type::BILLBOARD,
+        data: &BILLBOARD_CAPABILITY,
+    };
+    let mut container = CapabilityClass {
+        capability_type: capability_type::CONTAINER_ID,
+        data: &[0x00; 17],
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .device_class(descriptor::device_class::BILLBOARD)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut container, &mut billboard])
+        .expect("enumerate");
+
+    // The class is written as set, and the BOS descriptor has the matching capability
+    assert_eq!(result.device.device_class, 0x11);
+    let caps = result.bos.expect("bos").capabilities;
+    assert_eq!(caps.len(), 3);
+    assert_eq!(caps[2].capability_type, capability_type::BILLBOARD);
+    assert_eq!(caps[2].data, &BILLBOARD_CAPABILITY[..]);
+
+    // A Billboard device without the capability doesn't send a BOS descriptor
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut container],
+        descriptor_type::BOS, 0, 0, 0xff);
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+#[test]
+fn highest_endpoint_number() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep_out = usb_bus.alloc(Some(EndpointAddress::from(0x0f)), EndpointType::Bulk, 8, 0)
+        .expect("alloc");
+    let mut class = OutClass { ep: ep_out, received: Vec::new(), overflows: 0 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    usb_dev.bus().host_write(15, &[1, 2, 3]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.received, [1, 2, 3]);
+}
+
+struct PeriodicClass<'a> {
+    iface: InterfaceNumber,
+    eps: Vec<EndpointIn<'a, EmulatedUsbBus>>,
+    bulk: EndpointOut<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for PeriodicClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.bulk)?;
+
+        for ep in &self.eps {
+            writer.endpoint(ep)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn periodic_config(sizes: &[(EndpointType, u16)]) -> test_util::ConfigurationDescriptor {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = PeriodicClass {
+        iface: usb_bus.interface(),
+        eps: sizes.iter()
+            .map(|&(ep_type, size)| usb_bus.alloc(None, ep_type, size, 1).expect("alloc"))
+            .collect(),
+        bulk: usb_bus.bulk(64),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate").configuration
+}
+
+#[test]
+fn periodic_bandwidth() {
+    use test_util::{fits_periodic_bandwidth, periodic_bandwidth};
+
+    // Bulk endpoints don't count
+    let config = periodic_config(&[
+        (EndpointType::Isochronous, 960),
+        (EndpointType::Interrupt, 63),
+    ]);
+    assert_eq!(periodic_bandwidth(&config, UsbSpeed::Full), 1023);
+    assert!(fits_periodic_bandwidth(&config, UsbSpeed::Full));
+
+    let config = periodic_config(&[
+        (EndpointType::Isochronous, 512),
+        (EndpointType::Isochronous, 512),
+    ]);
+    assert_eq!(periodic_bandwidth(&config, UsbSpeed::Full), 1024);
+    assert!(!fits_periodic_bandwidth(&config, UsbSpeed::Full));
+    assert!(fits_periodic_bandwidth(&config, UsbSpeed::High));
+
+    // High bandwidth endpoint with two additional transactions per microframe
+    let config = periodic_config(&[(EndpointType::Isochronous, (2 << 11) | 1024)]);
+    assert_eq!(periodic_bandwidth(&config, UsbSpeed::High), 3072);
+    assert!(fits_periodic_bandwidth(&config, UsbSpeed::High));
+
+    let config = periodic_config(&[
+        (EndpointType::Isochronous, (2 << 11) | 1024),
+        (EndpointType::Interrupt, 8),
+    ]);
+    assert!(!fits_periodic_bandwidth(&config, UsbSpeed::High));
+}
+
+#[test]
+fn periodic_bandwidth_alternate_settings() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = AltPacketSizeClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.alloc(None, EndpointType::Isochronous, 196, 1).expect("alloc"),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // Only the largest alternate setting counts
+    assert_eq!(test_util::periodic_bandwidth(&result.configuration, UsbSpeed::Full), 196);
+}
+
+#[test]
+fn set_vid_pid() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    usb_dev.set_vid_pid(UsbVidPid(0x1209, 0x0001)).expect("set_vid_pid");
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!((result.device.vendor_id, result.device.product_id), (0x1209, 0x0001));
+
+    // Not allowed while enumerated
+    assert!(matches!(
+        usb_dev.set_vid_pid(UsbVidPid(0x1209, 0x0002)),
+        Err(UsbError::InvalidState)));
+
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut []);
+    usb_dev.set_vid_pid(UsbVidPid(0x1209, 0x0002)).expect("set_vid_pid after reset");
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("re-enumerate");
+    assert_eq!((result.device.vendor_id, result.device.product_id), (0x1209, 0x0002));
+}
+
+struct NamedInterfacesClass {
+    named: InterfaceNumber,
+    named_string: StringIndex,
+    dangling: InterfaceNumber,
+    dangling_string: StringIndex,
+}
+
+impl<B: UsbBus> UsbClass<B> for NamedInterfacesClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.named, 0, 0xff, 0x00, 0x00, Some(self.named_string))?;
+        writer.interface_alt(self.dangling, 0, 0xff, 0x00, 0x00, Some(self.dangling_string))
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        // Forgets to serve dangling_string
+        if index == self.named_string { Some("Named") } else { None }
+    }
+}
+
+#[test]
+fn dangling_string_references() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = NamedInterfacesClass {
+        named: usb_bus.interface(),
+        named_string: usb_bus.string(),
+        dangling: usb_bus.interface(),
+        dangling_string: usb_bus.string(),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .product("Product")
+        .build();
+
+    let named: u8 = class.named_string.into();
+    let dangling: u8 = class.dangling_string.into();
+
+    assert_eq!(test_util::class_string_indices(&class, 255, 0x0409), [named]);
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(test_util::referenced_string_indices(&result), [2, named, dangling]);
+
+    assert_eq!(
+        test_util::dangling_string_indices(&mut usb_dev, &mut [&mut class]),
+        Ok(vec![dangling]));
+}
+
+struct IdleClass {
+    idle: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for IdleClass {
+    fn control_idle(&mut self) {
+        self.idle += 1;
+    }
+}
+
+#[test]
+fn control_idle_after_transfers() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = IdleClass { idle: 0 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // Control IN transfer
+    class.idle = 0;
+    test_util::get_descriptor(&mut usb_dev, &mut [&mut class], descriptor_type::DEVICE, 0, 0, 18)
+        .expect("device descriptor");
+    assert_eq!(class.idle, 1);
+
+    // Control OUT transfer, which only completes when the host reads the status stage
+    usb_dev.bus().host_setup(0, [0x00, Request::SET_FEATURE, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.idle, 1);
+
+    assert_eq!(usb_dev.bus().host_read(0), Ok(Some(vec![])));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.idle, 2);
+
+    // Rejected transfers don't count
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::CONFIGURATION, 5, 0, 9);
+    assert_eq!(res, Err(HostError::Stall));
+    assert_eq!(class.idle, 2);
+}
+
+struct SuperSpeedPlusClass {
+    speed_id_count: u8,
+    min_speed_id: u8,
+    attributes: &'static [u32],
+    result: Cell<Option<bool>>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SuperSpeedPlusClass {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        let res = writer.superspeed_plus(
+            self.speed_id_count, self.min_speed_id, 1, 1, self.attributes);
+
+        self.result.set(Some(res.is_ok()));
+
+        match res {
+            Err(UsbError::MalformedDescriptor) => Ok(()),
+            res => res,
+        }
+    }
+}
+
+// Sublink speed ID 1, 10 Gb/s (mantissa 10, exponent Gb/s), SuperSpeedPlus protocol
+const SSP_GEN2_RX: u32 = (10 << 16) | (1 << 14) | (3 << 4) | 1;
+const SSP_GEN2_TX: u32 = SSP_GEN2_RX | (1 << 7);
+
+#[test]
+fn superspeed_plus_capability() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SuperSpeedPlusClass {
+        speed_id_count: 1,
+        min_speed_id: 1,
+        attributes: &[SSP_GEN2_RX, SSP_GEN2_TX],
+        result: Cell::new(None),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    let caps = result.bos.expect("bos").capabilities;
+
+    assert_eq!(caps.len(), 2);
+    assert_eq!(caps[1].capability_type, capability_type::SUPERSPEED_PLUS);
+    assert_eq!(caps[1].data, [
+        0x00,
+        0x01, 0x00, 0x00, 0x00, // 2 attributes, 1 speed ID
+        0x01, 0x11, // minimum speed ID 1, one lane each way
+        0x00, 0x00,
+        0x31, 0x40, 0x0a, 0x00,
+        0xb1, 0x40, 0x0a, 0x00,
+    ]);
+
+    let invalid: [(u8, u8, &'static [u32]); 3] = [
+        (2, 1, &[SSP_GEN2_RX, SSP_GEN2_TX]),
+        (1, 2, &[SSP_GEN2_RX, SSP_GEN2_TX]),
+        (1, 1, &[]),
+    ];
+
+    for &(speed_id_count, min_speed_id, attributes) in &invalid {
+        class.speed_id_count = speed_id_count;
+        class.min_speed_id = min_speed_id;
+        class.attributes = attributes;
+        class.result.set(None);
+
+        test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+        assert_eq!(class.result.get(), Some(false));
+    }
+}
+
+const REQ_ROUTE: u8 = 0x04;
+
+struct RoutingClass {
+    iface: InterfaceNumber,
+    targets: Vec<(UsbDirection, RecipientTarget)>,
+}
+
+impl<B: UsbBus> UsbClass<B> for RoutingClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if xfer.request().request_type == RequestType::Vendor
+            && xfer.request().request == REQ_ROUTE
+        {
+            self.targets.push((UsbDirection::In, xfer.recipient_target()));
+            xfer.accept_with(&[]).expect("accept");
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if xfer.request().request_type == RequestType::Vendor
+            && xfer.request().request == REQ_ROUTE
+        {
+            self.targets.push((UsbDirection::Out, xfer.recipient_target()));
+            xfer.accept().expect("accept");
+        }
+    }
+}
+
+#[test]
+fn recipient_targets() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = RoutingClass { iface: usb_bus.interface(), targets: Vec::new() };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let recipients = [
+        (Recipient::Device, 0),
+        (Recipient::Interface, 0),
+        (Recipient::Endpoint, 0x81),
+        (Recipient::Other, 0),
+    ];
+
+    for &(recipient, index) in &recipients {
+        let req = Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Vendor,
+            recipient,
+            request: REQ_ROUTE,
+            value: 0,
+            index,
+            length: 0,
+        };
+
+        test_util::control_in(&mut usb_dev, &mut [&mut class], req).expect("control in");
+        test_util::control_out(&mut usb_dev, &mut [&mut class], req, &[]).expect("control out");
+    }
+
+    let iface = class.iface;
+    let targets: Vec<RecipientTarget> = class.targets.iter().map(|&(_, t)| t).collect();
+
+    assert_eq!(class.targets.len(), 8);
+    assert!(class.targets.chunks(2).all(|pair|
+        pair[0].0 == UsbDirection::In && pair[1].0 == UsbDirection::Out && pair[0].1 == pair[1].1));
+
+    assert!(matches!(targets[0], RecipientTarget::Device));
+    assert!(matches!(targets[2], RecipientTarget::Interface(n) if n == iface));
+    assert!(matches!(
+        targets[4],
+        RecipientTarget::Endpoint(addr) if addr == EndpointAddress::from(0x81)));
+    assert!(matches!(targets[6], RecipientTarget::Other));
+}
+
+struct LargeDescriptorClass<'a> {
+    ifaces: [InterfaceNumber; 3],
+    ep_out: [EndpointOut<'a, EmulatedUsbBus>; 3],
+    generated: Cell<usize>,
+}
+
+impl UsbClass<EmulatedUsbBus> for LargeDescriptorClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        self.generated.set(self.generated.get() + 1);
+
+        for (i, (&iface, ep)) in self.ifaces.iter().zip(&self.ep_out).enumerate() {
+            writer.interface(iface, 0xff, 0x00, 0x00)?;
+            writer.write(0x41, &[i as u8; 100])?;
+            writer.endpoint(ep)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn configuration_descriptor_larger_than_buffer() {
+    for &max_packet_size_0 in &[8, 64] {
+        let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+        let mut class = LargeDescriptorClass {
+            ifaces: [usb_bus.interface(), usb_bus.interface(), usb_bus.interface()],
+            ep_out: [usb_bus.bulk(32), usb_bus.bulk(32), usb_bus.bulk(32)],
+            generated: Cell::new(0),
+        };
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+            .max_packet_size_0(max_packet_size_0)
+            .build();
+
+        let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+        // Each interface has an interface, a 102 byte vendor and an endpoint descriptor
+        let config = &result.configuration;
+        assert_eq!(config.total_length, 9 + 3 * (9 + 102 + 7));
+        assert_eq!(config.num_interfaces, 3);
+
+        for (i, iface) in config.interfaces.iter().enumerate() {
+            assert_eq!(iface.interface_number, u8::from(class.ifaces[i]));
+            assert_eq!(iface.num_endpoints, 1);
+            assert_eq!(iface.extra[0].data, vec![i as u8; 100]);
+            assert_eq!(iface.endpoints[0].address, u8::from(class.ep_out[i].address()));
+        }
+
+        // The descriptor doesn't fit in the buffer, so it's generated more than once per transfer
+        class.generated.set(0);
+
+        let full = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut class],
+            descriptor_type::CONFIGURATION, 0, 0, 0xffff).expect("get descriptor");
+
+        assert_eq!(full.len(), config.total_length as usize);
+        assert!(class.generated.get() > 1);
+
+        for &len in &[9, 100, 200, 300, full.len() as u16 - 1] {
+            let data = test_util::get_descriptor(
+                &mut usb_dev, &mut [&mut class],
+                descriptor_type::CONFIGURATION, 0, 0, len).expect("get descriptor");
+
+            assert_eq!(&data[..], &full[..len as usize]);
+        }
+    }
+}
+
+struct ForgetfulClass<'a> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, EmulatedUsbBus>,
+    ep_out: EndpointOut<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for ForgetfulClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_in)?;
+
+        // The OUT endpoint is left out, and a descriptor is written by hand for an endpoint that
+        // was never allocated.
+        writer.write(descriptor_type::ENDPOINT, &[0x8f, 0x02, 64, 0, 0])
+    }
+}
+
+#[test]
+fn endpoint_descriptor_mismatches() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TestClass::new(&usb_bus);
+    let mut usb_dev = class.make_device(&usb_bus);
+
+    let mismatches = test_util::endpoint_mismatches(&mut usb_dev, &mut [&mut class])
+        .expect("endpoint mismatches");
+
+    assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = ForgetfulClass {
+        iface: usb_bus.interface(),
+        ep_in: usb_bus.bulk(64),
+        ep_out: usb_bus.bulk(64),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let mismatches = test_util::endpoint_mismatches(&mut usb_dev, &mut [&mut class])
+        .expect("endpoint mismatches");
+
+    assert_eq!(mismatches.undescribed, [u8::from(class.ep_out.address())]);
+    assert_eq!(mismatches.unallocated, [0x8f]);
+    assert_eq!(usb_dev.bus().allocated_endpoints().len(), 2);
+}
+
+#[test]
+fn early_status_stage() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = VendorDataClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .max_packet_size_0(8)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    // Vendor IN request for 128 bytes, of which the host only reads the first two packets
+    usb_dev.bus().host_setup(0, [0xc0, REQ_READ_VENDOR_DATA, 0, 0, 0, 0, 128, 0]);
+
+    let mut data = Vec::new();
+
+    while data.len() < 16 {
+        usb_dev.poll(&mut [&mut class]);
+
+        if let Some(packet) = usb_dev.bus().host_read(0).expect("read") {
+            data.extend_from_slice(&packet);
+        }
+    }
+
+    assert_eq!(data, &VendorDataClass::data()[..16]);
+
+    usb_dev.bus().host_write(0, &[]).expect("status");
+
+    for _ in 0..4 {
+        usb_dev.poll(&mut [&mut class]);
+    }
+
+    // The transfer completed without stalling, and no more data is sent
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x00)));
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x80)));
+    assert!(!usb_dev.bus().host_out_pending(0));
+
+    // The next transfer works normally
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: REQ_READ_VENDOR_DATA,
+        value: 0,
+        index: 0,
+        length: 128,
+    };
+
+    let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req)
+        .expect("vendor request");
+
+    assert_eq!(data, VendorDataClass::data());
+}
+
+const REQ_FALLBACK_READ: u8 = 0x10;
+const REQ_FALLBACK_WRITE: u8 = 0x11;
+// A standard request code that the device doesn't handle
+const REQ_FALLBACK_STANDARD: u8 = 0x30;
+
+static FALLBACK_DATA: std::sync::Mutex<Vec<u8>> = std::sync::Mutex::new(Vec::new());
+
+fn fallback_in(xfer: ControlIn<EmulatedUsbBus>) {
+    let req = *xfer.request();
+
+    if (req.request_type == RequestType::Vendor && req.request == REQ_FALLBACK_READ)
+        || (req.request_type == RequestType::Standard && req.request == REQ_FALLBACK_STANDARD)
+    {
+        xfer.accept_with(&FALLBACK_DATA.lock().unwrap()).expect("accept");
+    }
+}
+
+fn fallback_out(xfer: ControlOut<EmulatedUsbBus>) {
+    let req = *xfer.request();
+
+    if (req.request_type == RequestType::Vendor && req.request == REQ_FALLBACK_WRITE)
+        || (req.request_type == RequestType::Standard && req.request == REQ_FALLBACK_STANDARD)
+    {
+        *FALLBACK_DATA.lock().unwrap() = xfer.data().to_vec();
+        xfer.accept().expect("accept");
+    }
+}
+
+#[test]
+fn control_fallback() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = VendorDataClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .control_in_fallback(fallback_in)
+        .control_out_fallback(fallback_out)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let req = |request, length| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value: 0,
+        index: 0,
+        length,
+    };
+
+    // Handled by the fallback
+    test_util::control_out(
+        &mut usb_dev, &mut [&mut class], req(REQ_FALLBACK_WRITE, 0), &[1, 2, 3])
+        .expect("fallback write");
+
+    let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req(REQ_FALLBACK_READ, 64))
+        .expect("fallback read");
+
+    assert_eq!(data, [1, 2, 3]);
+
+    // Handled by the class before the fallback
+    let data = test_util::control_in(
+        &mut usb_dev, &mut [&mut class], req(REQ_READ_VENDOR_DATA, 128))
+        .expect("class read");
+
+    assert_eq!(data, VendorDataClass::data());
+
+    // Standard requests that the device doesn't handle reach the fallback in both directions
+    let standard = |length| Request {
+        request_type: RequestType::Standard,
+        request: REQ_FALLBACK_STANDARD,
+        ..req(0, length)
+    };
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], standard(0), &[4, 5])
+        .expect("fallback standard write");
+
+    let data = test_util::control_in(&mut usb_dev, &mut [&mut class], standard(64))
+        .expect("fallback standard read");
+
+    assert_eq!(data, [4, 5]);
+
+    // Handled by neither
+    let res = test_util::control_in(&mut usb_dev, &mut [&mut class], req(0x7f, 64));
+
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+struct StreamingClass<'a> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, EmulatedUsbBus>,
+}
+
+impl UsbClass<EmulatedUsbBus> for StreamingClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.zero_bandwidth_interface(self.iface, 0x01, 0x02, 0x20, None)?;
+        writer.write(descriptor_type::CS_INTERFACE, &[0x01, 0x00])?;
+        writer.endpoint(&self.ep)
+    }
+}
+
+#[test]
+fn zero_bandwidth_default_setting() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = StreamingClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.alloc(None, EndpointType::Isochronous, 192, 1).expect("alloc"),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    let config = &result.configuration;
+
+    assert_eq!(config.num_interfaces, 1);
+    assert_eq!(config.interfaces.len(), 2);
+
+    let alt0 = &config.interfaces[0];
+    assert_eq!((alt0.alternate_setting, alt0.num_endpoints), (0, 0));
+    assert!(alt0.endpoints.is_empty() && alt0.extra.is_empty());
+
+    let alt1 = &config.interfaces[1];
+    assert_eq!((alt1.alternate_setting, alt1.num_endpoints), (1, 1));
+    assert_eq!(alt1.interface_number, alt0.interface_number);
+    assert_eq!((alt1.interface_class, alt1.interface_sub_class), (0x01, 0x02));
+    assert_eq!(alt1.extra.len(), 1);
+    assert_eq!(alt1.endpoints[0].address, u8::from(class.ep.address()));
+
+    // Only alternate setting 1 uses bandwidth
+    assert_eq!(test_util::periodic_bandwidth(config, UsbSpeed::Full), 192);
+}
+
+// Only offers the high-bandwidth alternate setting at high speed
+struct SpeedLimitedClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    low_bandwidth: EndpointIn<'a, B>,
+    high_bandwidth: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SpeedLimitedClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.zero_bandwidth_interface(self.iface, 0xff, 0x00, 0x00, None)?;
+        writer.endpoint(&self.low_bandwidth)?;
+
+        if writer.speed() == UsbSpeed::High {
+            writer.interface_alt(self.iface, 2, 0xff, 0x00, 0x00, None)?;
+            writer.endpoint(&self.high_bandwidth)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn speed_specific_alternate_settings() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SpeedLimitedClass {
+        iface: usb_bus.interface(),
+        low_bandwidth: usb_bus.interrupt(64, 1),
+        high_bandwidth: usb_bus.interrupt(1024, 1),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .high_speed_capable(true)
+        .build();
+
+    let alternate_settings = |config: &test_util::ConfigurationDescriptor| -> Vec<u8> {
+        config.interfaces.iter().map(|iface| iface.alternate_setting).collect()
+    };
+
+    usb_dev.bus().set_speed(UsbSpeed::High);
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(alternate_settings(&result.configuration), [0, 1, 2]);
+
+    let mut other = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::OTHER_SPEED_CONFIGURATION, 0, 0, 0xff)
+        .expect("other speed configuration");
+    other[1] = descriptor_type::CONFIGURATION;
+    let other = test_util::parse_configuration_descriptor(&other).expect("parse");
+    assert_eq!(alternate_settings(&other), [0, 1]);
+
+    usb_dev.bus().set_speed(UsbSpeed::Full);
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(alternate_settings(&result.configuration), [0, 1]);
+}
+
+struct HighBandwidthClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, B>,
+    result: Cell<Option<Result<()>>>,
+}
+
+impl<B: UsbBus> UsbClass<B> for HighBandwidthClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.zero_bandwidth_interface(self.iface, 0xff, 0x00, 0x00, None)?;
+        self.result.set(Some(writer.endpoint_high_bandwidth(&self.ep, 2)));
+
+        Ok(())
+    }
+}
+
+#[test]
+fn high_bandwidth_endpoint() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = HighBandwidthClass {
+        iface: usb_bus.interface(),
+        ep: usb_bus.alloc(None, EndpointType::Isochronous, 1024, 1).expect("alloc"),
+        result: Cell::new(None),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    // Three transactions of 1024 bytes per microframe
+    usb_dev.bus().set_speed(UsbSpeed::High);
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert!(matches!(class.result.take(), Some(Ok(()))));
+    assert_eq!(result.configuration.interfaces[1].endpoints[0].max_packet_size, 0x1400);
+
+    // Additional transactions are rejected at full speed
+    usb_dev.bus().set_speed(UsbSpeed::Full);
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert!(matches!(class.result.take(), Some(Err(UsbError::InvalidEndpoint))));
+    assert!(result.configuration.interfaces[1].endpoints.is_empty());
+}
+
+struct HaltingClass<'a> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, EmulatedUsbBus>,
+    fault: bool,
+}
+
+impl UsbClass<EmulatedUsbBus> for HaltingClass<'_> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn poll(&mut self) {
+        if self.fault && !self.ep_in.is_stalled() {
+            self.ep_in.set_stalled(true);
+            self.fault = false;
+        }
+    }
+}
+
+#[test]
+fn class_endpoint_stall() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = HaltingClass {
+        iface: usb_bus.interface(),
+        ep_in: usb_bus.bulk(64),
+        fault: false,
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let addr = class.ep_in.address();
+
+    // Transfer one packet so that the data toggle is DATA1
+    class.ep_in.write(&[1, 2, 3]).expect("write");
+    usb_dev.bus().host_read(addr.index()).expect("read");
+    assert!(usb_dev.bus().host_data_toggle(addr));
+
+    // The class stalls the endpoint
+    class.fault = true;
+    test_util::control_in(&mut usb_dev, &mut [&mut class], Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Endpoint,
+        request: Request::GET_STATUS,
+        value: 0,
+        index: u8::from(addr) as u16,
+        length: 2,
+    }).expect("get status");
+
+    assert!(class.ep_in.is_stalled());
+    assert!(matches!(usb_dev.bus().host_read(addr.index()), Err(HostError::Stall)));
+
+    // The host clears the halt, which also resets the data toggle
+    test_util::control_out(&mut usb_dev, &mut [&mut class], Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Endpoint,
+        request: Request::CLEAR_FEATURE,
+        value: Feature::EndpointHalt.selector(),
+        index: u8::from(addr) as u16,
+        length: 0,
+    }, &[]).expect("clear feature");
+
+    assert!(!class.ep_in.is_stalled());
+    assert!(!usb_dev.bus().host_data_toggle(addr));
+
+    // Clearing the stall from the class resets the toggle as well
+    class.ep_in.write(&[4]).expect("write");
+    usb_dev.bus().host_read(addr.index()).expect("read");
+    class.ep_in.set_stalled(false);
+    assert!(!usb_dev.bus().host_data_toggle(addr));
+}
+
+struct FaultyClass {
+    faults: usize,
+    polls: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for FaultyClass {
+    fn try_poll(&mut self) -> Result<()> {
+        self.polls += 1;
+
+        if self.faults > 0 {
+            self.faults -= 1;
+            Err(UsbError::InvalidState)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn class_poll_errors() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut healthy = FaultyClass { faults: 0, polls: 0 };
+    let mut faulty = FaultyClass { faults: 2, polls: 0 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::bus_reset(&mut usb_dev, &mut [&mut healthy, &mut faulty]);
+    assert!(usb_dev.take_class_error().is_none());
+
+    // Classes are polled when there are events to process
+    test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut healthy, &mut faulty],
+        descriptor_type::DEVICE, 0, 0, 18).expect("get descriptor");
+
+    // Only the first of the two errors is kept, and polling continues
+    assert!(matches!(
+        usb_dev.take_class_error(),
+        Some(ClassError { class_index: 1, error: UsbError::InvalidState })));
+    assert!(usb_dev.take_class_error().is_none());
+    assert_eq!(faulty.faults, 0);
+    assert_eq!(healthy.polls, faulty.polls);
+
+    test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut healthy, &mut faulty],
+        descriptor_type::DEVICE, 0, 0, 18).expect("get descriptor");
+
+    assert!(usb_dev.take_class_error().is_none());
+}
+
+#[test]
+fn serial_from_unique_id() {
+    let id = [0x00, 0x1f, 0x20, 0x3a, 0x4b, 0x5c, 0x6d, 0x7e, 0x8f, 0x90, 0xa1, 0xff];
+    let mut buf = [0u8; 24];
+
+    assert_eq!(
+        descriptor::format_serial_hex(&id, &mut buf).expect("format"),
+        "001F203A4B5C6D7E8F90A1FF");
+
+    let mut buf = [0u8; 32];
+    assert_eq!(descriptor::format_serial_hex(&[0xde, 0xad], &mut buf).expect("format"), "DEAD");
+    assert_eq!(descriptor::format_serial_hex(&[], &mut buf).expect("format"), "");
+
+    let mut buf = [0u8; 23];
+    assert!(matches!(
+        descriptor::format_serial_hex(&id, &mut buf),
+        Err(UsbError::BufferOverflow)));
+
+    // The result can be used as the serial number
+    let mut buf = [0u8; 24];
+    let serial = descriptor::format_serial_hex(&id, &mut buf).expect("format");
+
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .serial_number(serial)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(result.serial_number.as_deref(), Some("001F203A4B5C6D7E8F90A1FF"));
+}
+
+#[test]
+fn runtime_serial_number() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+
+    // Created after the allocator, so it only lives as long as the device
+    let serial = format!("SN-{}-\u{e9}", 40 + 2);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .serial_number(&serial)
+        .build();
+
+    let data = test_util::get_descriptor(
+        &mut usb_dev, &mut [],
+        descriptor_type::STRING, 3, 0x0409, 0xff).expect("serial number");
+
+    assert_eq!(data[0] as usize, data.len());
+    let chars: Vec<u16> = data[2..].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    assert_eq!(chars, "SN-42-\u{e9}".encode_utf16().collect::<Vec<_>>());
+}
+
+#[test]
+fn endpoint_fifo_offsets() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+    let ep_out: EndpointOut<_> = usb_bus.bulk(32);
+    let _usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    // The emulated bus lays out the buffers in allocation order
+    assert_eq!(ep_in.fifo_offset(), Some(0));
+    assert_eq!(ep_out.fifo_offset(), Some(64));
+
+    // Buses that don't report offsets return None
+    let usb_bus = UsbBusAllocator::new(LatchingBus(EmulatedUsbBus::new()));
+    let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+    let _usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    assert_eq!(ep_in.fifo_offset(), None);
+}
+
+struct ManualIadClass {
+    comm: InterfaceNumber,
+    data: InterfaceNumber,
+    count: u8,
+    twice: bool,
+    invalid_state: Cell<bool>,
+}
+
+impl<B: UsbBus> UsbClass<B> for ManualIadClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        let mut res = writer.iad(self.comm, self.count, 0x02, 0x02, 0x01);
+
+        if self.twice && res.is_ok() {
+            res = writer.iad(self.comm, self.count, 0x02, 0x02, 0x01);
+        }
+
+        if let Err(err) = res {
+            self.invalid_state.set(matches!(err, UsbError::InvalidState));
+            return Err(err);
+        }
+
+        writer.interface(self.comm, 0x02, 0x02, 0x01)?;
+        writer.interface(self.data, 0x0a, 0x00, 0x00)
+    }
+}
+
+#[test]
+fn interface_association_descriptor_helper() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut other = SequenceClass { iface: usb_bus.interface(), alt: 0, callbacks: Vec::new() };
+    let mut function = ManualIadClass {
+        comm: usb_bus.interface(),
+        data: usb_bus.interface(),
+        count: 2,
+        twice: false,
+        invalid_state: Cell::new(false),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .device_class(0xef)
+        .device_sub_class(0x02)
+        .device_protocol(0x01)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut other, &mut function])
+        .expect("enumerate");
+    let config = &result.configuration;
+
+    // The IAD follows the interface of the first class and starts from the allocated number
+    assert_eq!(config.num_interfaces, 3);
+    let iad = &config.interfaces[0].extra[0];
+    assert_eq!(iad.descriptor_type, descriptor_type::IAD);
+    assert_eq!(iad.data, [u8::from(function.comm), 2, 0x02, 0x02, 0x01, 0]);
+
+    for &(count, twice, invalid_state) in &[(0, false, true), (2, true, true), (3, false, false)] {
+        function.count = count;
+        function.twice = twice;
+        function.invalid_state.set(false);
+
+        let res = test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut other, &mut function],
+            descriptor_type::CONFIGURATION, 0, 0, 0xff);
+
+        assert!(matches!(res, Err(HostError::Stall)), "interface count {}", count);
+        assert_eq!(function.invalid_state.get(), invalid_state);
+    }
+}
+
+// Rounds endpoint packet sizes up to a power of two like some peripherals do
+struct RoundingBus {
+    bus: EmulatedUsbBus,
+    sizes: [[u16; 16]; 2],
+}
+
+impl UsbBus for RoundingBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8) -> Result<EndpointAddress>
+    {
+        let size = max_packet_size.next_power_of_two();
+        let addr = self.bus.alloc_ep(ep_dir, ep_addr, ep_type, size, interval)?;
+
+        self.sizes[addr.is_in() as usize][addr.index()] = size;
+
+        Ok(addr)
+    }
+
+    fn allocated_packet_size(&self, ep_addr: EndpointAddress) -> Option<u16> {
+        Some(self.sizes[ep_addr.is_in() as usize][ep_addr.index()])
+    }
+
+    fn enable(&mut self) { self.bus.enable() }
+    fn reset(&self) { self.bus.reset() }
+    fn set_device_address(&self, addr: u8) { self.bus.set_device_address(addr) }
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.bus.write(ep_addr, buf)
+    }
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.bus.read(ep_addr, buf)
+    }
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.bus.set_stalled(ep_addr, stalled)
+    }
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool { self.bus.is_stalled(ep_addr) }
+    fn suspend(&self) { self.bus.suspend() }
+    fn resume(&self) { self.bus.resume() }
+    fn poll(&self) -> PollResult { self.bus.poll() }
+}
+
+#[test]
+fn allocated_packet_size() {
+    let usb_bus = UsbBusAllocator::new(RoundingBus {
+        bus: EmulatedUsbBus::new(),
+        sizes: [[0; 16]; 2],
+    });
+    let ep_in: EndpointIn<_> = usb_bus.bulk(50);
+    let ep_out: EndpointOut<_> = usb_bus.interrupt(8, 1);
+
+    assert_eq!(ep_in.max_packet_size(), 64);
+    assert_eq!(ep_out.max_packet_size(), 8);
+
+    // Buses that use the requested size as is
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep_in: EndpointIn<_> = usb_bus.bulk(50);
+
+    assert_eq!(ep_in.max_packet_size(), 50);
+}
+
+#[test]
+fn remote_wakeup_lifecycle() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .supports_remote_wakeup(true)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(result.configuration.attributes & 0x20, 0x20);
+
+    let feature = |request| Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request,
+        value: Feature::DeviceRemoteWakeup.selector(),
+        index: 0,
+        length: 0,
+    };
+
+    let get_status = |usb_dev: &mut UsbDevice<_>| {
+        test_util::control_in(usb_dev, &mut [], Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Standard,
+            recipient: Recipient::Device,
+            request: Request::GET_STATUS,
+            value: 0,
+            index: 0,
+            length: 2,
+        }).expect("get status")
+    };
+
+    // Not enabled by the host yet
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut []);
+    assert!(matches!(usb_dev.remote_wakeup(), Err(UsbError::InvalidState)));
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut []);
+
+    test_util::control_out(&mut usb_dev, &mut [], feature(Request::SET_FEATURE), &[])
+        .expect("set feature");
+    assert!(usb_dev.remote_wakeup_enabled());
+    assert_eq!(get_status(&mut usb_dev), [0x02, 0x00]);
+
+    // Only allowed while suspended
+    assert!(matches!(usb_dev.remote_wakeup(), Err(UsbError::InvalidState)));
+
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut []);
+    usb_dev.remote_wakeup().expect("remote wakeup");
+    assert!(usb_dev.bus().host_take_remote_wakeup());
+    assert!(usb_dev.is_suspended());
+
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut []);
+    assert!(!usb_dev.is_suspended());
+
+    // Disabled again by the host
+    test_util::control_out(&mut usb_dev, &mut [], feature(Request::CLEAR_FEATURE), &[])
+        .expect("clear feature");
+    assert!(!usb_dev.remote_wakeup_enabled());
+    assert_eq!(get_status(&mut usb_dev), [0x00, 0x00]);
+
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut []);
+    assert!(matches!(usb_dev.remote_wakeup(), Err(UsbError::InvalidState)));
+    assert!(!usb_dev.bus().host_take_remote_wakeup());
+}
+
+#[test]
+fn latest_report_per_poll() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep_in = usb_bus.interrupt::<In>(8, 10);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    let index = ep_in.address().index();
+    let mut buf = [0u8; 4];
+    let mut report = LatestReport::new(&mut buf);
+
+    // Nothing is sent before the first update
+    assert!(matches!(report.flush_on_poll(&ep_in), Ok(false)));
+
+    // Only the last of several updates within one interval is sent
+    report.update(&[1]).expect("update");
+    report.update(&[2, 2]).expect("update");
+    report.update(&[3, 3, 3]).expect("update");
+    assert!(matches!(report.update(&[0; 5]), Err(UsbError::BufferOverflow)));
+    assert!(report.is_pending());
+
+    assert!(matches!(report.flush_on_poll(&ep_in), Ok(true)));
+    assert!(!report.is_pending());
+    assert!(matches!(report.flush_on_poll(&ep_in), Ok(false)));
+
+    // While the host hasn't read the previous report, updates keep replacing the pending one
+    report.update(&[4]).expect("update");
+    assert!(matches!(report.flush_on_poll(&ep_in), Ok(false)));
+    report.update(&[5, 5]).expect("update");
+    assert!(report.is_pending());
+
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![3, 3, 3]));
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), None);
+
+    assert!(matches!(report.flush_on_poll(&ep_in), Ok(true)));
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![5, 5]));
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), None);
+}
+
+const REQ_FIRMWARE_WRITE: u8 = 0x20;
+
+struct FirmwareClass {
+    image: Vec<u8>,
+    accept: bool,
+    // Size of the flash, writing past it fails
+    capacity: usize,
+    control_out_calls: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for FirmwareClass {
+    fn control_out_chunk(&mut self, req: &Request, offset: usize, chunk: &[u8]) -> Result<bool> {
+        if req.request_type != RequestType::Vendor || req.request != REQ_FIRMWARE_WRITE {
+            return Ok(false);
+        }
+
+        assert_eq!(offset, self.image.len());
+
+        if offset + chunk.len() > self.capacity {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.image.extend_from_slice(chunk);
+
+        Ok(true)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_FIRMWARE_WRITE {
+            assert!(xfer.data().is_empty());
+            self.control_out_calls += 1;
+
+            if self.accept && self.image.len() == req.length as usize {
+                xfer.accept().ok();
+            } else {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+#[test]
+fn streamed_control_out() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = FirmwareClass {
+        image: Vec::new(),
+        accept: true,
+        capacity: 8192,
+        control_out_calls: 0,
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: REQ_FIRMWARE_WRITE,
+        value: 0,
+        index: 0,
+        length: 0,
+    };
+
+    // Much larger than the control buffer, and not a multiple of the packet size
+    let image: Vec<u8> = (0..4100u32).map(|i| (i * 7) as u8).collect();
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], req, &image).expect("control out");
+    assert_eq!(class.image, image);
+
+    // The class can still reject the transfer after receiving all of the data
+    class.image.clear();
+    class.accept = false;
+
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class], req, &image);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert_eq!(class.image, image);
+
+    // Without a class that handles it, a long data stage is stalled as well
+    let res = test_util::control_out(&mut usb_dev, &mut [], req, &image);
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    // Streaming is opt-in, so a request the class doesn't handle in control_out_chunk is stalled
+    // before control_out is called
+    class.image.clear();
+    class.control_out_calls = 0;
+
+    let other_req = Request { request: REQ_FIRMWARE_WRITE + 1, ..req };
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class], other_req, &image);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert!(class.image.is_empty());
+    assert_eq!(class.control_out_calls, 0);
+
+    // An error from the class stalls the transfer right away, and the rest of the data and the
+    // status stage never reach the class
+    class.image.clear();
+    class.accept = true;
+    class.capacity = 1000;
+    class.control_out_calls = 0;
+
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class], req, &image);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert_eq!(class.image, image[..1000]);
+    assert_eq!(class.control_out_calls, 0);
+
+    // The next transfer works normally
+    class.image.clear();
+    class.capacity = 8192;
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], req, &image).expect("control out");
+    assert_eq!(class.image, image);
+    assert_eq!(class.control_out_calls, 1);
+}
+
+const REQ_READ_PROGRESS: u8 = 0x21;
+
+struct ProgressClass {
+    offered: Option<(usize, usize)>,
+}
+
+impl<B: UsbBus> UsbClass<B> for ProgressClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_READ_PROGRESS {
+            self.offered = Some((xfer.requested_len(), xfer.sent_len()));
+            xfer.accept_with(&[0x55; 20]).ok();
+        }
+    }
+}
+
+#[test]
+fn control_in_progress() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = ProgressClass { offered: None };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .max_packet_size_0(8)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(usb_dev.control_in_sent_len(), None);
+
+    // The host asks for 40 bytes, but only 20 are sent in packets of 8, 8 and 4 bytes
+    usb_dev.bus().host_setup(0, [0xc0, REQ_READ_PROGRESS, 0, 0, 0, 0, 40, 0]);
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(class.offered, Some((40, 0)));
+
+    for &(sent, packet_len) in &[(8, 8), (16, 8), (20, 4)] {
+        assert_eq!(usb_dev.control_in_sent_len(), Some(sent));
+
+        let packet = usb_dev.bus().host_read(0).expect("read").expect("packet");
+        assert_eq!(packet.len(), packet_len);
+
+        usb_dev.poll(&mut [&mut class]);
+    }
+
+    // Waiting for the status stage
+    assert_eq!(usb_dev.control_in_sent_len(), None);
+
+    usb_dev.bus().host_write(0, &[]).expect("status");
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(usb_dev.control_in_sent_len(), None);
+    assert!(!usb_dev.bus().host_is_stalled(EndpointAddress::from(0x80)));
+}
+
+const REQ_READ_STREAM: u8 = 0x22;
+
+struct StreamingReportClass {
+    len: usize,
+    fail_at: Option<usize>,
+    offsets: Vec<usize>,
+}
+
+impl StreamingReportClass {
+    fn byte(offset: usize) -> u8 {
+        (offset * 3 + 1) as u8
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(Self::byte).collect()
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for StreamingReportClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == RequestType::Vendor && req.request == REQ_READ_STREAM {
+            self.offsets.clear();
+            xfer.accept_stream().expect("accept_stream");
+        }
+    }
+
+    fn control_in_chunk(&mut self, req: &Request, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        assert_eq!(req.request, REQ_READ_STREAM);
+
+        if self.fail_at.map_or(false, |fail_at| offset >= fail_at) {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.offsets.push(offset);
+
+        let count = buf.len().min(self.len.saturating_sub(offset));
+
+        for (i, b) in buf[..count].iter_mut().enumerate() {
+            *b = Self::byte(offset + i);
+        }
+
+        Ok(count)
+    }
+}
+
+#[test]
+fn streamed_control_in() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = StreamingReportClass { len: 0, fail_at: None, offsets: Vec::new() };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .max_packet_size_0(8)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let req = |length| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: REQ_READ_STREAM,
+        value: 0,
+        index: 0,
+        length,
+    };
+
+    // Longer than the control buffer, not a multiple of the packet size, exactly a multiple of the
+    // packet size (which needs a ZLP) and empty
+    for &len in &[1000, 301, 512, 0] {
+        class.len = len;
+
+        let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req(2000))
+            .expect("control in");
+
+        assert_eq!(data, StreamingReportClass::data(len), "length {}", len);
+        assert_eq!(class.offsets[0], 0);
+    }
+
+    // The data is truncated to wLength, and isn't requested past it
+    class.len = 1000;
+
+    let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req(300))
+        .expect("control in");
+
+    assert_eq!(data, StreamingReportClass::data(300));
+    assert!(class.offsets.iter().all(|&offset| offset < 300));
+
+    // An error from the class stalls the transfer
+    class.fail_at = Some(200);
+
+    let res = test_util::control_in(&mut usb_dev, &mut [&mut class], req(2000));
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    // The one-shot methods still work after a streamed transfer
+    let data = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class],
+        descriptor_type::DEVICE, 0, 0, 18).expect("device descriptor");
+    assert_eq!(data.len(), 18);
+}
+
+const REQ_READ_OVERLONG: u8 = 0x23;
+
+static OVERLONG_DATA: [u8; 300] = [0x5a; 300];
+
+struct OverlongClass;
+
+impl<B: UsbBus> UsbClass<B> for OverlongClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type != RequestType::Vendor || req.request != REQ_READ_OVERLONG {
+            return;
+        }
+
+        // Every method responds with more data than the host asked for, and more than fits in the
+        // control buffer
+        match req.value {
+            0 => xfer.accept_with(&OVERLONG_DATA).expect("accept_with"),
+            1 => xfer.accept_with_static(&OVERLONG_DATA).expect("accept_with_static"),
+            _ => xfer.accept(|buf| {
+                let len = buf.len().min(req.length as usize);
+                buf[..len].copy_from_slice(&OVERLONG_DATA[..len]);
+                Ok(len)
+            }).expect("accept"),
+        }
+    }
+}
+
+#[test]
+fn overlong_control_in_truncated() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = OverlongClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .max_packet_size_0(8)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    for method in 0..3 {
+        for &length in &[1, 20, 64] {
+            let req = Request {
+                direction: UsbDirection::In,
+                request_type: RequestType::Vendor,
+                recipient: Recipient::Device,
+                request: REQ_READ_OVERLONG,
+                value: method,
+                index: 0,
+                length,
+            };
+
+            let data = test_util::control_in(&mut usb_dev, &mut [&mut class], req)
+                .expect("control in");
+
+            assert_eq!(data, &OVERLONG_DATA[..length as usize], "method {}", method);
+        }
+
+        // The host gets exactly wLength bytes, so a full last packet isn't followed by a ZLP
+        usb_dev.bus().host_setup(0, [0xc0, REQ_READ_OVERLONG, method as u8, 0, 0, 0, 16, 0]);
+
+        let mut packets = Vec::new();
+
+        for _ in 0..8 {
+            usb_dev.poll(&mut [&mut class]);
+
+            if let Some(packet) = usb_dev.bus().host_read(0).expect("read") {
+                packets.push(packet.len());
+            }
+        }
+
+        assert_eq!(packets, [8, 8], "method {}", method);
+        assert_eq!(usb_dev.control_in_sent_len(), None, "method {}", method);
+
+        usb_dev.bus().host_write(0, &[]).expect("status");
+        usb_dev.poll(&mut [&mut class]);
+    }
+}
+
+struct VendorProtocolClass {
+    iface: InterfaceNumber,
+    interface_class: u8,
+}
+
+impl<B: UsbBus> UsbClass<B> for VendorProtocolClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, self.interface_class, 0xf0, 0x01)?;
+        writer.interface_alt(self.iface, 1, self.interface_class, 0xf0, 0x02, None)
+    }
+}
+
+#[test]
+fn vendor_interface_protocol() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = VendorProtocolClass { iface: usb_bus.interface(), interface_class: 0xff };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .vendor_specific(0xf0, 0x01)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let device = &result.device;
+    assert_eq!((device.device_class, device.device_sub_class, device.device_protocol),
+        (0xff, 0xf0, 0x01));
+
+    let codes: Vec<_> = result.configuration.interfaces.iter()
+        .map(|i| (i.alternate_setting, i.interface_class, i.interface_sub_class,
+            i.interface_protocol))
+        .collect();
+    assert_eq!(codes, [(0, 0xff, 0xf0, 0x01), (1, 0xff, 0xf0, 0x02)]);
+
+    // Class 0x00 is reserved for the device descriptor
+    class.interface_class = 0x00;
+
+    let res = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class],
+        descriptor_type::CONFIGURATION, 0, 0, 0xff);
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+#[test]
+fn frame_number_wraps() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    let start = usb_dev.frame_number().expect("frame number");
+    usb_dev.bus().host_sof();
+    assert_eq!(usb_dev.frame_number().expect("frame number"), (start + 1) & 0x7ff);
+
+    // The 11-bit counter wraps from 2047 to 0
+    while usb_dev.frame_number().expect("frame number") != 2047 {
+        usb_dev.bus().host_sof();
+    }
+
+    usb_dev.bus().host_sof();
+    assert_eq!(usb_dev.frame_number().expect("frame number"), 0);
+
+    // Buses that don't provide the frame number report it as unsupported
+    let usb_bus = UsbBusAllocator::new(LatchingBus(EmulatedUsbBus::new()));
+    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    assert!(matches!(usb_dev.frame_number(), Err(UsbError::Unsupported)));
+}
+
+#[derive(Default)]
+struct StateTrackingClass {
+    transitions: Vec<(UsbDeviceState, UsbDeviceState)>,
+}
+
+impl<B: UsbBus> UsbClass<B> for StateTrackingClass {
+    fn device_state_changed(&mut self, old: UsbDeviceState, new: UsbDeviceState) {
+        self.transitions.push((old, new));
+    }
+}
+
+#[test]
+fn device_state_transitions() {
+    use UsbDeviceState::*;
+
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = StateTrackingClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    // The initial Default state is reported on the first reset
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(class.transitions, [
+        (Default, Default),
+        (Default, Addressed),
+        (Addressed, Configured),
+    ]);
+
+    // Both halves of a suspend are reported in order
+    class.transitions.clear();
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.transitions, [(Configured, Suspend), (Suspend, Configured)]);
+
+    // Configuring again doesn't change the state
+    class.transitions.clear();
+    test_util::control_out(&mut usb_dev, &mut [&mut class], Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_CONFIGURATION,
+        value: 1,
+        index: 0,
+        length: 0,
+    }, &[]).expect("set configuration");
+    assert!(class.transitions.is_empty());
+
+    // A reset while suspended goes straight to the Default state
+    usb_dev.bus().host_suspend();
+    usb_dev.poll(&mut [&mut class]);
+    usb_dev.bus().host_reset();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.transitions, [
+        (Configured, Suspend),
+        (Suspend, Default),
+    ]);
+}
+
+// Only allows isochronous endpoints on endpoint numbers 3 and up
+struct HighIsochronousStrategy;
+
+impl EndpointAllocationStrategy for HighIsochronousStrategy {
+    fn endpoint_number(&self, ep_dir: UsbDirection, ep_type: EndpointType, allocated: u16)
+        -> Option<usize>
+    {
+        match ep_type {
+            EndpointType::Isochronous => (3..16).find(|&i| (allocated & (1 << i)) == 0),
+            _ => LowestAvailable.endpoint_number(ep_dir, ep_type, allocated),
+        }
+    }
+}
+
+#[test]
+fn custom_allocation_strategy() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    usb_bus.set_allocation_strategy(&HighIsochronousStrategy);
+
+    let bulk: EndpointIn<_> = usb_bus.bulk(64);
+    let iso1: EndpointIn<_> = usb_bus.alloc(None, EndpointType::Isochronous, 64, 1)
+        .expect("alloc");
+    let interrupt: EndpointIn<_> = usb_bus.interrupt(8, 10);
+    let iso2: EndpointIn<_> = usb_bus.alloc(None, EndpointType::Isochronous, 64, 1)
+        .expect("alloc");
+    let iso_out: EndpointOut<_> = usb_bus.alloc(None, EndpointType::Isochronous, 64, 1)
+        .expect("alloc");
+    let bulk_out: EndpointOut<_> = usb_bus.bulk(64);
+
+    // A specific address bypasses the strategy
+    let fixed: EndpointIn<_> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x89)), EndpointType::Isochronous, 64, 1).expect("alloc");
+
+    let addrs: Vec<u8> = [&bulk, &iso1, &interrupt, &iso2, &fixed].iter()
+        .map(|ep| ep.address().into())
+        .collect();
+    assert_eq!(addrs, [0x81, 0x83, 0x82, 0x84, 0x89]);
+    assert_eq!(u8::from(iso_out.address()), 0x03);
+    assert_eq!(u8::from(bulk_out.address()), 0x01);
+
+    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+    assert_eq!(usb_dev.bus().allocated_endpoints(),
+        [0x01, 0x03, 0x81, 0x82, 0x83, 0x84, 0x89]);
+}
+
+// Records the addresses requested from the bus
+struct RecordingBus {
+    bus: EmulatedUsbBus,
+    requested: Vec<Option<EndpointAddress>>,
+}
+
+impl UsbBus for RecordingBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8) -> Result<EndpointAddress>
+    {
+        self.requested.push(ep_addr);
+        self.bus.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+    }
+
+    fn enable(&mut self) { self.bus.enable() }
+    fn reset(&self) { self.bus.reset() }
+    fn set_device_address(&self, addr: u8) { self.bus.set_device_address(addr) }
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.bus.write(ep_addr, buf)
+    }
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.bus.read(ep_addr, buf)
+    }
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.bus.set_stalled(ep_addr, stalled)
+    }
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool { self.bus.is_stalled(ep_addr) }
+    fn suspend(&self) { self.bus.suspend() }
+    fn resume(&self) { self.bus.resume() }
+    fn poll(&self) -> PollResult { self.bus.poll() }
+}
+
+#[test]
+fn default_allocation_strategy() {
+    let usb_bus = UsbBusAllocator::new(RecordingBus {
+        bus: EmulatedUsbBus::new(),
+        requested: Vec::new(),
+    });
+
+    // By default the lowest free number is requested from the bus
+    let _bulk: EndpointIn<_> = usb_bus.bulk(64);
+    let _interrupt: EndpointOut<_> = usb_bus.interrupt(8, 10);
+    let _fixed: EndpointIn<_> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x85)), EndpointType::Bulk, 64, 0).expect("alloc");
+    let _lowest: EndpointIn<_> = usb_bus.bulk(64);
+
+    // Leaving the choice to the bus is opt-in
+    usb_bus.set_allocation_strategy(&BusChooses);
+    let _chosen: EndpointIn<_> = usb_bus.bulk(64);
+
+    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+    let requested: Vec<Option<u8>> = usb_dev.bus().requested.iter()
+        .map(|addr| addr.map(u8::from))
+        .collect();
+
+    // The last two are the control endpoints allocated when the device is built
+    assert_eq!(requested, [
+        Some(0x81), Some(0x01), Some(0x85), Some(0x82), None, Some(0x00), Some(0x80),
+    ]);
+}
+
+const WEBUSB_VENDOR_CODE: u8 = 0x30;
+
+struct WebUsbClass {
+    url: String,
+}
+
+impl<B: UsbBus> UsbClass<B> for WebUsbClass {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        descriptor::webusb::capability(writer, WEBUSB_VENDOR_CODE, 1)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        match descriptor::webusb::url_request(xfer.request(), WEBUSB_VENDOR_CODE) {
+            Some(1) => {
+                let url = &self.url;
+                xfer.accept(|buf| {
+                    descriptor::webusb::url_descriptor(descriptor::webusb::scheme::HTTPS, url, buf)
+                }).ok();
+            },
+            Some(_) => { xfer.reject().ok(); },
+            None => { },
+        }
+    }
+}
+
+#[test]
+fn webusb_landing_page() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = WebUsbClass { url: String::from("example.com/app") };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let caps = result.bos.expect("bos").capabilities;
+    assert_eq!(caps[1].capability_type, capability_type::PLATFORM);
+    assert_eq!(caps[1].data[1..17], descriptor::webusb::PLATFORM_CAPABILITY_UUID);
+    assert_eq!(caps[1].data[17..], [0x00, 0x01, WEBUSB_VENDOR_CODE, 1]);
+
+    let get_url = |index| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: WEBUSB_VENDOR_CODE,
+        value: index,
+        index: descriptor::webusb::GET_URL,
+        length: 0xff,
+    };
+
+    let url = test_util::control_in(&mut usb_dev, &mut [&mut class], get_url(1))
+        .expect("get url");
+    assert_eq!(url[..3], [18, descriptor::webusb::URL_DESCRIPTOR_TYPE, 1]);
+    assert_eq!(&url[3..], b"example.com/app");
+
+    let res = test_util::control_in(&mut usb_dev, &mut [&mut class], get_url(2));
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    // A URL too long for a descriptor is rejected instead of being sent truncated
+    class.url = "x".repeat(300);
+    let res = test_util::control_in(&mut usb_dev, &mut [&mut class], get_url(1));
+    assert!(matches!(res, Err(HostError::Stall)));
+}
+
+struct DualSpeedClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    bulk: EndpointIn<'a, B>,
+    interrupt: EndpointOut<'a, B>,
+    other_speed: Cell<Option<UsbSpeed>>,
+}
+
+impl<B: UsbBus> UsbClass<B> for DualSpeedClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        self.other_speed.set(writer.other_speed());
+
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.bulk)?;
+        writer.endpoint(&self.interrupt)?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn device_qualifier_and_other_speed() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = DualSpeedClass {
+        iface: usb_bus.interface(),
+        bulk: usb_bus.bulk(512),
+        interrupt: usb_bus.interrupt(1024, 4),
+        other_speed: Cell::new(None),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .high_speed_capable(true)
+        .device_class(0xef)
+        .build();
+
+    usb_dev.bus().set_speed(UsbSpeed::High);
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(class.other_speed.get(), None);
+
+    let packet_sizes = |config: &test_util::ConfigurationDescriptor| -> Vec<u16> {
+        config.interfaces[0].endpoints.iter().map(|ep| ep.max_packet_size).collect()
+    };
+    assert_eq!(packet_sizes(&result.configuration), [512, 1024]);
+
+    let qualifier = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::DEVICE_QUALIFIER, 0, 0, 0xff)
+        .expect("device qualifier");
+    assert_eq!(qualifier,
+        [10, descriptor_type::DEVICE_QUALIFIER, 0x00, 0x02, 0xef, 0x00, 0x00, 8, 1, 0]);
+
+    // The other speed configuration describes the device at full speed
+    let mut other = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::OTHER_SPEED_CONFIGURATION, 0, 0, 0xff)
+        .expect("other speed configuration");
+    assert_eq!(other[1], descriptor_type::OTHER_SPEED_CONFIGURATION);
+    assert_eq!(class.other_speed.get(), Some(UsbSpeed::Full));
+
+    other[1] = descriptor_type::CONFIGURATION;
+    let other = test_util::parse_configuration_descriptor(&other).expect("parse");
+    assert_eq!(other.total_length, result.configuration.total_length);
+    assert_eq!(packet_sizes(&other), [64, 64]);
+
+    // From full speed, the other speed is high speed, where endpoint 0 uses 64 byte packets
+    usb_dev.bus().set_speed(UsbSpeed::Full);
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let qualifier = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::DEVICE_QUALIFIER, 0, 0, 0xff)
+        .expect("device qualifier");
+    assert_eq!(qualifier[7], 64);
+
+    test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::OTHER_SPEED_CONFIGURATION, 0, 0, 0xff)
+        .expect("other speed configuration");
+    assert_eq!(class.other_speed.get(), Some(UsbSpeed::High));
+
+    // Full speed only devices stall both
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    for &dtype in &[descriptor_type::DEVICE_QUALIFIER, descriptor_type::OTHER_SPEED_CONFIGURATION] {
+        let res = test_util::get_descriptor(&mut usb_dev, &mut [], dtype, 0, 0, 0xff);
+        assert!(matches!(res, Err(HostError::Stall)));
+    }
+}
+
+#[derive(Default)]
+struct DescriptorStoreClass {
+    stored: Vec<(u8, u8, u16, Vec<u8>)>,
+}
+
+impl<B: UsbBus> UsbClass<B> for DescriptorStoreClass {
+    fn set_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16, data: &[u8])
+        -> Result<()>
+    {
+        if descriptor_type != descriptor_type::STRING {
+            return Err(UsbError::Unsupported);
+        }
+
+        self.stored.push((descriptor_type, index, lang_id, data.to_vec()));
+        Ok(())
+    }
+}
+
+#[test]
+fn set_descriptor_dispatch() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut other = DescriptorStoreClass::default();
+    let mut class = DescriptorStoreClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let set_descriptor = |descriptor_type: u8, index: u8, lang_id| Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_DESCRIPTOR,
+        value: ((descriptor_type as u16) << 8) | index as u16,
+        index: lang_id,
+        length: 0,
+    };
+
+    let string = [6, descriptor_type::STRING, b'h', 0, b'i', 0];
+
+    // Not allowed before the device has an address
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class],
+        set_descriptor(descriptor_type::STRING, 4, 0x0409), &string);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert!(class.stored.is_empty());
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class, &mut other],
+        set_descriptor(descriptor_type::STRING, 4, 0x0409), &string).expect("set descriptor");
+    assert_eq!(class.stored, [(descriptor_type::STRING, 4, 0x0409, string.to_vec())]);
+
+    // The first class that accepts it wins
+    assert!(other.stored.is_empty());
+
+    // Stalled if no class accepts it
+    let res = test_util::control_out(&mut usb_dev, &mut [&mut class],
+        set_descriptor(descriptor_type::DEVICE, 0, 0), &[18, descriptor_type::DEVICE]);
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    let res = test_util::control_out(&mut usb_dev, &mut [],
+        set_descriptor(descriptor_type::STRING, 4, 0x0409), &string);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert_eq!(class.stored.len(), 1);
+}
+
+// Only part of the second configuration, records the configurations selected by the host
+struct SecondConfigClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    changes: Vec<u8>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SecondConfigClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        if writer.configuration_value() == 2 {
+            writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+            writer.endpoint(&self.ep_in)?;
+        }
+
+        Ok(())
+    }
+
+    fn configuration_changed(&mut self, value: u8) {
+        self.changes.push(value);
+    }
+}
+
+#[test]
+fn multiple_configurations() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SecondConfigClass {
+        iface: usb_bus.interface(),
+        ep_in: usb_bus.bulk(64),
+        changes: Vec::new(),
+    };
+    let ep_addr = class.ep_in.address();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .num_configurations(2)
+        .build();
+
+    let set_configuration = |value| Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_CONFIGURATION,
+        value,
+        index: 0,
+        length: 0,
+    };
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(result.device.num_configurations, 2);
+    assert_eq!(result.configuration.configuration_value, 1);
+    assert!(result.configuration.interfaces.is_empty());
+    assert_eq!(usb_dev.configuration(), Some(1));
+
+    let data = test_util::get_descriptor(
+        &mut usb_dev, &mut [&mut class], descriptor_type::CONFIGURATION, 1, 0, 255)
+        .expect("get_descriptor");
+    let config = test_util::parse_configuration_descriptor(&data).expect("parse");
+    assert_eq!(config.configuration_value, 2);
+    assert_eq!(config.num_interfaces, 1);
+    assert_eq!(config.interfaces[0].endpoints[0].address, u8::from(ep_addr));
+
+    assert_eq!(
+        test_util::get_descriptor(
+            &mut usb_dev, &mut [&mut class], descriptor_type::CONFIGURATION, 2, 0, 255),
+        Err(HostError::Stall));
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], set_configuration(2), &[])
+        .expect("set_configuration");
+    assert_eq!(usb_dev.configuration(), Some(2));
+    assert_eq!(
+        test_util::control_in(&mut usb_dev, &mut [&mut class], Request {
+            direction: UsbDirection::In,
+            request: Request::GET_CONFIGURATION,
+            length: 1,
+            ..set_configuration(0)
+        }),
+        Ok(vec![2]));
+
+    assert_eq!(
+        test_util::control_out(&mut usb_dev, &mut [&mut class], set_configuration(3), &[]),
+        Err(HostError::Stall));
+
+    // Deconfiguring stalls the endpoints until a configuration is selected again
+    test_util::control_out(&mut usb_dev, &mut [&mut class], set_configuration(0), &[])
+        .expect("deconfigure");
+    assert_eq!(usb_dev.state(), UsbDeviceState::Addressed);
+    assert_eq!(usb_dev.configuration(), None);
+    assert!(usb_dev.bus().host_is_stalled(ep_addr));
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], set_configuration(1), &[])
+        .expect("set_configuration");
+    assert!(!usb_dev.bus().host_is_stalled(ep_addr));
+
+    assert_eq!(class.changes, [1, 2, 0, 1]);
+}
+
+// Forwards only the endpoint events for the endpoints of the wrapped class
+struct EndpointFilter<C> {
+    inner: C,
+    endpoints: EndpointSet,
+}
+
+impl<B: UsbBus, C: UsbClass<B>> UsbClass<B> for EndpointFilter<C> {
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if self.endpoints.contains(addr) {
+            self.inner.endpoint_out(addr);
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if self.endpoints.contains(addr) {
+            self.inner.endpoint_in_complete(addr);
+        }
+    }
+}
+
+#[derive(Default)]
+struct EndpointEventClass {
+    events: Vec<EndpointAddress>,
+}
+
+impl<B: UsbBus> UsbClass<B> for EndpointEventClass {
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        self.events.push(addr);
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        self.events.push(addr);
+    }
+}
+
+#[test]
+fn endpoint_set_from_checkpoint() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let other_out: EndpointOut<_> = usb_bus.bulk(64);
+
+    let checkpoint = usb_bus.checkpoint();
+    let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+    let ep_in: EndpointIn<_> = usb_bus.interrupt(8, 10);
+    let endpoints = usb_bus.endpoints_since(checkpoint);
+
+    assert!(endpoints.contains(ep_out.address()));
+    assert!(endpoints.contains(ep_in.address()));
+    assert!(!endpoints.contains(other_out.address()));
+    assert_eq!(endpoints.iter().collect::<Vec<_>>(), [ep_out.address(), ep_in.address()]);
+
+    let mut manual = EndpointSet::new();
+    assert!(manual.is_empty());
+    manual.insert(ep_in.address());
+    manual.insert(ep_out.address());
+    assert_eq!(manual, endpoints);
+
+    let mut class = EndpointFilter { inner: EndpointEventClass::default(), endpoints };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    usb_dev.bus().host_write(other_out.address().index(), &[1]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    other_out.read(&mut [0; 64]).expect("read");
+
+    usb_dev.bus().host_write(ep_out.address().index(), &[2]).expect("write");
+    usb_dev.poll(&mut [&mut class]);
+    ep_out.read(&mut [0; 64]).expect("read");
+
+    ep_in.write(&[3]).expect("write");
+    usb_dev.bus().host_read(ep_in.address().index()).expect("read");
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(class.inner.events, [ep_out.address(), ep_in.address()]);
+}
+
+struct TerminatedWriteClass<'a, B: UsbBus> {
+    ep: EndpointIn<'a, B>,
+    completed: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for TerminatedWriteClass<'_, B> {
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep.address() {
+            assert!(!self.ep.is_zlp_pending());
+            self.completed += 1;
+        }
+    }
+}
+
+#[test]
+fn terminated_write_appends_zlp() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TerminatedWriteClass { ep: usb_bus.bulk(16), completed: 0 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let index = class.ep.address().index();
+
+    // A short last packet ends the transfer by itself
+    class.ep.write(&[1; 16]).expect("write");
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![1; 16]));
+    usb_dev.poll(&mut [&mut class]);
+    class.ep.write_packet_terminated(&[2; 4]).expect("write");
+    assert!(!class.ep.is_zlp_pending());
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![2; 4]));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.completed, 2);
+
+    // A full last packet is followed by a zero-length packet, which the device writes by itself
+    class.completed = 0;
+    class.ep.write_packet_terminated(&[3; 16]).expect("write");
+    assert!(class.ep.is_zlp_pending());
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![3; 16]));
+    usb_dev.poll(&mut [&mut class]);
+    assert!(!class.ep.is_zlp_pending());
+    assert_eq!(class.completed, 0);
+
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![]));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.completed, 1);
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), None);
+}
+
+#[test]
+fn terminated_write_uses_current_packet_size() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = TerminatedWriteClass { ep: usb_bus.bulk(64), completed: 0 };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let index = class.ep.address().index();
+
+    // A full packet at the reduced size is followed by a zero-length packet
+    class.ep.set_max_packet_size(32).expect("packet size");
+    class.ep.write_packet_terminated(&[1; 32]).expect("write");
+    assert!(class.ep.is_zlp_pending());
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![1; 32]));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![]));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.completed, 1);
+
+    // A bus reset drops the zero-length packet of an unfinished transfer
+    class.ep.write_packet_terminated(&[2; 32]).expect("write");
+    assert!(class.ep.is_zlp_pending());
+    test_util::bus_reset(&mut usb_dev, &mut [&mut class]);
+    assert!(!class.ep.is_zlp_pending());
+}
+
+#[test]
+fn usb_error_display() {
+    assert_eq!(UsbError::BufferOverflow.to_string(),
+        "data does not fit in the buffer or length limit");
+
+    #[cfg(feature = "core-error")]
+    {
+        let err: Box<dyn std::error::Error> = Box::new(UsbError::Unsupported);
+        assert_eq!(err.to_string(), "operation not supported");
+    }
+}
+
+#[derive(Default)]
+struct LpmClass {
+    besl: Option<u8>,
+    states: Vec<UsbDeviceState>,
+}
+
+impl<B: UsbBus> UsbClass<B> for LpmClass {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        writer.lpm(Some(0x04), None)
+    }
+
+    fn device_state_changed(&mut self, _old: UsbDeviceState, new: UsbDeviceState) {
+        self.states.push(new);
+    }
+
+    fn lpm_suspend(&mut self, besl: u8) {
+        self.besl = Some(besl);
+    }
+}
+
+#[test]
+fn lpm_sleep() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = LpmClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    let bos = result.bos.expect("bos");
+    assert_eq!(bos.capabilities[0].capability_type, capability_type::USB_2_0_EXTENSION);
+    assert_eq!(bos.capabilities[0].data, [0x0e, 0x04, 0x00, 0x00]);
+
+    // Not acknowledged until enabled
+    assert!(!usb_dev.bus().host_lpm_sleep(0x04));
+
+    usb_dev.set_lpm_enabled(true).expect("enable lpm");
+    class.states.clear();
+
+    assert!(usb_dev.bus().host_lpm_sleep(0x02));
+    assert!(usb_dev.poll_events(&mut [&mut class]).suspend);
+    assert_eq!(class.besl, Some(0x02));
+    assert_eq!(usb_dev.state(), UsbDeviceState::Suspend);
+    assert!(usb_dev.bus().is_suspended());
+
+    usb_dev.bus().host_resume();
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Configured);
+    assert_eq!(class.states, [UsbDeviceState::Suspend, UsbDeviceState::Configured]);
+}
+
+#[test]
+fn lpm_sleep_default_besl() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = LpmClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    usb_dev.set_lpm_enabled(true).expect("enable lpm");
+
+    // A bus that can't read the BESL field reports the shortest latency
+    usb_dev.bus().set_besl_supported(false);
+    assert!(usb_dev.bus().host_lpm_sleep(0x09));
+    usb_dev.poll(&mut [&mut class]);
+    assert_eq!(class.besl, Some(DEFAULT_BESL));
+    assert_eq!(DEFAULT_BESL, 0);
+}
+
+#[derive(Default)]
+struct BufferSizeClass {
+    buffer_len: usize,
+    out_data: Option<Result<Vec<u8>>>,
+    stream: bool,
+}
+
+impl<B: UsbBus> UsbClass<B> for BufferSizeClass {
+    fn control_out_chunk(&mut self, req: &Request, _offset: usize, _chunk: &[u8]) -> Result<bool> {
+        Ok(self.stream && req.is_vendor(Recipient::Device, 0x02))
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if xfer.request().is_vendor(Recipient::Device, 0x01) {
+            self.buffer_len = xfer.buffer_len();
+            let len = xfer.request().value as usize;
+            xfer.accept_with(&vec![0x55; len]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if xfer.request().is_vendor(Recipient::Device, 0x02) {
+            self.out_data = Some(xfer.try_data().map(|data| data.to_vec()));
+            xfer.accept().ok();
+        }
+    }
+}
+
+#[test]
+fn configured_control_buffer_size() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = BufferSizeClass::default();
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .control_buffer_size(64)
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let vendor_request = |request: u8, value: u16| Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value,
+        index: 0,
+        length: 255,
+    };
+
+    let data = test_util::control_in(&mut usb_dev, &mut [&mut class], vendor_request(0x01, 64))
+        .expect("control in");
+    assert_eq!(data.len(), 64);
+    assert_eq!(class.buffer_len, 64);
+
+    let res = test_util::control_in(&mut usb_dev, &mut [&mut class], vendor_request(0x01, 65));
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], vendor_request(0x02, 0), &[1; 64])
+        .expect("control out");
+    assert!(matches!(&class.out_data, Some(Ok(data)) if *data == [1; 64]));
+
+    // Longer data stages are stalled unless the class streams them
+    class.out_data = None;
+    let res = test_util::control_out(
+        &mut usb_dev, &mut [&mut class], vendor_request(0x02, 0), &[1; 65]);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert!(class.out_data.is_none());
+
+    // When streamed, the whole data isn't available in control_out
+    class.stream = true;
+    test_util::control_out(&mut usb_dev, &mut [&mut class], vendor_request(0x02, 0), &[1; 65])
+        .expect("control out");
+    assert!(matches!(class.out_data, Some(Err(UsbError::BufferOverflow))));
+}
+
+struct NamedInterfaceClass {
+    iface: InterfaceNumber,
+    name: StringIndex,
+    unreferenced: StringIndex,
+}
+
+impl<B: UsbBus> UsbClass<B> for NamedInterfaceClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.iface, 0, 0xff, 0, 0, Some(self.name))
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        if lang_id != 0x0409 {
+            None
+        } else if index == self.name {
+            Some("Interface")
+        } else if index == self.unreferenced {
+            Some("Unreferenced")
+        } else {
+            None
+        }
+    }
+}
+
+// Claims every string index
+struct GreedyStringClass;
+
+impl<B: UsbBus> UsbClass<B> for GreedyStringClass {
+    fn get_string(&self, _index: StringIndex, _lang_id: u16) -> Option<&str> {
+        Some("Greedy")
+    }
+}
+
+#[test]
+fn strings_routed_to_owning_class() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let name = usb_bus.string();
+    let unreferenced = usb_bus.string();
+    let mut class = NamedInterfaceClass { iface: usb_bus.interface(), name, unreferenced };
+    let mut greedy = GreedyStringClass;
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .supported_languages(&[0x0409, 0x040c])
+        .build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut greedy, &mut class]).expect("enumerate");
+
+    let get_string = |usb_dev: &mut UsbDevice<_>,
+        classes: &mut [&mut dyn UsbClass<EmulatedUsbBus>], index: StringIndex, lang_id|
+    {
+        test_util::get_descriptor(usb_dev, classes, descriptor_type::STRING, index.into(), lang_id,
+            255).map(|data| test_util::parse_string_descriptor(&data).expect("string"))
+    };
+
+    // The class that references the string answers even though another class comes first
+    let res = get_string(&mut usb_dev, &mut [&mut greedy, &mut class], name, 0x0409);
+    assert_eq!(res.as_deref(), Ok("Interface"));
+
+    // The owner isn't overridden by other classes for languages it doesn't support
+    let res = get_string(&mut usb_dev, &mut [&mut greedy, &mut class], name, 0x040c);
+    assert!(matches!(res, Err(HostError::Stall)));
+
+    // Strings that no descriptor references are still passed to all classes in turn
+    let res = get_string(&mut usb_dev, &mut [&mut greedy, &mut class], unreferenced, 0x0409);
+    assert_eq!(res.as_deref(), Ok("Greedy"));
+
+    let res = get_string(&mut usb_dev, &mut [&mut class], unreferenced, 0x0409);
+    assert_eq!(res.as_deref(), Ok("Unreferenced"));
+}
+
+struct ModeSwitchClass {
+    switched: bool,
+}
+
+impl<B: UsbBus> UsbClass<B> for ModeSwitchClass {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.is_vendor(Recipient::Device, 0x42) {
+            self.switched = true;
+            xfer.accept_and_reenumerate().ok();
+        }
+    }
+}
+
+#[test]
+fn reenumerate_after_status_stage() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = ModeSwitchClass { switched: false };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert!(!usb_dev.bus().host_take_disconnect());
+
+    let switch = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: 0x42,
+        value: 0,
+        index: 0,
+        length: 0,
+    };
+
+    // The device stays connected until the host has read the status stage
+    usb_dev.bus().host_setup(0, [0x40, 0x42, 0, 0, 0, 0, 0, 0]);
+    usb_dev.poll(&mut [&mut class]);
+    assert!(class.switched);
+    assert!(!usb_dev.bus().host_take_disconnect());
+
+    assert_eq!(usb_dev.bus().host_read(0).expect("status"), Some(Vec::new()));
+    usb_dev.poll(&mut [&mut class]);
+    assert!(usb_dev.bus().host_take_disconnect());
+
+    // Aborting the transfer with a new SETUP packet cancels the disconnect
+    usb_dev.bus().host_setup(0, [0x40, 0x42, 0, 0, 0, 0, 0, 0]);
+    usb_dev.poll(&mut [&mut class]);
+    let res = test_util::control_out(&mut usb_dev, &mut [], Request { request: 0x43, ..switch }, &[]);
+    assert!(matches!(res, Err(HostError::Stall)));
+    assert!(!usb_dev.bus().host_take_disconnect());
+
+    test_util::control_out(&mut usb_dev, &mut [&mut class], switch, &[]).expect("switch");
+    assert!(usb_dev.bus().host_take_disconnect());
+
+    // Disconnects immediately when no transfer is in progress
+    usb_dev.force_reenumerate().expect("force_reenumerate");
+    assert!(usb_dev.bus().host_take_disconnect());
+}
+
+#[test]
+fn negotiated_speed() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep: EndpointIn<_> = usb_bus.bulk(512);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(usb_dev.speed(), UsbSpeed::Full);
+
+    // The endpoint is too large for full speed
+    let limit = ep.speed().max_packet_size(EndpointType::Bulk);
+    assert_eq!(limit, 64);
+    assert!(ep.max_packet_size() > limit);
+
+    usb_dev.bus().set_speed(UsbSpeed::High);
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(usb_dev.speed(), UsbSpeed::High);
+    assert_eq!(ep.speed().max_packet_size(EndpointType::Bulk), 512);
+
+    let sizes: Vec<u16> = [
+        EndpointType::Control, EndpointType::Isochronous, EndpointType::Bulk,
+        EndpointType::Interrupt,
+    ].iter()
+        .map(|&ep_type| UsbSpeed::Low.max_packet_size(ep_type))
+        .collect();
+    assert_eq!(sizes, [8, 0, 0, 8]);
+    assert_eq!(UsbSpeed::Full.max_packet_size(EndpointType::Isochronous), 1023);
+    assert_eq!(UsbSpeed::High.max_packet_size(EndpointType::Interrupt), 1024);
+    assert_eq!(UsbSpeed::Super.max_packet_size(EndpointType::Control), 512);
+}
+
+#[test]
+fn double_buffered_endpoint() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let single: EndpointIn<_> = usb_bus.bulk(64);
+    let double: EndpointIn<_> = usb_bus.bulk(64);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    usb_dev.bus().set_double_buffered(double.address(), true);
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    assert!(!single.is_double_buffered());
+    assert!(double.is_double_buffered());
+
+    // A single buffered endpoint takes one packet at a time
+    single.write(&[1]).expect("write");
+    assert!(matches!(single.write(&[2]), Err(UsbError::WouldBlock)));
+
+    // A double buffered one takes the next packet while the first is still in flight
+    double.write(&[1]).expect("write");
+    double.write(&[2]).expect("write");
+    assert!(matches!(double.write(&[3]), Err(UsbError::WouldBlock)));
+
+    let index = double.address().index();
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![1]));
+    double.write(&[3]).expect("write");
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![2]));
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), Some(vec![3]));
+    assert_eq!(usb_dev.bus().host_read(index).expect("read"), None);
+
+    // Both packets completing before the next poll are reported as one event
+    let events = usb_dev.poll_events(&mut []);
+    assert_eq!(events, PollEvents { endpoint_in_complete: true, ..PollEvents::default() });
+    assert!(usb_dev.poll_events(&mut []).is_idle());
+}
+
+#[test]
+fn poll_events_summary() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+    let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+
+    let events = usb_dev.poll_events(&mut []);
+    assert!(events.is_idle());
+    assert!(!events.has_data());
+
+    usb_dev.bus().host_write(ep_out.address().index(), &[1, 2, 3]).expect("write");
+    let events = usb_dev.poll_events(&mut []);
+    assert_eq!(events, PollEvents { endpoint_out: true, ..PollEvents::default() });
+    assert!(events.has_data());
+    ep_out.read(&mut [0u8; 64]).expect("read");
+
+    ep_in.write(&[4, 5]).expect("write");
+    usb_dev.bus().host_read(ep_in.address().index()).expect("read");
+    let events = usb_dev.poll_events(&mut []);
+    assert_eq!(events, PollEvents { endpoint_in_complete: true, ..PollEvents::default() });
+
+    // GET_DESCRIPTOR(DEVICE)
+    usb_dev.bus().host_setup(0, [0x80, 0x06, 0x00, 0x01, 0, 0, 18, 0]);
+    let events = usb_dev.poll_events(&mut []);
+    assert_eq!(events, PollEvents { control: true, ..PollEvents::default() });
+
+    usb_dev.bus().host_suspend();
+    assert_eq!(usb_dev.poll_events(&mut []), PollEvents { suspend: true, ..PollEvents::default() });
+    assert!(usb_dev.poll_events(&mut []).is_idle());
+
+    usb_dev.bus().host_resume();
+    assert_eq!(usb_dev.poll_events(&mut []), PollEvents { resume: true, ..PollEvents::default() });
+
+    usb_dev.bus().host_reset();
+    let events = usb_dev.poll_events(&mut []);
+    assert_eq!(events, PollEvents { reset: true, ..PollEvents::default() });
+    assert!(!events.has_data());
+}
+
+#[test]
+fn reserved_endpoint_addresses() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+
+    usb_bus.reserve_endpoint(EndpointAddress::from(0x81)).expect("reserve");
+    usb_bus.reserve_endpoint(EndpointAddress::from(0x02)).expect("reserve");
+
+    assert!(matches!(usb_bus.reserve_endpoint(EndpointAddress::from(0x81)),
+        Err(UsbError::InvalidEndpoint)));
+    assert!(matches!(usb_bus.reserve_endpoint(EndpointAddress::from(0x80)),
+        Err(UsbError::InvalidEndpoint)));
+
+    // Endpoints without a specific address skip the reserved ones
+    let auto_in: EndpointIn<_> = usb_bus.bulk(64);
+    let auto_out1: EndpointOut<_> = usb_bus.bulk(64);
+    let auto_out2: EndpointOut<_> = usb_bus.bulk(64);
+
+    assert_eq!(u8::from(auto_in.address()), 0x82);
+    assert_eq!(u8::from(auto_out1.address()), 0x01);
+    assert_eq!(u8::from(auto_out2.address()), 0x03);
+
+    // A class created later can still get the reserved addresses
+    let fixed_in: EndpointIn<_> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x81)), EndpointType::Interrupt, 8, 1).expect("alloc");
+    let fixed_out: EndpointOut<_> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x02)), EndpointType::Bulk, 64, 0).expect("alloc");
+
+    assert_eq!(u8::from(fixed_in.address()), 0x81);
+    assert_eq!(u8::from(fixed_out.address()), 0x02);
+
+    // But not twice, or with the wrong direction
+    let res: Result<EndpointIn<_>> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x81)), EndpointType::Interrupt, 8, 1);
+    assert!(matches!(res, Err(UsbError::InvalidEndpoint)));
+
+    let res: Result<EndpointOut<_>> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x85)), EndpointType::Bulk, 64, 0);
+    assert!(matches!(res, Err(UsbError::InvalidEndpoint)));
+
+    assert!(matches!(usb_bus.reserve_endpoint(EndpointAddress::from(0x02)),
+        Err(UsbError::InvalidEndpoint)));
+
+    // Reservations also apply when the number is otherwise left to the bus
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    usb_bus.set_allocation_strategy(&BusChooses);
+    usb_bus.reserve_endpoint(EndpointAddress::from(0x81)).expect("reserve");
+
+    let auto_in: EndpointIn<_> = usb_bus.bulk(64);
+    let fixed_in: EndpointIn<_> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x81)), EndpointType::Bulk, 64, 0).expect("alloc");
+
+    assert_eq!(u8::from(auto_in.address()), 0x82);
+    assert_eq!(u8::from(fixed_in.address()), 0x81);
+
+    // An address that is already allocated is refused
+    let res: Result<EndpointIn<_>> = usb_bus.alloc(
+        Some(EndpointAddress::from(0x82)), EndpointType::Bulk, 64, 0);
+    assert!(matches!(res, Err(UsbError::InvalidEndpoint)));
+}
+
+#[test]
+fn self_powered_status() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678))
+        .self_powered(true)
+        .supports_remote_wakeup(true)
+        .max_power(500)
+        .build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(result.configuration.attributes, 0x80 | 0x40 | 0x20);
+    assert_eq!(result.configuration.max_power, 250);
+    assert!(usb_dev.self_powered());
+
+    let get_status = |usb_dev: &mut UsbDevice<_>| {
+        test_util::control_in(usb_dev, &mut [], Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Standard,
+            recipient: Recipient::Device,
+            request: Request::GET_STATUS,
+            value: 0,
+            index: 0,
+            length: 2,
+        }).expect("get status")
+    };
+
+    assert_eq!(get_status(&mut usb_dev), [0x01, 0x00]);
+
+    test_util::control_out(&mut usb_dev, &mut [], Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::SET_FEATURE,
+        value: Feature::DeviceRemoteWakeup.selector(),
+        index: 0,
+        length: 0,
+    }, &[]).expect("set feature");
+    assert_eq!(get_status(&mut usb_dev), [0x03, 0x00]);
+
+    // Losing the external supply only changes the status, not the descriptor
+    usb_dev.set_self_powered(false);
+    assert_eq!(get_status(&mut usb_dev), [0x02, 0x00]);
+
+    // Bus-powered devices report it from the start
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut []).expect("enumerate");
+    assert_eq!(result.configuration.attributes, 0x80);
+    assert_eq!(get_status(&mut usb_dev), [0x00, 0x00]);
+}
+
+#[test]
+fn request_matching() {
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Interface,
+        request: 0x42,
+        value: 0,
+        index: 0,
+        length: 0,
+    };
+
+    assert!(req.is_vendor(Recipient::Interface, 0x42));
+    assert!(req.is(RequestType::Vendor, Recipient::Interface, 0x42));
+    assert!(!req.is_vendor(Recipient::Device, 0x42));
+    assert!(!req.is_vendor(Recipient::Interface, 0x43));
+    assert!(!req.is_class(Recipient::Interface, 0x42));
+    assert!(!req.is_standard(Recipient::Interface, 0x42));
+
+    let req = Request { request_type: RequestType::Class, recipient: Recipient::Other, ..req };
+    assert!(req.is_class(Recipient::Other, 0x42));
+    assert!(!req.is_vendor(Recipient::Other, 0x42));
+
+    let req = Request {
+        request_type: RequestType::Standard,
+        recipient: Recipient::Endpoint,
+        request: Request::SYNCH_FRAME,
+        ..req
+    };
+    assert!(req.is_standard(Recipient::Endpoint, Request::SYNCH_FRAME));
+}
+
+struct SpaceCheckingClass {
+    iface: InterfaceNumber,
+    space: Cell<Option<(usize, usize, usize)>>,
+    overflowed: Cell<Option<u8>>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SpaceCheckingClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        let before = writer.remaining();
+        let position = writer.position();
+
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+
+        self.space.set(Some((position, before, writer.remaining())));
+
+        // Too long for the one byte bLength field, so this fails without writing anything
+        let res = writer.write(descriptor_type::CS_INTERFACE, &[0u8; 254]);
+        assert!(matches!(res, Err(UsbError::BufferOverflow)));
+
+        self.overflowed.set(writer.overflowed_descriptor_type());
+
+        Ok(())
+    }
+}
+
+#[test]
+fn descriptor_writer_space() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SpaceCheckingClass {
+        iface: usb_bus.interface(),
+        space: Cell::new(None),
+        overflowed: Cell::new(None),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+    assert_eq!(result.configuration.total_length, 9 + 9);
+
+    // Configuration descriptors are limited by wTotalLength, not the control buffer
+    let (position, before, after) = class.space.get().expect("descriptors written");
+    assert_eq!(position, 9);
+    assert_eq!(before, 0xffff - 9);
+    assert_eq!(after, before - 9);
+    assert_eq!(class.overflowed.get(), Some(descriptor_type::CS_INTERFACE));
+}
+
+struct SecondaryControlClass<'a, B: UsbBus> {
+    handled_out: EndpointOut<'a, B>,
+    _handled_in: EndpointIn<'a, B>,
+    ignored_out: EndpointOut<'a, B>,
+    _ignored_in: EndpointIn<'a, B>,
+    setups: Vec<[u8; 8]>,
+}
+
+impl<B: UsbBus> UsbClass<B> for SecondaryControlClass<'_, B> {
+    fn endpoint_setup(&mut self, addr: EndpointAddress) {
+        if addr == self.handled_out.address() {
+            let mut setup = [0u8; 8];
+            self.handled_out.read(&mut setup).expect("read setup");
+            self.setups.push(setup);
+        }
+    }
+
+    fn endpoint_setup_handled(&self, addr: EndpointAddress) -> bool {
+        addr == self.handled_out.address()
+    }
+}
+
+// Doesn't override endpoint_setup_handled
+struct UntrackedSetupClass;
+
+impl<B: UsbBus> UsbClass<B> for UntrackedSetupClass { }
+
+#[test]
+fn unhandled_endpoint_setup_stalls() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = SecondaryControlClass {
+        handled_out: usb_bus.control(8),
+        _handled_in: usb_bus.control(8),
+        ignored_out: usb_bus.control(8),
+        _ignored_in: usb_bus.control(8),
+        setups: Vec::new(),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let handled = class.handled_out.address();
+    let ignored = class.ignored_out.address();
+    let packet = [0xc0, 0x01, 0, 0, 0, 0, 0x08, 0];
+
+    usb_dev.bus().host_setup(handled.index(), packet);
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(class.setups, [packet]);
+    assert!(!usb_dev.bus().host_is_stalled(handled));
+
+    // A SETUP packet no class handles is discarded, and the endpoint is stalled in both
+    // directions so that the host doesn't wait for a response
+    usb_dev.bus().host_setup(ignored.index(), packet);
+    usb_dev.poll(&mut [&mut class]);
+
+    assert_eq!(class.setups, [packet]);
+    assert!(!usb_dev.bus().host_out_pending(ignored.index()));
+    assert!(usb_dev.bus().host_is_stalled(ignored));
+    assert!(usb_dev.bus().host_is_stalled(
+        EndpointAddress::from_parts(ignored.index(), UsbDirection::In)));
+
+    // The next SETUP packet clears the stall
+    usb_dev.bus().host_setup(ignored.index(), packet);
+    assert!(!usb_dev.bus().host_is_stalled(ignored));
+
+    // The stall is opt-in, so a class that doesn't track its endpoints prevents it
+    usb_dev.poll(&mut [&mut class, &mut UntrackedSetupClass]);
+
+    assert_eq!(class.setups, [packet]);
+    assert!(usb_dev.bus().host_out_pending(ignored.index()));
+    assert!(!usb_dev.bus().host_is_stalled(ignored));
+}
+
+#[test]
+fn unallocated_endpoint_setup_stalls() {
+    // Allocated on the bus behind the allocator's back, so no class can own it
+    let mut bus = EmulatedUsbBus::new();
+    let hidden = bus.alloc_ep(
+        UsbDirection::Out, Some(EndpointAddress::from(0x0e)), EndpointType::Control, 8, 0)
+        .expect("alloc");
+    bus.alloc_ep(
+        UsbDirection::In, Some(EndpointAddress::from(0x8e)), EndpointType::Control, 8, 0)
+        .expect("alloc");
+
+    let usb_bus = UsbBusAllocator::new(bus);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    test_util::enumerate(&mut usb_dev, &mut [&mut UntrackedSetupClass]).expect("enumerate");
+
+    // Stalled even though the class doesn't opt in
+    usb_dev.bus().host_setup(hidden.index(), [0xc0, 0x01, 0, 0, 0, 0, 0x08, 0]);
+    usb_dev.poll(&mut [&mut UntrackedSetupClass]);
+
+    assert!(!usb_dev.bus().host_out_pending(hidden.index()));
+    assert!(usb_dev.bus().host_is_stalled(hidden));
+    assert!(usb_dev.bus().host_is_stalled(EndpointAddress::from(0x8e)));
+}
+
+#[cfg(feature = "resend-last-packet")]
+#[test]
+fn resend_last_packet() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let ep: EndpointIn<_> = usb_bus.interrupt(8, 1);
+    let index = ep.address().index();
+    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    assert!(matches!(ep.resend_last(), Err(UsbError::InvalidState)));
+
+    ep.write(&[1, 2, 3]).expect("write");
+    assert_eq!(usb_dev.bus().host_read(index), Ok(Some(vec![1, 2, 3])));
+
+    ep.resend_last().expect("resend");
+    assert_eq!(usb_dev.bus().host_read(index), Ok(Some(vec![1, 2, 3])));
+}
+
+const MS_OS_VENDOR_CODE: u8 = 0x21;
+
+struct MsOs20Class {
+    iface: InterfaceNumber,
+    descriptor_set: &'static [u8],
+}
+
+impl MsOs20Class {
+    fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> MsOs20Class {
+        let iface = alloc.interface();
+        let mut buf = vec![0; 256];
+
+        let mut set = descriptor::msos20::MsOs20Descriptor::new(&mut buf).expect("set header");
+        set.configuration_subset(0).expect("configuration subset");
+        set.function_subset(iface).expect("function subset");
+        set.compatible_id("WINUSB", "").expect("compatible id");
+        set.device_interface_guid("{5a0f08a7-8a07-4e1c-9e3f-f7e8a15e4a5b}").expect("guid");
+        let len = set.finish();
+
+        buf.truncate(len);
+
+        MsOs20Class { iface, descriptor_set: Box::leak(buf.into_boxed_slice()) }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MsOs20Class {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        descriptor::msos20::capability(
+            writer, MS_OS_VENDOR_CODE, self.descriptor_set.len() as u16)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if descriptor::msos20::descriptor_set_request(xfer.request(), MS_OS_VENDOR_CODE) {
+            xfer.accept_with_static(self.descriptor_set).ok();
+        }
+    }
+}
+
+#[test]
+fn ms_os_20_descriptor_set() {
+    let usb_bus = UsbBusAllocator::new(EmulatedUsbBus::new());
+    let mut class = MsOs20Class::new(&usb_bus);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1234, 0x5678)).build();
+
+    let result = test_util::enumerate(&mut usb_dev, &mut [&mut class]).expect("enumerate");
+
+    let len = class.descriptor_set.len();
+    assert_eq!(len, 10 + 8 + 8 + 20 + 132);
+
+    let caps = result.bos.expect("bos").capabilities;
+    assert_eq!(caps[1].capability_type, capability_type::PLATFORM);
+    assert_eq!(caps[1].data[1..17], descriptor::msos20::PLATFORM_CAPABILITY_UUID);
+    assert_eq!(caps[1].data[17..],
+        [0x00, 0x00, 0x03, 0x06, len as u8, 0x00, MS_OS_VENDOR_CODE, 0x00]);
+
+    let set = test_util::control_in(&mut usb_dev, &mut [&mut class], Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request: MS_OS_VENDOR_CODE,
+        value: 0,
+        index: descriptor::msos20::DESCRIPTOR_INDEX,
+        length: len as u16,
+    }).expect("descriptor set");
+    assert_eq!(set, class.descriptor_set);
+
+    // Set header with the total length
+    assert_eq!(set[..10], [10, 0, 0, 0, 0x00, 0x00, 0x03, 0x06, len as u8, 0]);
+    // Configuration and function subsets including everything after them
+    assert_eq!(set[10..18], [8, 0, 1, 0, 0, 0, (len - 10) as u8, 0]);
+    assert_eq!(set[18..26], [8, 0, 2, 0, u8::from(class.iface), 0, (len - 18) as u8, 0]);
+    // Compatible ID
+    assert_eq!(set[26..30], [20, 0, 3, 0]);
+    assert_eq!(&set[30..46], b"WINUSB\0\0\0\0\0\0\0\0\0\0");
+    // Registry property with a REG_MULTI_SZ value
+    assert_eq!(set[46..52], [132, 0, 4, 0, 7, 0]);
+    assert_eq!(set[52..54], [42, 0]);
+    assert_eq!(set[len - 6..], [b'}', 0, 0, 0, 0, 0]);
+}
@@ -49,6 +49,31 @@ fn string_descriptors(dev, _out) {
         dev.read_string_descriptor(dev.en_us, 4, TIMEOUT)
             .expect("read custom string"),
         test_class::CUSTOM_STRING);
+
+    assert_eq!(
+        dev.read_string_descriptor(dev.en_us, 5, TIMEOUT)
+            .expect("read empty string"),
+        "");
+
+    match dev.read_string_descriptor(dev.en_us, 6, TIMEOUT) {
+        Err(Error::Pipe) => { },
+        res => panic!("unknown string index did not stall: {:?}", res),
+    }
+}
+
+fn invalid_configuration_descriptor(dev, _out) {
+    let mut response = [0u8; 255];
+
+    // GET_DESCRIPTOR(CONFIGURATION) for an index past the only configuration
+    let res = dev.read_control(
+        request_type(Direction::In, RequestType::Standard, Recipient::Device),
+        0x06, (0x02 << 8) | 5, 0,
+        &mut response, TIMEOUT);
+
+    match res {
+        Err(Error::Pipe) => { },
+        res => panic!("invalid configuration index did not stall: {:?}", res),
+    }
 }
 
 fn control_request(dev, _out) {